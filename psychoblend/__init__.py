@@ -50,6 +50,15 @@ class RenderPsychopathSettingsScene(PropertyGroup):
         min=0.0001, max=100.0, soft_min=0.125, soft_max=1.0, default=0.25
         )
 
+    shading = EnumProperty(
+        name="Shading", description="When surfaces are shaded",
+        items=[
+            ('Preshaded', 'Preshaded', "Dice surfaces into micropolygons and shade them before rendering"),
+            ('Direct', 'Direct', "Shade surfaces when rays hit them, and only dice meshes that are displaced"),
+        ],
+        default="Preshaded"
+        )
+
     motion_blur_segments = IntProperty(
         name="Motion Segments", description="The number of segments to use in motion blur.  Zero means no motion blur.  Will be rounded down to the nearest power of two.",
         min=0, max=256, default=0
@@ -65,6 +74,34 @@ class RenderPsychopathSettingsScene(PropertyGroup):
         min=-1.0, max=1.0, soft_min=0.0, soft_max=1.0, default=0.5
         )
 
+    shutter_curve = EnumProperty(
+        name="Shutter Curve", description="How the shutter opens and closes",
+        items=[
+            ('Box', 'Box', "The shutter opens and closes instantly"),
+            ('Trapezoid', 'Trapezoid', "The shutter opens and closes linearly"),
+        ],
+        default="Box"
+        )
+
+    shutter_ramp = FloatProperty(
+        name="Shutter Ramp", description="Fraction of the shutter interval that the shutter takes to open, and to close",
+        min=0.0, max=0.5, default=0.1
+        )
+
+    rolling_shutter = FloatProperty(
+        name="Rolling Shutter", description="Fraction of the shutter interval that the shutter takes to sweep from the top to the bottom of the image.  Zero means a global shutter",
+        min=0.0, max=1.0, default=0.0
+        )
+
+    bvh_splits = EnumProperty(
+        name="BVH Splits", description="How to split acceleration structure nodes when building them",
+        items=[
+            ('Object', 'Object', "Only partition objects between nodes"),
+            ('Spatial', 'Spatial', "Also split objects between nodes, which is slower to build but faster to render for long thin objects"),
+        ],
+        default="Object"
+        )
+
     export_path = StringProperty(
         name="Export Path", description="The path to where the .psy files should be exported when rendering.  If left blank, /tmp or the equivalent is used.",
         subtype='FILE_PATH'
@@ -77,6 +114,37 @@ class PsychopathCamera(bpy.types.PropertyGroup):
         min=0.0, max=10000.0, soft_min=0.0, soft_max=2.0, default=0.0
         )
 
+    aperture_blades = IntProperty(
+        name="Aperture Blades", description="Number of blades in the camera's aperture.  Zero gives a round aperture",
+        min=0, max=100, default=0
+        )
+
+    aperture_rotation = FloatProperty(
+        name="Aperture Rotation", description="Rotation of the aperture blades, in degrees",
+        min=-360.0, max=360.0, default=0.0
+        )
+
+    aperture_mask = StringProperty(
+        name="Aperture Mask", description="Optional grayscale PGM image to use as the shape of the aperture.  Overrides the aperture blades",
+        subtype='FILE_PATH'
+        )
+
+    panorama_type = EnumProperty(
+        name="Panorama Type", description="The projection to use for panoramic cameras",
+        items=[
+            ('Equirectangular', 'Equirectangular', ""),
+            ('Cylindrical', 'Cylindrical', ""),
+            ('FisheyeEquidistant', 'Fisheye Equidistant', ""),
+            ('FisheyeEquisolid', 'Fisheye Equisolid', ""),
+        ],
+        default="Equirectangular"
+        )
+
+    panorama_fov = FloatProperty(
+        name="Panorama FOV", description="Horizontal field of view of panoramic cameras, in degrees",
+        min=0.0, max=360.0, soft_min=0.0, soft_max=360.0, default=360.0
+        )
+
 # Psychopath material
 class PsychopathLight(bpy.types.PropertyGroup):
     color_type = EnumProperty(
@@ -101,11 +169,29 @@ class PsychopathMesh(bpy.types.PropertyGroup):
         default=False
         )
 
+# Custom particle system properties
+class PsychopathParticleSettings(bpy.types.PropertyGroup):
+    root_width = FloatProperty(
+        name="Root Width", description="Width of the hair strands at their roots",
+        min=0.0, soft_min=0.0, soft_max=0.1, default=0.002, precision=4
+        )
+
+    tip_width = FloatProperty(
+        name="Tip Width", description="Width of the hair strands at their tips",
+        min=0.0, soft_min=0.0, soft_max=0.1, default=0.0005, precision=4
+        )
+
+    shape = EnumProperty(
+        name="Shape", description="Cross-section shape of the hair strands",
+        items=[('Round', 'Round', "Round strands, like hair and fur"), ('Ribbon', 'Ribbon', "Flat strands that always face the viewer, cheaper but less accurate")],
+        default="Round"
+        )
+
 # Psychopath material
 class PsychopathMaterial(bpy.types.PropertyGroup):
     surface_shader_type = EnumProperty(
         name="Surface Shader Type", description="",
-        items=[('Emit', 'Emit', ""), ('Lambert', 'Lambert', ""), ('GGX', 'GGX', "")],
+        items=[('Emit', 'Emit', ""), ('Lambert', 'Lambert', ""), ('GGX', 'GGX', ""), ('Hair', 'Hair', ""), ('Subsurface', 'Subsurface', "")],
         default="Lambert"
         )
 
@@ -146,6 +232,12 @@ class PsychopathMaterial(bpy.types.PropertyGroup):
         min=0.0, max=1.0, soft_min=0.0, soft_max=1.0, default=0.9
         )
 
+    mean_free_path = FloatVectorProperty(
+        name="Mean Free Path", description="Average distance light travels inside the surface before scattering, per color channel",
+        min=0.0, soft_min=0.0, soft_max=1.0,
+        default=[1.0,0.5,0.25]
+        )
+
 
 # Addon Preferences
 class PsychopathPreferences(AddonPreferences):
@@ -169,11 +261,13 @@ def register():
     bpy.utils.register_class(PsychopathCamera)
     bpy.utils.register_class(PsychopathLight)
     bpy.utils.register_class(PsychopathMesh)
+    bpy.utils.register_class(PsychopathParticleSettings)
     bpy.utils.register_class(PsychopathMaterial)
     bpy.types.Scene.psychopath = PointerProperty(type=RenderPsychopathSettingsScene)
     bpy.types.Camera.psychopath = PointerProperty(type=PsychopathCamera)
     bpy.types.Lamp.psychopath = PointerProperty(type=PsychopathLight)
     bpy.types.Mesh.psychopath = PointerProperty(type=PsychopathMesh)
+    bpy.types.ParticleSettings.psychopath = PointerProperty(type=PsychopathParticleSettings)
     bpy.types.Material.psychopath = PointerProperty(type=PsychopathMaterial)
     render.register()
     ui.register()
@@ -185,11 +279,13 @@ def unregister():
     bpy.utils.unregister_class(PsychopathCamera)
     bpy.utils.unregister_class(PsychopathLight)
     bpy.utils.unregister_class(PsychopathMesh)
+    bpy.utils.unregister_class(PsychopathParticleSettings)
     bpy.utils.unregister_class(PsychopathMaterial)
     del bpy.types.Scene.psychopath
     del bpy.types.Camera.psychopath
     del bpy.types.Lamp.psychopath
     del bpy.types.Mesh.psychopath
+    del bpy.types.ParticleSettings.psychopath
     del bpy.types.Material.psychopath
     render.unregister()
     ui.unregister()
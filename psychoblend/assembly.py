@@ -40,6 +40,13 @@ class Assembly:
                         self.objects += [Assembly(self.render_engine, ob.dupli_group.objects, ob.dupli_group.layers, name, ob.dupli_group.dupli_offset*-1)]
             elif ob.type == 'MESH':
                 name = self.get_mesh(ob, group_prefix)
+
+                # Hair particle systems are exported as separate curve
+                # objects, each with its own instance.
+                for psys in ob.particle_systems:
+                    hair_name = self.get_hair(ob, psys, group_prefix)
+                    if hair_name != None:
+                        self.instances += [Instance(render_engine, ob, hair_name, self.get_psys_material(ob, psys))]
             elif ob.type == 'LAMP' and ob.data.type == 'POINT':
                 name = self.get_sphere_lamp(ob, group_prefix)
             elif ob.type == 'LAMP' and ob.data.type == 'AREA':
@@ -133,6 +140,29 @@ class Assembly:
             return None
 
 
+    def get_hair(self, ob, psys, group_prefix):
+        settings = psys.settings
+        if settings.type != 'HAIR' or settings.render_type != 'PATH' or len(psys.particles) == 0:
+            return None
+
+        name = group_prefix + escape_name("__" + ob.name + "__" + psys.name + "_")
+        self.objects += [Hair(self.render_engine, ob, psys, name)]
+
+        # Get material
+        mat = self.get_psys_material(ob, psys)
+        if mat != None and mat.name not in self.material_names:
+            self.material_names.add(mat.name)
+            self.materials += [Material(self.render_engine, mat)]
+
+        return name
+
+    def get_psys_material(self, ob, psys):
+        # The particle settings' material index is one-based.
+        index = psys.settings.material - 1
+        if index >= 0 and index < len(ob.material_slots):
+            return ob.material_slots[index].material
+        return None
+
     def get_sphere_lamp(self, ob, group_prefix):
         name = group_prefix + "__" + escape_name(ob.name)
         self.objects += [SphereLamp(self.render_engine, ob, name)]
@@ -197,11 +227,94 @@ class Mesh:
         w.write(" ".join([("%d"%v) for p in self.time_meshes[0].polygons for v in p.vertices]), False)
         w.write("]\n", False)
 
+        # Write creases, if it's a subdivision surface and has any
+        if self.ob.data.psychopath.is_subdivision_surface:
+            creases = [e for e in self.time_meshes[0].edges if e.crease > 0.0]
+            if len(creases) > 0:
+                w.write("Creases [")
+                w.write(" ".join([("%d %d %f" % (e.vertices[0], e.vertices[1], e.crease * 10.0)) for e in creases]), False)
+                w.write("]\n", False)
+
         # MeshSurface/SubdivisionSurface section end
         w.unindent()
         w.write("}\n")
 
 
+class Hair:
+    """ Holds data for a hair particle system to be exported.
+    """
+    def __init__(self, render_engine, ob, psys, name):
+        self.ob = ob
+        self.psys = psys
+        self.name = name
+        self.needs_mb = needs_def_mb(self.ob)
+        self.time_curves = []
+
+    def take_sample(self, render_engine, scene, time):
+        if len(self.time_curves) == 0 or self.needs_mb:
+            render_engine.update_stats("", "Psychopath: Collecting '{}' hair at time {}".format(self.ob.name, time))
+
+            settings = self.psys.settings
+            step_count = 2 ** settings.render_step
+            curve_count = len(self.psys.particles)
+            if settings.child_type != 'NONE':
+                curve_count += len(self.psys.child_particles)
+
+            # The hair positions are in world space, but we want them in
+            # the object's local space.
+            inv_mat = self.ob.matrix_world.inverted()
+
+            self.psys.set_resolution(scene, self.ob, 'RENDER')
+            curves = []
+            for pi in range(curve_count):
+                curves += [[inv_mat * self.psys.co_hair(self.ob, pi, step) for step in range(step_count + 1)]]
+            self.psys.set_resolution(scene, self.ob, 'PREVIEW')
+
+            self.time_curves += [curves]
+
+    def cleanup(self):
+        pass
+
+    def export(self, render_engine, w):
+        render_engine.update_stats("", "Psychopath: Exporting %s" % self.ob.name)
+
+        settings = self.psys.settings.psychopath
+
+        w.write("CurveSurface $%s {\n" % self.name)
+        w.indent()
+        w.write("Basis [BSpline]\n")
+        w.write("Shape [%s]\n" % settings.shape)
+
+        # Write vertices, tripling the end points so that the B-splines
+        # reach them.
+        for curves in self.time_curves:
+            w.write("Vertices [")
+            w.write(" ".join([("%f" % i) for curve in curves for vert in tripled_ends(curve) for i in vert]), False)
+            w.write("]\n", False)
+
+        # Write widths, tapering from root to tip
+        w.write("Widths [")
+        widths = []
+        for curve in self.time_curves[0]:
+            n = len(curve) - 1
+            widths += tripled_ends([settings.root_width + ((settings.tip_width - settings.root_width) * (i / n)) for i in range(n + 1)])
+        w.write(" ".join([("%f" % width) for width in widths]), False)
+        w.write("]\n", False)
+
+        # Write curve vertex counts
+        w.write("CurveVertCounts [")
+        w.write(" ".join([("%d" % (len(curve) + 4)) for curve in self.time_curves[0]]), False)
+        w.write("]\n", False)
+
+        # CurveSurface section end
+        w.unindent()
+        w.write("}\n")
+
+
+def tripled_ends(items):
+    return [items[0], items[0]] + list(items) + [items[-1], items[-1]]
+
+
 class SphereLamp:
     """ Holds data for a sphere light to be exported.
     """
@@ -292,9 +405,10 @@ class RectLamp:
 
 
 class Instance:
-    def __init__(self, render_engine, ob, data_name):
+    def __init__(self, render_engine, ob, data_name, material=None):
         self.ob = ob
         self.data_name = data_name
+        self.material = material
         self.needs_mb = needs_xform_mb(self.ob)
         self.time_xforms = []
 
@@ -315,10 +429,13 @@ class Instance:
         w.write("Data [$%s]\n" % self.data_name)
         for mat in self.time_xforms:
             w.write("Transform [%s]\n" % mat2str(mat.inverted()))
-        for ms in self.ob.material_slots:
-            if ms != None:
-                w.write("SurfaceShaderBind [$%s]\n" % escape_name(ms.material.name))
-                break
+        if self.material != None:
+            w.write("SurfaceShaderBind [$%s]\n" % escape_name(self.material.name))
+        else:
+            for ms in self.ob.material_slots:
+                if ms != None:
+                    w.write("SurfaceShaderBind [$%s]\n" % escape_name(ms.material.name))
+                    break
         w.unindent()
         w.write("}\n")
 
@@ -389,6 +506,45 @@ class Material:
                 ))
             w.write("Roughness [%f]\n" % self.mat.psychopath.roughness)
             w.write("Fresnel [%f]\n" % self.mat.psychopath.fresnel)
+        elif self.mat.psychopath.surface_shader_type == 'Hair':
+            w.write("Type [Hair]\n")
+            if self.mat.psychopath.color_type == 'Rec709':
+                col = self.mat.psychopath.color
+                w.write("Color [rec709, %f %f %f]\n" % (
+                    col[0], col[1], col[2],
+                ))
+            elif self.mat.psychopath.color_type == 'Blackbody':
+                w.write("Color [blackbody, %f %f]\n" % (
+                    self.mat.psychopath.color_blackbody_temp,
+                    1.0,
+                ))
+            elif self.mat.psychopath.color_type == 'ColorTemperature':
+                w.write("Color [color_temperature, %f %f]\n" % (
+                    self.mat.psychopath.color_blackbody_temp,
+                    1.0,
+                ))
+            w.write("Roughness [%f]\n" % self.mat.psychopath.roughness)
+        elif self.mat.psychopath.surface_shader_type == 'Subsurface':
+            w.write("Type [Subsurface]\n")
+            if self.mat.psychopath.color_type == 'Rec709':
+                col = self.mat.psychopath.color
+                w.write("Color [rec709, %f %f %f]\n" % (
+                    col[0], col[1], col[2],
+                ))
+            elif self.mat.psychopath.color_type == 'Blackbody':
+                w.write("Color [blackbody, %f %f]\n" % (
+                    self.mat.psychopath.color_blackbody_temp,
+                    1.0,
+                ))
+            elif self.mat.psychopath.color_type == 'ColorTemperature':
+                w.write("Color [color_temperature, %f %f]\n" % (
+                    self.mat.psychopath.color_blackbody_temp,
+                    1.0,
+                ))
+            mfp = self.mat.psychopath.mean_free_path
+            w.write("MeanFreePath [rec709, %f %f %f]\n" % (
+                mfp[0], mfp[1], mfp[2],
+            ))
         else:
             raise "Unsupported surface shader type '%s'" % self.mat.psychopath.surface_shader_type
         w.unindent()
@@ -43,9 +43,12 @@ class PsychoExporter:
         else:
             self.time_samples = 1
 
-        # pre-calculate useful values for exporting motion blur
-        self.shutter_start = scene.psychopath.shutter_start
-        self.shutter_diff = (scene.psychopath.shutter_end - scene.psychopath.shutter_start) / max(1, (self.time_samples-1))
+        # pre-calculate useful values for exporting motion blur.  The time
+        # samples span the frame, extended to cover the shutter interval if
+        # it falls outside of it.
+        self.time_start = min(0.0, scene.psychopath.shutter_start)
+        self.time_end = max(1.0, scene.psychopath.shutter_end)
+        self.time_diff = (self.time_end - self.time_start) / max(1, (self.time_samples-1))
 
         self.fr = scene.frame_current
 
@@ -97,8 +100,24 @@ class PsychoExporter:
         self.w.write('Resolution [%d %d]\n' % (res_x, res_y))
         self.w.write("SamplesPerPixel [%d]\n" % self.scene.psychopath.spp)
         self.w.write("DicingRate [%f]\n" % self.scene.psychopath.dicing_rate)
+        self.w.write('Shading [%s]\n' % self.scene.psychopath.shading)
         self.w.write('Seed [%d]\n' % self.fr)
 
+        # Shutter times in the .psy file are relative to the span of the
+        # time samples, rather than to the frame.
+        time_span = self.time_end - self.time_start
+        shutter_open = (self.scene.psychopath.shutter_start - self.time_start) / time_span
+        shutter_close = (self.scene.psychopath.shutter_end - self.time_start) / time_span
+        self.w.write('ShutterOpen [%f]\n' % shutter_open)
+        self.w.write('ShutterClose [%f]\n' % shutter_close)
+        if self.scene.psychopath.shutter_curve == 'Trapezoid':
+            self.w.write('ShutterCurve [Trapezoid %f]\n' % self.scene.psychopath.shutter_ramp)
+        if self.scene.psychopath.rolling_shutter > 0.0:
+            rolling = self.scene.psychopath.rolling_shutter * (shutter_close - shutter_open)
+            self.w.write('RollingShutter [%f]\n' % rolling)
+        if self.scene.psychopath.bvh_splits == 'Spatial':
+            self.w.write('BVHSplits [Spatial]\n')
+
         # RenderSettings section end
         self.w.unindent()
         self.w.write("}\n")
@@ -114,8 +133,8 @@ class PsychoExporter:
 
             # Collect data for each time sample
             for i in range(self.time_samples):
-                time = self.fr + self.shutter_start + (self.shutter_diff*i)
-                self.set_frame(self.fr, self.shutter_start + (self.shutter_diff*i))
+                time = self.fr + self.time_start + (self.time_diff*i)
+                self.set_frame(self.fr, self.time_start + (self.time_diff*i))
                 world.take_sample(self.render_engine, self.scene, time)
                 root_assembly.take_sample(self.render_engine, self.scene, time)
 
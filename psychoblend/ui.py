@@ -40,14 +40,20 @@ class RENDER_PT_psychopath_render_settings(PsychopathPanel, bpy.types.Panel):
 
         col.label(text="Dicing")
         col.prop(scene.psychopath, "dicing_rate")
+        col.prop(scene.psychopath, "shading")
 
         col.label(text="Motion Blur")
         col.prop(scene.psychopath, "motion_blur_segments")
         col.prop(scene.psychopath, "shutter_start")
         col.prop(scene.psychopath, "shutter_end")
+        col.prop(scene.psychopath, "shutter_curve")
+        if scene.psychopath.shutter_curve == 'Trapezoid':
+            col.prop(scene.psychopath, "shutter_ramp")
+        col.prop(scene.psychopath, "rolling_shutter")
 
         col.label(text="Performance")
         col.prop(scene.psychopath, "max_samples_per_bucket")
+        col.prop(scene.psychopath, "bvh_splits")
 
 
 class RENDER_PT_psychopath_export_settings(PsychopathPanel, bpy.types.Panel):
@@ -101,6 +107,30 @@ class DATA_PT_psychopath_camera_dof(PsychopathPanel, bpy.types.Panel):
         col.prop(ob.data, "dof_object")
         col.prop(ob.data, "dof_distance")
         col.prop(ob.data.psychopath, "aperture_radius")
+        col.prop(ob.data.psychopath, "aperture_blades")
+        col.prop(ob.data.psychopath, "aperture_rotation")
+        col.prop(ob.data.psychopath, "aperture_mask")
+
+
+class DATA_PT_psychopath_camera_panorama(PsychopathPanel, bpy.types.Panel):
+    bl_label = "Panorama"
+    bl_space_type = 'PROPERTIES'
+    bl_region_type = 'WINDOW'
+    bl_context = "data"
+
+    @classmethod
+    def poll(cls, context):
+        engine = context.scene.render.engine
+        return context.camera and context.camera.type == 'PANO' and PsychopathPanel.poll(context)
+
+    def draw(self, context):
+        ob = context.active_object
+        layout = self.layout
+
+        col = layout.column()
+
+        col.prop(ob.data.psychopath, "panorama_type")
+        col.prop(ob.data.psychopath, "panorama_fov")
 
 
 class DATA_PT_psychopath_lamp(PsychopathPanel, bpy.types.Panel):
@@ -183,6 +213,29 @@ class DATA_PT_psychopath_mesh(PsychopathPanel, bpy.types.Panel):
         layout.row().prop(mesh.psychopath, "is_subdivision_surface")
 
 
+class PARTICLE_PT_psychopath_hair(PsychopathPanel, bpy.types.Panel):
+    bl_label = "Psychopath Hair Properties"
+    bl_space_type = 'PROPERTIES'
+    bl_region_type = 'WINDOW'
+    bl_context = "particle"
+
+    @classmethod
+    def poll(cls, context):
+        engine = context.scene.render.engine
+        psys = context.particle_system
+        return psys and psys.settings.type == 'HAIR' and (engine in cls.COMPAT_ENGINES)
+
+    def draw(self, context):
+        layout = self.layout
+
+        settings = context.particle_system.settings
+
+        col = layout.column()
+        col.prop(settings.psychopath, "shape")
+        col.prop(settings.psychopath, "root_width")
+        col.prop(settings.psychopath, "tip_width")
+
+
 class MATERIAL_PT_psychopath_context_material(PsychopathPanel, bpy.types.Panel):
     bl_label = ""
     bl_space_type = "PROPERTIES"
@@ -266,15 +319,23 @@ class MATERIAL_PT_psychopath_surface(PsychopathPanel, bpy.types.Panel):
             layout.prop(mat.psychopath, "roughness")
             layout.prop(mat.psychopath, "fresnel")
 
+        if mat.psychopath.surface_shader_type == 'Hair':
+            layout.prop(mat.psychopath, "roughness")
+
+        if mat.psychopath.surface_shader_type == 'Subsurface':
+            layout.prop(mat.psychopath, "mean_free_path")
+
 
 def register():
     bpy.utils.register_class(RENDER_PT_psychopath_render_settings)
     bpy.utils.register_class(RENDER_PT_psychopath_export_settings)
     bpy.utils.register_class(WORLD_PT_psychopath_background)
     bpy.utils.register_class(DATA_PT_psychopath_camera_dof)
+    bpy.utils.register_class(DATA_PT_psychopath_camera_panorama)
     bpy.utils.register_class(DATA_PT_psychopath_mesh)
     bpy.utils.register_class(DATA_PT_psychopath_lamp)
     bpy.utils.register_class(DATA_PT_psychopath_area_lamp)
+    bpy.utils.register_class(PARTICLE_PT_psychopath_hair)
     bpy.utils.register_class(MATERIAL_PT_psychopath_context_material)
     bpy.utils.register_class(MATERIAL_PT_psychopath_surface)
 
@@ -283,8 +344,10 @@ def unregister():
     bpy.utils.unregister_class(RENDER_PT_psychopath_export_settings)
     bpy.utils.unregister_class(WORLD_PT_psychopath_background)
     bpy.utils.unregister_class(DATA_PT_psychopath_camera_dof)
+    bpy.utils.unregister_class(DATA_PT_psychopath_camera_panorama)
     bpy.utils.register_class(DATA_PT_psychopath_mesh)
     bpy.utils.unregister_class(DATA_PT_psychopath_lamp)
     bpy.utils.unregister_class(DATA_PT_psychopath_area_lamp)
+    bpy.utils.unregister_class(PARTICLE_PT_psychopath_hair)
     bpy.utils.unregister_class(MATERIAL_PT_psychopath_context_material)
     bpy.utils.unregister_class(MATERIAL_PT_psychopath_surface)
@@ -53,7 +53,15 @@ class Camera:
         self.ob = ob
         self.aspect_ratio = aspect_ratio
 
+        if self.ob.data.type == 'ORTHO':
+            self.camera_type = "Orthographic"
+        elif self.ob.data.type == 'PANO':
+            self.camera_type = self.ob.data.psychopath.panorama_type
+        else:
+            self.camera_type = "Perspective"
+
         self.fovs = []
+        self.ortho_widths = []
         self.aperture_radii = []
         self.focal_distances = []
         self.xforms = []
@@ -62,7 +70,14 @@ class Camera:
         render_engine.update_stats("", "Psychopath: Collecting '{}' at time {}".format(self.ob.name, time))
 
         # Fov
-        if self.aspect_ratio >= 1.0:
+        if self.camera_type == "Orthographic":
+            if self.aspect_ratio >= 1.0:
+                self.ortho_widths += [self.ob.data.ortho_scale]
+            else:
+                self.ortho_widths += [self.ob.data.ortho_scale * self.aspect_ratio]
+        elif self.camera_type != "Perspective":
+            self.fovs += [self.ob.data.psychopath.panorama_fov]
+        elif self.aspect_ratio >= 1.0:
             self.fovs += [degrees(self.ob.data.angle)]
         else:
             self.fovs += [degrees(2.0 * atan(tan(self.ob.data.angle * 0.5) * self.aspect_ratio))]
@@ -89,15 +104,27 @@ class Camera:
         w.write("Camera {\n")
         w.indent()
 
+        w.write("Type [%s]\n" % self.camera_type)
+
         for fov in self.fovs:
             w.write("Fov [%f]\n" % fov)
 
+        for width in self.ortho_widths:
+            w.write("OrthoWidth [%f]\n" % width)
+
         for rad in self.aperture_radii:
             w.write("ApertureRadius [%f]\n" % rad)
 
         for dist in self.focal_distances:
             w.write("FocalDistance [%f]\n" % dist)
 
+        aperture = self.ob.data.psychopath
+        if aperture.aperture_mask != "":
+            w.write("ApertureMask [\"%s\"]\n" % bpy.path.abspath(aperture.aperture_mask))
+        elif aperture.aperture_blades >= 3:
+            w.write("ApertureBlades [%d]\n" % aperture.aperture_blades)
+            w.write("ApertureRotation [%f]\n" % aperture.aperture_rotation)
+
         for mat in self.xforms:
             w.write("Transform [%s]\n" % mat2str(mat))
 
@@ -6,14 +6,11 @@
 
 use std::mem::{transmute, MaybeUninit};
 
-use glam::Vec4Mask;
-
 use kioku::Arena;
 
 use crate::{
     bbox::BBox,
     bbox4::BBox4,
-    boundable::Boundable,
     lerp::lerp_slice,
     math::Vector,
     ray::{RayBatch, RayStack},
@@ -21,7 +18,8 @@ use crate::{
 
 use super::{
     bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH},
-    ACCEL_NODE_RAY_TESTS,
+    wide_bvh::{WideBVHNode, WideBVHTree, WideBounds},
+    SplitMode, ACCEL_NODE_RAY_TESTS,
 };
 
 use bvh_order::{calc_traversal_code, SplitAxes, TRAVERSAL_TABLE};
@@ -33,26 +31,8 @@ pub fn ray_code(dir: Vector) -> usize {
         + ((ray_sign_is_neg[2] as usize) << 2)
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct BVH4<'a> {
-    root: Option<&'a BVH4Node<'a>>,
-    depth: usize,
-    node_count: usize,
-    _bounds: Option<&'a [BBox]>,
-}
-
-#[derive(Copy, Clone, Debug)]
-pub enum BVH4Node<'a> {
-    Internal {
-        bounds: &'a [BBox4],
-        children: &'a [BVH4Node<'a>],
-        traversal_code: u8,
-    },
-
-    Leaf {
-        object_range: (usize, usize),
-    },
-}
+pub type BVH4<'a> = WideBVHTree<'a, BBox4, u8>;
+pub type BVH4Node<'a> = WideBVHNode<'a, BBox4, u8>;
 
 impl<'a> BVH4<'a> {
     pub fn from_objects<'b, T, F>(
@@ -62,7 +42,8 @@ impl<'a> BVH4<'a> {
         bounder: F,
     ) -> BVH4<'a>
     where
-        F: 'b + Fn(&T) -> &'b [BBox],
+        T: Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
     {
         if objects.is_empty() {
             BVH4 {
@@ -73,29 +54,63 @@ impl<'a> BVH4<'a> {
             }
         } else {
             let base = BVHBase::from_objects(objects, objects_per_leaf, bounder);
+            BVH4::from_base(arena, &base)
+        }
+    }
 
-            let fill_node = arena.alloc_align_uninit::<BVH4Node>(32);
-            let node_count = BVH4::construct_from_base(
-                arena,
-                &base,
-                &base.nodes[base.root_node_index()],
-                fill_node,
+    /// Builds a BVH4 over the given objects with the given split mode.
+    ///
+    /// Returns the BVH4 along with the list of objects that its leaves
+    /// refer to, which should be used in place of `objects` when
+    /// traversing it.  See `BVHBase::from_objects_with_splits()` for
+    /// details.
+    pub fn from_objects_with_splits<'b, T, F, C>(
+        arena: &'a Arena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        split_mode: SplitMode,
+        bounder: F,
+        clipper: C,
+    ) -> (BVH4<'a>, Vec<T>)
+    where
+        T: Clone + Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+        C: Fn(&T, BBox, usize, f32) -> (BBox, BBox),
+    {
+        if objects.is_empty() {
+            let bvh = BVH4 {
+                root: None,
+                depth: 0,
+                node_count: 0,
+                _bounds: None,
+            };
+            (bvh, Vec::new())
+        } else {
+            let (base, objects) = BVHBase::from_objects_with_splits(
+                objects,
+                objects_per_leaf,
+                split_mode,
+                bounder,
+                clipper,
             );
-
-            BVH4 {
-                root: Some(unsafe { transmute(fill_node) }),
-                depth: (base.depth / 2) + 1,
-                node_count: node_count,
-                _bounds: {
-                    let range = base.nodes[base.root_node_index()].bounds_range();
-                    Some(arena.copy_slice(&base.bounds[range.0..range.1]))
-                },
-            }
+            (BVH4::from_base(arena, &base), objects)
         }
     }
 
-    pub fn tree_depth(&self) -> usize {
-        self.depth
+    fn from_base(arena: &'a Arena, base: &BVHBase) -> BVH4<'a> {
+        let fill_node = arena.alloc_align_uninit::<BVH4Node>(32);
+        let node_count =
+            BVH4::construct_from_base(arena, base, &base.nodes[base.root_node_index()], fill_node);
+
+        BVH4 {
+            root: Some(unsafe { transmute(fill_node) }),
+            depth: (base.depth / 2) + 1,
+            node_count: node_count,
+            _bounds: {
+                let range = base.nodes[base.root_node_index()].bounds_range();
+                Some(arena.copy_slice(&base.bounds[range.0..range.1]))
+            },
+        }
     }
 
     pub fn traverse<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, mut obj_ray_test: F)
@@ -113,6 +128,9 @@ impl<'a> BVH4<'a> {
 
         // +2 of max depth for root and last child
         let mut node_stack = [self.root.unwrap(); (BVH_MAX_DEPTH * 3) + 2];
+        // The time interval that the bounds of each node on the stack are
+        // sampled over, which is narrowed by temporal nodes.
+        let mut time_stack = [(0.0f32, 1.0f32); (BVH_MAX_DEPTH * 3) + 2];
         let mut stack_ptr = 1;
 
         while stack_ptr > 0 {
@@ -123,12 +141,14 @@ impl<'a> BVH4<'a> {
                     traversal_code,
                 } => {
                     node_tests += ray_stack.ray_count_in_next_task() as u64;
-                    let mut all_hits = Vec4Mask::default();
+                    let (t0, t1) = time_stack[stack_ptr];
+                    let time_scale = 1.0 / (t1 - t0);
+                    let mut all_hits = 0;
 
                     // Ray testing
                     ray_stack.pop_do_next_task_and_push_rays(children.len(), |ray_idx| {
                         if rays.is_done(ray_idx) {
-                            Vec4Mask::default()
+                            0
                         } else {
                             let hits = if bounds.len() == 1 {
                                 bounds[0].intersect_ray(
@@ -137,19 +157,24 @@ impl<'a> BVH4<'a> {
                                     rays.max_t(ray_idx),
                                 )
                             } else {
-                                lerp_slice(bounds, rays.time(ray_idx)).intersect_ray(
+                                lerp_slice(
+                                    bounds,
+                                    ((rays.time(ray_idx) - t0) * time_scale).max(0.0).min(1.0),
+                                )
+                                .intersect_ray(
                                     rays.orig_local(ray_idx),
                                     rays.dir_inv_local(ray_idx),
                                     rays.max_t(ray_idx),
                                 )
-                            };
+                            }
+                            .bitmask();
                             all_hits |= hits;
                             hits
                         }
                     });
 
                     // If there were any intersections, create tasks.
-                    if all_hits.any() {
+                    if all_hits != 0 {
                         let order_code = traversal_table[traversal_code as usize];
                         let mut lane_count = 0;
                         let mut i = children.len() as u8;
@@ -158,6 +183,48 @@ impl<'a> BVH4<'a> {
                             let child_i = ((order_code >> (i * 2)) & 3) as usize;
                             if ray_stack.push_lane_to_task(child_i) {
                                 node_stack[stack_ptr + lane_count] = &children[child_i];
+                                time_stack[stack_ptr + lane_count] = (t0, t1);
+                                lane_count += 1;
+                            }
+                        }
+
+                        stack_ptr += lane_count - 1;
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                BVH4Node::Temporal {
+                    split_time,
+                    children,
+                } => {
+                    let (t0, t1) = time_stack[stack_ptr];
+                    let mut all_hits = 0;
+
+                    // Send each ray to the child whose time interval it's in.
+                    ray_stack.pop_do_next_task_and_push_rays(2, |ray_idx| {
+                        if rays.is_done(ray_idx) {
+                            0
+                        } else {
+                            let hits = if rays.time(ray_idx) < split_time {
+                                0b01
+                            } else {
+                                0b10
+                            };
+                            all_hits |= hits;
+                            hits
+                        }
+                    });
+
+                    // If any rays are left, create tasks.
+                    if all_hits != 0 {
+                        let mut lane_count = 0;
+                        for &(child_i, time_range) in
+                            [(1, (split_time, t1)), (0, (t0, split_time))].iter()
+                        {
+                            if ray_stack.push_lane_to_task(child_i) {
+                                node_stack[stack_ptr + lane_count] = &children[child_i];
+                                time_stack[stack_ptr + lane_count] = time_range;
                                 lane_count += 1;
                             }
                         }
@@ -227,7 +294,7 @@ impl<'a> BVH4<'a> {
                                 ];
                                 split_info = SplitAxes::Full((split_axis, s_l, s_r));
                             }
-                            BVHBaseNode::Leaf { .. } => {
+                            _ => {
                                 // Three nodes with left split
                                 child_count = 3;
                                 children = [
@@ -240,7 +307,7 @@ impl<'a> BVH4<'a> {
                             }
                         }
                     }
-                    BVHBaseNode::Leaf { .. } => {
+                    _ => {
                         match *child_r {
                             BVHBaseNode::Internal {
                                 children_indices: i_r,
@@ -257,7 +324,7 @@ impl<'a> BVH4<'a> {
                                 ];
                                 split_info = SplitAxes::Right((split_axis, s_r));
                             }
-                            BVHBaseNode::Leaf { .. } => {
+                            _ => {
                                 // Two nodes
                                 child_count = 2;
                                 children = [Some(child_l), Some(child_r), None, None];
@@ -343,6 +410,38 @@ impl<'a> BVH4<'a> {
                 }
             }
 
+            // Create temporal node
+            BVHBaseNode::Temporal {
+                children_indices,
+                split_time,
+                ..
+            } => {
+                node_count += 2;
+
+                // Construct child nodes
+                let child_nodes = arena.alloc_array_align_uninit::<BVH4Node>(2, 32);
+                node_count += BVH4::construct_from_base(
+                    arena,
+                    base,
+                    &base.nodes[children_indices.0],
+                    &mut child_nodes[0],
+                );
+                node_count += BVH4::construct_from_base(
+                    arena,
+                    base,
+                    &base.nodes[children_indices.1],
+                    &mut child_nodes[1],
+                );
+
+                // Build this node
+                unsafe {
+                    *fill_node.as_mut_ptr() = BVH4Node::Temporal {
+                        split_time: split_time,
+                        children: transmute(child_nodes),
+                    };
+                }
+            }
+
             // Create internal node
             BVHBaseNode::Leaf { object_range, .. } => {
                 unsafe {
@@ -358,8 +457,12 @@ impl<'a> BVH4<'a> {
     }
 }
 
-impl<'a> Boundable for BVH4<'a> {
-    fn bounds<'b>(&'b self) -> &'b [BBox] {
-        self._bounds.unwrap_or(&[])
+impl WideBounds for BBox4 {
+    const WIDTH: usize = 4;
+    const CACHE_KIND: [u8; 4] = *b"BVH4";
+
+    fn from_bboxes(bboxes: &[BBox]) -> BBox4 {
+        let lane = |i: usize| bboxes.get(i).copied().unwrap_or_else(BBox::new);
+        BBox4::from_bboxes(lane(0), lane(1), lane(2), lane(3))
     }
 }
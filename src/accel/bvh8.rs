@@ -0,0 +1,482 @@
+//! An 8-wide version of the BVH4, for CPUs that can test all eight child
+//! bounding boxes of a node at once.  See bvh4.rs for the ideas behind it.
+
+#![allow(dead_code)]
+
+use std::mem::{transmute, MaybeUninit};
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    bbox8::{simd8_is_supported, BBox8},
+    lerp::lerp_slice,
+    math::{Point, Vector},
+    ray::{RayBatch, RayStack},
+};
+
+use super::{
+    bvh4::ray_code,
+    bvh_base::{BVHBase, BVHBaseNode, BVH_MAX_DEPTH},
+    wide_bvh::{WideBVHNode, WideBVHTree, WideBounds},
+    SplitMode, ACCEL_NODE_RAY_TESTS,
+};
+
+use bvh_order::{calc_traversal_code_8, SplitAxes8, SubSplitAxes, TRAVERSAL_TABLE_8};
+
+pub type BVH8<'a> = WideBVHTree<'a, BBox8, u16>;
+pub type BVH8Node<'a> = WideBVHNode<'a, BBox8, u16>;
+
+impl<'a> BVH8<'a> {
+    pub fn from_objects<'b, T, F>(
+        arena: &'a Arena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> BVH8<'a>
+    where
+        T: Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+    {
+        if objects.is_empty() {
+            BVH8 {
+                root: None,
+                depth: 0,
+                node_count: 0,
+                _bounds: None,
+            }
+        } else {
+            let base = BVHBase::from_objects(objects, objects_per_leaf, bounder);
+            BVH8::from_base(arena, &base)
+        }
+    }
+
+    /// Builds a BVH8 over the given objects with the given split mode.
+    ///
+    /// See `BVH4::from_objects_with_splits()` for details.
+    pub fn from_objects_with_splits<'b, T, F, C>(
+        arena: &'a Arena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        split_mode: SplitMode,
+        bounder: F,
+        clipper: C,
+    ) -> (BVH8<'a>, Vec<T>)
+    where
+        T: Clone + Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+        C: Fn(&T, BBox, usize, f32) -> (BBox, BBox),
+    {
+        if objects.is_empty() {
+            let bvh = BVH8 {
+                root: None,
+                depth: 0,
+                node_count: 0,
+                _bounds: None,
+            };
+            (bvh, Vec::new())
+        } else {
+            let (base, objects) = BVHBase::from_objects_with_splits(
+                objects,
+                objects_per_leaf,
+                split_mode,
+                bounder,
+                clipper,
+            );
+            (BVH8::from_base(arena, &base), objects)
+        }
+    }
+
+    fn from_base(arena: &'a Arena, base: &BVHBase) -> BVH8<'a> {
+        let fill_node = arena.alloc_align_uninit::<BVH8Node>(32);
+        let node_count =
+            BVH8::construct_from_base(arena, base, &base.nodes[base.root_node_index()], fill_node);
+
+        BVH8 {
+            root: Some(unsafe { transmute(fill_node) }),
+            depth: (base.depth / 3) + 1,
+            node_count: node_count,
+            _bounds: {
+                let range = base.nodes[base.root_node_index()].bounds_range();
+                Some(arena.copy_slice(&base.bounds[range.0..range.1]))
+            },
+        }
+    }
+
+    pub fn traverse<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, obj_ray_test: F)
+    where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+    {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            if simd8_is_supported() {
+                unsafe {
+                    self.traverse_avx2(rays, ray_stack, obj_ray_test);
+                }
+                return;
+            }
+        }
+
+        self.traverse_with(rays, ray_stack, obj_ray_test, |b, orig, dir_inv, max_t| {
+            b.intersect_ray(orig, dir_inv, max_t)
+        });
+    }
+
+    // Traverses with the whole traversal loop compiled for AVX2, so that the
+    // box tests can be inlined into it.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[target_feature(enable = "avx2")]
+    unsafe fn traverse_avx2<F>(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        obj_ray_test: F,
+    ) where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+    {
+        self.traverse_with(rays, ray_stack, obj_ray_test, |b, orig, dir_inv, max_t| {
+            b.intersect_ray_avx2(orig, dir_inv, max_t)
+        });
+    }
+
+    #[inline(always)]
+    fn traverse_with<F, B>(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        mut obj_ray_test: F,
+        intersect_bbox8: B,
+    ) where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+        B: Fn(&BBox8, Point, Vector, f32) -> u32,
+    {
+        if self.root.is_none() {
+            return;
+        }
+
+        let mut node_tests: u64 = 0;
+
+        let traversal_table =
+            &TRAVERSAL_TABLE_8[ray_code(rays.dir_inv_local(ray_stack.next_task_ray_idx(0)))];
+
+        // +2 of max depth for root and last child
+        let mut node_stack = [self.root.unwrap(); (BVH_MAX_DEPTH * 7) + 2];
+        // The time interval that the bounds of each node on the stack are
+        // sampled over, which is narrowed by temporal nodes.
+        let mut time_stack = [(0.0f32, 1.0f32); (BVH_MAX_DEPTH * 7) + 2];
+        let mut stack_ptr = 1;
+
+        while stack_ptr > 0 {
+            match *node_stack[stack_ptr] {
+                BVH8Node::Internal {
+                    bounds,
+                    children,
+                    traversal_code,
+                } => {
+                    node_tests += ray_stack.ray_count_in_next_task() as u64;
+                    let (t0, t1) = time_stack[stack_ptr];
+                    let time_scale = 1.0 / (t1 - t0);
+                    let mut all_hits = 0;
+
+                    // Ray testing
+                    ray_stack.pop_do_next_task_and_push_rays(children.len(), |ray_idx| {
+                        if rays.is_done(ray_idx) {
+                            0
+                        } else {
+                            let hits = if bounds.len() == 1 {
+                                intersect_bbox8(
+                                    &bounds[0],
+                                    rays.orig_local(ray_idx),
+                                    rays.dir_inv_local(ray_idx),
+                                    rays.max_t(ray_idx),
+                                )
+                            } else {
+                                intersect_bbox8(
+                                    &lerp_slice(
+                                        bounds,
+                                        ((rays.time(ray_idx) - t0) * time_scale).max(0.0).min(1.0),
+                                    ),
+                                    rays.orig_local(ray_idx),
+                                    rays.dir_inv_local(ray_idx),
+                                    rays.max_t(ray_idx),
+                                )
+                            };
+                            all_hits |= hits;
+                            hits
+                        }
+                    });
+
+                    // If there were any intersections, create tasks.
+                    if all_hits != 0 {
+                        let order_code = traversal_table[traversal_code as usize];
+                        let mut lane_count = 0;
+                        let mut i = children.len() as u32;
+                        while i > 0 {
+                            i -= 1;
+                            let child_i = ((order_code >> (i * 3)) & 7) as usize;
+                            if ray_stack.push_lane_to_task(child_i) {
+                                node_stack[stack_ptr + lane_count] = &children[child_i];
+                                time_stack[stack_ptr + lane_count] = (t0, t1);
+                                lane_count += 1;
+                            }
+                        }
+
+                        stack_ptr += lane_count - 1;
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                BVH8Node::Temporal {
+                    split_time,
+                    children,
+                } => {
+                    let (t0, t1) = time_stack[stack_ptr];
+                    let mut all_hits = 0;
+
+                    // Send each ray to the child whose time interval it's in.
+                    ray_stack.pop_do_next_task_and_push_rays(2, |ray_idx| {
+                        if rays.is_done(ray_idx) {
+                            0
+                        } else {
+                            let hits = if rays.time(ray_idx) < split_time {
+                                0b01
+                            } else {
+                                0b10
+                            };
+                            all_hits |= hits;
+                            hits
+                        }
+                    });
+
+                    // If any rays are left, create tasks.
+                    if all_hits != 0 {
+                        let mut lane_count = 0;
+                        for &(child_i, time_range) in
+                            [(1, (split_time, t1)), (0, (t0, split_time))].iter()
+                        {
+                            if ray_stack.push_lane_to_task(child_i) {
+                                node_stack[stack_ptr + lane_count] = &children[child_i];
+                                time_stack[stack_ptr + lane_count] = time_range;
+                                lane_count += 1;
+                            }
+                        }
+
+                        stack_ptr += lane_count - 1;
+                    } else {
+                        stack_ptr -= 1;
+                    }
+                }
+
+                BVH8Node::Leaf { object_range } => {
+                    // Do the ray tests.
+                    obj_ray_test(object_range.0..object_range.1, rays, ray_stack);
+
+                    stack_ptr -= 1;
+                }
+            }
+        }
+
+        ACCEL_NODE_RAY_TESTS.with(|anv| {
+            let v = anv.get();
+            anv.set(v + node_tests);
+        });
+    }
+
+    fn construct_from_base(
+        arena: &'a Arena,
+        base: &BVHBase,
+        node: &BVHBaseNode,
+        fill_node: &mut MaybeUninit<BVH8Node<'a>>,
+    ) -> usize {
+        let mut node_count = 0;
+
+        match *node {
+            // Create internal node
+            BVHBaseNode::Internal {
+                children_indices,
+                split_axis,
+                ..
+            } => {
+                // Collect the up-to-eight nodes three levels down that will
+                // be this node's children, along with the splits that led
+                // to them.
+                let mut children = Vec::with_capacity(8);
+                let left = collect_sub_tree(base, &base.nodes[children_indices.0], &mut children);
+                let right = collect_sub_tree(base, &base.nodes[children_indices.1], &mut children);
+                let split_info = SplitAxes8 {
+                    top: split_axis,
+                    left: left,
+                    right: right,
+                };
+                let child_count = children.len();
+
+                node_count += child_count;
+
+                // Construct bounds
+                let bounds = {
+                    let bounds_len = children
+                        .iter()
+                        .map(|c| {
+                            let len = c.bounds_range().1 - c.bounds_range().0;
+                            debug_assert!(len >= 1);
+                            len
+                        })
+                        .max()
+                        .unwrap();
+                    debug_assert!(bounds_len >= 1);
+                    let bounds = arena.alloc_array_align_uninit(bounds_len, 32);
+                    if bounds_len < 2 {
+                        let child_bounds: Vec<BBox> = children
+                            .iter()
+                            .map(|c| base.bounds[c.bounds_range().0])
+                            .collect();
+                        unsafe {
+                            *bounds[0].as_mut_ptr() = BBox8::from_bboxes(&child_bounds);
+                        }
+                    } else {
+                        for (i, b) in bounds.iter_mut().enumerate() {
+                            let time = i as f32 / (bounds_len - 1) as f32;
+
+                            let child_bounds: Vec<BBox> = children
+                                .iter()
+                                .map(|c| {
+                                    let (x, y) = c.bounds_range();
+                                    lerp_slice(&base.bounds[x..y], time)
+                                })
+                                .collect();
+                            unsafe {
+                                *b.as_mut_ptr() = BBox8::from_bboxes(&child_bounds);
+                            }
+                        }
+                    }
+                    bounds
+                };
+
+                // Construct child nodes
+                let child_nodes = arena.alloc_array_align_uninit::<BVH8Node>(child_count, 32);
+                for (i, c) in children.iter().enumerate() {
+                    node_count += BVH8::construct_from_base(arena, base, c, &mut child_nodes[i]);
+                }
+
+                // Build this node
+                unsafe {
+                    *fill_node.as_mut_ptr() = BVH8Node::Internal {
+                        bounds: transmute(bounds),
+                        children: transmute(child_nodes),
+                        traversal_code: calc_traversal_code_8(split_info),
+                    };
+                }
+            }
+
+            // Create temporal node
+            BVHBaseNode::Temporal {
+                children_indices,
+                split_time,
+                ..
+            } => {
+                node_count += 2;
+
+                // Construct child nodes
+                let child_nodes = arena.alloc_array_align_uninit::<BVH8Node>(2, 32);
+                node_count += BVH8::construct_from_base(
+                    arena,
+                    base,
+                    &base.nodes[children_indices.0],
+                    &mut child_nodes[0],
+                );
+                node_count += BVH8::construct_from_base(
+                    arena,
+                    base,
+                    &base.nodes[children_indices.1],
+                    &mut child_nodes[1],
+                );
+
+                // Build this node
+                unsafe {
+                    *fill_node.as_mut_ptr() = BVH8Node::Temporal {
+                        split_time: split_time,
+                        children: transmute(child_nodes),
+                    };
+                }
+            }
+
+            // Create internal node
+            BVHBaseNode::Leaf { object_range, .. } => {
+                unsafe {
+                    *fill_node.as_mut_ptr() = BVH8Node::Leaf {
+                        object_range: object_range,
+                    };
+                }
+                node_count += 1;
+            }
+        }
+
+        return node_count;
+    }
+}
+
+/// Collects the nodes up to two levels below `node` that will become
+/// children of a BVH8 node, in left-to-right order, and returns the splits
+/// that led to them.
+fn collect_sub_tree<'b>(
+    base: &'b BVHBase,
+    node: &'b BVHBaseNode,
+    children: &mut Vec<&'b BVHBaseNode>,
+) -> Option<SubSplitAxes> {
+    match *node {
+        BVHBaseNode::Internal {
+            children_indices,
+            split_axis,
+            ..
+        } => {
+            let left = collect_split(base, &base.nodes[children_indices.0], children);
+            let right = collect_split(base, &base.nodes[children_indices.1], children);
+            Some(SubSplitAxes {
+                top: split_axis,
+                left: left,
+                right: right,
+            })
+        }
+
+        _ => {
+            children.push(node);
+            None
+        }
+    }
+}
+
+/// Collects the nodes up to one level below `node` that will become
+/// children of a BVH8 node, in left-to-right order, and returns the split
+/// that led to them.
+fn collect_split<'b>(
+    base: &'b BVHBase,
+    node: &'b BVHBaseNode,
+    children: &mut Vec<&'b BVHBaseNode>,
+) -> Option<u8> {
+    match *node {
+        BVHBaseNode::Internal {
+            children_indices,
+            split_axis,
+            ..
+        } => {
+            children.push(&base.nodes[children_indices.0]);
+            children.push(&base.nodes[children_indices.1]);
+            Some(split_axis)
+        }
+
+        _ => {
+            children.push(node);
+            None
+        }
+    }
+}
+
+impl WideBounds for BBox8 {
+    const WIDTH: usize = 8;
+    const CACHE_KIND: [u8; 4] = *b"BVH8";
+
+    fn from_bboxes(bboxes: &[BBox]) -> BBox8 {
+        BBox8::from_bboxes(bboxes)
+    }
+}
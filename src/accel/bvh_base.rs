@@ -1,16 +1,36 @@
 #![allow(dead_code)]
 
-use crate::{algorithm::merge_slices_append, bbox::BBox, lerp::lerp_slice, math::log2_64};
+use crate::{
+    algorithm::merge_slices_append,
+    bbox::BBox,
+    lerp::{lerp, lerp_slice},
+    math::log2_64,
+};
 
-use super::objects_split::{median_split, sah_split};
+use super::{
+    build_thread_count,
+    objects_split::{
+        median_split, parallel_sah_split, sah_split, spatial_split_plane, split_references,
+        SplitReference,
+    },
+    SplitMode,
+};
 
 pub const BVH_MAX_DEPTH: usize = 42;
 
+// Object count below which subtrees are always built on a single thread,
+// since spawning threads for them would cost more than it saves.
+const PARALLEL_BUILD_MIN_OBJECTS: usize = 1 << 12;
+
 // Amount bigger the union of all time samples can be
 // and still use the union rather than preserve the
 // individual time samples.
 const USE_UNION_FACTOR: f32 = 1.4;
 
+// The shortest time interval, as a fraction of the shutter interval, that
+// temporal splits will still split in two.
+const MIN_TEMPORAL_SPLIT_INTERVAL: f32 = 1.0 / 32.0;
+
 /// An intermediary structure for creating a BVH.
 #[derive(Debug)]
 pub struct BVHBase {
@@ -32,14 +52,23 @@ pub enum BVHBaseNode {
         bounds_range: (usize, usize),
         object_range: (usize, usize),
     },
+
+    // Splits the time interval of the node between its children at
+    // `split_time`, rather than splitting its objects.  The bounds of
+    // each child are sampled over just its part of the interval.
+    Temporal {
+        bounds_range: (usize, usize),
+        children_indices: (usize, usize),
+        split_time: f32,
+    },
 }
 
 impl BVHBaseNode {
     pub fn bounds_range(&self) -> (usize, usize) {
         match *self {
-            BVHBaseNode::Internal { bounds_range, .. } | BVHBaseNode::Leaf { bounds_range, .. } => {
-                bounds_range
-            }
+            BVHBaseNode::Internal { bounds_range, .. }
+            | BVHBaseNode::Leaf { bounds_range, .. }
+            | BVHBaseNode::Temporal { bounds_range, .. } => bounds_range,
         }
     }
 }
@@ -54,15 +83,88 @@ impl BVHBase {
         }
     }
 
+    /// Builds a BVH over the given objects, reordering them to match.
+    ///
+    /// The build is split over as many threads as `build_thread_count()`,
+    /// both by building subtrees in parallel and by binning large nodes in
+    /// parallel.  The resulting tree is the same regardless of the thread
+    /// count.
     pub fn from_objects<'b, T, F>(objects: &mut [T], objects_per_leaf: usize, bounder: F) -> BVHBase
     where
-        F: 'b + Fn(&T) -> &'b [BBox],
+        T: Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
     {
         let mut bvh = BVHBase::new();
-        bvh.recursive_build(0, 0, objects_per_leaf, objects, &bounder);
+        bvh.recursive_build(
+            0,
+            0,
+            objects_per_leaf,
+            objects,
+            &bounder,
+            build_thread_count(),
+        );
         bvh
     }
 
+    /// Builds a BVH over the given objects with the given split mode.
+    ///
+    /// Since spatial splits can split objects between nodes, this doesn't
+    /// reorder `objects`, but instead returns the list of objects that the
+    /// BVH's leaves refer to, some of which may be duplicates.
+    ///
+    /// `clipper(object, bounds, axis, pos)` should return the bounds of the
+    /// parts of `object` that are within `bounds` and below and above the
+    /// plane at `pos` on `axis`, the same as `BBox::split()`.
+    ///
+    /// Objects with more than one time sample of bounds are never clipped.
+    /// Instead, the parts of the tree with any of those in them also
+    /// consider temporal splits, which split the time interval of a node
+    /// between its children.  See `recursive_build_temporal()`.  The rest of
+    /// the tree is built according to the split mode.
+    pub fn from_objects_with_splits<'b, T, F, C>(
+        objects: &[T],
+        objects_per_leaf: usize,
+        split_mode: SplitMode,
+        bounder: F,
+        clipper: C,
+    ) -> (BVHBase, Vec<T>)
+    where
+        T: Clone + Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+        C: Fn(&T, BBox, usize, f32) -> (BBox, BBox),
+    {
+        let min_overlap_area = match split_mode {
+            SplitMode::Spatial(overlap_threshold) => {
+                let root_area = objects
+                    .iter()
+                    .flat_map(|obj| bounder(obj).iter())
+                    .fold(BBox::new(), |b1, b2| b1 | *b2)
+                    .surface_area();
+                Some(root_area * overlap_threshold)
+            }
+
+            SplitMode::Object => None,
+        };
+
+        let mut bvh = BVHBase::new();
+        let mut leaf_order = Vec::new();
+        if !objects.is_empty() {
+            bvh.recursive_build_temporal(
+                0,
+                objects_per_leaf,
+                (0..objects.len()).collect(),
+                (0.0, 1.0),
+                &|i| bounder(&objects[i]),
+                min_overlap_area,
+                &|i, bounds, axis, pos| clipper(&objects[i], bounds, axis, pos),
+                &mut leaf_order,
+            );
+        }
+
+        let objects = leaf_order.iter().map(|&i| objects[i].clone()).collect();
+        (bvh, objects)
+    }
+
     pub fn root_node_index(&self) -> usize {
         0
     }
@@ -93,6 +195,456 @@ impl BVHBase {
         }
     }
 
+    /// Appends the nodes and bounds of another BVHBase to this one,
+    /// returning the new index and bounds range of its root node.
+    fn append(&mut self, other: BVHBase) -> (usize, (usize, usize)) {
+        let node_offset = self.nodes.len();
+        let bounds_offset = self.bounds.len();
+        let offset_range =
+            |range: (usize, usize), offset: usize| (range.0 + offset, range.1 + offset);
+
+        self.nodes
+            .extend(other.nodes.iter().map(|node| match *node {
+                BVHBaseNode::Internal {
+                    bounds_range,
+                    children_indices,
+                    split_axis,
+                } => BVHBaseNode::Internal {
+                    bounds_range: offset_range(bounds_range, bounds_offset),
+                    children_indices: offset_range(children_indices, node_offset),
+                    split_axis: split_axis,
+                },
+
+                BVHBaseNode::Leaf {
+                    bounds_range,
+                    object_range,
+                } => BVHBaseNode::Leaf {
+                    bounds_range: offset_range(bounds_range, bounds_offset),
+                    object_range: object_range,
+                },
+
+                BVHBaseNode::Temporal {
+                    bounds_range,
+                    children_indices,
+                    split_time,
+                } => BVHBaseNode::Temporal {
+                    bounds_range: offset_range(bounds_range, bounds_offset),
+                    children_indices: offset_range(children_indices, node_offset),
+                    split_time: split_time,
+                },
+            }));
+        self.bounds.extend_from_slice(&other.bounds);
+        self.depth = self.depth.max(other.depth);
+
+        let root_bounds_range = other.nodes[other.root_node_index()].bounds_range();
+        (
+            node_offset + other.root_node_index(),
+            offset_range(root_bounds_range, bounds_offset),
+        )
+    }
+
+    /// Pushes time-sampled bounds onto `bounds`, reducing them to their
+    /// union if it's not worth keeping the individual time samples, and
+    /// returns their range.
+    fn push_bounds(&mut self, bounds: &[BBox]) -> (usize, usize) {
+        let bi = self.bounds.len();
+        let union_bounds = bounds.iter().fold(BBox::new(), |b1, b2| b1 | *b2);
+        if union_bounds.surface_area() <= (time_averaged_area(bounds) * USE_UNION_FACTOR) {
+            self.bounds.push(union_bounds);
+        } else {
+            self.bounds.extend_from_slice(bounds);
+        }
+        (bi, self.bounds.len())
+    }
+
+    /// The temporal split counterpart of `recursive_build()`, for when
+    /// there are moving objects.
+    ///
+    /// Each node is split either by partitioning its objects or by splitting
+    /// its time interval, `time_range`, in half, whichever a time-aware SAH
+    /// favors.  A temporal split gives both children all of the node's
+    /// objects, but since each child's bounds only need to cover its half of
+    /// the interval, they're much tighter for fast moving objects.
+    ///
+    /// Subtrees without any moving objects are handed off to
+    /// `build_static()` instead, along with `min_overlap_area` and
+    /// `clipper`.
+    ///
+    /// `refs` are indices of the objects, which `bounder` returns the bounds
+    /// of.  The indices of the objects referenced by each leaf are appended
+    /// to `leaf_order`, which the leaves' object ranges index into.
+    #[allow(clippy::too_many_arguments)]
+    fn recursive_build_temporal<'a, B, C>(
+        &mut self,
+        depth: usize,
+        objects_per_leaf: usize,
+        refs: Vec<usize>,
+        time_range: (f32, f32),
+        bounder: &B,
+        min_overlap_area: Option<f32>,
+        clipper: &C,
+        leaf_order: &mut Vec<usize>,
+    ) -> (usize, (usize, usize))
+    where
+        B: 'a + Fn(usize) -> &'a [BBox] + Sync,
+        C: Fn(usize, BBox, usize, f32) -> (BBox, BBox),
+    {
+        if refs.iter().all(|&r| bounder(r).len() == 1) {
+            return self.build_static(
+                depth,
+                objects_per_leaf,
+                refs,
+                bounder,
+                min_overlap_area,
+                clipper,
+                leaf_order,
+            );
+        }
+
+        let me = self.nodes.len();
+
+        // Get the bounds of the objects over this node's time interval.
+        let mut store = Vec::new();
+        let mut items: Vec<(usize, (usize, usize))> = refs
+            .iter()
+            .map(|&r| {
+                let start = store.len();
+                interval_bounds(bounder(r), time_range, &mut store);
+                (r, (start, store.len()))
+            })
+            .collect();
+        let bounds = union_bounds(items.iter().map(|item| &store[(item.1).0..(item.1).1]));
+
+        if items.len() <= objects_per_leaf {
+            // Leaf node
+            let bounds_range = self.push_bounds(&bounds);
+            let start = leaf_order.len();
+            leaf_order.extend(items.iter().map(|item| item.0));
+            self.nodes.push(BVHBaseNode::Leaf {
+                bounds_range: bounds_range,
+                object_range: (start, leaf_order.len()),
+            });
+
+            if self.depth < depth {
+                self.depth = depth;
+            }
+
+            return (me, bounds_range);
+        }
+
+        // Not a leaf node
+        self.nodes.push(BVHBaseNode::Internal {
+            bounds_range: (0, 0),
+            children_indices: (0, 0),
+            split_axis: 0,
+        });
+
+        // Partition the objects, the same as `recursive_build()`.
+        let room_to_play = (log2_64(items.len() as u64) as usize) < (BVH_MAX_DEPTH - depth);
+        let (split_index, split_axis) = {
+            let store = &store[..];
+            let item_bounder = |item: &(usize, (usize, usize))| &store[(item.1).0..(item.1).1];
+            if room_to_play {
+                sah_split(&mut items[..], &item_bounder)
+            } else {
+                median_split(&mut items[..], &item_bounder)
+            }
+        };
+
+        // See if splitting the time interval does better than the object
+        // partitioning.  The SAH cost of each side is weighted by the
+        // fraction of the node's time interval that it covers.
+        let (t0, t1) = time_range;
+        let split_time = (t0 + t1) * 0.5;
+        let temporal_split = room_to_play
+            && depth < (BVH_MAX_DEPTH / 2)
+            && (t1 - t0) > MIN_TEMPORAL_SPLIT_INTERVAL
+            && bounds.len() > 1
+            && {
+                let object_cost = {
+                    let side_cost = |items: &[(usize, (usize, usize))]| {
+                        let bounds =
+                            union_bounds(items.iter().map(|item| &store[(item.1).0..(item.1).1]));
+                        time_averaged_area(&bounds) * items.len() as f32
+                    };
+                    side_cost(&items[..split_index]) + side_cost(&items[split_index..])
+                };
+                let temporal_cost = {
+                    let half_cost = |half_range: (f32, f32)| {
+                        let mut half_store = Vec::new();
+                        let ranges: Vec<_> = items
+                            .iter()
+                            .map(|item| {
+                                let start = half_store.len();
+                                interval_bounds(bounder(item.0), half_range, &mut half_store);
+                                (start, half_store.len())
+                            })
+                            .collect();
+                        let bounds = union_bounds(ranges.iter().map(|r| &half_store[r.0..r.1]));
+                        time_averaged_area(&bounds) * items.len() as f32 * 0.5
+                    };
+                    half_cost((t0, split_time)) + half_cost((split_time, t1))
+                };
+                temporal_cost < object_cost
+            };
+        drop(store);
+
+        // Create child nodes
+        let node = if temporal_split {
+            let refs: Vec<usize> = items.iter().map(|item| item.0).collect();
+            let (c1_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                objects_per_leaf,
+                refs.clone(),
+                (t0, split_time),
+                bounder,
+                min_overlap_area,
+                clipper,
+                leaf_order,
+            );
+            let (c2_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                objects_per_leaf,
+                refs,
+                (split_time, t1),
+                bounder,
+                min_overlap_area,
+                clipper,
+                leaf_order,
+            );
+            BVHBaseNode::Temporal {
+                bounds_range: self.push_bounds(&bounds),
+                children_indices: (c1_index, c2_index),
+                split_time: split_time,
+            }
+        } else {
+            let (c1_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                objects_per_leaf,
+                items[..split_index].iter().map(|item| item.0).collect(),
+                time_range,
+                bounder,
+                min_overlap_area,
+                clipper,
+                leaf_order,
+            );
+            let (c2_index, _) = self.recursive_build_temporal(
+                depth + 1,
+                objects_per_leaf,
+                items[split_index..].iter().map(|item| item.0).collect(),
+                time_range,
+                bounder,
+                min_overlap_area,
+                clipper,
+                leaf_order,
+            );
+            BVHBaseNode::Internal {
+                bounds_range: self.push_bounds(&bounds),
+                children_indices: (c1_index, c2_index),
+                split_axis: split_axis as u8,
+            }
+        };
+
+        // Set node
+        let bounds_range = node.bounds_range();
+        self.nodes[me] = node;
+
+        (me, bounds_range)
+    }
+
+    /// Builds a subtree over objects that all have a single time sample of
+    /// bounds, with `recursive_build()` or, if `min_overlap_area` is given,
+    /// `recursive_build_spatial()`.
+    ///
+    /// The arguments are the same as for `recursive_build_temporal()`.
+    #[allow(clippy::too_many_arguments)]
+    fn build_static<'a, B, C>(
+        &mut self,
+        depth: usize,
+        objects_per_leaf: usize,
+        mut refs: Vec<usize>,
+        bounder: &B,
+        min_overlap_area: Option<f32>,
+        clipper: &C,
+        leaf_order: &mut Vec<usize>,
+    ) -> (usize, (usize, usize))
+    where
+        B: 'a + Fn(usize) -> &'a [BBox] + Sync,
+        C: Fn(usize, BBox, usize, f32) -> (BBox, BBox),
+    {
+        if let Some(min_overlap_area) = min_overlap_area {
+            let mut store: Vec<_> = refs
+                .iter()
+                .map(|&r| SplitReference {
+                    index: r,
+                    bounds: bounder(r)[0],
+                })
+                .collect();
+            self.recursive_build_spatial(
+                depth,
+                objects_per_leaf,
+                (0..store.len()).collect(),
+                &mut store,
+                leaf_order,
+                min_overlap_area,
+                clipper,
+            )
+        } else {
+            let offset = leaf_order.len();
+            let node = self.recursive_build(
+                offset,
+                depth,
+                objects_per_leaf,
+                &mut refs[..],
+                &|&r: &usize| bounder(r),
+                build_thread_count(),
+            );
+            leaf_order.extend_from_slice(&refs);
+            node
+        }
+    }
+
+    /// The spatial split counterpart of `recursive_build()`.
+    ///
+    /// `refs` are indices into `store`, which holds the actual references.
+    /// The indices of the objects referenced by each leaf are appended to
+    /// `leaf_order`, which the leaves' object ranges index into.
+    #[allow(clippy::too_many_arguments)]
+    fn recursive_build_spatial<C>(
+        &mut self,
+        depth: usize,
+        objects_per_leaf: usize,
+        mut refs: Vec<usize>,
+        store: &mut Vec<SplitReference>,
+        leaf_order: &mut Vec<usize>,
+        min_overlap_area: f32,
+        clipper: &C,
+    ) -> (usize, (usize, usize))
+    where
+        C: Fn(usize, BBox, usize, f32) -> (BBox, BBox),
+    {
+        let me = self.nodes.len();
+        let bounds = refs.iter().fold(BBox::new(), |b, &r| b | store[r].bounds);
+
+        if refs.len() <= objects_per_leaf {
+            // Leaf node
+            let bi = self.bounds.len();
+            self.bounds.push(bounds);
+            let start = leaf_order.len();
+            leaf_order.extend(refs.iter().map(|&r| store[r].index));
+            self.nodes.push(BVHBaseNode::Leaf {
+                bounds_range: (bi, bi + 1),
+                object_range: (start, leaf_order.len()),
+            });
+
+            if self.depth < depth {
+                self.depth = depth;
+            }
+
+            return (me, (bi, bi + 1));
+        }
+
+        // Not a leaf node
+        self.nodes.push(BVHBaseNode::Internal {
+            bounds_range: (0, 0),
+            children_indices: (0, 0),
+            split_axis: 0,
+        });
+
+        // Partition the references, the same as `recursive_build()`.
+        let room_to_play = (log2_64(refs.len() as u64) as usize) < (BVH_MAX_DEPTH - depth);
+        let (split_index, split_axis) = {
+            let store = &store[..];
+            let ref_bounder = |&r: &usize| std::slice::from_ref(&store[r].bounds);
+            if room_to_play {
+                sah_split(&mut refs[..], &ref_bounder)
+            } else {
+                median_split(&mut refs[..], &ref_bounder)
+            }
+        };
+
+        // If the two sides overlap too much, see if a spatial split does
+        // better.  Spatial splits can make the tree deeper than the object
+        // count suggests, so they're only done with plenty of room to play.
+        let spatial_split = {
+            let bounds_1 = refs[..split_index]
+                .iter()
+                .fold(BBox::new(), |b, &r| b | store[r].bounds);
+            let bounds_2 = refs[split_index..]
+                .iter()
+                .fold(BBox::new(), |b, &r| b | store[r].bounds);
+            let overlap = bounds_1 & bounds_2;
+            let overlap_area = if overlap.is_empty() {
+                0.0
+            } else {
+                overlap.surface_area()
+            };
+
+            if room_to_play && depth < (BVH_MAX_DEPTH / 2) && overlap_area > min_overlap_area {
+                let object_cost = (bounds_1.surface_area() * split_index as f32)
+                    + (bounds_2.surface_area() * (refs.len() - split_index) as f32);
+                match spatial_split_plane(&refs, store, bounds, clipper) {
+                    Some((axis, pos, cost)) if cost < object_cost => {
+                        let (refs_1, refs_2) = split_references(&refs, store, axis, pos, clipper);
+                        // Make sure it actually splits things up, so that
+                        // the recursion terminates.
+                        if !refs_1.is_empty()
+                            && !refs_2.is_empty()
+                            && refs_1.len() < refs.len()
+                            && refs_2.len() < refs.len()
+                        {
+                            Some((refs_1, refs_2, axis))
+                        } else {
+                            None
+                        }
+                    }
+                    _ => None,
+                }
+            } else {
+                None
+            }
+        };
+        let (refs_1, refs_2, split_axis) = if let Some(split) = spatial_split {
+            split
+        } else {
+            let refs_2 = refs.split_off(split_index);
+            (refs, refs_2, split_axis)
+        };
+
+        // Create child nodes
+        let (c1_index, c1_bounds) = self.recursive_build_spatial(
+            depth + 1,
+            objects_per_leaf,
+            refs_1,
+            store,
+            leaf_order,
+            min_overlap_area,
+            clipper,
+        );
+        let (c2_index, c2_bounds) = self.recursive_build_spatial(
+            depth + 1,
+            objects_per_leaf,
+            refs_2,
+            store,
+            leaf_order,
+            min_overlap_area,
+            clipper,
+        );
+
+        // Set node
+        let bi = self.bounds.len();
+        self.bounds
+            .push(self.bounds[c1_bounds.0] | self.bounds[c2_bounds.0]);
+        self.nodes[me] = BVHBaseNode::Internal {
+            bounds_range: (bi, bi + 1),
+            children_indices: (c1_index, c2_index),
+            split_axis: split_axis as u8,
+        };
+
+        (me, (bi, bi + 1))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn recursive_build<'a, T, F>(
         &mut self,
         offset: usize,
@@ -100,9 +652,11 @@ impl BVHBase {
         objects_per_leaf: usize,
         objects: &mut [T],
         bounder: &F,
+        thread_count: usize,
     ) -> (usize, (usize, usize))
     where
-        F: 'a + Fn(&T) -> &'a [BBox],
+        T: Send + Sync,
+        F: 'a + Fn(&T) -> &'a [BBox] + Sync,
     {
         let me = self.nodes.len();
 
@@ -158,27 +712,64 @@ impl BVHBase {
             let (split_index, split_axis) =
                 if (log2_64(objects.len() as u64) as usize) < (BVH_MAX_DEPTH - depth) {
                     // SAH splitting, when we have room to play
-                    sah_split(objects, &bounder)
+                    parallel_sah_split(objects, bounder, thread_count)
                 } else {
                     // Balanced splitting, when we don't have room to play
                     median_split(objects, &bounder)
                 };
 
             // Create child nodes
-            let (c1_index, c1_bounds) = self.recursive_build(
-                offset,
-                depth + 1,
-                objects_per_leaf,
-                &mut objects[..split_index],
-                bounder,
-            );
-            let (c2_index, c2_bounds) = self.recursive_build(
-                offset + split_index,
-                depth + 1,
-                objects_per_leaf,
-                &mut objects[split_index..],
-                bounder,
-            );
+            let ((c1_index, c1_bounds), (c2_index, c2_bounds)) =
+                if thread_count > 1 && objects.len() >= PARALLEL_BUILD_MIN_OBJECTS {
+                    // Build the second child on another thread, splitting the
+                    // threads between the children.  It gets built as a
+                    // separate BVHBase, which is then appended to this one.
+                    let (objects_1, objects_2) = objects.split_at_mut(split_index);
+                    let thread_count_2 = thread_count / 2;
+                    let thread_count_1 = thread_count - thread_count_2;
+                    let (c1, bvh_2) = crossbeam::scope(|scope| {
+                        let handle = scope.spawn(move || {
+                            let mut bvh_2 = BVHBase::new();
+                            bvh_2.recursive_build(
+                                offset + split_index,
+                                depth + 1,
+                                objects_per_leaf,
+                                objects_2,
+                                bounder,
+                                thread_count_2,
+                            );
+                            bvh_2
+                        });
+                        let c1 = self.recursive_build(
+                            offset,
+                            depth + 1,
+                            objects_per_leaf,
+                            objects_1,
+                            bounder,
+                            thread_count_1,
+                        );
+                        (c1, handle.join())
+                    });
+                    (c1, self.append(bvh_2))
+                } else {
+                    let c1 = self.recursive_build(
+                        offset,
+                        depth + 1,
+                        objects_per_leaf,
+                        &mut objects[..split_index],
+                        bounder,
+                        thread_count,
+                    );
+                    let c2 = self.recursive_build(
+                        offset + split_index,
+                        depth + 1,
+                        objects_per_leaf,
+                        &mut objects[split_index..],
+                        bounder,
+                        thread_count,
+                    );
+                    (c1, c2)
+                };
 
             // Determine bounds
             // TODO: do merging without the temporary vec.
@@ -214,3 +805,322 @@ impl BVHBase {
         }
     }
 }
+
+/// Returns the average surface area of time-sampled bounds over their time
+/// interval, which is what the time-aware SAH weighs nodes by.
+fn time_averaged_area(bounds: &[BBox]) -> f32 {
+    bounds.iter().fold(0.0, |area, bb| area + bb.surface_area()) / bounds.len() as f32
+}
+
+/// Returns the union of time-sampled bounds, with as many time samples as
+/// the most of any of them.
+pub fn union_bounds<'a, I>(bounds: I) -> Vec<BBox>
+where
+    I: Iterator<Item = &'a [BBox]> + Clone,
+{
+    let max_len = bounds.clone().map(|b| b.len()).max().unwrap_or(1);
+    let mut union = vec![BBox::new(); max_len];
+    for b in bounds {
+        if b.len() == max_len {
+            for (u, b) in union.iter_mut().zip(b.iter()) {
+                *u |= *b;
+            }
+        } else {
+            let s = (max_len - 1) as f32;
+            for (i, u) in union.iter_mut().enumerate() {
+                *u |= lerp_slice(b, i as f32 / s);
+            }
+        }
+    }
+    union
+}
+
+/// Returns the union of the bounds of objects over `time_range`, the same
+/// as the builds bound a node containing those objects.
+pub fn objects_interval_bounds<'a, I>(bounds: I, time_range: (f32, f32)) -> Vec<BBox>
+where
+    I: Iterator<Item = &'a [BBox]>,
+{
+    let mut store = Vec::new();
+    let ranges: Vec<_> = bounds
+        .map(|b| {
+            let start = store.len();
+            interval_bounds(b, time_range, &mut store);
+            (start, store.len())
+        })
+        .collect();
+    union_bounds(ranges.iter().map(|r| &store[r.0..r.1]))
+}
+
+/// Appends time samples of `bounds`, which are evenly spaced over the whole
+/// shutter interval, that are instead evenly spaced over just `time_range`.
+///
+/// There are as many new samples as needed to keep the same time resolution.
+/// Where the original samples don't line up with the new ones, the new ones
+/// are grown so that interpolating them still bounds the original motion.
+fn interval_bounds(bounds: &[BBox], time_range: (f32, f32), out: &mut Vec<BBox>) {
+    let (t0, t1) = time_range;
+    if bounds.len() == 1 || (t0 == 0.0 && t1 == 1.0) {
+        out.extend_from_slice(bounds);
+        return;
+    }
+
+    let segments = (bounds.len() - 1) as f32;
+    let new_segments = ((t1 - t0) * segments).ceil().max(1.0) as usize;
+    let start = out.len();
+    out.extend(
+        (0..=new_segments)
+            .map(|i| lerp_slice(bounds, lerp(t0, t1, i as f32 / new_segments as f32))),
+    );
+
+    // The motion between samples is linear, so the new samples only need to
+    // be grown to bound the original samples that fall between them.
+    for (i, b) in bounds.iter().enumerate() {
+        let t = i as f32 / segments;
+        if t <= t0 || t >= t1 {
+            continue;
+        }
+        let n = (t - t0) / (t1 - t0) * new_segments as f32;
+        let seg = (n as usize).min(new_segments - 1);
+        let approx = lerp(out[start + seg], out[start + seg + 1], n - seg as f32);
+        let grow_min = approx.min - approx.min.min(b.min);
+        let grow_max = approx.max.max(b.max) - approx.max;
+        for bb in &mut out[(start + seg)..(start + seg + 2)] {
+            bb.min = bb.min - grow_min;
+            bb.max = bb.max + grow_max;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{hash::hash_u32_to_f32, math::Point};
+
+    // The bounds of a row of `count` boxes with `samples` time samples
+    // each, with the row mirrored at every other sample, so that the boxes
+    // keep crossing each other.
+    fn crossing_bounds(count: usize, samples: usize) -> Vec<Vec<BBox>> {
+        (0..count)
+            .map(|i| {
+                (0..samples)
+                    .map(|s| {
+                        let x = if s % 2 == 0 { i } else { count - 1 - i } as f32;
+                        let y = (i % 3) as f32;
+                        BBox::from_points(Point::new(x, y, 0.0), Point::new(x + 0.5, y + 0.5, 0.5))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn build(bounds: &[Vec<BBox>], split_mode: SplitMode) -> (BVHBase, Vec<usize>) {
+        let objects: Vec<usize> = (0..bounds.len()).collect();
+        BVHBase::from_objects_with_splits(
+            &objects,
+            1,
+            split_mode,
+            |&i| &bounds[i][..],
+            |&i, clip_bounds, axis, pos| (bounds[i][0] & clip_bounds).split(axis, pos),
+        )
+    }
+
+    // Calls `f` with each node of `bvh` and the time interval it covers.
+    fn visit_nodes<F>(bvh: &BVHBase, node_index: usize, time_range: (f32, f32), f: &mut F)
+    where
+        F: FnMut(&BVHBaseNode, (f32, f32)),
+    {
+        let node = &bvh.nodes[node_index];
+        f(node, time_range);
+        match *node {
+            BVHBaseNode::Internal {
+                children_indices, ..
+            } => {
+                visit_nodes(bvh, children_indices.0, time_range, f);
+                visit_nodes(bvh, children_indices.1, time_range, f);
+            }
+
+            BVHBaseNode::Temporal {
+                children_indices,
+                split_time,
+                ..
+            } => {
+                visit_nodes(bvh, children_indices.0, (time_range.0, split_time), f);
+                visit_nodes(bvh, children_indices.1, (split_time, time_range.1), f);
+            }
+
+            BVHBaseNode::Leaf { .. } => {}
+        }
+    }
+
+    #[test]
+    fn temporal_splits_stop_at_min_interval() {
+        // Identical boxes that grow over time.  Partitioning them doesn't
+        // help at all, but since the surface area of a growing box is
+        // convex over time, the time-averaged area from the ends of each
+        // interval always goes down when it's split.  So the build would
+        // keep splitting time if it weren't for the limit.
+        let bounds = vec![
+            vec![
+                BBox::from_points(Point::new(0.0, 0.0, 0.0), Point::new(0.1, 0.1, 0.1)),
+                BBox::from_points(
+                    Point::new(-10.0, -10.0, -10.0),
+                    Point::new(10.0, 10.0, 10.0)
+                ),
+            ];
+            8
+        ];
+        let (bvh, _) = build(&bounds, SplitMode::Object);
+
+        let mut shortest_interval = 1.0f32;
+        visit_nodes(
+            &bvh,
+            bvh.root_node_index(),
+            (0.0, 1.0),
+            &mut |node, (t0, t1)| {
+                if let BVHBaseNode::Temporal { .. } = *node {
+                    assert!((t1 - t0) > MIN_TEMPORAL_SPLIT_INTERVAL);
+                }
+                shortest_interval = shortest_interval.min(t1 - t0);
+            },
+        );
+        assert_eq!(shortest_interval, MIN_TEMPORAL_SPLIT_INTERVAL);
+    }
+
+    #[test]
+    fn static_objects_skip_temporal_splits() {
+        // Moving boxes, followed by a grid of static boxes off to the side,
+        // with a long static bar across the grid.
+        let mut bounds = crossing_bounds(16, 2);
+        let moving_count = bounds.len();
+        for i in 0..64 {
+            let p = Point::new(100.0 + (i % 8) as f32, (i / 8) as f32, 0.0);
+            let bb = BBox::from_points(p, Point::new(p.x() + 0.5, p.y() + 0.5, 0.5));
+            bounds.push(vec![bb]);
+        }
+        let bar = bounds.len();
+        bounds.push(vec![BBox::from_points(
+            Point::new(100.0, 3.6, 0.0),
+            Point::new(107.5, 3.9, 0.5),
+        )]);
+
+        for &split_mode in &[SplitMode::Object, SplitMode::Spatial(0.0)] {
+            let (bvh, leaf_objects) = build(&bounds, split_mode);
+
+            // Find the objects that are under temporal nodes.
+            let mut temporal_count = 0;
+            let mut temporal_objects = Vec::new();
+            visit_nodes(&bvh, bvh.root_node_index(), (0.0, 1.0), &mut |node, _| {
+                if let BVHBaseNode::Temporal {
+                    children_indices, ..
+                } = *node
+                {
+                    temporal_count += 1;
+                    for &child in &[children_indices.0, children_indices.1] {
+                        visit_nodes(&bvh, child, (0.0, 1.0), &mut |node, _| {
+                            if let BVHBaseNode::Leaf { object_range, .. } = *node {
+                                temporal_objects.extend_from_slice(
+                                    &leaf_objects[object_range.0..object_range.1],
+                                );
+                            }
+                        });
+                    }
+                }
+            });
+
+            assert!(temporal_count > 0);
+            assert!(temporal_objects.iter().all(|&i| i < moving_count));
+
+            // The static objects are built as they would be without any
+            // moving objects, so only spatial splits duplicate them.
+            let leaf_count = |i| leaf_objects.iter().filter(|&&j| j == i).count();
+            for i in moving_count..bar {
+                assert_eq!(leaf_count(i), 1);
+            }
+            match split_mode {
+                SplitMode::Object => assert_eq!(leaf_count(bar), 1),
+                SplitMode::Spatial(_) => assert!(leaf_count(bar) > 1),
+            }
+        }
+    }
+
+    // Boxes scattered over a cube, every fifth of them moving.
+    fn scattered_bounds(count: usize) -> Vec<Vec<BBox>> {
+        let rand = |n: usize| hash_u32_to_f32(n as u32, 0) * 100.0;
+        (0..count)
+            .map(|i| {
+                let p = Point::new(rand(i * 4), rand(i * 4 + 1), rand(i * 4 + 2));
+                let size = rand(i * 4 + 3) * 0.01;
+                let bb = BBox::from_points(p, Point::new(p.x() + size, p.y() + size, p.z() + size));
+                if i % 5 == 0 {
+                    vec![bb, bb.expanded(size)]
+                } else {
+                    vec![bb]
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn thread_count_doesnt_change_tree() {
+        // Enough boxes to build subtrees in parallel.
+        let bounds = scattered_bounds(PARALLEL_BUILD_MIN_OBJECTS * 2 + 100);
+        let build_with = |thread_count| {
+            let mut objects: Vec<usize> = (0..bounds.len()).collect();
+            let mut bvh = BVHBase::new();
+            bvh.recursive_build(0, 0, 4, &mut objects, &|&i| &bounds[i][..], thread_count);
+            (
+                format!("{:?}", bvh.nodes),
+                format!("{:?}", bvh.bounds),
+                bvh.depth,
+                objects,
+            )
+        };
+
+        let single = build_with(1);
+        for &thread_count in &[2, 3, 8] {
+            let multi = build_with(thread_count);
+            assert!(
+                single.0 == multi.0,
+                "nodes differ with {} threads",
+                thread_count
+            );
+            assert!(
+                single.1 == multi.1,
+                "bounds differ with {} threads",
+                thread_count
+            );
+            assert_eq!(single.2, multi.2);
+            assert!(
+                single.3 == multi.3,
+                "objects differ with {} threads",
+                thread_count
+            );
+        }
+    }
+
+    #[test]
+    fn thread_count_doesnt_change_sah_split() {
+        // Enough boxes to bin in parallel (see `PARALLEL_BINNING_MIN_OBJECTS`
+        // in objects_split.rs).
+        let bounds = scattered_bounds((1 << 16) + 100);
+        let split_with = |thread_count| {
+            let mut objects: Vec<usize> = (0..bounds.len()).collect();
+            let split =
+                parallel_sah_split(&mut objects, &|&i: &usize| &bounds[i][..], thread_count);
+            (split, objects)
+        };
+
+        let single = split_with(1);
+        for &thread_count in &[2, 3, 8] {
+            let multi = split_with(thread_count);
+            assert_eq!(single.0, multi.0);
+            assert!(
+                single.1 == multi.1,
+                "objects differ with {} threads",
+                thread_count
+            );
+        }
+    }
+}
@@ -3,6 +3,7 @@ use kioku::Arena;
 use crate::{
     bbox::BBox,
     math::{Normal, Point, Vector},
+    normal_cone::NormalCone,
     shading::surface_closure::SurfaceClosure,
 };
 
@@ -12,6 +13,7 @@ use super::LightAccel;
 pub struct LightArray<'a> {
     indices: &'a [usize],
     aprx_energy: f32,
+    cone: NormalCone,
 }
 
 impl<'a> LightArray<'a> {
@@ -22,21 +24,24 @@ impl<'a> LightArray<'a> {
         info_getter: F,
     ) -> LightArray<'a>
     where
-        F: 'b + Fn(&T) -> (&'b [BBox], f32),
+        F: 'b + Fn(&T) -> (&'b [BBox], f32, NormalCone),
     {
         let mut indices = Vec::new();
         let mut aprx_energy = 0.0;
+        let mut cone: Option<NormalCone> = None;
         for (i, thing) in objects.iter().enumerate() {
-            let (_, power) = info_getter(thing);
+            let (_, power, thing_cone) = info_getter(thing);
             if power > 0.0 {
                 indices.push(i);
                 aprx_energy += power;
+                cone = Some(cone.map_or(thing_cone, |c| c.union(&thing_cone)));
             }
         }
 
         LightArray {
             indices: arena.copy_slice(&indices),
             aprx_energy: aprx_energy,
+            cone: cone.unwrap_or_else(NormalCone::full_sphere),
         }
     }
 }
@@ -76,4 +81,24 @@ impl<'a> LightAccel for LightArray<'a> {
     fn approximate_energy(&self) -> f32 {
         self.aprx_energy
     }
+
+    fn normal_cone(&self) -> NormalCone {
+        self.cone
+    }
+
+    fn approximate_contribution(
+        &self,
+        inc: Vector,
+        pos: Point,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+    ) -> f32 {
+        let _ = (inc, pos, nor, nor_g, sc, time); // Not using these, silence warnings
+
+        // No spatial information is kept, so the best we can do is the
+        // total energy.
+        self.aprx_energy
+    }
 }
@@ -1,4 +1,7 @@
-use std::mem::{transmute, MaybeUninit};
+use std::{
+    f32::consts::PI as PI_32,
+    mem::{transmute, MaybeUninit},
+};
 
 use kioku::Arena;
 
@@ -7,6 +10,7 @@ use crate::{
     bbox::BBox,
     lerp::lerp_slice,
     math::{Normal, Point, Vector},
+    normal_cone::NormalCone,
     shading::surface_closure::SurfaceClosure,
 };
 
@@ -29,11 +33,13 @@ enum Node<'a> {
         children: &'a [Node<'a>],
         bounds: &'a [BBox],
         energy: f32,
+        cone: NormalCone,
     },
     Leaf {
         light_index: usize,
         bounds: &'a [BBox],
         energy: f32,
+        cone: NormalCone,
     },
 }
 
@@ -50,12 +56,75 @@ impl<'a> Node<'a> {
         }
     }
 
+    fn cone(&self) -> NormalCone {
+        match *self {
+            Node::Inner { cone, .. } | Node::Leaf { cone, .. } => cone,
+        }
+    }
+
     fn light_index(&self) -> usize {
         match *self {
             Node::Inner { .. } => panic!(),
             Node::Leaf { light_index, .. } => light_index,
         }
     }
+
+    /// Fills `node_mem` with a refitted copy of this node.  See
+    /// `LightTree::refit()`.
+    fn refit<'b, 'c, T, F>(
+        &self,
+        arena: &'b Arena,
+        objects: &[T],
+        info_getter: &F,
+        node_mem: &mut MaybeUninit<Node<'b>>,
+    ) where
+        F: 'c + Fn(&T) -> (&'c [BBox], f32, NormalCone),
+    {
+        let node = match *self {
+            Node::Leaf { light_index, .. } => {
+                let (bounds, energy, cone) = info_getter(&objects[light_index]);
+                Node::Leaf {
+                    light_index: light_index,
+                    bounds: arena.copy_slice(bounds),
+                    energy: energy,
+                    cone: cone,
+                }
+            }
+
+            Node::Inner { children, .. } => {
+                let new_children = arena.alloc_array_uninit::<Node>(children.len());
+                for (child, child_mem) in children.iter().zip(new_children.iter_mut()) {
+                    child.refit(arena, objects, info_getter, child_mem);
+                }
+                let new_children: &'b [Node<'b>] = unsafe { transmute(new_children) };
+
+                // Merge the children's bounds, energy, and cones, the same
+                // as the builder does.
+                let mut bounds = new_children[0].bounds().to_vec();
+                let mut merged = Vec::new();
+                let mut energy = new_children[0].energy();
+                let mut cone = new_children[0].cone();
+                for child in &new_children[1..] {
+                    merged.clear();
+                    merge_slices_append(&bounds, child.bounds(), &mut merged, |b1, b2| *b1 | *b2);
+                    std::mem::swap(&mut bounds, &mut merged);
+                    energy += child.energy();
+                    cone = cone.union(&child.cone());
+                }
+
+                Node::Inner {
+                    children: new_children,
+                    bounds: arena.copy_slice(&bounds),
+                    energy: energy,
+                    cone: cone,
+                }
+            }
+        };
+
+        unsafe {
+            *node_mem.as_mut_ptr() = node;
+        }
+    }
 }
 
 impl<'a> LightTree<'a> {
@@ -65,7 +134,7 @@ impl<'a> LightTree<'a> {
         info_getter: F,
     ) -> LightTree<'a>
     where
-        F: 'b + Fn(&T) -> (&'b [BBox], f32),
+        F: 'b + Fn(&T) -> (&'b [BBox], f32, NormalCone),
     {
         if objects.is_empty() {
             LightTree {
@@ -86,6 +155,42 @@ impl<'a> LightTree<'a> {
         }
     }
 
+    /// Creates a light tree with the same tree as this one, but with the
+    /// bounds, energy, and normal cones of its nodes recomputed from the
+    /// current state of `objects`.
+    ///
+    /// `objects` must be the objects the tree was built over, in the order
+    /// they were left in by the build.  This is much faster than building a
+    /// new tree, but the tree gets worse the further the lights move from
+    /// where they were when it was built.
+    #[allow(dead_code)]
+    pub fn refit<'b, 'c, T, F>(
+        &self,
+        arena: &'b Arena,
+        objects: &[T],
+        info_getter: F,
+    ) -> LightTree<'b>
+    where
+        F: 'c + Fn(&T) -> (&'c [BBox], f32, NormalCone),
+    {
+        match self.root {
+            None => LightTree {
+                root: None,
+                depth: 0,
+            },
+
+            Some(root) => {
+                let node_mem = arena.alloc_uninit::<Node>();
+                root.refit(arena, objects, &info_getter, node_mem);
+
+                LightTree {
+                    root: Some(unsafe { transmute(node_mem) }),
+                    depth: self.depth,
+                }
+            }
+        }
+    }
+
     fn construct_from_builder(
         arena: &'a Arena,
         base: &LightTreeBuilder,
@@ -102,6 +207,7 @@ impl<'a> LightTree<'a> {
                     light_index: base.nodes[node_index].child_index,
                     bounds: bounds,
                     energy: base.nodes[node_index].energy,
+                    cone: base.nodes[node_index].cone,
                 };
             }
         } else {
@@ -125,6 +231,7 @@ impl<'a> LightTree<'a> {
                     children: transmute(children),
                     bounds: bounds,
                     energy: base.nodes[node_index].energy,
+                    cone: base.nodes[node_index].cone,
                 };
             }
         }
@@ -152,7 +259,11 @@ impl<'a> LightAccel for LightTree<'a> {
             // Get the approximate amount of light contribution from the
             // composite light source.
             let approx_contrib = sc.estimate_eval_over_sphere_light(inc, d, r2, nor, nor_g);
-            node_ref.energy() * inv_surface_area * approx_contrib
+
+            // Account for the lights possibly facing away from us.
+            let falloff = node_ref.cone().estimate_falloff(bbox.center(), r2, pos);
+
+            node_ref.energy() * inv_surface_area * approx_contrib * falloff
         };
 
         // Traverse down the tree, keeping track of the relative probabilities
@@ -207,6 +318,42 @@ impl<'a> LightAccel for LightTree<'a> {
             0.0
         }
     }
+
+    fn normal_cone(&self) -> NormalCone {
+        if let Some(node) = self.root {
+            node.cone()
+        } else {
+            NormalCone::full_sphere()
+        }
+    }
+
+    fn approximate_contribution(
+        &self,
+        inc: Vector,
+        pos: Point,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+    ) -> f32 {
+        if let Some(node) = self.root {
+            // Treat the whole tree as a single spherical light source
+            // enclosing the root bounds, with its energy spread over the
+            // sphere's projected area.  This puts it in roughly the same
+            // units as the world lights' estimates.
+            let bbox = lerp_slice(node.bounds(), time);
+            let d = bbox.center() - pos;
+            let r2 = bbox.diagonal2() * 0.25;
+            let inv_projected_area = 1.0 / (r2 * PI_32);
+            let falloff = node.cone().estimate_falloff(bbox.center(), r2, pos);
+            node.energy()
+                * inv_projected_area
+                * sc.estimate_eval_over_sphere_light(inc, d, r2, nor, nor_g)
+                * falloff
+        } else {
+            0.0
+        }
+    }
 }
 
 struct LightTreeBuilder {
@@ -220,6 +367,7 @@ struct BuilderNode {
     is_leaf: bool,
     bounds_range: (usize, usize),
     energy: f32,
+    cone: NormalCone,
     child_index: usize,
 }
 
@@ -301,7 +449,7 @@ impl LightTreeBuilder {
         info_getter: &F,
     ) -> (usize, (usize, usize))
     where
-        F: 'a + Fn(&T) -> (&'a [BBox], f32),
+        F: 'a + Fn(&T) -> (&'a [BBox], f32, NormalCone),
     {
         let me_index = self.nodes.len();
 
@@ -310,12 +458,13 @@ impl LightTreeBuilder {
         } else if objects.len() == 1 {
             // Leaf node
             let bi = self.bounds.len();
-            let (obj_bounds, energy) = info_getter(&objects[0]);
+            let (obj_bounds, energy, cone) = info_getter(&objects[0]);
             self.bounds.extend(obj_bounds);
             self.nodes.push(BuilderNode {
                 is_leaf: true,
                 bounds_range: (bi, self.bounds.len()),
                 energy: energy,
+                cone: cone,
                 child_index: offset,
             });
 
@@ -330,6 +479,7 @@ impl LightTreeBuilder {
                 is_leaf: false,
                 bounds_range: (0, 0),
                 energy: 0.0,
+                cone: NormalCone::full_sphere(),
                 child_index: 0,
             });
 
@@ -360,10 +510,14 @@ impl LightTreeBuilder {
 
             // Set node
             let energy = self.nodes[me_index + 1].energy + self.nodes[c2_index].energy;
+            let cone = self.nodes[me_index + 1]
+                .cone
+                .union(&self.nodes[c2_index].cone);
             self.nodes[me_index] = BuilderNode {
                 is_leaf: false,
                 bounds_range: (bi, self.bounds.len()),
                 energy: energy,
+                cone: cone,
                 child_index: c2_index,
             };
 
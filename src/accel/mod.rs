@@ -1,22 +1,30 @@
 // mod bvh;
 mod bvh4;
+mod bvh8;
 mod bvh_base;
 mod light_array;
 mod light_tree;
 mod objects_split;
+mod wide_bvh;
 
-use std::cell::Cell;
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use crate::{
     math::{Normal, Point, Vector},
+    normal_cone::NormalCone,
     shading::surface_closure::SurfaceClosure,
 };
 
 pub use self::{
     // bvh::{BVHNode, BVH},
-    bvh4::{ray_code, BVH4Node, BVH4},
+    bvh4::{ray_code, BVH4Node},
+    bvh8::BVH8Node,
     light_array::LightArray,
     light_tree::LightTree,
+    wide_bvh::WideBVH,
 };
 
 // Track BVH traversal time
@@ -24,6 +32,53 @@ thread_local! {
     pub static ACCEL_NODE_RAY_TESTS: Cell<u64> = Cell::new(0);
 }
 
+/// How the nodes of a BVH are split during construction.
+///
+/// This only applies to the parts of the BVH with only static objects.  The
+/// nodes with moving objects under them are instead split either by
+/// partitioning their objects or by splitting their time interval, as in
+/// "STBVH: A Spatial-Temporal BVH for Efficient Multi-Segment Motion Blur"
+/// by Woop et al.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SplitMode {
+    /// Only ever partition the objects between child nodes.
+    Object,
+
+    /// Also consider splitting objects themselves between child nodes
+    /// at spatial split planes, as in "Spatial Splits in Bounding Volume
+    /// Hierarchies" by Stich et al.  This is only done where the child
+    /// nodes of the best object partitioning overlap by more than the
+    /// given fraction of the whole BVH's surface area.
+    ///
+    /// This makes for better trees around long, thin objects that don't
+    /// line up with the axes, at the cost of a slower build.
+    Spatial(f32),
+}
+
+/// A good general-purpose overlap threshold for `SplitMode::Spatial`, as
+/// suggested in the spatial splits paper.
+pub const DEFAULT_SPATIAL_SPLIT_THRESHOLD: f32 = 0.000_01;
+
+// The number of threads to build acceleration structures with, where zero
+// means the number of logical cores.
+static BUILD_THREAD_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Sets the number of threads used to build acceleration structures.
+///
+/// Zero means to use as many threads as there are logical cores, which is
+/// also the default.
+pub fn set_build_thread_count(thread_count: usize) {
+    BUILD_THREAD_COUNT.store(thread_count, Ordering::Relaxed);
+}
+
+/// Returns the number of threads used to build acceleration structures.
+pub fn build_thread_count() -> usize {
+    match BUILD_THREAD_COUNT.load(Ordering::Relaxed) {
+        0 => num_cpus::get(),
+        n => n,
+    }
+}
+
 pub trait LightAccel {
     /// Returns (index_of_light, selection_pdf, whittled_n)
     fn select(
@@ -38,4 +93,24 @@ pub trait LightAccel {
     ) -> Option<(usize, f32, f32)>;
 
     fn approximate_energy(&self) -> f32;
+
+    /// Returns a cone bounding the emission directions of all of the lights
+    /// in the accel.
+    fn normal_cone(&self) -> NormalCone;
+
+    /// Returns a rough estimate of the light arriving at the given point
+    /// from all of the lights in the accel, as seen through the closure `sc`.
+    ///
+    /// The estimate is in the same units as
+    /// `WorldLightSource::approximate_contribution()`, so that the two can be
+    /// weighed against each other.
+    fn approximate_contribution(
+        &self,
+        inc: Vector,
+        pos: Point,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+    ) -> f32;
 }
@@ -13,6 +13,10 @@ use crate::{
 const SAH_BIN_COUNT: usize = 13; // Prime numbers work best, for some reason
 const SPLIT_PLANE_COUNT: usize = 5;
 
+// Object count below which SAH binning is always done on a single thread,
+// since spawning threads for it would cost more than it saves.
+const PARALLEL_BINNING_MIN_OBJECTS: usize = 1 << 16;
+
 /// Takes a slice of boundable objects and partitions them based on the Surface
 /// Area Heuristic, but using arbitrarily oriented planes.
 ///
@@ -153,6 +157,67 @@ where
 pub fn sah_split<'a, T, F>(objects: &mut [T], bounder: &F) -> (usize, usize)
 where
     F: Fn(&T) -> &'a [BBox],
+{
+    binned_sah_split(objects, bounder, |objects, sah_divs| {
+        fill_sah_bins(objects, sah_divs, bounder)
+    })
+}
+
+/// The same as `sah_split()`, except that the binning is split over up to
+/// `thread_count` threads when there are enough objects to make that
+/// worthwhile.  The result is the same regardless of the thread count.
+pub fn parallel_sah_split<'a, T, F>(
+    objects: &mut [T],
+    bounder: &F,
+    thread_count: usize,
+) -> (usize, usize)
+where
+    T: Sync,
+    F: Fn(&T) -> &'a [BBox] + Sync,
+{
+    binned_sah_split(objects, bounder, |objects, sah_divs| {
+        if thread_count < 2 || objects.len() < PARALLEL_BINNING_MIN_OBJECTS {
+            return fill_sah_bins(objects, sah_divs, bounder);
+        }
+
+        // Bin chunks of the objects in parallel, and then merge the bins.
+        let chunk_size = (objects.len() + thread_count - 1) / thread_count;
+        crossbeam::scope(|scope| {
+            let handles: Vec<_> = objects
+                .chunks(chunk_size)
+                .map(|chunk| scope.spawn(move || fill_sah_bins(chunk, sah_divs, bounder)))
+                .collect();
+
+            let mut sah_bins = [[(BBox::new(), BBox::new(), 0, 0); SAH_BIN_COUNT - 1]; 3];
+            for handle in handles {
+                let chunk_bins = handle.join();
+                for d in 0..3 {
+                    for div in 0..(SAH_BIN_COUNT - 1) {
+                        sah_bins[d][div].0 |= chunk_bins[d][div].0;
+                        sah_bins[d][div].1 |= chunk_bins[d][div].1;
+                        sah_bins[d][div].2 += chunk_bins[d][div].2;
+                        sah_bins[d][div].3 += chunk_bins[d][div].3;
+                    }
+                }
+            }
+            sah_bins
+        })
+    })
+}
+
+/// The SAH div points for each axis.
+type SahDivs = [[f32; SAH_BIN_COUNT - 1]; 3];
+
+/// The SAH bins for each axis and div point: the bounds and number of
+/// objects on either side of the div point.
+type SahBins = [[(BBox, BBox, usize, usize); SAH_BIN_COUNT - 1]; 3];
+
+/// The shared implementation of `sah_split()` and `parallel_sah_split()`,
+/// with `binner` doing the binning.
+fn binned_sah_split<'a, T, F, B>(objects: &mut [T], bounder: &F, binner: B) -> (usize, usize)
+where
+    F: Fn(&T) -> &'a [BBox],
+    B: FnOnce(&[T], &SahDivs) -> SahBins,
 {
     // Get combined object centroid extents
     let bounds = {
@@ -177,26 +242,7 @@ where
     };
 
     // Build SAH bins
-    let sah_bins = {
-        let mut sah_bins = [[(BBox::new(), BBox::new(), 0, 0); SAH_BIN_COUNT - 1]; 3];
-        for obj in objects.iter() {
-            let tb = lerp_slice(bounder(obj), 0.5);
-            let centroid = (tb.min.into_vector() + tb.max.into_vector()) * 0.5;
-
-            for d in 0..3 {
-                for div in 0..(SAH_BIN_COUNT - 1) {
-                    if centroid.get_n(d) <= sah_divs[d][div] {
-                        sah_bins[d][div].0 |= tb;
-                        sah_bins[d][div].2 += 1;
-                    } else {
-                        sah_bins[d][div].1 |= tb;
-                        sah_bins[d][div].3 += 1;
-                    }
-                }
-            }
-        }
-        sah_bins
-    };
+    let sah_bins = binner(objects, &sah_divs);
 
     // Find best split axis and div point
     let (split_axis, div) = {
@@ -237,6 +283,174 @@ where
     (split_i, split_axis)
 }
 
+/// Bins the given objects for `binned_sah_split()`.
+fn fill_sah_bins<'a, T, F>(objects: &[T], sah_divs: &SahDivs, bounder: &F) -> SahBins
+where
+    F: Fn(&T) -> &'a [BBox],
+{
+    let mut sah_bins = [[(BBox::new(), BBox::new(), 0, 0); SAH_BIN_COUNT - 1]; 3];
+    for obj in objects.iter() {
+        let tb = lerp_slice(bounder(obj), 0.5);
+        let centroid = (tb.min.into_vector() + tb.max.into_vector()) * 0.5;
+
+        for d in 0..3 {
+            for div in 0..(SAH_BIN_COUNT - 1) {
+                if centroid.get_n(d) <= sah_divs[d][div] {
+                    sah_bins[d][div].0 |= tb;
+                    sah_bins[d][div].2 += 1;
+                } else {
+                    sah_bins[d][div].1 |= tb;
+                    sah_bins[d][div].3 += 1;
+                }
+            }
+        }
+    }
+    sah_bins
+}
+
+/// A reference to an object, for building BVHs with spatial splits.
+///
+/// An object split by a spatial split plane ends up with a reference on
+/// either side of it, each bounding just the part of the object on its
+/// side.
+#[derive(Debug, Copy, Clone)]
+pub struct SplitReference {
+    pub index: usize, // Index of the referenced object
+    pub bounds: BBox,
+}
+
+/// Finds the best spatial split plane for the given references, which
+/// are all within `bounds`, based on the Surface Area Heuristic.
+///
+/// `refs` are indices into `store`, which holds the actual references.
+///
+/// `clipper(index, bounds, axis, pos)` should return the bounds of the
+/// parts of the object at `index` that are within `bounds` and below and
+/// above the plane at `pos` on `axis`, the same as `BBox::split()`.
+///
+/// Returns the axis (0 = x, 1 = y, 2 = z), position and SAH cost of the
+/// best split plane, if there is one.
+pub fn spatial_split_plane<C>(
+    refs: &[usize],
+    store: &[SplitReference],
+    bounds: BBox,
+    clipper: &C,
+) -> Option<(usize, f32, f32)>
+where
+    C: Fn(usize, BBox, usize, f32) -> (BBox, BBox),
+{
+    let mut best: Option<(usize, f32, f32)> = None;
+
+    for axis in 0..3 {
+        let min = bounds.min.get_n(axis);
+        let extent = bounds.max.get_n(axis) - min;
+        if extent <= 0.0 {
+            continue;
+        }
+        let bin_width = extent / SAH_BIN_COUNT as f32;
+        let bin_pos = |i: usize| min + (bin_width * i as f32);
+        let bin_index = |n: f32| (((n - min) / bin_width) as usize).min(SAH_BIN_COUNT - 1);
+
+        // Build the bins: the clipped bounds of everything in each bin,
+        // and how many references start and end in each bin.
+        let mut bins = [(BBox::new(), 0usize, 0usize); SAH_BIN_COUNT];
+        for r in refs.iter().map(|&i| &store[i]) {
+            let first = bin_index(r.bounds.min.get_n(axis));
+            let last = bin_index(r.bounds.max.get_n(axis));
+            bins[first].1 += 1;
+            bins[last].2 += 1;
+
+            // Clip the reference into each of the bins it spans.
+            let mut rest = r.bounds;
+            for (i, bin) in bins.iter_mut().enumerate().take(last).skip(first) {
+                let (below, above) = clipper(r.index, rest, axis, bin_pos(i + 1));
+                if !below.is_empty() {
+                    bin.0 |= below;
+                }
+                rest = above;
+            }
+            if !rest.is_empty() {
+                bins[last].0 |= rest;
+            }
+        }
+
+        // Sweep from the right to get the bounds and counts on that side
+        // of each plane...
+        let mut right = [(BBox::new(), 0usize); SAH_BIN_COUNT];
+        let mut acc = (BBox::new(), 0);
+        for i in (1..SAH_BIN_COUNT).rev() {
+            acc.0 |= bins[i].0;
+            acc.1 += bins[i].2;
+            right[i] = acc;
+        }
+
+        // ...and then from the left to find the best plane.
+        let mut left = (BBox::new(), 0);
+        for i in 1..SAH_BIN_COUNT {
+            left.0 |= bins[i - 1].0;
+            left.1 += bins[i - 1].1;
+            let (right_bounds, right_count) = right[i];
+            if left.1 == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = (left.0.surface_area() * left.1 as f32)
+                + (right_bounds.surface_area() * right_count as f32);
+            if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, bin_pos(i), cost));
+            }
+        }
+    }
+
+    best
+}
+
+/// Splits the given references with a spatial split plane, as found by
+/// `spatial_split_plane()`.  References that straddle the plane are split
+/// in two with `clipper`, with the new references added to `store`.
+///
+/// Returns the references below and above the plane.
+pub fn split_references<C>(
+    refs: &[usize],
+    store: &mut Vec<SplitReference>,
+    axis: usize,
+    pos: f32,
+    clipper: &C,
+) -> (Vec<usize>, Vec<usize>)
+where
+    C: Fn(usize, BBox, usize, f32) -> (BBox, BBox),
+{
+    let mut below = Vec::new();
+    let mut above = Vec::new();
+
+    for &ri in refs {
+        let r = store[ri];
+        if r.bounds.max.get_n(axis) <= pos {
+            below.push(ri);
+        } else if r.bounds.min.get_n(axis) >= pos {
+            above.push(ri);
+        } else {
+            let (bounds_below, bounds_above) = clipper(r.index, r.bounds, axis, pos);
+            if !bounds_below.is_empty() {
+                below.push(store.len());
+                store.push(SplitReference {
+                    index: r.index,
+                    bounds: bounds_below,
+                });
+            }
+            if !bounds_above.is_empty() {
+                above.push(store.len());
+                store.push(SplitReference {
+                    index: r.index,
+                    bounds: bounds_above,
+                });
+            }
+        }
+    }
+
+    (below, above)
+}
+
 /// Takes a slice of boundable objects and partitions them based on the bounds mean heuristic.
 ///
 /// Returns the index of the partition boundary and the axis that it split on
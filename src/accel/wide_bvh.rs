@@ -0,0 +1,654 @@
+//! A BVH that's 8 wide on CPUs that can test eight bounding boxes at once,
+//! and 4 wide otherwise.  Which one is decided at build time, based on the
+//! CPU being rendered on.
+//!
+//! This also has the code that's shared between the two widths of BVH.
+
+#![allow(dead_code)]
+
+use std::{
+    convert::TryFrom,
+    mem::{transmute, MaybeUninit},
+};
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    bbox8::simd8_is_supported,
+    boundable::Boundable,
+    cache::{CacheReader, CacheWriter, Cacheable},
+    lerp::lerp_slice,
+    ray::{RayBatch, RayStack},
+};
+
+use super::{
+    bvh4::BVH4,
+    bvh8::BVH8,
+    bvh_base::{objects_interval_bounds, union_bounds, BVH_MAX_DEPTH},
+    SplitMode,
+};
+
+#[derive(Copy, Clone, Debug)]
+pub enum WideBVH<'a> {
+    BVH4(BVH4<'a>),
+    BVH8(BVH8<'a>),
+}
+
+impl<'a> WideBVH<'a> {
+    pub fn from_objects<'b, T, F>(
+        arena: &'a Arena,
+        objects: &mut [T],
+        objects_per_leaf: usize,
+        bounder: F,
+    ) -> WideBVH<'a>
+    where
+        T: Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+    {
+        if simd8_is_supported() {
+            WideBVH::BVH8(BVH8::from_objects(
+                arena,
+                objects,
+                objects_per_leaf,
+                bounder,
+            ))
+        } else {
+            WideBVH::BVH4(BVH4::from_objects(
+                arena,
+                objects,
+                objects_per_leaf,
+                bounder,
+            ))
+        }
+    }
+
+    /// Builds a BVH over the given objects with the given split mode.
+    ///
+    /// See `BVH4::from_objects_with_splits()` for details.
+    pub fn from_objects_with_splits<'b, T, F, C>(
+        arena: &'a Arena,
+        objects: &[T],
+        objects_per_leaf: usize,
+        split_mode: SplitMode,
+        bounder: F,
+        clipper: C,
+    ) -> (WideBVH<'a>, Vec<T>)
+    where
+        T: Clone + Send + Sync,
+        F: 'b + Fn(&T) -> &'b [BBox] + Sync,
+        C: Fn(&T, BBox, usize, f32) -> (BBox, BBox),
+    {
+        if simd8_is_supported() {
+            let (bvh, objects) = BVH8::from_objects_with_splits(
+                arena,
+                objects,
+                objects_per_leaf,
+                split_mode,
+                bounder,
+                clipper,
+            );
+            (WideBVH::BVH8(bvh), objects)
+        } else {
+            let (bvh, objects) = BVH4::from_objects_with_splits(
+                arena,
+                objects,
+                objects_per_leaf,
+                split_mode,
+                bounder,
+                clipper,
+            );
+            (WideBVH::BVH4(bvh), objects)
+        }
+    }
+
+    pub fn tree_depth(&self) -> usize {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.tree_depth(),
+            WideBVH::BVH8(ref bvh) => bvh.tree_depth(),
+        }
+    }
+
+    /// Returns the object range of each leaf of the BVH.
+    pub fn leaf_object_ranges(&self) -> Vec<(usize, usize)> {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.leaf_object_ranges(),
+            WideBVH::BVH8(ref bvh) => bvh.leaf_object_ranges(),
+        }
+    }
+
+    /// Creates a BVH with the same tree as this one, but with its bounds
+    /// recomputed from the current bounds of `objects`.
+    ///
+    /// See `WideBVHTree::refit()` for details.
+    pub fn refit<'b, 'c, T, F>(&self, arena: &'b Arena, objects: &[T], bounder: F) -> WideBVH<'b>
+    where
+        F: 'c + Fn(&T) -> &'c [BBox],
+    {
+        match *self {
+            WideBVH::BVH4(ref bvh) => WideBVH::BVH4(bvh.refit(arena, objects, bounder)),
+            WideBVH::BVH8(ref bvh) => WideBVH::BVH8(bvh.refit(arena, objects, bounder)),
+        }
+    }
+
+    pub fn traverse<F>(&self, rays: &mut RayBatch, ray_stack: &mut RayStack, obj_ray_test: F)
+    where
+        F: FnMut(std::ops::Range<usize>, &mut RayBatch, &mut RayStack),
+    {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.traverse(rays, ray_stack, obj_ray_test),
+            WideBVH::BVH8(ref bvh) => bvh.traverse(rays, ray_stack, obj_ray_test),
+        }
+    }
+}
+
+impl<'a> Boundable for WideBVH<'a> {
+    fn bounds<'b>(&'b self) -> &'b [BBox] {
+        match *self {
+            WideBVH::BVH4(ref bvh) => bvh.bounds(),
+            WideBVH::BVH8(ref bvh) => bvh.bounds(),
+        }
+    }
+}
+
+impl<'a> Cacheable<'a> for WideBVH<'a> {
+    const KIND: [u8; 4] = *b"WBVH";
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        match *self {
+            WideBVH::BVH4(ref bvh) => {
+                writer.write_u8(4);
+                bvh.write_cache(writer);
+            }
+            WideBVH::BVH8(ref bvh) => {
+                writer.write_u8(8);
+                bvh.write_cache(writer);
+            }
+        }
+    }
+
+    fn read_cache(arena: &'a Arena, reader: &mut CacheReader<'a, '_>) -> Option<WideBVH<'a>> {
+        match reader.read_u8()? {
+            4 => Some(WideBVH::BVH4(BVH4::read_cache(arena, reader)?)),
+            8 => Some(WideBVH::BVH8(BVH8::read_cache(arena, reader)?)),
+            _ => None,
+        }
+    }
+}
+
+/// The bounds of the children of a wide BVH node, which are all tested
+/// against a ray at once.
+pub trait WideBounds: Copy {
+    /// The most children a node can have.
+    const WIDTH: usize;
+
+    /// Identifies BVHs with these bounds in the cache.
+    const CACHE_KIND: [u8; 4];
+
+    /// Packs together the bounds of up to `WIDTH` children.
+    fn from_bboxes(bboxes: &[BBox]) -> Self;
+}
+
+/// A BVH whose nodes have up to `B::WIDTH` children, with the children's
+/// bounds stored together as a `B`, and traversal codes of type `C`.
+///
+/// See `BVH4` and `BVH8`.
+#[derive(Copy, Clone, Debug)]
+pub struct WideBVHTree<'a, B, C> {
+    pub(super) root: Option<&'a WideBVHNode<'a, B, C>>,
+    pub(super) depth: usize,
+    pub(super) node_count: usize,
+    pub(super) _bounds: Option<&'a [BBox]>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum WideBVHNode<'a, B, C> {
+    Internal {
+        bounds: &'a [B],
+        children: &'a [WideBVHNode<'a, B, C>],
+        traversal_code: C,
+    },
+
+    Leaf {
+        object_range: (usize, usize),
+    },
+
+    Temporal {
+        split_time: f32,
+        children: &'a [WideBVHNode<'a, B, C>],
+    },
+}
+
+impl<'a, B, C> WideBVHTree<'a, B, C> {
+    pub fn tree_depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Returns the object range of each leaf of the BVH.
+    pub fn leaf_object_ranges(&self) -> Vec<(usize, usize)> {
+        let mut ranges = Vec::new();
+        if let Some(root) = self.root {
+            root.collect_leaf_object_ranges(&mut ranges);
+        }
+        ranges
+    }
+    /// Creates a BVH with the same tree as this one, but with its bounds
+    /// recomputed from the current bounds of `objects`.
+    ///
+    /// `objects` must be the objects the BVH was built over, in the order
+    /// they were left in by the build (or as returned by
+    /// `from_objects_with_splits()`).  This is much faster than building a
+    /// new BVH, but the tree gets worse the further the objects move from
+    /// where they were when it was built.  Objects split by spatial splits
+    /// aren't clipped again, so their full bounds are used in every leaf
+    /// that refers to them.
+    pub fn refit<'b, 'c, T, F>(
+        &self,
+        arena: &'b Arena,
+        objects: &[T],
+        bounder: F,
+    ) -> WideBVHTree<'b, B, C>
+    where
+        B: WideBounds,
+        C: Copy,
+        F: 'c + Fn(&T) -> &'c [BBox],
+    {
+        match self.root {
+            None => WideBVHTree {
+                root: None,
+                depth: 0,
+                node_count: 0,
+                _bounds: None,
+            },
+
+            Some(root) => {
+                let fill_node = arena.alloc_align_uninit::<WideBVHNode<B, C>>(32);
+                let bounds = root.refit(arena, objects, &bounder, (0.0, 1.0), fill_node);
+
+                WideBVHTree {
+                    root: Some(unsafe { transmute(fill_node) }),
+                    depth: self.depth,
+                    node_count: self.node_count,
+                    _bounds: Some(arena.copy_slice(&bounds)),
+                }
+            }
+        }
+    }
+}
+
+impl<'a, B, C> Boundable for WideBVHTree<'a, B, C> {
+    fn bounds<'b>(&'b self) -> &'b [BBox] {
+        self._bounds.unwrap_or(&[])
+    }
+}
+
+impl<'a, B, C> Cacheable<'a> for WideBVHTree<'a, B, C>
+where
+    B: WideBounds,
+    C: Copy + Into<u32> + TryFrom<u32>,
+{
+    const KIND: [u8; 4] = B::CACHE_KIND;
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        writer.write_u64(self.depth as u64);
+        writer.write_u64(self.node_count as u64);
+        writer.write_slice(self._bounds.unwrap_or(&[]));
+        match self.root {
+            None => writer.write_u8(0),
+            Some(root) => {
+                writer.write_u8(1);
+                root.write_cache(writer);
+            }
+        }
+    }
+
+    fn read_cache(
+        arena: &'a Arena,
+        reader: &mut CacheReader<'a, '_>,
+    ) -> Option<WideBVHTree<'a, B, C>> {
+        let depth = reader.read_u64()? as usize;
+        let node_count = reader.read_u64()? as usize;
+        let bounds = reader.read_slice(arena)?;
+        let root = match reader.read_u8()? {
+            0 => None,
+            1 => {
+                let fill_node = arena.alloc_align_uninit::<WideBVHNode<B, C>>(32);
+                WideBVHNode::read_cache(arena, reader, fill_node, 0)?;
+                Some(unsafe { transmute(fill_node) })
+            }
+            _ => return None,
+        };
+
+        Some(WideBVHTree {
+            root: root,
+            depth: depth,
+            node_count: node_count,
+            _bounds: if root.is_some() { Some(bounds) } else { None },
+        })
+    }
+}
+
+impl<'a, B, C> WideBVHNode<'a, B, C> {
+    pub(super) fn collect_leaf_object_ranges(&self, ranges: &mut Vec<(usize, usize)>) {
+        match *self {
+            WideBVHNode::Internal { children, .. } | WideBVHNode::Temporal { children, .. } => {
+                for child in children {
+                    child.collect_leaf_object_ranges(ranges);
+                }
+            }
+
+            WideBVHNode::Leaf { object_range } => ranges.push(object_range),
+        }
+    }
+
+    /// Fills `fill_node` with a refitted copy of this node, and returns
+    /// the node's new bounds over `time_range`.  See
+    /// `WideBVHTree::refit()`.
+    fn refit<'b, 'c, T, F>(
+        &self,
+        arena: &'b Arena,
+        objects: &[T],
+        bounder: &F,
+        time_range: (f32, f32),
+        fill_node: &mut MaybeUninit<WideBVHNode<'b, B, C>>,
+    ) -> Vec<BBox>
+    where
+        B: WideBounds,
+        C: Copy,
+        F: 'c + Fn(&T) -> &'c [BBox],
+    {
+        let (node, bounds) = match *self {
+            WideBVHNode::Internal {
+                children,
+                traversal_code,
+                ..
+            } => {
+                // Refit the children
+                let child_nodes =
+                    arena.alloc_array_align_uninit::<WideBVHNode<B, C>>(children.len(), 32);
+                let child_bounds: Vec<Vec<BBox>> = children
+                    .iter()
+                    .zip(child_nodes.iter_mut())
+                    .map(|(child, fill)| child.refit(arena, objects, bounder, time_range, fill))
+                    .collect();
+
+                // Construct bounds, the same as the builds do.
+                let bounds_len = child_bounds.iter().map(|b| b.len()).max().unwrap();
+                let bounds = arena.alloc_array_align_uninit(bounds_len, 32);
+                for (i, b) in bounds.iter_mut().enumerate() {
+                    let time = if bounds_len < 2 {
+                        0.0
+                    } else {
+                        i as f32 / (bounds_len - 1) as f32
+                    };
+                    let lanes: Vec<BBox> =
+                        child_bounds.iter().map(|b| lerp_slice(b, time)).collect();
+                    unsafe {
+                        *b.as_mut_ptr() = B::from_bboxes(&lanes);
+                    }
+                }
+
+                let node = WideBVHNode::Internal {
+                    bounds: unsafe { transmute(bounds) },
+                    children: unsafe { transmute(child_nodes) },
+                    traversal_code: traversal_code,
+                };
+                (node, union_bounds(child_bounds.iter().map(|b| &b[..])))
+            }
+
+            WideBVHNode::Temporal {
+                split_time,
+                children,
+            } => {
+                let (t0, t1) = time_range;
+                let child_nodes = arena.alloc_array_align_uninit::<WideBVHNode<B, C>>(2, 32);
+                children[0].refit(
+                    arena,
+                    objects,
+                    bounder,
+                    (t0, split_time),
+                    &mut child_nodes[0],
+                );
+                children[1].refit(
+                    arena,
+                    objects,
+                    bounder,
+                    (split_time, t1),
+                    &mut child_nodes[1],
+                );
+
+                // The children's bounds are over different time intervals,
+                // so this node's bounds come straight from its objects.
+                let mut ranges = Vec::new();
+                self.collect_leaf_object_ranges(&mut ranges);
+                let bounds = objects_interval_bounds(
+                    ranges
+                        .iter()
+                        .flat_map(|r| objects[r.0..r.1].iter())
+                        .map(bounder),
+                    time_range,
+                );
+
+                let node = WideBVHNode::Temporal {
+                    split_time: split_time,
+                    children: unsafe { transmute(child_nodes) },
+                };
+                (node, bounds)
+            }
+
+            WideBVHNode::Leaf { object_range } => {
+                let bounds = objects_interval_bounds(
+                    objects[object_range.0..object_range.1].iter().map(bounder),
+                    time_range,
+                );
+                (
+                    WideBVHNode::Leaf {
+                        object_range: object_range,
+                    },
+                    bounds,
+                )
+            }
+        };
+
+        unsafe {
+            *fill_node.as_mut_ptr() = node;
+        }
+        bounds
+    }
+}
+
+impl<'a, B, C> WideBVHNode<'a, B, C>
+where
+    B: WideBounds,
+    C: Copy + Into<u32> + TryFrom<u32>,
+{
+    // Node tags in the cache.
+    const CACHE_INTERNAL: u8 = 0;
+    const CACHE_LEAF: u8 = 1;
+    const CACHE_TEMPORAL: u8 = 2;
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        match *self {
+            WideBVHNode::Internal {
+                bounds,
+                children,
+                traversal_code,
+            } => {
+                writer.write_u8(Self::CACHE_INTERNAL);
+                writer.write_slice(bounds);
+                writer.write_u32(traversal_code.into());
+                writer.write_u8(children.len() as u8);
+                for child in children {
+                    child.write_cache(writer);
+                }
+            }
+
+            WideBVHNode::Leaf { object_range } => {
+                writer.write_u8(Self::CACHE_LEAF);
+                writer.write_u64(object_range.0 as u64);
+                writer.write_u64(object_range.1 as u64);
+            }
+
+            WideBVHNode::Temporal {
+                split_time,
+                children,
+            } => {
+                writer.write_u8(Self::CACHE_TEMPORAL);
+                writer.write_f32(split_time);
+                writer.write_u8(children.len() as u8);
+                for child in children {
+                    child.write_cache(writer);
+                }
+            }
+        }
+    }
+
+    fn read_cache(
+        arena: &'a Arena,
+        reader: &mut CacheReader<'a, '_>,
+        fill_node: &mut MaybeUninit<WideBVHNode<'a, B, C>>,
+        depth: usize,
+    ) -> Option<()> {
+        // Guard against runaway recursion on malformed data.
+        if depth > BVH_MAX_DEPTH * 2 {
+            return None;
+        }
+
+        let node = match reader.read_u8()? {
+            Self::CACHE_INTERNAL => {
+                let bounds = reader.read_slice_align::<B>(arena, 32)?;
+                let traversal_code = C::try_from(reader.read_u32()?).ok()?;
+                let children = Self::read_cache_children(arena, reader, B::WIDTH, depth)?;
+                WideBVHNode::Internal {
+                    bounds: bounds,
+                    children: children,
+                    traversal_code: traversal_code,
+                }
+            }
+
+            Self::CACHE_LEAF => WideBVHNode::Leaf {
+                object_range: (reader.read_u64()? as usize, reader.read_u64()? as usize),
+            },
+
+            Self::CACHE_TEMPORAL => WideBVHNode::Temporal {
+                split_time: reader.read_f32()?,
+                children: Self::read_cache_children(arena, reader, 2, depth)?,
+            },
+
+            _ => return None,
+        };
+
+        unsafe {
+            *fill_node.as_mut_ptr() = node;
+        }
+        Some(())
+    }
+
+    fn read_cache_children(
+        arena: &'a Arena,
+        reader: &mut CacheReader<'a, '_>,
+        max_count: usize,
+        depth: usize,
+    ) -> Option<&'a [WideBVHNode<'a, B, C>]> {
+        let child_count = reader.read_u8()? as usize;
+        if child_count == 0 || child_count > max_count {
+            return None;
+        }
+        let child_nodes = arena.alloc_array_align_uninit::<WideBVHNode<B, C>>(child_count, 32);
+        for child in child_nodes.iter_mut() {
+            Self::read_cache(arena, reader, child, depth + 1)?;
+        }
+        Some(unsafe { transmute(child_nodes) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        accel::ray_code,
+        math::{Matrix4x4, Point, Vector},
+        ray::Ray,
+    };
+
+    // The bounds of a row of boxes at two time samples each, with the row
+    // mirrored at the second one, so that the boxes cross each other and
+    // the build makes temporal splits.
+    fn crossing_bounds() -> Vec<Vec<BBox>> {
+        (0..32)
+            .map(|i| {
+                [i, 31 - i]
+                    .iter()
+                    .map(|&x| {
+                        let min = Point::new(x as f32, (i % 3) as f32, 0.0);
+                        BBox::from_points(min, min + Vector::new(0.5, 0.5, 0.5))
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn temporal_traversal_finds_lerped_bounds() {
+        let arena = Arena::new();
+        let bounds = crossing_bounds();
+        let objects: Vec<usize> = (0..bounds.len()).collect();
+        let (bvh, objects) = WideBVH::from_objects_with_splits(
+            &arena,
+            &objects,
+            1,
+            SplitMode::Object,
+            |&i| &bounds[i][..],
+            |_, bounds, _, _| (bounds, bounds),
+        );
+        assert!(format!("{:?}", bvh).contains("Temporal"));
+
+        // A ray straight down through the center of each box at each of a
+        // range of times.
+        let mut rays = RayBatch::new();
+        let mut targets = Vec::new();
+        for i in 0..bounds.len() {
+            for t in 0..=16 {
+                let time = t as f32 / 16.0;
+                let bb = lerp_slice(&bounds[i], time);
+                let center = bb.min + ((bb.max - bb.min) * 0.5);
+                rays.push(
+                    Ray {
+                        orig: Point::new(center.x(), center.y(), 10.0),
+                        dir: Vector::new(0.0, 0.0, -1.0),
+                        time: time,
+                        wavelength: 500.0,
+                        max_t: f32::INFINITY,
+                    },
+                    false,
+                );
+                targets.push(i);
+            }
+        }
+
+        // Traverse, noting the objects that each ray reaches.
+        let mut ray_stack = RayStack::new();
+        let ident = Matrix4x4::new();
+        for i in 0..rays.len() {
+            rays.update_local(i, &ident);
+        }
+        ray_stack.ensure_lane_count(8);
+        for i in 0..rays.len() {
+            ray_stack.push_ray_index(i, ray_code(rays.dir(i)));
+        }
+        ray_stack.push_lanes_to_tasks(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        let mut reached = vec![Vec::new(); rays.len()];
+        while !ray_stack.is_empty() {
+            bvh.traverse(&mut rays, &mut ray_stack, |idx_range, _, ray_stack| {
+                ray_stack.pop_do_next_task(|ray_idx| {
+                    reached[ray_idx].extend_from_slice(&objects[idx_range.clone()]);
+                });
+            });
+        }
+
+        for (reached, target) in reached.iter().zip(targets.iter()) {
+            assert!(reached.contains(target));
+        }
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::{
     iter::Iterator,
-    ops::{BitOr, BitOrAssign},
+    ops::{BitAnd, BitOr, BitOrAssign},
 };
 
 use crate::{
@@ -79,6 +79,12 @@ impl BBox {
         b
     }
 
+    /// Returns the BBox grown by `amount` on all sides.
+    pub fn expanded(&self, amount: f32) -> BBox {
+        let offset = Vector::new(amount, amount, amount);
+        BBox::from_points(self.min - offset, self.max + offset)
+    }
+
     pub fn surface_area(&self) -> f32 {
         let d = self.max - self.min;
         ((d.x() * d.y()) + (d.y() * d.z()) + (d.z() * d.x())) * 2.0
@@ -95,6 +101,39 @@ impl BBox {
     pub fn diagonal2(&self) -> f32 {
         (self.max - self.min).length2()
     }
+
+    /// Returns whether the BBox is empty, i.e. its min is greater than its
+    /// max on any axis.
+    pub fn is_empty(&self) -> bool {
+        self.min.x() > self.max.x() || self.min.y() > self.max.y() || self.min.z() > self.max.z()
+    }
+
+    /// Splits the BBox with a plane at `pos` along the given axis (0 = x,
+    /// 1 = y, 2 = z), returning the parts below and above the plane.
+    ///
+    /// Parts that would be empty are returned as degenerate BBoxes, the
+    /// same as from `BBox::new()`.
+    pub fn split(&self, axis: usize, pos: f32) -> (BBox, BBox) {
+        let with_n = |p: Point, n: f32| match axis {
+            0 => Point::new(n, p.y(), p.z()),
+            1 => Point::new(p.x(), n, p.z()),
+            2 => Point::new(p.x(), p.y(), n),
+            _ => panic!("Attempt to split on dimension beyond z."),
+        };
+
+        let below = if self.min.get_n(axis) <= pos {
+            BBox::from_points(self.min, with_n(self.max, self.max.get_n(axis).min(pos)))
+        } else {
+            BBox::new()
+        };
+        let above = if self.max.get_n(axis) >= pos {
+            BBox::from_points(with_n(self.min, self.min.get_n(axis).max(pos)), self.max)
+        } else {
+            BBox::new()
+        };
+
+        (below, above)
+    }
 }
 
 /// Union of two `BBox`es.
@@ -119,6 +158,22 @@ impl BitOrAssign for BBox {
     }
 }
 
+/// Intersection of two `BBox`es.
+impl BitAnd for BBox {
+    type Output = BBox;
+
+    fn bitand(self, rhs: BBox) -> BBox {
+        BBox::from_points(
+            Point {
+                co: self.min.co.max(rhs.min.co),
+            },
+            Point {
+                co: self.max.co.min(rhs.max.co),
+            },
+        )
+    }
+}
+
 /// Expand `BBox` by a point.
 impl BitOr<Point> for BBox {
     type Output = BBox;
@@ -0,0 +1,241 @@
+#![allow(dead_code)]
+
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+use crate::{
+    bbox::BBox,
+    lerp::{lerp, Lerp},
+    math::{Point, Vector},
+};
+
+const BBOX_MAXT_ADJUST: f32 = 1.000_000_24;
+
+/// Returns whether the CPU supports the instructions that `BBox8` uses to
+/// test all eight of its boxes at once.
+///
+/// `BBox8` still works without them, but is slower than using `BBox4`s.
+pub fn simd8_is_supported() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("avx2")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    {
+        false
+    }
+}
+
+/// A SIMD set of 8 3D axis-aligned bounding boxes.
+#[derive(Debug, Copy, Clone)]
+#[repr(C, align(32))]
+pub struct BBox8 {
+    pub x: ([f32; 8], [f32; 8]), // (min, max)
+    pub y: ([f32; 8], [f32; 8]), // (min, max)
+    pub z: ([f32; 8], [f32; 8]), // (min, max)
+}
+
+impl BBox8 {
+    /// Creates a degenerate BBox with +infinity min and -infinity max.
+    pub fn new() -> BBox8 {
+        BBox8 {
+            x: ([f32::INFINITY; 8], [f32::NEG_INFINITY; 8]),
+            y: ([f32::INFINITY; 8], [f32::NEG_INFINITY; 8]),
+            z: ([f32::INFINITY; 8], [f32::NEG_INFINITY; 8]),
+        }
+    }
+
+    /// Creates a BBox8 from up to eight BBoxes.  Any lanes past the end of
+    /// `bboxes` are left degenerate.
+    pub fn from_bboxes(bboxes: &[BBox]) -> BBox8 {
+        debug_assert!(bboxes.len() <= 8);
+
+        let mut bbox8 = BBox8::new();
+        for (i, b) in bboxes.iter().enumerate() {
+            bbox8.x.0[i] = b.min.x();
+            bbox8.x.1[i] = b.max.x();
+            bbox8.y.0[i] = b.min.y();
+            bbox8.y.1[i] = b.max.y();
+            bbox8.z.0[i] = b.min.z();
+            bbox8.z.1[i] = b.max.z();
+        }
+        bbox8
+    }
+
+    /// The same as `intersect_ray()`, but using AVX2 to test all eight boxes
+    /// at once.
+    ///
+    /// # Safety
+    ///
+    /// Must only be called on CPUs that support AVX2.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[inline]
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn intersect_ray_avx2(&self, orig: Point, dir_inv: Vector, max_t: f32) -> u32 {
+        // Get the ray data into SIMD format.
+        let ro_x = _mm256_set1_ps(orig.co.x());
+        let ro_y = _mm256_set1_ps(orig.co.y());
+        let ro_z = _mm256_set1_ps(orig.co.z());
+        let rdi_x = _mm256_set1_ps(dir_inv.co.x());
+        let rdi_y = _mm256_set1_ps(dir_inv.co.y());
+        let rdi_z = _mm256_set1_ps(dir_inv.co.z());
+        let max_t = _mm256_set1_ps(max_t);
+
+        // Slab tests
+        let min_x = _mm256_loadu_ps(self.x.0.as_ptr());
+        let min_y = _mm256_loadu_ps(self.y.0.as_ptr());
+        let min_z = _mm256_loadu_ps(self.z.0.as_ptr());
+        let max_x = _mm256_loadu_ps(self.x.1.as_ptr());
+        let max_y = _mm256_loadu_ps(self.y.1.as_ptr());
+        let max_z = _mm256_loadu_ps(self.z.1.as_ptr());
+        let t1_x = _mm256_mul_ps(_mm256_sub_ps(min_x, ro_x), rdi_x);
+        let t1_y = _mm256_mul_ps(_mm256_sub_ps(min_y, ro_y), rdi_y);
+        let t1_z = _mm256_mul_ps(_mm256_sub_ps(min_z, ro_z), rdi_z);
+        let t2_x = _mm256_mul_ps(_mm256_sub_ps(max_x, ro_x), rdi_x);
+        let t2_y = _mm256_mul_ps(_mm256_sub_ps(max_y, ro_y), rdi_y);
+        let t2_z = _mm256_mul_ps(_mm256_sub_ps(max_z, ro_z), rdi_z);
+
+        // Get the far and near t hits for each axis.
+        let t_far_x = _mm256_max_ps(t1_x, t2_x);
+        let t_far_y = _mm256_max_ps(t1_y, t2_y);
+        let t_far_z = _mm256_max_ps(t1_z, t2_z);
+        let t_near_x = _mm256_min_ps(t1_x, t2_x);
+        let t_near_y = _mm256_min_ps(t1_y, t2_y);
+        let t_near_z = _mm256_min_ps(t1_z, t2_z);
+
+        // Calculate over-all far t hit.
+        let far_t = _mm256_min_ps(
+            _mm256_mul_ps(
+                _mm256_min_ps(t_far_x, _mm256_min_ps(t_far_y, t_far_z)),
+                _mm256_set1_ps(BBOX_MAXT_ADJUST),
+            ),
+            max_t,
+        );
+
+        // Calculate over-all near t hit.
+        let near_t = _mm256_max_ps(
+            _mm256_max_ps(t_near_x, t_near_y),
+            _mm256_max_ps(t_near_z, _mm256_setzero_ps()),
+        );
+
+        // Hit results
+        _mm256_movemask_ps(_mm256_cmp_ps(near_t, far_t, _CMP_LT_OQ)) as u32
+    }
+
+    /// Returns whether the given ray intersects with the bboxes, as a bitmask
+    /// with one bit per box.
+    ///
+    /// This tests the boxes one at a time.  Callers on CPUs that support AVX2
+    /// should use `intersect_ray_avx2()` instead, checking for it once up
+    /// front rather than per box test.
+    pub fn intersect_ray(&self, orig: Point, dir_inv: Vector, max_t: f32) -> u32 {
+        let mut hits = 0;
+        for i in 0..8 {
+            // Slab tests
+            let t1_x = (self.x.0[i] - orig.co.x()) * dir_inv.co.x();
+            let t1_y = (self.y.0[i] - orig.co.y()) * dir_inv.co.y();
+            let t1_z = (self.z.0[i] - orig.co.z()) * dir_inv.co.z();
+            let t2_x = (self.x.1[i] - orig.co.x()) * dir_inv.co.x();
+            let t2_y = (self.y.1[i] - orig.co.y()) * dir_inv.co.y();
+            let t2_z = (self.z.1[i] - orig.co.z()) * dir_inv.co.z();
+
+            // Calculate over-all far and near t hits.
+            let far_t = (t1_x.max(t2_x).min(t1_y.max(t2_y).min(t1_z.max(t2_z))) * BBOX_MAXT_ADJUST)
+                .min(max_t);
+            let near_t = t1_x
+                .min(t2_x)
+                .max(t1_y.min(t2_y))
+                .max(t1_z.min(t2_z).max(0.0));
+
+            if near_t < far_t {
+                hits |= 1 << i;
+            }
+        }
+        hits
+    }
+}
+
+impl Lerp for BBox8 {
+    fn lerp(self, other: BBox8, alpha: f32) -> BBox8 {
+        let lerp8 = |a: [f32; 8], b: [f32; 8]| {
+            let mut c = a;
+            for (c, (&a, &b)) in c.iter_mut().zip(a.iter().zip(b.iter())) {
+                *c = lerp(a, b, alpha);
+            }
+            c
+        };
+
+        BBox8 {
+            x: (lerp8(self.x.0, other.x.0), lerp8(self.x.1, other.x.1)),
+            y: (lerp8(self.y.0, other.y.0), lerp8(self.y.1, other.y.1)),
+            z: (lerp8(self.z.0, other.z.0), lerp8(self.z.1, other.z.1)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_u32_to_f32;
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn intersect_ray_avx2_matches_portable() {
+        if !simd8_is_supported() {
+            return;
+        }
+
+        let rand = |n: u32, seed: u32| hash_u32_to_f32(n, seed) * 4.0 - 2.0;
+        let mut hit_count = 0;
+        for seed in 0..2000 {
+            // Random boxes, leaving some lanes degenerate, and making some
+            // of them flat.  (The degenerate lanes' results don't mean
+            // anything, but should still match.)
+            let box_count = (seed % 9) as usize;
+            let bboxes: Vec<_> = (0..box_count as u32)
+                .map(|i| {
+                    let a = Point::new(
+                        rand(i * 6, seed),
+                        rand(i * 6 + 1, seed),
+                        rand(i * 6 + 2, seed),
+                    );
+                    let mut b = Point::new(
+                        rand(i * 6 + 3, seed),
+                        rand(i * 6 + 4, seed),
+                        rand(i * 6 + 5, seed),
+                    );
+                    if seed % 7 == 0 {
+                        b = Point::new(a.x(), b.y(), b.z());
+                    }
+                    BBox::from_points(a.min(b), a.max(b))
+                })
+                .collect();
+            let bbox8 = BBox8::from_bboxes(&bboxes);
+
+            // Random rays, some of them axis-aligned, so that their inverse
+            // directions have infinities.
+            let orig = Point::new(rand(100, seed), rand(101, seed), rand(102, seed));
+            let mut dir = Vector::new(rand(103, seed), rand(104, seed), rand(105, seed));
+            match seed % 5 {
+                0 => dir = Vector::new(0.0, dir.y(), dir.z()),
+                1 => dir = Vector::new(0.0, 0.0, dir.z()),
+                _ => {}
+            }
+            let dir_inv = Vector::new(1.0 / dir.x(), 1.0 / dir.y(), 1.0 / dir.z());
+
+            for &max_t in &[f32::INFINITY, 1.0, 0.0] {
+                let hits = bbox8.intersect_ray(orig, dir_inv, max_t);
+                let hits_avx2 = unsafe { bbox8.intersect_ray_avx2(orig, dir_inv, max_t) };
+                assert_eq!(
+                    hits, hits_avx2,
+                    "{:?} {:?} {:?} {}",
+                    bboxes, orig, dir, max_t
+                );
+                hit_count += (hits & ((1 << box_count) - 1)).count_ones();
+            }
+        }
+        assert!(hit_count > 1000);
+    }
+}
@@ -0,0 +1,442 @@
+//! An on-disk cache of built scene data, so that later renders of the same
+//! data can load it instead of building it again.
+//!
+//! Each cache entry is a file in the cache directory, named by a hash of
+//! the content it was built from.  Data is stored in the same layout it has
+//! in memory, so that it can be copied straight into the arena when loading.
+//! That also means an entry is only valid for the build of Psychopath that
+//! wrote it, so each file starts with a header identifying that, and entries
+//! with a mismatched header are treated as missing.
+
+use std::{
+    fs,
+    mem::{size_of, transmute},
+    path::PathBuf,
+    sync::RwLock,
+};
+
+use kioku::Arena;
+use lazy_static::lazy_static;
+
+use crate::{
+    bbox::BBox, bbox4::BBox4, bbox8::BBox8, hash::ContentHasher, math::Point, paging::PageFiles,
+};
+
+/// The version of the cache file format.  Bump this whenever the way any
+/// data is written to the cache changes.
+pub const CACHE_VERSION: u32 = 5;
+
+const MAGIC: &[u8; 8] = b"PSYCACHE";
+
+lazy_static! {
+    static ref CACHE_DIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+}
+
+/// Sets the directory to cache built data in, or disables caching if
+/// `None`.  Caching is disabled by default.
+pub fn set_cache_dir(dir: Option<PathBuf>) {
+    *CACHE_DIR.write().unwrap() = dir;
+}
+
+/// Returns the directory built data is cached in, if caching is enabled.
+pub fn cache_dir() -> Option<PathBuf> {
+    CACHE_DIR.read().unwrap().clone()
+}
+
+/// Data that can be written to and read back from the cache.
+pub trait Cacheable<'a>: Sized {
+    /// Identifies the kind of data in a cache entry, so that entries for
+    /// one kind of data are never read as another.
+    const KIND: [u8; 4];
+
+    fn write_cache(&self, writer: &mut CacheWriter);
+
+    /// Reads data written by `write_cache()`, allocating it in `arena`.
+    ///
+    /// Returns `None` if the data is malformed.
+    fn read_cache(arena: &'a Arena, reader: &mut CacheReader<'a, '_>) -> Option<Self>;
+}
+
+/// Loads the data cached under the key returned by `key` if there is any,
+/// and otherwise builds it with `build` and caches it for next time.
+///
+/// When caching is disabled this just calls `build`, without computing the
+/// key.  Errors from `build` are passed on, but failing to write to the
+/// cache isn't an error, since the data has been built regardless.
+///
+/// Page files that the loaded data refers to are opened into `page_files`.
+pub fn load_or_build<'a, T, E, K, B>(
+    arena: &'a Arena,
+    page_files: &'a PageFiles,
+    key: K,
+    build: B,
+) -> Result<T, E>
+where
+    T: Cacheable<'a>,
+    K: FnOnce() -> u64,
+    B: FnOnce() -> Result<T, E>,
+{
+    let dir = match cache_dir() {
+        Some(dir) => dir,
+        None => return build(),
+    };
+
+    let path = dir.join(format!("{:016x}.psycache", key()));
+    if let Some(data) = load(arena, page_files, &path) {
+        return Ok(data);
+    }
+
+    let data = build()?;
+
+    // Write to a temporary file first, so that other renders sharing the
+    // cache never see a partially written entry.
+    let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+    if fs::write(&tmp_path, write_entry(&data)).is_ok() && fs::rename(&tmp_path, &path).is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+
+    Ok(data)
+}
+
+fn load<'a, T: Cacheable<'a>>(
+    arena: &'a Arena,
+    page_files: &'a PageFiles,
+    path: &PathBuf,
+) -> Option<T> {
+    read_entry(arena, page_files, &fs::read(path).ok()?)
+}
+
+/// Returns the contents of a cache entry holding `data`.
+fn write_entry<'a, T: Cacheable<'a>>(data: &T) -> Vec<u8> {
+    let mut writer = CacheWriter::new();
+    write_header::<T>(&mut writer);
+    data.write_cache(&mut writer);
+    writer.data
+}
+
+/// Reads the data in a cache entry written by `write_entry()`.
+///
+/// Returns `None` if the entry is malformed, or was written for a different
+/// kind of data or by a different build.
+fn read_entry<'a, T: Cacheable<'a>>(
+    arena: &'a Arena,
+    page_files: &'a PageFiles,
+    bytes: &[u8],
+) -> Option<T> {
+    let mut header = CacheWriter::new();
+    write_header::<T>(&mut header);
+    if !bytes.starts_with(&header.data) {
+        return None;
+    }
+
+    let mut reader = CacheReader {
+        data: &bytes[header.data.len()..],
+        page_files: page_files,
+    };
+    let data = T::read_cache(arena, &mut reader)?;
+    if reader.data.is_empty() {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+fn write_header<'a, T: Cacheable<'a>>(writer: &mut CacheWriter) {
+    writer.write_bytes(MAGIC);
+    writer.write_u32(CACHE_VERSION);
+    writer.write_bytes(&T::KIND);
+
+    // Identify the build that wrote the data, since it's stored in its
+    // in-memory layout.
+    let mut layout = ContentHasher::new();
+    layout.write_bytes(env!("CARGO_PKG_VERSION").as_bytes());
+    layout.write_u64(cfg!(target_endian = "little") as u64);
+    layout.write_u64(size_of::<usize>() as u64);
+    layout.write_u64(size_of::<Point>() as u64);
+    layout.write_u64(size_of::<BBox>() as u64);
+    layout.write_u64(size_of::<BBox4>() as u64);
+    layout.write_u64(size_of::<BBox8>() as u64);
+    writer.write_u64(layout.finish());
+}
+
+/// Accumulates the data of a cache entry.
+pub struct CacheWriter {
+    data: Vec<u8>,
+}
+
+impl CacheWriter {
+    fn new() -> CacheWriter {
+        CacheWriter { data: Vec::new() }
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_u8(&mut self, n: u8) {
+        self.data.push(n);
+    }
+
+    pub fn write_u32(&mut self, n: u32) {
+        self.write_bytes(&n.to_ne_bytes());
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.write_bytes(&n.to_ne_bytes());
+    }
+
+    pub fn write_f32(&mut self, n: f32) {
+        self.write_bytes(&n.to_ne_bytes());
+    }
+
+    /// Writes the length of a slice of plain data followed by its
+    /// in-memory representation.
+    ///
+    /// `T` must not contain any padding bytes or pointers.
+    pub fn write_slice<T: Copy>(&mut self, data: &[T]) {
+        self.write_u64(data.len() as u64);
+        self.write_bytes(unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+        });
+    }
+}
+
+/// Reads back the data of a cache entry.
+///
+/// All of the methods return `None` if there isn't enough data left.
+pub struct CacheReader<'a, 'd> {
+    data: &'d [u8],
+    page_files: &'a PageFiles,
+}
+
+impl<'a, 'd> CacheReader<'a, 'd> {
+    /// The page files of the data being read, which page files it refers to
+    /// should be opened into.
+    pub fn page_files(&self) -> &'a PageFiles {
+        self.page_files
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Option<&'d [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Option<u8> {
+        Some(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Option<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Some(u32::from_ne_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Option<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Some(u64::from_ne_bytes(bytes))
+    }
+
+    pub fn read_f32(&mut self) -> Option<f32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Some(f32::from_ne_bytes(bytes))
+    }
+
+    /// Reads a slice written by `CacheWriter::write_slice()` into `arena`.
+    pub fn read_slice<'b, T: Copy>(&mut self, arena: &'b Arena) -> Option<&'b [T]> {
+        self.read_slice_align(arena, std::mem::align_of::<T>())
+    }
+
+    /// Like `read_slice()`, but with the given alignment.
+    pub fn read_slice_align<'b, T: Copy>(
+        &mut self,
+        arena: &'b Arena,
+        align: usize,
+    ) -> Option<&'b [T]> {
+        let len = self.read_u64()? as usize;
+        let bytes = self.read_bytes(len.checked_mul(size_of::<T>())?)?;
+        let slice = arena.alloc_array_align_uninit::<T>(len, align);
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                bytes.as_ptr(),
+                slice.as_mut_ptr() as *mut u8,
+                bytes.len(),
+            );
+            Some(transmute(slice))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        accel::{SplitMode, WideBVH},
+        boundable::Boundable,
+        math::Normal,
+        surface::triangle_mesh::{MeshStorage, TriangleMesh},
+    };
+
+    // The bounds of a row of boxes at two time samples each, with the row
+    // mirrored at the second one, so that the boxes cross each other and
+    // the build makes temporal splits.
+    fn object_bounds() -> Vec<BBox> {
+        let mut bounds = Vec::new();
+        for i in 0..32 {
+            for &x in &[i as f32, (31 - i) as f32] {
+                let min = Point::new(x, (i % 3) as f32, 0.0);
+                let max = Point::new(x + 0.5, (i % 3) as f32 + 0.5, 0.5);
+                bounds.push(BBox::from_points(min, max));
+            }
+        }
+        bounds
+    }
+
+    fn build_bvh<'a>(arena: &'a Arena, bounds: &[BBox]) -> WideBVH<'a> {
+        let objects: Vec<usize> = (0..(bounds.len() / 2)).collect();
+        WideBVH::from_objects_with_splits(
+            arena,
+            &objects,
+            1,
+            SplitMode::Object,
+            |&i| &bounds[(i * 2)..((i + 1) * 2)],
+            |_, bounds, _, _| (bounds, bounds),
+        )
+        .0
+    }
+
+    fn build_mesh<'a>(
+        arena: &'a Arena,
+        page_files: &'a PageFiles,
+        storage: MeshStorage,
+    ) -> TriangleMesh<'a> {
+        let res = 4;
+        let mut verts = Vec::new();
+        let mut normals = Vec::new();
+        for y in 0..=res {
+            for x in 0..=res {
+                verts.push(Point::new(x as f32, y as f32, ((x * y) % 3) as f32 * 0.25));
+                normals.push(Normal::new(0.0, (x as f32).sin(), 1.0).normalized());
+            }
+        }
+        let mut tris = Vec::new();
+        for y in 0..res {
+            for x in 0..res {
+                let i = y * (res + 1) + x;
+                tris.push((i, i + 1, i + res + 1));
+                tris.push((i + 1, i + res + 2, i + res + 1));
+            }
+        }
+        TriangleMesh::from_verts_and_indices(
+            arena,
+            page_files,
+            &[verts],
+            &Some(vec![normals]),
+            &tris,
+            SplitMode::Object,
+            storage,
+        )
+        .unwrap()
+    }
+
+    /// Checks that `data` can be read back from its cache entry, and returns
+    /// the data read back after checking that it writes the same entry.
+    fn round_trip<'a, T: Cacheable<'a>>(
+        arena: &'a Arena,
+        page_files: &'a PageFiles,
+        data: &T,
+    ) -> T {
+        let entry = write_entry(data);
+        let read: T = read_entry(arena, page_files, &entry).unwrap();
+        assert_eq!(write_entry(&read), entry);
+        read
+    }
+
+    fn assert_same_bounds(a: &[BBox], b: &[BBox]) {
+        assert_eq!(a.len(), b.len());
+        for (a, b) in a.iter().zip(b.iter()) {
+            assert_eq!((a.min, a.max), (b.min, b.max));
+        }
+    }
+
+    #[test]
+    fn bvh_round_trip() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let bounds = object_bounds();
+        let bvh = build_bvh(&arena, &bounds);
+        assert!(format!("{:?}", bvh).contains("Temporal"));
+
+        let read = round_trip(&arena, &page_files, &bvh);
+        assert_same_bounds(read.bounds(), bvh.bounds());
+        assert_eq!(read.leaf_object_ranges(), bvh.leaf_object_ranges());
+        assert_eq!(read.tree_depth(), bvh.tree_depth());
+    }
+
+    #[test]
+    fn mesh_round_trip() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        for &storage in &[MeshStorage::Full, MeshStorage::Compressed] {
+            let mesh = build_mesh(&arena, &page_files, storage);
+            let read = round_trip(&arena, &page_files, &mesh);
+            assert_same_bounds(read.bounds(), mesh.bounds());
+        }
+    }
+
+    #[test]
+    fn paged_mesh_outside_cache_dir_doesnt_load() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let mesh = build_mesh(&arena, &page_files, MeshStorage::Paged);
+        let entry = write_entry(&mesh);
+        assert!(read_entry::<TriangleMesh>(&arena, &page_files, &entry).is_none());
+    }
+
+    #[test]
+    fn truncated_entries_dont_load() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let bounds = object_bounds();
+
+        let entry = write_entry(&build_bvh(&arena, &bounds));
+        for len in 0..entry.len() {
+            assert!(read_entry::<WideBVH>(&arena, &page_files, &entry[..len]).is_none());
+        }
+
+        let entry = write_entry(&build_mesh(&arena, &page_files, MeshStorage::Compressed));
+        for len in 0..entry.len() {
+            assert!(read_entry::<TriangleMesh>(&arena, &page_files, &entry[..len]).is_none());
+        }
+    }
+
+    #[test]
+    fn mismatched_entries_dont_load() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let bounds = object_bounds();
+        let entry = write_entry(&build_bvh(&arena, &bounds));
+        assert!(read_entry::<WideBVH>(&arena, &page_files, &entry).is_some());
+
+        // Another kind of data.
+        assert!(read_entry::<TriangleMesh>(&arena, &page_files, &entry).is_none());
+
+        // Another magic number, version, kind, or build.
+        for &i in &[0, MAGIC.len(), MAGIC.len() + 4, MAGIC.len() + 8] {
+            let mut bad_entry = entry.clone();
+            bad_entry[i] ^= 1;
+            assert!(read_entry::<WideBVH>(&arena, &page_files, &bad_entry).is_none());
+        }
+
+        // Extra data at the end.
+        let mut long_entry = entry.clone();
+        long_entry.push(0);
+        assert!(read_entry::<WideBVH>(&arena, &page_files, &long_entry).is_none());
+    }
+}
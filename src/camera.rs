@@ -1,19 +1,76 @@
 #![allow(dead_code)]
 
+use std::f32::consts::PI as PI_32;
+
 use kioku::Arena;
 
 use crate::{
     lerp::lerp_slice,
-    math::{Matrix4x4, Point, Vector},
+    math::{coordinate_system_from_vector, Matrix4x4, Point, Vector},
     ray::Ray,
-    sampling::square_to_circle,
+    sampling::{square_to_circle, square_to_polygon, Distribution2D},
 };
 
+/// The projection a camera uses to map the image plane to rays.
+///
+/// In all cases the camera looks down +z, and the image plane coordinates
+/// span [-1, 1] horizontally.  For everything but `Orthographic`, the
+/// horizontal extent of the image corresponds to the camera's fov.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum CameraType {
+    Perspective,
+
+    /// Parallel rays, with the horizontal extent of the image covering
+    /// the camera's ortho width in camera space.
+    Orthographic,
+
+    /// Latitude-longitude mapping.  An fov of 360 degrees with a 2:1
+    /// image gives a full spherical panorama.
+    Equirectangular,
+
+    /// Longitude mapped horizontally and height on a unit cylinder mapped
+    /// vertically.
+    Cylindrical,
+
+    /// Fisheye where the distance from the image center is proportional
+    /// to the angle from the view direction.  Nothing is rendered outside
+    /// of the image circle.
+    FisheyeEquidistant,
+
+    /// Fisheye where the distance from the image center is proportional
+    /// to the solid angle from the view direction.  Nothing is rendered
+    /// outside of the image circle.
+    FisheyeEquisolid,
+}
+
+/// The shape of the camera's aperture, which determines the shape of
+/// out-of-focus highlights.
+///
+/// All shapes are scaled by the aperture radius.
+#[derive(Copy, Clone, Debug)]
+pub enum ApertureShape<'a> {
+    Circle,
+
+    /// A regular polygon inscribed in the aperture circle, e.g. to mimic
+    /// the blades of a lens' iris.  `rotation` is in radians.
+    Polygon {
+        blade_count: u32,
+        rotation: f32,
+    },
+
+    /// An image covering the square that bounds the aperture circle,
+    /// with pixel values giving the aperture's transmission.
+    Mask(&'a Distribution2D<'a>),
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Camera<'a> {
+    camera_type: CameraType,
+    aperture_shape: ApertureShape<'a>,
     transforms: &'a [Matrix4x4],
     fovs: &'a [f32],
     tfovs: &'a [f32],
+    ortho_widths: &'a [f32],
     aperture_radii: &'a [f32],
     focus_distances: &'a [f32],
 }
@@ -21,13 +78,22 @@ pub struct Camera<'a> {
 impl<'a> Camera<'a> {
     pub fn new(
         arena: &'a Arena,
+        camera_type: CameraType,
+        aperture_shape: ApertureShape<'a>,
         transforms: &[Matrix4x4],
-        fovs: &[f32],
+        mut fovs: &[f32],
+        mut ortho_widths: &[f32],
         mut aperture_radii: &[f32],
         mut focus_distances: &[f32],
     ) -> Camera<'a> {
         assert!(!transforms.is_empty(), "Camera has no transform(s)!");
-        assert!(!fovs.is_empty(), "Camera has no fov(s)!");
+        if camera_type == CameraType::Orthographic {
+            assert!(!ortho_widths.is_empty(), "Camera has no ortho width(s)!");
+            fovs = &[1.0];
+        } else {
+            assert!(!fovs.is_empty(), "Camera has no fov(s)!");
+            ortho_widths = &[1.0];
+        }
 
         // Aperture needs focus distance and vice-versa.
         if aperture_radii.is_empty() || focus_distances.is_empty() {
@@ -63,41 +129,190 @@ impl<'a> Camera<'a> {
             .collect();
 
         Camera {
+            camera_type: camera_type,
+            aperture_shape: aperture_shape,
             transforms: arena.copy_slice(&transforms),
             fovs: arena.copy_slice(&fovs),
             tfovs: arena.copy_slice(&tfovs),
+            ortho_widths: arena.copy_slice(&ortho_widths),
             aperture_radii: arena.copy_slice(&aperture_radii),
             focus_distances: arena.copy_slice(&focus_distances),
         }
     }
 
-    pub fn generate_ray(&self, x: f32, y: f32, time: f32, wavelength: f32, u: f32, v: f32) -> Ray {
+    /// Generates a camera ray for image plane coordinates `x` and `y`.
+    ///
+    /// Returns `None` if the camera doesn't see anything there, which is
+    /// the case outside the image circle of the fisheye cameras.
+    pub fn generate_ray(
+        &self,
+        x: f32,
+        y: f32,
+        time: f32,
+        wavelength: f32,
+        u: f32,
+        v: f32,
+    ) -> Option<Ray> {
         // Get time-interpolated camera settings
         let transform = lerp_slice(self.transforms, time);
-        let tfov = lerp_slice(self.tfovs, time);
         let aperture_radius = lerp_slice(self.aperture_radii, time);
         let focus_distance = lerp_slice(self.focus_distances, time);
 
-        // Ray origin
-        let orig = {
-            let (u, v) = square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0);
-            Point::new(aperture_radius * u, aperture_radius * v, 0.0)
+        // Point on the lens
+        let lens_uv = {
+            let (u, v) = self.sample_aperture(u, v);
+            (aperture_radius * u, aperture_radius * v)
         };
 
-        // Ray direction
-        let dir = Vector::new(
-            (x * tfov) - (orig.x() / focus_distance),
-            (y * tfov) - (orig.y() / focus_distance),
-            1.0,
-        )
-        .normalized();
+        let (orig, dir) = match self.camera_type {
+            CameraType::Perspective => {
+                let tfov = lerp_slice(self.tfovs, time);
+                let orig = Point::new(lens_uv.0, lens_uv.1, 0.0);
+                let dir = Vector::new(
+                    (x * tfov) - (orig.x() / focus_distance),
+                    (y * tfov) - (orig.y() / focus_distance),
+                    1.0,
+                );
+                (orig, dir)
+            }
+
+            CameraType::Orthographic => {
+                let half_width = lerp_slice(self.ortho_widths, time) * 0.5;
+                let orig = Point::new(
+                    (x * half_width) + lens_uv.0,
+                    (y * half_width) + lens_uv.1,
+                    0.0,
+                );
+                let dir = Vector::new(
+                    -lens_uv.0 / focus_distance,
+                    -lens_uv.1 / focus_distance,
+                    1.0,
+                );
+                (orig, dir)
+            }
+
+            _ => {
+                // Panoramic projections.  We first compute the pinhole ray
+                // direction, and then do depth of field by focusing on
+                // the sphere of radius `focus_distance`.
+                let dir = self.panoramic_direction(x, y, lerp_slice(self.fovs, time))?;
+                if aperture_radius > 0.0 {
+                    let (_, tangent, bitangent) = coordinate_system_from_vector(dir);
+                    let lens_offset = (tangent * lens_uv.0) + (bitangent * lens_uv.1);
+                    let orig = Point::new(0.0, 0.0, 0.0) + lens_offset;
+                    (orig, (dir * focus_distance) - lens_offset)
+                } else {
+                    (Point::new(0.0, 0.0, 0.0), dir)
+                }
+            }
+        };
 
-        Ray {
+        Some(Ray {
             orig: orig * transform,
-            dir: dir * transform,
+            dir: dir.normalized() * transform,
             time: time,
             wavelength: wavelength,
             max_t: std::f32::INFINITY,
+        })
+    }
+
+    /// Returns the approximate world-space width of a pixel at world-space
+    /// position `pos`, for an image `image_width` pixels wide.
+    ///
+    /// This is used to decide how finely to dice geometry.  Depth of field
+    /// is ignored, so out-of-focus geometry is diced as if it were in focus.
+    pub fn pixel_width_at(&self, pos: Point, time: f32, image_width: usize) -> f32 {
+        let transform = lerp_slice(self.transforms, time);
+        let dist = (pos - (Point::new(0.0, 0.0, 0.0) * transform)).length();
+        let image_width = image_width.max(1) as f32;
+
+        match self.camera_type {
+            CameraType::Perspective => 2.0 * lerp_slice(self.tfovs, time) * dist / image_width,
+            CameraType::Orthographic => lerp_slice(self.ortho_widths, time) / image_width,
+            _ => lerp_slice(self.fovs, time) * dist / image_width,
+        }
+    }
+
+    /// Returns the normalized direction of the pinhole camera ray that
+    /// reaches world-space position `pos`.
+    ///
+    /// This is used to pre-shade geometry with the same incoming direction
+    /// it will be shaded with when it's hit by camera rays.
+    pub fn direction_to(&self, pos: Point, time: f32) -> Vector {
+        let transform = lerp_slice(self.transforms, time);
+        match self.camera_type {
+            CameraType::Orthographic => (Vector::new(0.0, 0.0, 1.0) * transform).normalized(),
+            _ => (pos - (Point::new(0.0, 0.0, 0.0) * transform)).normalized(),
+        }
+    }
+
+    /// Maps `u` and `v` in [0, 1) to a point on the aperture, before
+    /// scaling by the aperture radius.
+    fn sample_aperture(&self, u: f32, v: f32) -> (f32, f32) {
+        match self.aperture_shape {
+            ApertureShape::Circle => square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0),
+
+            ApertureShape::Polygon {
+                blade_count,
+                rotation,
+            } => square_to_polygon(u, v, blade_count, rotation),
+
+            ApertureShape::Mask(dist) => {
+                // The mask image is stored top-down.
+                let (x, y) = dist.sample(u, v);
+                ((x * 2.0) - 1.0, 1.0 - (y * 2.0))
+            }
+        }
+    }
+
+    /// Returns the (normalized) camera-space ray direction for image plane
+    /// coordinates `x` and `y`, for the panoramic camera types, or `None` if
+    /// they're outside of the image.
+    fn panoramic_direction(&self, x: f32, y: f32, fov: f32) -> Option<Vector> {
+        let half_fov = fov * 0.5;
+        match self.camera_type {
+            CameraType::Equirectangular => {
+                let longitude = x * half_fov;
+                let latitude = (y * half_fov).max(-PI_32 * 0.5).min(PI_32 * 0.5);
+                Some(Vector::new(
+                    longitude.sin() * latitude.cos(),
+                    latitude.sin(),
+                    longitude.cos() * latitude.cos(),
+                ))
+            }
+
+            CameraType::Cylindrical => {
+                let longitude = x * half_fov;
+                Some(Vector::new(longitude.sin(), y * half_fov, longitude.cos()).normalized())
+            }
+
+            CameraType::FisheyeEquidistant | CameraType::FisheyeEquisolid => {
+                // Outside of the image circle.
+                let r = ((x * x) + (y * y)).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+
+                let theta = if self.camera_type == CameraType::FisheyeEquidistant {
+                    r * half_fov
+                } else {
+                    2.0 * (r * (half_fov * 0.5).sin()).asin()
+                }
+                .min(PI_32);
+
+                if r > 0.0 {
+                    let sin_theta = theta.sin();
+                    Some(Vector::new(
+                        x / r * sin_theta,
+                        y / r * sin_theta,
+                        theta.cos(),
+                    ))
+                } else {
+                    Some(Vector::new(0.0, 0.0, 1.0))
+                }
+            }
+
+            CameraType::Perspective | CameraType::Orthographic => unreachable!(),
         }
     }
 }
@@ -3,7 +3,7 @@
 //! This is based on the work in section 3.9 of "Physically Based Rendering:
 //! From Theory to Implementation" 3rd edition by Pharr et al.
 
-use crate::math::{dot, Normal, Point, Vector};
+use crate::math::{dot, Matrix4x4, Normal, Point, Vector};
 
 #[inline(always)]
 pub fn fp_gamma(n: u32) -> f32 {
@@ -81,6 +81,58 @@ pub fn robust_ray_origin(pos: Point, pos_err: f32, nor: Normal, ray_dir: Vector)
     Point::new(x, y, z)
 }
 
+/// Solves the quadratic equation `a*x^2 + b*x + c = 0`, returning the two
+/// roots in ascending order, or `None` if there are no real roots.
+///
+/// The discriminant is computed in double precision, and the roots are
+/// computed in the numerically stable form that avoids catastrophic
+/// cancellation.
+pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Option<(f32, f32)> {
+    let (a, b, c) = (a as f64, b as f64, c as f64);
+
+    let discriminant = (b * b) - (4.0 * a * c);
+    if discriminant < 0.0 || a == 0.0 {
+        return None;
+    }
+    let discriminant = discriminant.sqrt();
+
+    let q = if b < 0.0 {
+        -0.5 * (b - discriminant)
+    } else {
+        -0.5 * (b + discriminant)
+    };
+
+    let t0 = (q / a) as f32;
+    let t1 = if q != 0.0 { (c / q) as f32 } else { t0 };
+
+    if t0 <= t1 {
+        Some((t0, t1))
+    } else {
+        Some((t1, t0))
+    }
+}
+
+/// Returns the error magnitude of `pos * xform`, given the error magnitude
+/// `pos_err` of `pos`.
+///
+/// This accounts for both the propagation of the existing error and the
+/// rounding error of the transform itself.
+pub fn transformed_pos_err(pos: Point, pos_err: f32, xform: &Matrix4x4) -> f32 {
+    // The absolute values of the transform's columns.
+    let x = (Vector::new(1.0, 0.0, 0.0) * *xform).abs();
+    let y = (Vector::new(0.0, 1.0, 0.0) * *xform).abs();
+    let z = (Vector::new(0.0, 0.0, 1.0) * *xform).abs();
+    let translation = (Point::new(0.0, 0.0, 0.0) * *xform).into_vector().abs();
+
+    let p = pos.into_vector().abs();
+    let abs_transformed = (x * p.x()) + (y * p.y()) + (z * p.z()) + translation;
+    let propagated = (x + y + z) * pos_err;
+
+    ((abs_transformed * fp_gamma(3)) + (propagated * (1.0 + fp_gamma(3))))
+        .co
+        .max_element()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -128,4 +180,29 @@ mod tests {
         assert_eq!(increment_ulp(decrement_ulp(1.2)), 1.2);
         assert_eq!(increment_ulp(decrement_ulp(-1.2)), -1.2);
     }
+
+    #[test]
+    fn quadratic_roots() {
+        let (t0, t1) = solve_quadratic(1.0, -3.0, 2.0).unwrap();
+        assert_eq!(t0, 1.0);
+        assert_eq!(t1, 2.0);
+
+        let (t0, t1) = solve_quadratic(-1.0, 3.0, -2.0).unwrap();
+        assert_eq!(t0, 1.0);
+        assert_eq!(t1, 2.0);
+    }
+
+    #[test]
+    fn quadratic_no_roots() {
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_none());
+        assert!(solve_quadratic(0.0, 1.0, 1.0).is_none());
+    }
+
+    #[test]
+    fn quadratic_stable() {
+        // Naively computed, the small root here cancels to zero.
+        let (t0, t1) = solve_quadratic(1.0, 1.0e4, 1.0).unwrap();
+        assert!((t0 - -1.0e4).abs() < 1.0);
+        assert!((t1 - -1.0e-4).abs() < 1.0e-8);
+    }
 }
@@ -34,3 +34,51 @@ pub fn hash_u32_to_f32(n: u32, seed: u32) -> f32 {
 
     hash as f32 * INV_MAX
 }
+
+/// Incrementally computes a 64-bit hash of a stream of data, for
+/// identifying data by its content.
+#[derive(Debug, Copy, Clone)]
+pub struct ContentHasher {
+    hash: u64,
+    len: u64,
+}
+
+impl ContentHasher {
+    pub fn new() -> ContentHasher {
+        ContentHasher { hash: 0, len: 0 }
+    }
+
+    pub fn write_u64(&mut self, n: u64) {
+        self.hash = hash_u64(self.hash ^ n, self.len);
+        self.len += 8;
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        let mut chunks = bytes.chunks_exact(8);
+        for chunk in &mut chunks {
+            let mut word = [0u8; 8];
+            word.copy_from_slice(chunk);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+        let remainder = chunks.remainder();
+        if !remainder.is_empty() {
+            let mut word = [0u8; 8];
+            word[..remainder.len()].copy_from_slice(remainder);
+            self.write_u64(u64::from_le_bytes(word));
+        }
+        self.write_u64(bytes.len() as u64);
+    }
+
+    /// Hashes the in-memory representation of a slice of plain data.
+    ///
+    /// `T` must not contain any padding bytes or pointers.
+    pub fn write_slice<T: Copy>(&mut self, data: &[T]) {
+        self.write_bytes(unsafe {
+            std::slice::from_raw_parts(data.as_ptr() as *const u8, std::mem::size_of_val(data))
+        });
+    }
+
+    pub fn finish(&self) -> u64 {
+        hash_u64(self.hash, self.len)
+    }
+}
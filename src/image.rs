@@ -5,7 +5,7 @@ use std::{
     cmp,
     fs::File,
     io,
-    io::Write,
+    io::{Read, Write},
     marker::PhantomData,
     path::Path,
     sync::Mutex,
@@ -190,6 +190,85 @@ impl Image {
     }
 }
 
+/// Reads a grayscale PGM image (either ascii "P2" or binary "P5").
+///
+/// Returns the resolution and the pixel values, normalized to [0, 1] and
+/// stored in row-major order starting from the top-left.  The values are
+/// used as-is, without any gamma conversion.
+pub fn read_pgm(path: &Path) -> io::Result<((usize, usize), Vec<f32>)> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    let bad_format = || io::Error::new(io::ErrorKind::InvalidData, "Malformed PGM file.");
+
+    // Parse the header: magic number, width, height, and max value,
+    // separated by whitespace and possibly interspersed with comments.
+    let mut i = 0;
+    let mut header = Vec::new();
+    while header.len() < 4 {
+        while i < data.len() && (data[i] as char).is_ascii_whitespace() {
+            i += 1;
+        }
+        if i < data.len() && data[i] == b'#' {
+            while i < data.len() && data[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+        let start = i;
+        while i < data.len() && !(data[i] as char).is_ascii_whitespace() {
+            i += 1;
+        }
+        if start == i {
+            return Err(bad_format());
+        }
+        header.push(std::str::from_utf8(&data[start..i]).map_err(|_| bad_format())?);
+    }
+    let is_binary = match header[0] {
+        "P2" => false,
+        "P5" => true,
+        _ => return Err(bad_format()),
+    };
+    let width: usize = header[1].parse().map_err(|_| bad_format())?;
+    let height: usize = header[2].parse().map_err(|_| bad_format())?;
+    let max_val: u32 = header[3].parse().map_err(|_| bad_format())?;
+    if max_val == 0 || max_val > 65535 {
+        return Err(bad_format());
+    }
+    let pixel_count = width * height;
+
+    // Parse the pixels.
+    let mut pixels = Vec::with_capacity(pixel_count);
+    if is_binary {
+        // Exactly one whitespace character separates the header from the
+        // pixel data.
+        let data = &data[(i + 1).min(data.len())..];
+        let bytes_per_pixel = if max_val < 256 { 1 } else { 2 };
+        if data.len() < pixel_count * bytes_per_pixel {
+            return Err(bad_format());
+        }
+        for p in data.chunks(bytes_per_pixel).take(pixel_count) {
+            let v = if bytes_per_pixel == 1 {
+                p[0] as u32
+            } else {
+                ((p[0] as u32) << 8) | p[1] as u32
+            };
+            pixels.push(v as f32 / max_val as f32);
+        }
+    } else {
+        let text = std::str::from_utf8(&data[i..]).map_err(|_| bad_format())?;
+        for word in text.split_ascii_whitespace().take(pixel_count) {
+            let v: u32 = word.parse().map_err(|_| bad_format())?;
+            pixels.push(v as f32 / max_val as f32);
+        }
+        if pixels.len() < pixel_count {
+            return Err(bad_format());
+        }
+    }
+
+    Ok(((width, height), pixels))
+}
+
 #[derive(Debug)]
 pub struct Bucket<'a> {
     min: (u32, u32),
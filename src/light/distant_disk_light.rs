@@ -5,8 +5,9 @@ use kioku::Arena;
 use crate::{
     color::{Color, SpectralSample},
     lerp::lerp_slice,
-    math::{coordinate_system_from_vector, Vector},
+    math::{coordinate_system_from_vector, Normal, Vector},
     sampling::{uniform_sample_cone, uniform_sample_cone_pdf},
+    shading::surface_closure::SurfaceClosure,
 };
 
 use super::WorldLightSource;
@@ -94,4 +95,27 @@ impl<'a> WorldLightSource for DistantDiskLight<'a> {
             .fold(0.0, |a, &b| a + b.approximate_energy())
             / self.colors.len() as f32
     }
+
+    fn approximate_contribution(
+        &self,
+        inc: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+    ) -> f32 {
+        let radius: f64 = lerp_slice(self.radii, time) as f64;
+        let direction = lerp_slice(self.directions, time);
+        let col = lerp_slice(self.colors, time);
+        let solid_angle = 2.0 * PI_64 * (1.0 - radius.cos());
+
+        // Treat the light as a sphere at unit distance that subtends the
+        // same angle as the disk.
+        let to_light = -direction.normalized();
+        let radius_sin = radius.sin() as f32;
+        let radiance = col.approximate_energy() / solid_angle as f32;
+
+        radiance
+            * sc.estimate_eval_over_sphere_light(inc, to_light, radius_sin * radius_sin, nor, nor_g)
+    }
 }
@@ -7,6 +7,8 @@ use std::fmt::Debug;
 use crate::{
     color::SpectralSample,
     math::{Matrix4x4, Normal, Point, Vector},
+    normal_cone::NormalCone,
+    shading::surface_closure::SurfaceClosure,
     surface::Surface,
 };
 
@@ -55,6 +57,12 @@ pub trait SurfaceLight: Surface {
     /// for any surface that does emit light.  This is used for importance
     /// sampling.
     fn approximate_energy(&self) -> f32;
+
+    /// Returns a cone bounding the directions the surface emits light in,
+    /// in object space and over the whole shutter interval.
+    ///
+    /// This is used for importance sampling.
+    fn normal_cone(&self) -> NormalCone;
 }
 
 /// An infinite light source that cannot be bounded in space.  E.g.
@@ -91,4 +99,25 @@ pub trait WorldLightSource: Debug + Sync {
     /// for any light that emits any light.  This is used for importance
     /// sampling.
     fn approximate_energy(&self) -> f32;
+
+    /// Returns a rough estimate of the light arriving from the light source
+    /// at a surface with the given closure.
+    ///
+    /// - `inc`: The incoming ray direction at the surface.
+    /// - `nor`: The shading normal of the surface.
+    /// - `nor_g`: The geometric normal of the surface.
+    /// - `sc`: The surface closure to estimate with.
+    /// - `time`: The time to estimate at.
+    ///
+    /// This is used to decide between sampling world lights and local
+    /// lights, and is in the same units as
+    /// `LightAccel::approximate_contribution()`.
+    fn approximate_contribution(
+        &self,
+        inc: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        sc: &SurfaceClosure,
+        time: f32,
+    ) -> f32;
 }
@@ -6,6 +6,7 @@ use crate::{
     color::{Color, SpectralSample},
     lerp::lerp_slice,
     math::{cross, dot, Matrix4x4, Normal, Point, Vector},
+    normal_cone::NormalCone,
     ray::{RayBatch, RayStack},
     sampling::{
         spherical_triangle_solid_angle, triangle_surface_area, uniform_sample_spherical_triangle,
@@ -252,6 +253,11 @@ impl<'a> SurfaceLight for RectangleLight<'a> {
             .fold(0.0, |a, &b| a + b.approximate_energy())
             / self.colors.len() as f32
     }
+
+    fn normal_cone(&self) -> NormalCone {
+        // Rectangle lights currently emit from both sides.
+        NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), true)
+    }
 }
 
 impl<'a> Surface for RectangleLight<'a> {
@@ -262,10 +268,15 @@ impl<'a> Surface for RectangleLight<'a> {
         isects: &mut [SurfaceIntersection],
         shader: &dyn SurfaceShader,
         space: &[Matrix4x4],
+        object_id: usize,
     ) {
         let _ = shader; // Silence 'unused' warning
 
         ray_stack.pop_do_next_task(|ray_idx| {
+            if rays.is_done(ray_idx) {
+                return;
+            }
+
             let time = rays.time(ray_idx);
             let orig = rays.orig(ray_idx);
             let dir = rays.dir(ray_idx);
@@ -302,6 +313,9 @@ impl<'a> Surface for RectangleLight<'a> {
                                 pos_err: pos_err,
                                 nor: normal,
                                 nor_g: normal,
+                                tangent: Vector::new(0.0, 0.0, 0.0),
+                                color: None,
+                                object_id: object_id,
                                 local_space: xform,
                                 sample_pdf: self.sample_pdf(
                                     &xform,
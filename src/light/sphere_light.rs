@@ -8,6 +8,7 @@ use crate::{
     color::{Color, SpectralSample},
     lerp::lerp_slice,
     math::{coordinate_system_from_vector, dot, Matrix4x4, Normal, Point, Vector},
+    normal_cone::NormalCone,
     ray::{RayBatch, RayStack},
     sampling::{uniform_sample_cone, uniform_sample_cone_pdf, uniform_sample_sphere},
     shading::surface_closure::SurfaceClosure,
@@ -201,6 +202,10 @@ impl<'a> SurfaceLight for SphereLight<'a> {
             .fold(0.0, |a, &b| a + b.approximate_energy())
             / self.colors.len() as f32
     }
+
+    fn normal_cone(&self) -> NormalCone {
+        NormalCone::full_sphere()
+    }
 }
 
 impl<'a> Surface for SphereLight<'a> {
@@ -211,10 +216,15 @@ impl<'a> Surface for SphereLight<'a> {
         isects: &mut [SurfaceIntersection],
         shader: &dyn SurfaceShader,
         space: &[Matrix4x4],
+        object_id: usize,
     ) {
         let _ = shader; // Silence 'unused' warning
 
         ray_stack.pop_do_next_task(|ray_idx| {
+            if rays.is_done(ray_idx) {
+                return;
+            }
+
             let time = rays.time(ray_idx);
 
             // Get the transform space
@@ -306,6 +316,9 @@ impl<'a> Surface for SphereLight<'a> {
                     pos_err: pos_err,
                     nor: normal,
                     nor_g: normal,
+                    tangent: Vector::new(0.0, 0.0, 0.0),
+                    color: None,
+                    object_id: object_id,
                     local_space: xform,
                     sample_pdf: self.sample_pdf(
                         &xform,
@@ -18,7 +18,9 @@ mod accel;
 mod algorithm;
 mod bbox;
 mod bbox4;
+mod bbox8;
 mod boundable;
+mod cache;
 mod camera;
 mod color;
 mod fp_utils;
@@ -28,13 +30,18 @@ mod image;
 mod lerp;
 mod light;
 mod math;
+mod medium;
 mod mis;
+mod morton;
+mod normal_cone;
+mod paging;
 mod parse;
 mod ray;
 mod renderer;
 mod sampling;
 mod scene;
 mod shading;
+mod shutter;
 mod surface;
 mod timer;
 mod tracer;
@@ -48,11 +55,12 @@ use nom::bytes::complete::take_until;
 use kioku::Arena;
 
 use crate::{
-    accel::BVH4Node,
+    accel::{BVH4Node, BVH8Node},
     bbox::BBox,
+    paging::PageFiles,
     parse::{parse_scene, DataTree},
     renderer::LightPath,
-    surface::SurfaceIntersection,
+    surface::{triangle_mesh::MeshStorage, SurfaceIntersection},
     timer::Timer,
 };
 
@@ -123,8 +131,45 @@ fn main() {
                 .long("threads")
                 .value_name("N")
                 .help(
-                    "Number of threads to render with.  Defaults to the number of logical \
-                     cores on the system.",
+                    "Number of threads to build and render the scene with.  Defaults to the \
+                     number of logical cores on the system.",
+                )
+                .takes_value(true)
+                .validator(|s| {
+                    usize::from_str(&s)
+                        .and(Ok(()))
+                        .or(Err("must be an integer".to_string()))
+                }),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .value_name("DIR")
+                .help(
+                    "Cache built meshes and acceleration structures in DIR, and load them \
+                     from there on later renders of the same data.",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mesh_storage")
+                .long("mesh_storage")
+                .value_name("STORAGE")
+                .help(
+                    "How to store the geometry of meshes that don't specify it themselves.  \
+                     Compressed meshes take less memory, and Paged meshes are paged in from \
+                     disk as needed.  Defaults to Full.",
+                )
+                .takes_value(true)
+                .possible_values(&["Full", "Compressed", "Paged"]),
+        )
+        .arg(
+            Arg::with_name("page_budget")
+                .long("page_budget")
+                .value_name("MiB")
+                .help(
+                    "Memory budget for mesh geometry that's paged in from disk.  Defaults to \
+                     1024 MiB.",
                 )
                 .takes_value(true)
                 .validator(|s| {
@@ -167,6 +212,7 @@ fn main() {
         println!("BBox size: {} bytes", mem::size_of::<BBox>());
         // println!("BVHNode size: {} bytes", mem::size_of::<BVHNode>());
         println!("BVH4Node size: {} bytes", mem::size_of::<BVH4Node>());
+        println!("BVH8Node size: {} bytes", mem::size_of::<BVH8Node>());
         return;
     }
 
@@ -237,6 +283,30 @@ fn main() {
         println!("\tParsed scene file in {:.3}s", t.tick());
     }
 
+    let thread_count = if let Some(threads) = args.value_of("threads") {
+        u32::from_str(threads).unwrap()
+    } else {
+        num_cpus::get() as u32
+    };
+    accel::set_build_thread_count(thread_count as usize);
+
+    if let Some(dir) = args.value_of("cache") {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            panic!("Argument '--cache': could not create '{}': {}", dir, e);
+        }
+        cache::set_cache_dir(Some(dir.into()));
+    }
+
+    let mesh_storage = match args.value_of("mesh_storage") {
+        Some("Compressed") => MeshStorage::Compressed,
+        Some("Paged") => MeshStorage::Paged,
+        _ => MeshStorage::Full,
+    };
+
+    if let Some(mib) = args.value_of("page_budget") {
+        paging::set_page_budget(usize::from_str(mib).unwrap() << 20);
+    }
+
     // Iterate through scenes and render them
     if let DataTree::Internal { ref children, .. } = dt {
         for child in children {
@@ -247,10 +317,12 @@ fn main() {
                 }
 
                 let arena = Arena::new().with_block_size((1 << 20) * 4);
-                let mut r = parse_scene(&arena, child).unwrap_or_else(|e| {
-                    e.print(&psy_contents);
-                    panic!("Parse error.");
-                });
+                let page_files = PageFiles::new();
+                let mut r =
+                    parse_scene(&arena, &page_files, child, mesh_storage).unwrap_or_else(|e| {
+                        e.print(&psy_contents);
+                        panic!("Parse error.");
+                    });
 
                 if let Some(spp) = args.value_of("spp") {
                     if !args.is_present("serialized_output") {
@@ -266,12 +338,6 @@ fn main() {
                         4096
                     };
 
-                let thread_count = if let Some(threads) = args.value_of("threads") {
-                    u32::from_str(threads).unwrap()
-                } else {
-                    num_cpus::get() as u32
-                };
-
                 if !args.is_present("serialized_output") {
                     println!("\tBuilt scene in {:.3}s", t.tick());
                 }
@@ -300,6 +366,7 @@ fn main() {
                         (rstats.ray_count as f64 / (ntime * rstats.trace_time) as f64) as u64
                     );
                     println!("\t\t\tRay/node tests:       {}", rstats.accel_node_visits);
+                    println!("\t\t\tPage faults:          {}", rstats.page_faults);
                     println!(
                         "\t\tInitial ray generation: {:.3}s",
                         ntime * rstats.initial_ray_generation_time
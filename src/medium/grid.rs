@@ -0,0 +1,476 @@
+//! Heterogeneous media from dense voxel grids, e.g. smoke and fire from
+//! simulation caches.
+//!
+//! Voxel values are cell-centered and trilinearly interpolated.  To track
+//! through the grid efficiently, it's overlaid with a coarser grid of
+//! majorants, each the maximum density within a block of voxels.  Tracking
+//! then steps through the blocks, using each one's majorant in turn, so
+//! thin areas of the medium don't have to be tracked at the sampling rate
+//! of the densest area.
+
+use std::{
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use glam::Vec4;
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    color::Color,
+    math::{Point, Vector},
+};
+
+use super::{delta_track, ratio_track, Medium, MediumEvent};
+
+/// The width, in voxels, of the blocks of the majorant grid.
+const MAJORANT_BLOCK_SIZE: usize = 8;
+
+#[derive(Debug, Copy, Clone)]
+pub struct GridMedium<'a> {
+    bounds: BBox,
+    resolution: [usize; 3],
+    density: &'a [f32], // Voxel densities, x varying fastest and z slowest
+    temperature: Option<&'a [f32]>, // Voxel temperatures in kelvin, laid out the same as density
+    majorant_resolution: [usize; 3],
+    majorants: &'a [f32], // Maximum density of each majorant block
+
+    absorption: Color,
+    scattering: Color,
+    density_factor: f32,  // Multiplier for all densities
+    emission_factor: f32, // Brightness multiplier for the blackbody emission
+    anisotropy: f32,
+}
+
+impl<'a> GridMedium<'a> {
+    /// Creates a new grid medium.
+    ///
+    /// - `bounds`: The world-space box the grid fills.
+    /// - `resolution`: The number of voxels along each axis.
+    /// - `density`: The voxel densities, with x varying fastest and z
+    ///   slowest.
+    /// - `temperature`: Optional voxel temperatures in kelvin, laid out the
+    ///   same as `density`, which make the medium emit light as a blackbody.
+    #[allow(clippy::too_many_arguments)]
+    pub fn new<'b>(
+        arena: &'b Arena,
+        bounds: BBox,
+        resolution: [usize; 3],
+        density: &[f32],
+        temperature: Option<&[f32]>,
+        absorption: Color,
+        scattering: Color,
+        density_factor: f32,
+        emission_factor: f32,
+        anisotropy: f32,
+    ) -> GridMedium<'b> {
+        let voxel_count = resolution[0] * resolution[1] * resolution[2];
+        assert_eq!(density.len(), voxel_count);
+        if let Some(temperature) = temperature {
+            assert_eq!(temperature.len(), voxel_count);
+        }
+
+        // Build the majorant grid.  Each block's majorant has to cover the
+        // voxels just outside of it as well, since those contribute to the
+        // interpolated density near its edges.
+        let majorant_resolution = [
+            (resolution[0] + MAJORANT_BLOCK_SIZE - 1) / MAJORANT_BLOCK_SIZE,
+            (resolution[1] + MAJORANT_BLOCK_SIZE - 1) / MAJORANT_BLOCK_SIZE,
+            (resolution[2] + MAJORANT_BLOCK_SIZE - 1) / MAJORANT_BLOCK_SIZE,
+        ];
+        let majorants: &mut [f32] = {
+            let majorants = arena.alloc_array_uninit(
+                majorant_resolution[0] * majorant_resolution[1] * majorant_resolution[2],
+            );
+
+            let voxel_range = |block: usize, axis: usize| {
+                let start = (block * MAJORANT_BLOCK_SIZE).max(1) - 1;
+                let end = ((block + 1) * MAJORANT_BLOCK_SIZE + 1).min(resolution[axis]);
+                start..end
+            };
+            for bz in 0..majorant_resolution[2] {
+                for by in 0..majorant_resolution[1] {
+                    for bx in 0..majorant_resolution[0] {
+                        let mut max_density = 0.0f32;
+                        for z in voxel_range(bz, 2) {
+                            for y in voxel_range(by, 1) {
+                                for x in voxel_range(bx, 0) {
+                                    max_density = max_density.max(
+                                        density[x + (resolution[0] * (y + (resolution[1] * z)))],
+                                    );
+                                }
+                            }
+                        }
+                        let i =
+                            bx + (majorant_resolution[0] * (by + (majorant_resolution[1] * bz)));
+                        unsafe {
+                            *majorants[i].as_mut_ptr() = max_density;
+                        }
+                    }
+                }
+            }
+
+            unsafe { std::mem::transmute(majorants) }
+        };
+
+        GridMedium {
+            bounds: bounds,
+            resolution: resolution,
+            density: arena.copy_slice(density),
+            temperature: temperature.map(|temperature| &*arena.copy_slice(temperature)),
+            majorant_resolution: majorant_resolution,
+            majorants: majorants,
+
+            absorption: absorption,
+            scattering: scattering,
+            density_factor: density_factor,
+            emission_factor: emission_factor,
+            anisotropy: anisotropy,
+        }
+    }
+
+    /// Converts a world-space point to voxel space, where each voxel is a
+    /// unit cube and the grid starts at the origin.
+    fn voxel_space_point(&self, p: Point) -> Vector {
+        let size = self.bounds.max - self.bounds.min;
+        let v = p - self.bounds.min;
+        Vector::new(
+            v.x() * (self.resolution[0] as f32 / size.x()),
+            v.y() * (self.resolution[1] as f32 / size.y()),
+            v.z() * (self.resolution[2] as f32 / size.z()),
+        )
+    }
+
+    /// Returns the trilinearly interpolated value of a voxel grid at a
+    /// point in voxel space.
+    fn lookup(&self, voxels: &[f32], v: Vector) -> f32 {
+        let mut i0 = [0usize; 3];
+        let mut i1 = [0usize; 3];
+        let mut alpha = [0.0f32; 3];
+        for axis in 0..3 {
+            // Voxel values are at voxel centers.
+            let c = (v.get_n(axis) - 0.5).max(0.0);
+            let max_i = self.resolution[axis] - 1;
+            let fl = c.floor();
+            i0[axis] = (fl as usize).min(max_i);
+            i1[axis] = (i0[axis] + 1).min(max_i);
+            alpha[axis] = c - fl;
+        }
+
+        let voxel = |x: usize, y: usize, z: usize| {
+            voxels[x + (self.resolution[0] * (y + (self.resolution[1] * z)))]
+        };
+        let lerp = |a: f32, b: f32, alpha: f32| a + ((b - a) * alpha);
+        let x00 = lerp(
+            voxel(i0[0], i0[1], i0[2]),
+            voxel(i1[0], i0[1], i0[2]),
+            alpha[0],
+        );
+        let x10 = lerp(
+            voxel(i0[0], i1[1], i0[2]),
+            voxel(i1[0], i1[1], i0[2]),
+            alpha[0],
+        );
+        let x01 = lerp(
+            voxel(i0[0], i0[1], i1[2]),
+            voxel(i1[0], i0[1], i1[2]),
+            alpha[0],
+        );
+        let x11 = lerp(
+            voxel(i0[0], i1[1], i1[2]),
+            voxel(i1[0], i1[1], i1[2]),
+            alpha[0],
+        );
+        lerp(lerp(x00, x10, alpha[1]), lerp(x01, x11, alpha[1]), alpha[2])
+    }
+
+    /// Returns the absorption and scattering coefficients and the emission
+    /// at a point in voxel space, in the terms `delta_track()` expects.
+    fn coefficients(&self, v: Vector, wavelength: f32, sigma: (Vec4, Vec4)) -> (Vec4, Vec4, Vec4) {
+        let density = self.lookup(self.density, v);
+        let sigma_a = sigma.0 * density;
+        let sigma_s = sigma.1 * density;
+        let emission = if let Some(temperature) = self.temperature {
+            let temperature = self.lookup(temperature, v);
+            Color::new_blackbody(temperature, self.emission_factor)
+                .to_spectral_sample(wavelength)
+                .e
+                * sigma_a
+        } else {
+            Vec4::splat(0.0)
+        };
+
+        (sigma_a, sigma_s, emission)
+    }
+
+    /// Steps through the majorant blocks along a ray, calling `f` with the
+    /// t range of each block the ray passes through and its majorant
+    /// density.  Stops early if `f` returns false.
+    fn for_each_block<F>(&self, orig: Point, dir: Vector, max_t: f32, mut f: F)
+    where
+        F: FnMut((f32, f32), f32) -> bool,
+    {
+        // Ray in majorant block space.
+        let block_size = MAJORANT_BLOCK_SIZE as f32;
+        let orig_b = self.voxel_space_point(orig) / block_size;
+        let dir_b =
+            (self.voxel_space_point(orig + dir) - self.voxel_space_point(orig)) / block_size;
+
+        // Clip the ray to the grid.
+        let mut t_range = (0.0f32, max_t);
+        for axis in 0..3 {
+            let o = orig_b.get_n(axis);
+            let d = dir_b.get_n(axis);
+            let extent = self.resolution[axis] as f32 / block_size;
+            if d == 0.0 {
+                if o < 0.0 || o > extent {
+                    return;
+                }
+            } else {
+                let t0 = -o / d;
+                let t1 = (extent - o) / d;
+                t_range.0 = t_range.0.max(t0.min(t1));
+                t_range.1 = t_range.1.min(t0.max(t1));
+            }
+        }
+        if t_range.0 >= t_range.1 {
+            return;
+        }
+
+        // Set up the DDA.
+        let mut block = [0usize; 3];
+        let mut step = [0isize; 3];
+        let mut t_next = [std::f32::INFINITY; 3];
+        let mut t_delta = [std::f32::INFINITY; 3];
+        for axis in 0..3 {
+            let o = orig_b.get_n(axis) + (dir_b.get_n(axis) * t_range.0);
+            let d = dir_b.get_n(axis);
+            block[axis] = (o.max(0.0) as usize).min(self.majorant_resolution[axis] - 1);
+            if d > 0.0 {
+                step[axis] = 1;
+                t_next[axis] = t_range.0 + (((block[axis] + 1) as f32 - o) / d);
+                t_delta[axis] = 1.0 / d;
+            } else if d < 0.0 {
+                step[axis] = -1;
+                t_next[axis] = t_range.0 + ((block[axis] as f32 - o) / d);
+                t_delta[axis] = -1.0 / d;
+            }
+        }
+
+        // Step through the blocks.
+        let mut t = t_range.0;
+        loop {
+            let axis = if t_next[0] < t_next[1] {
+                if t_next[0] < t_next[2] {
+                    0
+                } else {
+                    2
+                }
+            } else if t_next[1] < t_next[2] {
+                1
+            } else {
+                2
+            };
+            let t_exit = t_next[axis].min(t_range.1);
+
+            let i = block[0]
+                + (self.majorant_resolution[0]
+                    * (block[1] + (self.majorant_resolution[1] * block[2])));
+            if t_exit > t && !f((t, t_exit), self.majorants[i]) {
+                return;
+            }
+
+            if t_exit >= t_range.1 {
+                return;
+            }
+            t = t_exit;
+            t_next[axis] += t_delta[axis];
+            let next = block[axis] as isize + step[axis];
+            if next < 0 || next >= self.majorant_resolution[axis] as isize {
+                return;
+            }
+            block[axis] = next as usize;
+        }
+    }
+
+    /// Returns the absorption and scattering coefficients at unit density
+    /// for the given hero wavelength.
+    fn unit_coefficients(&self, wavelength: f32) -> (Vec4, Vec4) {
+        (
+            self.absorption.to_spectral_sample(wavelength).e * self.density_factor,
+            self.scattering.to_spectral_sample(wavelength).e * self.density_factor,
+        )
+    }
+}
+
+impl<'a> Medium for GridMedium<'a> {
+    fn track(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        weight: &mut Vec4,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> MediumEvent {
+        let _ = time; // Silence "unused" compiler warning
+
+        // Everything is tracked in terms of the ray's t rather than
+        // distance, so the coefficients are scaled by the length of `dir`.
+        let dir_len = dir.length();
+        let sigma = self.unit_coefficients(wavelength);
+        let sigma = (sigma.0 * dir_len, sigma.1 * dir_len);
+        let max_sigma_t = (sigma.0 + sigma.1).max_element();
+
+        let mut event = MediumEvent::Pass;
+        self.for_each_block(orig, dir, max_t, |t_range, max_density| {
+            match delta_track(
+                t_range,
+                max_density * max_sigma_t,
+                |t| self.coefficients(self.voxel_space_point(orig + (dir * t)), wavelength, sigma),
+                weight,
+                rng,
+            ) {
+                Some(e) => {
+                    event = e;
+                    false
+                }
+                None => true,
+            }
+        });
+
+        event
+    }
+
+    fn transmittance(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> Vec4 {
+        let _ = time; // Silence "unused" compiler warning
+
+        let dir_len = dir.length();
+        let sigma = self.unit_coefficients(wavelength);
+        let sigma = (sigma.0 * dir_len, sigma.1 * dir_len);
+        let max_sigma_t = (sigma.0 + sigma.1).max_element();
+
+        let mut tr = Vec4::splat(1.0);
+        self.for_each_block(orig, dir, max_t, |t_range, max_density| {
+            tr *= ratio_track(
+                t_range,
+                max_density * max_sigma_t,
+                |t| {
+                    let v = self.voxel_space_point(orig + (dir * t));
+                    let density = self.lookup(self.density, v);
+                    (sigma.0 * density, sigma.1 * density, Vec4::splat(0.0))
+                },
+                rng,
+            );
+            tr.max_element() > 0.0
+        });
+
+        tr
+    }
+
+    fn anisotropy(&self) -> f32 {
+        self.anisotropy
+    }
+}
+
+/// Reads a dense voxel grid from a raw binary file.
+///
+/// The file is just the voxel values as little-endian 32-bit floats, with
+/// x varying fastest and z slowest, and must contain exactly as many as
+/// `resolution` calls for.
+pub fn read_raw_grid(path: &Path, resolution: [usize; 3]) -> io::Result<Vec<f32>> {
+    let mut data = Vec::new();
+    File::open(path)?.read_to_end(&mut data)?;
+
+    if data.len() != resolution[0] * resolution[1] * resolution[2] * 4 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Voxel grid file size doesn't match its resolution.",
+        ));
+    }
+
+    Ok(data
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grid_medium<'a>(
+        arena: &'a Arena,
+        resolution: [usize; 3],
+        density: &[f32],
+    ) -> GridMedium<'a> {
+        GridMedium::new(
+            arena,
+            BBox::from_points(Point::new(0.0, 0.0, 0.0), Point::new(1.0, 1.0, 1.0)),
+            resolution,
+            density,
+            None,
+            Color::new_xyz((1.0, 1.0, 1.0)),
+            Color::new_xyz((0.0, 0.0, 0.0)),
+            1.0,
+            1.0,
+            0.0,
+        )
+    }
+
+    #[test]
+    fn lookup_interpolates_between_voxel_centers() {
+        let arena = Arena::new();
+        let medium = grid_medium(&arena, [2, 1, 1], &[1.0, 3.0]);
+
+        let at = |x: f32| medium.lookup(medium.density, Vector::new(x, 0.5, 0.5));
+        assert_eq!(at(0.0), 1.0);
+        assert_eq!(at(0.5), 1.0);
+        assert_eq!(at(1.0), 2.0);
+        assert_eq!(at(1.5), 3.0);
+        assert_eq!(at(2.0), 3.0);
+    }
+
+    #[test]
+    fn majorants_bound_interpolated_density() {
+        let arena = Arena::new();
+        let resolution = [20, 3, 3];
+        let density: Vec<_> = (0..(20 * 3 * 3))
+            .map(|i| if i % 20 == 8 { 5.0 } else { 1.0 })
+            .collect();
+        let medium = grid_medium(&arena, resolution, &density);
+
+        // The spike at x = 8 is just inside the second block, but it
+        // affects the density at the end of the first block.
+        assert_eq!(medium.majorants, &[5.0, 5.0, 1.0]);
+
+        let mut max_t_seen = 0.0f32;
+        medium.for_each_block(
+            Point::new(0.0, 0.5, 0.5),
+            Vector::new(1.0, 0.0, 0.0),
+            std::f32::INFINITY,
+            |t_range, majorant| {
+                let steps = 16;
+                for i in 0..=steps {
+                    let t = t_range.0 + ((t_range.1 - t_range.0) * i as f32 / steps as f32);
+                    let v = medium.voxel_space_point(Point::new(t, 0.5, 0.5));
+                    assert!(medium.lookup(medium.density, v) <= majorant);
+                }
+                max_t_seen = max_t_seen.max(t_range.1);
+                true
+            },
+        );
+        assert_eq!(max_t_seen, 1.0);
+    }
+}
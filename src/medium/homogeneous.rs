@@ -0,0 +1,93 @@
+use glam::Vec4;
+
+use crate::{
+    color::Color,
+    math::{Point, Vector},
+};
+
+use super::{delta_track, Medium, MediumEvent};
+
+/// A medium with the same properties everywhere.
+#[derive(Debug, Copy, Clone)]
+pub struct HomogeneousMedium {
+    pub absorption: Color,
+    pub scattering: Color,
+    pub density: f32, // Multiplier for both absorption and scattering
+    pub anisotropy: f32,
+}
+
+impl HomogeneousMedium {
+    /// Returns the absorption and scattering coefficients for the given
+    /// hero wavelength.
+    fn coefficients(&self, wavelength: f32) -> (Vec4, Vec4) {
+        (
+            self.absorption.to_spectral_sample(wavelength).e * self.density,
+            self.scattering.to_spectral_sample(wavelength).e * self.density,
+        )
+    }
+}
+
+impl Medium for HomogeneousMedium {
+    fn track(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        weight: &mut Vec4,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> MediumEvent {
+        let _ = (orig, time); // Silence "unused" compiler warning
+
+        let (sigma_a, sigma_s) = self.coefficients(wavelength);
+        let majorant = (sigma_a + sigma_s).max_element();
+        let dir_len = dir.length();
+
+        match delta_track(
+            (0.0, max_t * dir_len),
+            majorant,
+            |_| (sigma_a, sigma_s, Vec4::splat(0.0)),
+            weight,
+            rng,
+        ) {
+            Some(MediumEvent::Scatter(d)) => MediumEvent::Scatter(d / dir_len),
+            Some(event) => event,
+            None => MediumEvent::Pass,
+        }
+    }
+
+    fn transmittance(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> Vec4 {
+        let _ = (orig, time, rng); // Silence "unused" compiler warning
+
+        // This can be calculated directly, so there's no need to track.
+        let (sigma_a, sigma_s) = self.coefficients(wavelength);
+        let sigma_t = sigma_a + sigma_s;
+        let dist = max_t * dir.length();
+        let tr = |sigma: f32| {
+            if sigma > 0.0 {
+                (-sigma * dist).exp()
+            } else {
+                1.0
+            }
+        };
+        Vec4::new(
+            tr(sigma_t.x()),
+            tr(sigma_t.y()),
+            tr(sigma_t.z()),
+            tr(sigma_t.w()),
+        )
+    }
+
+    fn anisotropy(&self) -> f32 {
+        self.anisotropy
+    }
+}
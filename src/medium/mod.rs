@@ -0,0 +1,249 @@
+//! Participating media, for fog, smoke and the like.
+//!
+//! Media fill either the whole world or the inside of closed surfaces that
+//! are bound to them.  Light paths are tracked through them with delta
+//! tracking, and shadow rays with ratio tracking.  Both of those work in
+//! terms of a majorant: an upper bound on the medium's extinction
+//! coefficient, which lets media with varying density be tracked without
+//! having to integrate their density along the ray.
+//!
+//! Since each light path carries four wavelengths, the tracking is the
+//! spectral variant from "Spectral and Decomposition Tracking for
+//! Rendering Heterogeneous Volumes" by Kutz et al.
+
+pub mod grid;
+pub mod homogeneous;
+
+use std::fmt::Debug;
+
+use glam::Vec4;
+
+use crate::math::{Point, Vector};
+
+pub use self::{grid::GridMedium, homogeneous::HomogeneousMedium};
+
+/// The result of tracking a ray through a medium.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MediumEvent {
+    /// The ray scattered at the given ray t.
+    Scatter(f32),
+
+    /// The ray was absorbed.  Any light emitted by the medium at the point
+    /// of absorption is left in the tracking weight.
+    Absorb,
+
+    /// The ray made it through without scattering or being absorbed.
+    Pass,
+}
+
+pub trait Medium: Debug + Sync {
+    /// Tracks a ray from `orig` in direction `dir` through the medium, up
+    /// to `max_t`, to find where it scatters, if anywhere.
+    ///
+    /// `dir` needn't be normalized, and any returned t is in terms of it.
+    /// The throughput of the tracked path is multiplied into `weight`, or
+    /// if the path is absorbed, the light emitted where it was absorbed.
+    /// `rng` is called for as many random numbers in [0, 1) as are needed.
+    #[allow(clippy::too_many_arguments)]
+    fn track(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        weight: &mut Vec4,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> MediumEvent;
+
+    /// Returns the transmittance through the medium along a ray from
+    /// `orig` in direction `dir`, up to `max_t`.
+    fn transmittance(
+        &self,
+        orig: Point,
+        dir: Vector,
+        max_t: f32,
+        time: f32,
+        wavelength: f32,
+        rng: &mut dyn FnMut() -> f32,
+    ) -> Vec4;
+
+    /// Returns the anisotropy of the medium's Henyey-Greenstein phase
+    /// function, from -1 (backward scattering) to 1 (forward scattering).
+    fn anisotropy(&self) -> f32;
+}
+
+/// Delta tracks over the distance range `d_range` with the given constant
+/// majorant.
+///
+/// `coefficients` returns the absorption and scattering coefficients at a
+/// distance, which must sum to no more than `majorant`, along with the
+/// emission there: the emitted radiance times the absorption coefficient.
+///
+/// Returns the scattering distance if the path scatters, `Absorb` if it's
+/// absorbed, and `None` if it makes it through the range.  The throughput
+/// is multiplied into `weight`, and on absorption the emission too.
+pub fn delta_track<F>(
+    d_range: (f32, f32),
+    majorant: f32,
+    coefficients: F,
+    weight: &mut Vec4,
+    rng: &mut dyn FnMut() -> f32,
+) -> Option<MediumEvent>
+where
+    F: Fn(f32) -> (Vec4, Vec4, Vec4),
+{
+    if majorant <= 0.0 {
+        return None;
+    }
+
+    let mut d = d_range.0;
+    loop {
+        d -= (1.0 - rng()).ln() / majorant;
+        if d >= d_range.1 {
+            return None;
+        }
+
+        // Choose between absorption, scattering, and a null collision,
+        // with probabilities based on the wavelength that's most likely to
+        // do each.
+        let (sigma_a, sigma_s, emission) = coefficients(d);
+        let sigma_n = (Vec4::splat(majorant) - sigma_a - sigma_s).max(Vec4::splat(0.0));
+        let p_a = (sigma_a * *weight).max_element();
+        let p_s = (sigma_s * *weight).max_element();
+        let p_n = (sigma_n * *weight).max_element();
+        let p_total = p_a + p_s + p_n;
+        if p_total <= 0.0 {
+            *weight = Vec4::splat(0.0);
+            return Some(MediumEvent::Absorb);
+        }
+
+        let n = rng() * p_total;
+        if n < p_a {
+            *weight *= emission * (p_total / (majorant * p_a));
+            return Some(MediumEvent::Absorb);
+        } else if n < (p_a + p_s) {
+            *weight *= sigma_s * (p_total / (majorant * p_s));
+            return Some(MediumEvent::Scatter(d));
+        } else {
+            *weight *= sigma_n * (p_total / (majorant * p_n));
+        }
+    }
+}
+
+/// Ratio tracks over the distance range `d_range` with the given constant
+/// majorant, returning the transmittance.
+///
+/// `coefficients` is the same as for `delta_track()`.
+pub fn ratio_track<F>(
+    d_range: (f32, f32),
+    majorant: f32,
+    coefficients: F,
+    rng: &mut dyn FnMut() -> f32,
+) -> Vec4
+where
+    F: Fn(f32) -> (Vec4, Vec4, Vec4),
+{
+    let mut tr = Vec4::splat(1.0);
+    if majorant <= 0.0 {
+        return tr;
+    }
+
+    let mut d = d_range.0;
+    loop {
+        d -= (1.0 - rng()).ln() / majorant;
+        if d >= d_range.1 {
+            return tr;
+        }
+
+        let (sigma_a, sigma_s, _) = coefficients(d);
+        tr *= (Vec4::splat(1.0) - ((sigma_a + sigma_s) * (1.0 / majorant))).max(Vec4::splat(0.0));
+
+        // Russian roulette once the transmittance gets low, so this
+        // doesn't go on forever.
+        let tr_max = tr.max_element();
+        if tr_max <= 0.0 {
+            return Vec4::splat(0.0);
+        } else if tr_max < 0.1 {
+            if rng() < tr_max {
+                tr *= 1.0 / tr_max;
+            } else {
+                return Vec4::splat(0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::hash::hash_u32_to_f32;
+
+    fn rng_from_seed(seed: u32) -> impl FnMut() -> f32 {
+        let mut n = 0;
+        move || {
+            n += 1;
+            hash_u32_to_f32(n, seed)
+        }
+    }
+
+    #[test]
+    fn delta_track_zero_majorant() {
+        let mut weight = Vec4::splat(1.0);
+        let mut rng = rng_from_seed(1);
+        let event = delta_track(
+            (0.0, 10.0),
+            0.0,
+            |_| (Vec4::splat(0.0), Vec4::splat(0.0), Vec4::splat(0.0)),
+            &mut weight,
+            &mut rng,
+        );
+        assert_eq!(event, None);
+        assert_eq!(weight, Vec4::splat(1.0));
+    }
+
+    #[test]
+    fn delta_track_pure_absorber() {
+        let mut rng = rng_from_seed(2);
+        for _ in 0..100 {
+            let mut weight = Vec4::splat(1.0);
+            let event = delta_track(
+                (0.0, 1.0),
+                2.0,
+                |_| (Vec4::splat(2.0), Vec4::splat(0.0), Vec4::splat(1.0)),
+                &mut weight,
+                &mut rng,
+            );
+            assert!(event == None || event == Some(MediumEvent::Absorb));
+        }
+    }
+
+    #[test]
+    fn ratio_track_converges() {
+        let mut rng = rng_from_seed(3);
+        let sigma = Vec4::new(0.5, 1.0, 1.5, 2.0);
+        let n = 20000;
+        let mut sum = Vec4::splat(0.0);
+        for _ in 0..n {
+            sum += ratio_track(
+                (0.0, 1.0),
+                4.0,
+                |_| (sigma, Vec4::splat(0.0), Vec4::splat(0.0)),
+                &mut rng,
+            );
+        }
+        let avg = sum * (1.0 / n as f32);
+
+        for (a, s) in [
+            (avg.x(), sigma.x()),
+            (avg.y(), sigma.y()),
+            (avg.z(), sigma.z()),
+            (avg.w(), sigma.w()),
+        ]
+        .iter()
+        {
+            assert!((a - (-s).exp()).abs() < 0.02);
+        }
+    }
+}
@@ -0,0 +1,74 @@
+#![allow(dead_code)]
+
+const N: u32 = 1 << 10;
+
+// Utility function used by the functions below.  Spreads the lower 10 bits
+// of `n` out so that there are two zero bits between each of them.
+fn part_1_by_2(n: u32) -> u32 {
+    let mut n = n & 0x0000_03ff;
+    n = (n | (n << 16)) & 0x0300_00ff;
+    n = (n | (n << 8)) & 0x0300_f00f;
+    n = (n | (n << 4)) & 0x030c_30c3;
+    n = (n | (n << 2)) & 0x0924_9249;
+    n
+}
+
+// Inverse of `part_1_by_2()`.
+fn compact_1_by_2(n: u32) -> u32 {
+    let mut n = n & 0x0924_9249;
+    n = (n | (n >> 2)) & 0x030c_30c3;
+    n = (n | (n >> 4)) & 0x0300_f00f;
+    n = (n | (n >> 8)) & 0x0300_00ff;
+    n = (n | (n >> 16)) & 0x0000_03ff;
+    n
+}
+
+/// Convert (x,y,z) to morton curve index.
+///
+/// x: The x coordinate.  Must be a positive integer no greater than 2^10-1.
+/// y: The y coordinate.  Must be a positive integer no greater than 2^10-1.
+/// z: The z coordinate.  Must be a positive integer no greater than 2^10-1.
+///
+/// Returns the morton curve index corresponding to the (x,y,z) coordinates given.
+pub fn xyz2d(x: u32, y: u32, z: u32) -> u32 {
+    assert!(x < N);
+    assert!(y < N);
+    assert!(z < N);
+
+    part_1_by_2(x) | (part_1_by_2(y) << 1) | (part_1_by_2(z) << 2)
+}
+
+/// Convert morton curve index to (x,y,z).
+///
+/// d: The morton curve index.
+///
+/// Returns the (x, y, z) coords at the given index.
+pub fn d2xyz(d: u32) -> (u32, u32, u32) {
+    (
+        compact_1_by_2(d),
+        compact_1_by_2(d >> 1),
+        compact_1_by_2(d >> 2),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reversible() {
+        let d = 0x2a5f_c31;
+        let (x, y, z) = d2xyz(d);
+        let d2 = xyz2d(x, y, z);
+
+        assert_eq!(d, d2);
+    }
+
+    #[test]
+    fn interleaving() {
+        assert_eq!(xyz2d(1, 0, 0), 1);
+        assert_eq!(xyz2d(0, 1, 0), 2);
+        assert_eq!(xyz2d(0, 0, 1), 4);
+        assert_eq!(xyz2d(N - 1, N - 1, N - 1), (1 << 30) - 1);
+    }
+}
@@ -0,0 +1,266 @@
+#![allow(dead_code)]
+
+use std::f32::consts::PI as PI_32;
+
+use crate::math::{clamp, cross, dot, Matrix4x4, Normal, Point, Vector};
+
+/// A cone bounding the emission directions of one or more lights.
+///
+/// This follows the orientation bounds from "Importance Sampling of Many
+/// Lights with Adaptive Tree Splitting" by Conty Estevez and Kulla:
+///
+/// - `axis`: The central direction of the cone.
+/// - `cos_theta_o`: Cosine of the angle that bounds all of the light
+///   surface normals around `axis`.
+/// - `cos_theta_e`: Cosine of the angle past the normals that light is
+///   emitted in.  For diffuse emitters this is cos(pi/2) = 0.
+/// - `two_sided`: Whether the emitters also emit in the opposite
+///   directions, as if `axis` were flipped.
+#[derive(Debug, Copy, Clone)]
+pub struct NormalCone {
+    pub axis: Vector,
+    pub cos_theta_o: f32,
+    pub cos_theta_e: f32,
+    pub two_sided: bool,
+}
+
+impl NormalCone {
+    /// Creates a cone that emits in all directions.
+    pub fn full_sphere() -> NormalCone {
+        NormalCone {
+            axis: Vector::new(0.0, 0.0, 1.0),
+            cos_theta_o: -1.0,
+            cos_theta_e: -1.0,
+            two_sided: false,
+        }
+    }
+
+    /// Creates a cone for a flat diffuse emitter with normal `nor`.
+    pub fn from_normal(nor: Normal, two_sided: bool) -> NormalCone {
+        NormalCone {
+            axis: nor.into_vector().normalized(),
+            cos_theta_o: 1.0,
+            cos_theta_e: 0.0,
+            two_sided: two_sided,
+        }
+    }
+
+    pub fn is_full_sphere(&self) -> bool {
+        self.cos_theta_o <= -1.0 && self.cos_theta_e <= -1.0
+    }
+
+    /// Creates a new cone transformed by `xform`.
+    ///
+    /// `xform` follows the same convention as for points: the axis is
+    /// transformed like a surface normal would be by the same matrix.
+    ///
+    /// TODO: the cone angle isn't adjusted, so this is only an approximation
+    /// for transforms with non-uniform scaling.
+    pub fn transformed(&self, xform: Matrix4x4) -> NormalCone {
+        if self.is_full_sphere() {
+            return *self;
+        }
+
+        let axis = (self.axis.into_normal() * xform).into_vector();
+        if axis.length2() > 0.0 {
+            NormalCone {
+                axis: axis.normalized(),
+                ..*self
+            }
+        } else {
+            NormalCone::full_sphere()
+        }
+    }
+
+    /// Returns a cone that bounds both `self` and `other`.
+    pub fn union(&self, other: &NormalCone) -> NormalCone {
+        if self.is_full_sphere() || other.is_full_sphere() {
+            return NormalCone::full_sphere();
+        }
+
+        let cos_theta_e = self.cos_theta_e.min(other.cos_theta_e);
+        let two_sided = self.two_sided || other.two_sided;
+
+        // If either cone is two-sided, the result is too, and we're free to
+        // flip the other axis to the nearest side.
+        let other_axis = if two_sided && dot(self.axis, other.axis) < 0.0 {
+            -other.axis
+        } else {
+            other.axis
+        };
+
+        let theta_a = self.cos_theta_o.acos();
+        let theta_b = other.cos_theta_o.acos();
+        let theta_d = clamp(dot(self.axis, other_axis), -1.0, 1.0).acos();
+
+        // If one cone already contains the other, we're done.
+        if (theta_d + theta_b).min(PI_32) <= theta_a {
+            return NormalCone {
+                axis: self.axis,
+                cos_theta_o: self.cos_theta_o,
+                cos_theta_e: cos_theta_e,
+                two_sided: two_sided,
+            };
+        }
+        if (theta_d + theta_a).min(PI_32) <= theta_b {
+            return NormalCone {
+                axis: other_axis,
+                cos_theta_o: other.cos_theta_o,
+                cos_theta_e: cos_theta_e,
+                two_sided: two_sided,
+            };
+        }
+
+        // Otherwise compute a new cone that just encloses both.
+        let theta_o = (theta_a + theta_d + theta_b) * 0.5;
+        if theta_o >= PI_32 {
+            return NormalCone::full_sphere();
+        }
+
+        // Rotate our axis towards the other axis to get the new axis.
+        let rot_axis = cross(self.axis, other_axis);
+        if rot_axis.length2() <= 0.0 {
+            return NormalCone::full_sphere();
+        }
+        let rot_axis = rot_axis.normalized();
+        let theta_r = theta_o - theta_a;
+        let axis = (self.axis * theta_r.cos()) + (cross(rot_axis, self.axis) * theta_r.sin());
+
+        NormalCone {
+            axis: axis.normalized(),
+            cos_theta_o: theta_o.cos(),
+            cos_theta_e: cos_theta_e,
+            two_sided: two_sided,
+        }
+    }
+
+    /// Returns a conservative estimate of the cosine falloff of light
+    /// emitted towards `pos` from lights with this cone that are contained
+    /// in a sphere at `center` with squared radius `radius2`.
+    ///
+    /// The result is in [0, 1], and is zero only if none of the lights can
+    /// emit towards `pos`.
+    pub fn estimate_falloff(&self, center: Point, radius2: f32, pos: Point) -> f32 {
+        if self.is_full_sphere() {
+            return 1.0;
+        }
+
+        let d = pos - center;
+        let dist2 = d.length2();
+        if dist2 <= radius2 || dist2 <= 0.0 {
+            // Inside the bounding sphere, so we can't say anything.
+            return 1.0;
+        }
+
+        // Angle between the axis and the direction to `pos`.
+        let cos_theta_w = {
+            let c = clamp(dot(self.axis, d.normalized()), -1.0, 1.0);
+            if self.two_sided {
+                c.abs()
+            } else {
+                c
+            }
+        };
+        let theta_w = cos_theta_w.acos();
+
+        // Angle subtended by the bounding sphere, as seen from `pos`.
+        let theta_b = (radius2 / dist2).sqrt().min(1.0).asin();
+
+        let theta_o = self.cos_theta_o.acos();
+        let theta_e = self.cos_theta_e.acos();
+
+        let theta = (theta_w - theta_o - theta_b).max(0.0);
+        if theta >= theta_e {
+            0.0
+        } else {
+            theta.cos().max(0.0)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn union_same_axis() {
+        let a = NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), false);
+        let c = a.union(&a);
+
+        assert!((c.cos_theta_o - 1.0).abs() < 0.0001);
+        assert!((c.axis.z() - 1.0).abs() < 0.0001);
+        assert!(!c.two_sided);
+    }
+
+    #[test]
+    fn union_perpendicular() {
+        let a = NormalCone::from_normal(Normal::new(1.0, 0.0, 0.0), false);
+        let b = NormalCone::from_normal(Normal::new(0.0, 1.0, 0.0), false);
+        let c = a.union(&b);
+
+        let h = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((c.axis.x() - h).abs() < 0.0001);
+        assert!((c.axis.y() - h).abs() < 0.0001);
+        assert!(c.axis.z().abs() < 0.0001);
+        assert!((c.cos_theta_o - h).abs() < 0.0001);
+    }
+
+    #[test]
+    fn union_opposite_is_full() {
+        let a = NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), false);
+        let b = NormalCone::from_normal(Normal::new(0.0, 0.0, -1.0), false);
+        let c = a.union(&b);
+
+        assert!(c.cos_theta_o <= -1.0);
+    }
+
+    #[test]
+    fn union_opposite_two_sided() {
+        let a = NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), true);
+        let b = NormalCone::from_normal(Normal::new(0.0, 0.0, -1.0), true);
+        let c = a.union(&b);
+
+        assert!((c.cos_theta_o - 1.0).abs() < 0.0001);
+        assert!(c.two_sided);
+    }
+
+    #[test]
+    fn union_contains() {
+        let a = NormalCone {
+            axis: Vector::new(0.0, 0.0, 1.0),
+            cos_theta_o: 0.0,
+            cos_theta_e: 0.0,
+            two_sided: false,
+        };
+        let b = NormalCone::from_normal(Normal::new(1.0, 0.0, 1.0), false);
+        let c = a.union(&b);
+
+        assert!((c.axis.z() - 1.0).abs() < 0.0001);
+        assert!(c.cos_theta_o.abs() < 0.0001);
+    }
+
+    #[test]
+    fn falloff_facing() {
+        let a = NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), false);
+        let center = Point::new(0.0, 0.0, 0.0);
+
+        let f1 = a.estimate_falloff(center, 0.01, Point::new(0.0, 0.0, 10.0));
+        let f2 = a.estimate_falloff(center, 0.01, Point::new(0.0, 0.0, -10.0));
+        let f3 = a.estimate_falloff(center, 0.01, Point::new(10.0, 0.0, 10.0));
+
+        assert!((f1 - 1.0).abs() < 0.0001);
+        assert_eq!(f2, 0.0);
+        assert!(f3 > 0.0 && f3 < 1.0);
+    }
+
+    #[test]
+    fn falloff_two_sided() {
+        let a = NormalCone::from_normal(Normal::new(0.0, 0.0, 1.0), true);
+        let center = Point::new(0.0, 0.0, 0.0);
+
+        let f1 = a.estimate_falloff(center, 0.01, Point::new(0.0, 0.0, 10.0));
+        let f2 = a.estimate_falloff(center, 0.01, Point::new(0.0, 0.0, -10.0));
+
+        assert!((f1 - f2).abs() < 0.0001);
+    }
+}
@@ -0,0 +1,379 @@
+//! Paging of scene data in from memory-mapped files, for scenes with more
+//! data than fits in memory.
+//!
+//! Data to be paged is written out to a page file, which is then memory
+//! mapped.  Individual pages are decoded from the mapping when they're
+//! needed, and the decoded pages are kept in a cache that's shared by all
+//! page files.  When the cache goes over its memory budget, the least
+//! recently used pages are evicted from it.
+//!
+//! Each thread also keeps references to the last few pages it used, so that
+//! using them again doesn't need to lock the shared cache.  Those pages stay
+//! in memory until the thread replaces them, even if the cache evicts them,
+//! so the memory used can go over the budget by `RECENT_PAGE_COUNT` pages
+//! per thread.
+
+use std::{
+    any::Any,
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap},
+    fmt, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use lazy_static::lazy_static;
+use memmap2::Mmap;
+
+/// The default memory budget of the page cache, in bytes.
+pub const DEFAULT_PAGE_BUDGET: usize = 1 << 30;
+
+/// The number of recently used pages each thread keeps references to.
+const RECENT_PAGE_COUNT: usize = 16;
+
+static NEXT_FILE_ID: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref PAGE_CACHE: Mutex<PageCache> = Mutex::new(PageCache::new(DEFAULT_PAGE_BUDGET));
+}
+
+// Track page faults
+thread_local! {
+    pub static PAGE_FAULTS: Cell<u64> = Cell::new(0);
+}
+
+thread_local! {
+    // Pages recently used by this thread, indexed by a hash of their key.
+    static RECENT_PAGES: RefCell<Vec<Option<(PageKey, PageData)>>> =
+        RefCell::new(vec![None; RECENT_PAGE_COUNT]);
+}
+
+/// Sets the memory budget of the page cache, in bytes.
+pub fn set_page_budget(bytes: usize) {
+    let mut cache = PAGE_CACHE.lock().unwrap();
+    cache.budget = bytes;
+    cache.evict_to_budget();
+}
+
+/// A memory-mapped file of pages.
+pub struct PageFile {
+    id: u64,
+    map: Mmap,
+    path: Option<PathBuf>,
+}
+
+impl PageFile {
+    /// Writes `data` to a new page file and maps it.
+    ///
+    /// If `path` is given the file is written there and kept, so that it can
+    /// be opened again with `open()`.  Otherwise it's written to a temporary
+    /// file, which is removed as soon as it's mapped where the platform
+    /// allows it.
+    pub fn create(data: &[u8], path: Option<&Path>) -> io::Result<PageFile> {
+        let id = NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed);
+        let write_path = match path {
+            Some(path) => path.to_path_buf(),
+            None => std::env::temp_dir().join(format!(
+                "psychopath_{}_{}.psypages",
+                std::process::id(),
+                id
+            )),
+        };
+
+        // Write to a temporary name first, so that other renders never see a
+        // partially written file.
+        let tmp_path = write_path.with_extension(format!("tmp{}", std::process::id()));
+        {
+            let mut f = fs::File::create(&tmp_path)?;
+            f.write_all(data)?;
+        }
+        if let Err(e) = fs::rename(&tmp_path, &write_path) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+
+        let map = map_file(&write_path)?;
+        if path.is_none() {
+            let _ = fs::remove_file(&write_path);
+        }
+
+        Ok(PageFile {
+            id: id,
+            map: map,
+            path: path.map(|p| p.to_path_buf()),
+        })
+    }
+
+    /// Maps an existing page file.
+    pub fn open(path: &Path) -> io::Result<PageFile> {
+        Ok(PageFile {
+            id: NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed),
+            map: map_file(path)?,
+            path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// The path of the file, if it's kept after the process exits.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Returns the page with index `page`, which is stored in the bytes
+    /// `range` of the file.
+    ///
+    /// If the page isn't in the cache, it's decoded from those bytes by
+    /// `load` and added to the cache.  That counts as a page fault.  `load`
+    /// returns the decoded page along with the number of bytes of memory it
+    /// takes, which is what's counted against the cache's budget.
+    pub fn page<T, L>(&self, page: usize, range: (usize, usize), load: L) -> Arc<T>
+    where
+        T: Any + Send + Sync,
+        L: FnOnce(&[u8]) -> (T, usize),
+    {
+        let key = (self.id, page);
+        let slot = (self.id as usize).wrapping_mul(31).wrapping_add(page) % RECENT_PAGE_COUNT;
+        let recent = RECENT_PAGES.with(|recent| match recent.borrow()[slot] {
+            Some((recent_key, ref data)) if recent_key == key => Some(data.clone()),
+            _ => None,
+        });
+
+        let data = match recent.or_else(|| PAGE_CACHE.lock().unwrap().get(key)) {
+            Some(data) => data,
+            None => {
+                // Decode the page outside of the lock, so that other threads
+                // can keep using the cache in the meantime.
+                PAGE_FAULTS.with(|pf| pf.set(pf.get() + 1));
+                let (data, size) = load(&self.map[range.0..range.1]);
+                let data: PageData = Arc::new(data);
+                PAGE_CACHE.lock().unwrap().insert(key, data.clone(), size);
+                data
+            }
+        };
+
+        RECENT_PAGES.with(|recent| recent.borrow_mut()[slot] = Some((key, data.clone())));
+        data.downcast()
+            .unwrap_or_else(|_| panic!("Page {} was decoded as a different type.", page))
+    }
+}
+
+impl fmt::Debug for PageFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("PageFile")
+            .field("id", &self.id)
+            .field("len", &self.map.len())
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+impl Drop for PageFile {
+    fn drop(&mut self) {
+        PAGE_CACHE.lock().unwrap().remove_file(self.id);
+    }
+}
+
+/// Owns the page files of a scene, so that they stay mapped for as long as
+/// the scene's data refers to them.
+///
+/// Files are only ever added, so references to them stay valid until the
+/// `PageFiles` is dropped.  That drops the files, which unmaps them and
+/// removes their pages from the cache.
+#[derive(Default)]
+pub struct PageFiles {
+    files: Mutex<Vec<Arc<PageFile>>>,
+}
+
+impl PageFiles {
+    pub fn new() -> PageFiles {
+        PageFiles::default()
+    }
+
+    /// Takes ownership of `file`, returning a reference to it that's valid
+    /// for as long as `self` is.
+    pub fn add(&self, file: PageFile) -> &PageFile {
+        let file = Arc::new(file);
+        let file_ref = Arc::as_ptr(&file);
+        self.files.lock().unwrap().push(file);
+        // The file is never removed before `self` is dropped, and stays at
+        // the same address when the `Vec` grows.
+        unsafe { &*file_ref }
+    }
+}
+
+fn map_file(path: &Path) -> io::Result<Mmap> {
+    let file = fs::File::open(path)?;
+    // Page files are only ever written before they're mapped.
+    unsafe { Mmap::map(&file) }
+}
+
+type PageKey = (u64, usize); // (file_id, page_index)
+type PageData = Arc<dyn Any + Send + Sync>;
+
+/// The cache of decoded pages, which evicts the least recently used pages
+/// when it's over budget.
+struct PageCache {
+    pages: HashMap<PageKey, CachedPage>,
+    lru: BTreeMap<u64, PageKey>, // Last use -> page
+    clock: u64,
+    size: usize,
+    budget: usize,
+}
+
+struct CachedPage {
+    data: PageData,
+    size: usize,
+    last_use: u64,
+}
+
+impl PageCache {
+    fn new(budget: usize) -> PageCache {
+        PageCache {
+            pages: HashMap::new(),
+            lru: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            budget: budget,
+        }
+    }
+
+    fn get(&mut self, key: PageKey) -> Option<PageData> {
+        let page = self.pages.get_mut(&key)?;
+        self.lru.remove(&page.last_use);
+        self.clock += 1;
+        page.last_use = self.clock;
+        self.lru.insert(self.clock, key);
+        Some(page.data.clone())
+    }
+
+    fn insert(&mut self, key: PageKey, data: PageData, size: usize) {
+        // Another thread may have loaded the same page in the meantime.
+        if let Some(page) = self.pages.remove(&key) {
+            self.lru.remove(&page.last_use);
+            self.size -= page.size;
+        }
+
+        self.clock += 1;
+        self.pages.insert(
+            key,
+            CachedPage {
+                data: data,
+                size: size,
+                last_use: self.clock,
+            },
+        );
+        self.lru.insert(self.clock, key);
+        self.size += size;
+
+        self.evict_to_budget();
+    }
+
+    /// Evicts the least recently used pages until the cache is within its
+    /// budget.  Evicted pages that are still in use stay alive until their
+    /// users are done with them.
+    fn evict_to_budget(&mut self) {
+        while self.size > self.budget {
+            let (&last_use, &key) = match self.lru.iter().next() {
+                Some(oldest) => oldest,
+                None => break,
+            };
+            self.lru.remove(&last_use);
+            if let Some(page) = self.pages.remove(&key) {
+                self.size -= page.size;
+            }
+        }
+    }
+
+    fn remove_file(&mut self, file_id: u64) {
+        let keys: Vec<PageKey> = self
+            .pages
+            .keys()
+            .filter(|key| key.0 == file_id)
+            .cloned()
+            .collect();
+        for key in keys {
+            let page = self.pages.remove(&key).unwrap();
+            self.lru.remove(&page.last_use);
+            self.size -= page.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(n: u32) -> PageData {
+        Arc::new(n)
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = PageCache::new(30);
+        cache.insert((0, 0), page(0), 10);
+        cache.insert((0, 1), page(1), 10);
+        cache.insert((0, 2), page(2), 10);
+
+        // Use the first page, so that the second is the oldest.
+        assert!(cache.get((0, 0)).is_some());
+        cache.insert((0, 3), page(3), 10);
+
+        assert!(cache.get((0, 1)).is_none());
+        assert!(cache.get((0, 0)).is_some());
+        assert!(cache.get((0, 2)).is_some());
+        assert!(cache.get((0, 3)).is_some());
+        assert_eq!(cache.size, 30);
+    }
+
+    #[test]
+    fn remove_file() {
+        let mut cache = PageCache::new(100);
+        cache.insert((0, 0), page(0), 10);
+        cache.insert((1, 0), page(1), 10);
+        cache.insert((0, 1), page(2), 10);
+        cache.remove_file(0);
+
+        assert!(cache.get((0, 0)).is_none());
+        assert!(cache.get((0, 1)).is_none());
+        assert!(cache.get((1, 0)).is_some());
+        assert_eq!(cache.size, 10);
+    }
+
+    #[test]
+    fn page_file_pages() {
+        let data: Vec<u8> = (0..64).collect();
+        let file = PageFile::create(&data, None).unwrap();
+        let faults = || PAGE_FAULTS.with(|pf| pf.get());
+        let start_faults = faults();
+
+        let sum = |bytes: &[u8]| (bytes.iter().map(|&b| b as u32).sum::<u32>(), 4);
+        assert_eq!(*file.page(0, (0, 16), sum), (0..16).sum::<u32>());
+        assert_eq!(*file.page(1, (16, 64), sum), (16..64).sum::<u32>());
+        assert_eq!(faults() - start_faults, 2);
+
+        // Already paged in, so no fault.
+        assert_eq!(*file.page(0, (0, 16), sum), (0..16).sum::<u32>());
+        assert_eq!(faults() - start_faults, 2);
+    }
+
+    #[test]
+    fn page_files_drop_their_pages() {
+        let data: Vec<u8> = (0..64).collect();
+        let files = PageFiles::new();
+        let file = files.add(PageFile::create(&data, None).unwrap());
+        let id = file.id;
+        file.page(0, (0, 16), |bytes| (bytes.len(), 123));
+        assert!(PAGE_CACHE.lock().unwrap().pages.contains_key(&(id, 0)));
+        assert_eq!(PAGE_CACHE.lock().unwrap().pages[&(id, 0)].size, 123);
+
+        drop(files);
+        assert!(!PAGE_CACHE.lock().unwrap().pages.contains_key(&(id, 0)));
+    }
+}
@@ -2,8 +2,15 @@ pub mod basics;
 mod data_tree;
 mod psy;
 mod psy_assembly;
+mod psy_curve_surface;
+mod psy_displacement_shader;
 mod psy_light;
+mod psy_medium;
 mod psy_mesh_surface;
+mod psy_patch;
+mod psy_points_surface;
+mod psy_quadric;
+mod psy_subdivision_surface;
 mod psy_surface_shader;
 
 pub use self::{data_tree::DataTree, psy::parse_scene};
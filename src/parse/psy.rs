@@ -1,25 +1,33 @@
 #![allow(dead_code)]
 
-use std::{f32, result::Result};
+use std::{collections::HashMap, f32, result::Result};
 
 use nom::{combinator::all_consuming, sequence::tuple, IResult};
 
 use kioku::Arena;
 
 use crate::{
-    camera::Camera,
+    accel::{SplitMode, DEFAULT_SPATIAL_SPLIT_THRESHOLD},
+    camera::{ApertureShape, Camera, CameraType},
     color::{rec709_e_to_xyz, Color},
+    image::read_pgm,
     light::WorldLightSource,
     math::Matrix4x4,
+    medium::Medium,
+    paging::PageFiles,
     renderer::Renderer,
+    sampling::Distribution2D,
     scene::Scene,
     scene::World,
+    shutter::{Shutter, ShutterCurve},
+    surface::{dicing::DicingContext, triangle_mesh::MeshStorage},
 };
 
 use super::{
     basics::{ws_f32, ws_u32},
     psy_assembly::parse_assembly,
     psy_light::parse_distant_disk_light,
+    psy_medium::parse_medium,
     DataTree,
 };
 
@@ -35,6 +43,7 @@ pub enum PsyParseError {
     IncorrectLeafData(usize, &'static str),     // Error message
     WrongNodeCount(usize, &'static str, usize), // Error message, sections found
     InstancedMissingData(usize, &'static str, String), // Error message, data name
+    IoError(usize, &'static str, String),       // Error message, I/O error
 }
 
 impl PsyParseError {
@@ -83,6 +92,11 @@ impl PsyParseError {
                 let line = line_count_to_byte_offset(psy_content, offset);
                 println!("Line {}: {} Data name: '{}'", line, error, data_name);
             }
+
+            PsyParseError::IoError(offset, error, ref io_error) => {
+                let line = line_count_to_byte_offset(psy_content, offset);
+                println!("Line {}: {}  {}", line, error, io_error);
+            }
         }
     }
 }
@@ -94,7 +108,9 @@ fn line_count_to_byte_offset(text: &str, offset: usize) -> usize {
 /// Takes in a `DataTree` representing a Scene node and returns
 pub fn parse_scene<'a>(
     arena: &'a Arena,
+    page_files: &'a PageFiles,
     tree: &'a DataTree,
+    mesh_storage: MeshStorage,
 ) -> Result<Renderer<'a>, PsyParseError> {
     // Verify we have the right number of each section
     if tree.iter_children_with_type("Output").count() != 1 {
@@ -158,13 +174,44 @@ pub fn parse_scene<'a>(
         tree.iter_children_with_type("Camera").nth(0).unwrap(),
     )?;
 
+    // Parse media
+    let mut media: Vec<&dyn Medium> = Vec::new();
+    let mut medium_map: HashMap<&str, usize> = HashMap::new();
+    for child in tree.iter_children_with_type("Medium") {
+        if let DataTree::Internal {
+            ident: Some(ident), ..
+        } = *child
+        {
+            medium_map.insert(ident, media.len());
+            media.push(parse_medium(arena, child)?);
+        } else {
+            // No ident
+            return Err(PsyParseError::UnknownError(child.byte_offset()));
+        }
+    }
+
     // Parse world
-    let world = parse_world(arena, tree.iter_children_with_type("World").nth(0).unwrap())?;
+    let world = parse_world(
+        arena,
+        tree.iter_children_with_type("World").nth(0).unwrap(),
+        &medium_map,
+    )?;
 
     // Parse root scene assembly
+    let dicing_ctx = DicingContext::new(
+        camera,
+        render_settings.resolution.0 as usize,
+        render_settings.dicing_rate,
+    );
     let assembly = parse_assembly(
         arena,
+        page_files,
         tree.iter_children_with_type("Assembly").nth(0).unwrap(),
+        &dicing_ctx,
+        render_settings.direct_shading,
+        render_settings.split_mode,
+        mesh_storage,
+        &medium_map,
     )?;
 
     // Put scene together
@@ -181,6 +228,7 @@ pub fn parse_scene<'a>(
         name: scene_name,
         camera: camera,
         world: world,
+        media: arena.copy_slice(&media),
         root: assembly,
     };
 
@@ -188,11 +236,13 @@ pub fn parse_scene<'a>(
     let renderer = Renderer {
         output_file: output_info.clone(),
         resolution: (
-            (render_settings.0).0 as usize,
-            (render_settings.0).1 as usize,
+            render_settings.resolution.0 as usize,
+            render_settings.resolution.1 as usize,
         ),
-        spp: render_settings.1 as usize,
-        seed: render_settings.2,
+        spp: render_settings.spp as usize,
+        seed: render_settings.seed,
+        shutter: render_settings.shutter,
+        sort_rays: render_settings.sort_rays,
         scene: scene,
     };
 
@@ -211,29 +261,8 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
                     contents,
                     byte_offset,
                 } if type_name == "Path" => {
-                    // Trim and validate
-                    let tc = contents.trim();
-                    if tc.chars().count() < 2 {
-                        return Err(PsyParseError::IncorrectLeafData(
-                            byte_offset,
-                            "File path format is \
-                             incorrect.",
-                        ));
-                    }
-                    if tc.chars().nth(0).unwrap() != '"' || !tc.ends_with('"') {
-                        return Err(PsyParseError::IncorrectLeafData(
-                            byte_offset,
-                            "File paths must be \
-                             surrounded by quotes.",
-                        ));
-                    }
-                    let len = tc.len();
-                    let tc = &tc[1..len - 1];
-
-                    // Parse
-                    // TODO: proper string escaping
                     found_path = true;
-                    path = tc.to_string();
+                    path = parse_file_path(contents, byte_offset)?.to_string();
                 }
 
                 _ => {}
@@ -257,13 +286,60 @@ fn parse_output_info(tree: &DataTree) -> Result<String, PsyParseError> {
     };
 }
 
-fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyParseError> {
+/// Parses a quoted file path leaf, returning the path without the quotes.
+pub fn parse_file_path(contents: &str, byte_offset: usize) -> Result<&str, PsyParseError> {
+    // Trim and validate
+    let tc = contents.trim();
+    if tc.chars().count() < 2 {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "File path format is \
+             incorrect.",
+        ));
+    }
+    if tc.chars().nth(0).unwrap() != '"' || !tc.ends_with('"') {
+        return Err(PsyParseError::IncorrectLeafData(
+            byte_offset,
+            "File paths must be \
+             surrounded by quotes.",
+        ));
+    }
+    let len = tc.len();
+
+    // TODO: proper string escaping
+    Ok(&tc[1..len - 1])
+}
+
+/// The contents of a scene's RenderSettings section.
+#[derive(Debug)]
+struct RenderSettings {
+    resolution: (u32, u32),
+    spp: u32,
+    seed: u32,
+    shutter: Shutter,
+    dicing_rate: f32,
+    direct_shading: bool, // Whether to shade diced geometry directly rather than pre-shading it
+    split_mode: SplitMode,
+    sort_rays: bool,
+}
+
+fn parse_render_settings(tree: &DataTree) -> Result<RenderSettings, PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
         let mut found_res = false;
         let mut found_spp = false;
         let mut res = (0, 0);
         let mut spp = 0;
         let mut seed = 0;
+        let mut shutter = Shutter::new();
+        let mut dicing_rate = 1.0;
+        let mut direct_shading = false;
+        let mut split_mode = SplitMode::Object;
+        let mut sort_rays = false;
+
+        // Where the shutter settings that are checked against each other
+        // afterwards were, for reporting errors.
+        let mut close_offset = tree.byte_offset();
+        let mut rolling_offset = tree.byte_offset();
 
         for child in children {
             match *child {
@@ -327,12 +403,206 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
                     }
                 }
 
+                // ShutterOpen, relative to the span of the time samples
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ShutterOpen" => {
+                    if let IResult::Ok((_, t)) = all_consuming(ws_f32)(contents) {
+                        shutter.open = t;
+                    } else {
+                        // Found ShutterOpen, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ShutterOpen should be a decimal \
+                             number specified in the form \
+                             '[time]'.",
+                        ));
+                    }
+                }
+
+                // ShutterClose, relative to the span of the time samples
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ShutterClose" => {
+                    if let IResult::Ok((_, t)) = all_consuming(ws_f32)(contents) {
+                        shutter.close = t;
+                        close_offset = byte_offset;
+                    } else {
+                        // Found ShutterClose, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ShutterClose should be a decimal \
+                             number specified in the form \
+                             '[time]'.",
+                        ));
+                    }
+                }
+
+                // ShutterCurve
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ShutterCurve" => {
+                    let contents = contents.trim();
+                    if contents == "Box" {
+                        shutter.curve = ShutterCurve::Box;
+                    } else if contents.starts_with("Trapezoid") {
+                        if let IResult::Ok((_, ramp)) =
+                            all_consuming(ws_f32)(&contents["Trapezoid".len()..])
+                        {
+                            if ramp < 0.0 || ramp > 0.5 {
+                                return Err(PsyParseError::IncorrectLeafData(
+                                    byte_offset,
+                                    "Trapezoid ShutterCurve ramp \
+                                     should be between 0.0 and 0.5.",
+                                ));
+                            }
+                            shutter.curve = ShutterCurve::Trapezoid(ramp);
+                        } else {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "Trapezoid ShutterCurve should be \
+                                 specified in the form \
+                                 '[Trapezoid ramp]'.",
+                            ));
+                        }
+                    } else {
+                        return Err(PsyParseError::UnknownVariant(
+                            byte_offset,
+                            "ShutterCurve should be either Box \
+                             or Trapezoid.",
+                        ));
+                    }
+                }
+
+                // RollingShutter
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "RollingShutter" => {
+                    if let IResult::Ok((_, d)) = all_consuming(ws_f32)(contents) {
+                        shutter.rolling_duration = d;
+                        rolling_offset = byte_offset;
+                    } else {
+                        // Found RollingShutter, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "RollingShutter should be a decimal \
+                             number specified in the form \
+                             '[duration]'.",
+                        ));
+                    }
+                }
+
+                // DicingRate
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "DicingRate" => {
+                    if let IResult::Ok((_, r)) = all_consuming(ws_f32)(contents) {
+                        if r <= 0.0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "DicingRate must be greater than zero.",
+                            ));
+                        }
+                        dicing_rate = r;
+                    } else {
+                        // Found DicingRate, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "DicingRate should be a decimal \
+                             number specified in the form \
+                             '[pixels]'.",
+                        ));
+                    }
+                }
+
+                // Shading
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Shading" => {
+                    direct_shading = match contents.trim() {
+                        "Preshaded" => false,
+                        "Direct" => true,
+                        _ => {
+                            return Err(PsyParseError::UnknownVariant(
+                                byte_offset,
+                                "Shading should be either Preshaded \
+                                 or Direct.",
+                            ));
+                        }
+                    };
+                }
+
+                // BVHSplits
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "BVHSplits" => {
+                    split_mode = parse_split_mode(contents, byte_offset)?;
+                }
+
+                // RaySorting
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "RaySorting" => {
+                    sort_rays = match contents.trim() {
+                        "Off" => false,
+                        "On" => true,
+                        _ => {
+                            return Err(PsyParseError::UnknownVariant(
+                                byte_offset,
+                                "RaySorting should be either On or Off.",
+                            ));
+                        }
+                    };
+                }
+
                 _ => {}
             }
         }
 
+        if shutter.close < shutter.open {
+            return Err(PsyParseError::IncorrectLeafData(
+                close_offset,
+                "ShutterClose must not be before ShutterOpen.",
+            ));
+        }
+        if shutter.rolling_duration < 0.0
+            || shutter.rolling_duration > 1.0
+            || shutter.rolling_duration > (shutter.close - shutter.open)
+        {
+            return Err(PsyParseError::IncorrectLeafData(
+                rolling_offset,
+                "RollingShutter must be between 0.0 and 1.0, and \
+                 no longer than the shutter duration.",
+            ));
+        }
+
         if found_res && found_spp {
-            return Ok((res, spp, seed));
+            return Ok(RenderSettings {
+                resolution: res,
+                spp: spp,
+                seed: seed,
+                shutter: shutter,
+                dicing_rate: dicing_rate,
+                direct_shading: direct_shading,
+                split_mode: split_mode,
+                sort_rays: sort_rays,
+            });
         } else {
             return Err(PsyParseError::MissingNode(
                 tree.byte_offset(),
@@ -352,14 +622,43 @@ fn parse_render_settings(tree: &DataTree) -> Result<((u32, u32), u32, u32), PsyP
 
 fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>, PsyParseError> {
     if let DataTree::Internal { ref children, .. } = *tree {
+        let mut camera_type = CameraType::Perspective;
+        let mut aperture_blades = 0;
+        let mut aperture_rotation = 0.0;
+        let mut aperture_mask = None;
         let mut mats = Vec::new();
         let mut fovs = Vec::new();
+        let mut ortho_widths = Vec::new();
         let mut focus_distances = Vec::new();
         let mut aperture_radii = Vec::new();
 
         // Parse
         for child in children.iter() {
             match *child {
+                // Type
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "Type" => {
+                    camera_type = match contents.trim() {
+                        "Perspective" => CameraType::Perspective,
+                        "Orthographic" => CameraType::Orthographic,
+                        "Equirectangular" => CameraType::Equirectangular,
+                        "Cylindrical" => CameraType::Cylindrical,
+                        "FisheyeEquidistant" => CameraType::FisheyeEquidistant,
+                        "FisheyeEquisolid" => CameraType::FisheyeEquisolid,
+                        _ => {
+                            return Err(PsyParseError::UnknownVariant(
+                                byte_offset,
+                                "Camera Type should be one of Perspective, \
+                                 Orthographic, Equirectangular, Cylindrical, \
+                                 FisheyeEquidistant, or FisheyeEquisolid.",
+                            ));
+                        }
+                    };
+                }
+
                 // Fov
                 DataTree::Leaf {
                     type_name,
@@ -379,6 +678,25 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
                     }
                 }
 
+                // OrthoWidth
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "OrthoWidth" => {
+                    if let IResult::Ok((_, width)) = all_consuming(ws_f32)(contents) {
+                        ortho_widths.push(width);
+                    } else {
+                        // Found OrthoWidth, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "OrthoWidth should be a \
+                             decimal number specified \
+                             in the form '[width]'.",
+                        ));
+                    }
+                }
+
                 // FocalDistance
                 DataTree::Leaf {
                     type_name,
@@ -417,6 +735,76 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
                     }
                 }
 
+                // ApertureBlades
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ApertureBlades" => {
+                    if let IResult::Ok((_, blades)) = all_consuming(ws_u32)(contents) {
+                        if blades != 0 && blades < 3 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "ApertureBlades should be zero \
+                                 (for a round aperture) or at \
+                                 least three.",
+                            ));
+                        }
+                        aperture_blades = blades;
+                    } else {
+                        // Found ApertureBlades, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ApertureBlades should be an \
+                             integer specified in the form \
+                             '[blade_count]'.",
+                        ));
+                    }
+                }
+
+                // ApertureRotation
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ApertureRotation" => {
+                    if let IResult::Ok((_, rot)) = all_consuming(ws_f32)(contents) {
+                        aperture_rotation = rot * (f32::consts::PI / 180.0);
+                    } else {
+                        // Found ApertureRotation, but its contents is not in the right format
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ApertureRotation should be a \
+                             decimal number specified in \
+                             the form '[degrees]'.",
+                        ));
+                    }
+                }
+
+                // ApertureMask
+                DataTree::Leaf {
+                    type_name,
+                    contents,
+                    byte_offset,
+                } if type_name == "ApertureMask" => {
+                    let path = parse_file_path(contents, byte_offset)?;
+                    if let Ok(((width, height), pixels)) = read_pgm(std::path::Path::new(path)) {
+                        if width == 0 || height == 0 {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "ApertureMask image is empty.",
+                            ));
+                        }
+                        aperture_mask = Some(Distribution2D::new(arena, width, height, &pixels));
+                    } else {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "ApertureMask should be the path \
+                             to a readable PGM image.",
+                        ));
+                    }
+                }
+
                 // Transform
                 DataTree::Leaf {
                     type_name,
@@ -435,10 +823,40 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
             }
         }
 
+        // Make sure the camera has what its type needs
+        if camera_type == CameraType::Orthographic {
+            if ortho_widths.is_empty() {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Orthographic Camera section must contain an OrthoWidth.",
+                ));
+            }
+        } else if fovs.is_empty() {
+            return Err(PsyParseError::MissingNode(
+                tree.byte_offset(),
+                "Camera section must contain a Fov.",
+            ));
+        }
+
+        // A mask image takes precedence over blades.
+        let aperture_shape = if let Some(dist) = aperture_mask {
+            ApertureShape::Mask(arena.alloc(dist))
+        } else if aperture_blades >= 3 {
+            ApertureShape::Polygon {
+                blade_count: aperture_blades,
+                rotation: aperture_rotation,
+            }
+        } else {
+            ApertureShape::Circle
+        };
+
         return Ok(Camera::new(
             arena,
+            camera_type,
+            aperture_shape,
             &mats,
             &fovs,
+            &ortho_widths,
             &aperture_radii,
             &focus_distances,
         ));
@@ -452,7 +870,11 @@ fn parse_camera<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Camera<'a>,
     }
 }
 
-fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, PsyParseError> {
+fn parse_world<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    medium_map: &HashMap<&str, usize>,
+) -> Result<World<'a>, PsyParseError> {
     if tree.is_internal() {
         let background_color;
         let mut lights: Vec<&dyn WorldLightSource> = Vec::new();
@@ -538,10 +960,14 @@ fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, Ps
             }
         }
 
+        // Parse medium binding, if any
+        let medium = parse_medium_bind(tree, medium_map)?;
+
         // Build and return the world
         return Ok(World {
             background_color: background_color,
             lights: arena.copy_slice(&lights),
+            medium: medium,
         });
     } else {
         return Err(PsyParseError::ExpectedInternalNode(
@@ -553,6 +979,61 @@ fn parse_world<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<World<'a>, Ps
     }
 }
 
+/// Parses the `MediumBind` leaf of `tree`, if any, into the index of the
+/// medium it refers to.
+pub fn parse_medium_bind(
+    tree: &DataTree,
+    medium_map: &HashMap<&str, usize>,
+) -> Result<Option<usize>, PsyParseError> {
+    if let Some((_, name, byte_offset)) = tree.iter_leaf_children_with_type("MediumBind").nth(0) {
+        if let Some(&index) = medium_map.get(name.trim()) {
+            Ok(Some(index))
+        } else {
+            Err(PsyParseError::InstancedMissingData(
+                byte_offset,
+                "Attempted to bind a medium that doesn't exist.",
+                name.to_string(),
+            ))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses the contents of a `BVHSplits` leaf, which is either `Object` or
+/// `Spatial`, optionally followed by the overlap threshold for spatial
+/// splits.
+pub fn parse_split_mode(contents: &str, byte_offset: usize) -> Result<SplitMode, PsyParseError> {
+    let contents = contents.trim();
+    if contents == "Object" {
+        Ok(SplitMode::Object)
+    } else if contents.starts_with("Spatial") {
+        let threshold = &contents["Spatial".len()..];
+        if threshold.trim().is_empty() {
+            Ok(SplitMode::Spatial(DEFAULT_SPATIAL_SPLIT_THRESHOLD))
+        } else if let IResult::Ok((_, threshold)) = all_consuming(ws_f32)(threshold) {
+            if threshold < 0.0 {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Spatial BVHSplits overlap threshold should not be negative.",
+                ));
+            }
+            Ok(SplitMode::Spatial(threshold))
+        } else {
+            Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Spatial BVHSplits should be specified in the form \
+                 '[Spatial]' or '[Spatial threshold]'.",
+            ))
+        }
+    } else {
+        Err(PsyParseError::UnknownVariant(
+            byte_offset,
+            "BVHSplits should be either Object or Spatial.",
+        ))
+    }
+}
+
 pub fn parse_matrix(contents: &str) -> Result<Matrix4x4, PsyParseError> {
     if let IResult::Ok((leftover, ns)) = all_consuming(tuple((
         ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32,
@@ -612,3 +1093,82 @@ pub fn parse_color(contents: &str) -> Result<Color, PsyParseError> {
         _ => return Err(PsyParseError::UnknownError(0)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_settings_text(contents: &str) -> String {
+        format!(
+            "RenderSettings {{ Resolution [64 32] SamplesPerPixel [4] {} }}",
+            contents
+        )
+    }
+
+    fn render_settings(contents: &str) -> Result<RenderSettings, PsyParseError> {
+        let text = render_settings_text(contents);
+        let tree = DataTree::from_str(&text).unwrap();
+        parse_render_settings(
+            tree.iter_children_with_type("RenderSettings")
+                .next()
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn render_settings_fields() {
+        let settings = render_settings(
+            "Seed [7] ShutterOpen [0.25] ShutterClose [0.75] RollingShutter [0.5] \
+             DicingRate [2.0] Shading [Direct] RaySorting [On]",
+        )
+        .unwrap();
+        assert_eq!(settings.resolution, (64, 32));
+        assert_eq!(settings.spp, 4);
+        assert_eq!(settings.seed, 7);
+        assert_eq!(settings.shutter.open, 0.25);
+        assert_eq!(settings.shutter.close, 0.75);
+        assert_eq!(settings.shutter.rolling_duration, 0.5);
+        assert_eq!(settings.dicing_rate, 2.0);
+        assert!(settings.direct_shading);
+        assert_eq!(settings.split_mode, SplitMode::Object);
+        assert!(settings.sort_rays);
+    }
+
+    #[test]
+    fn render_settings_shutter_validation() {
+        assert!(render_settings("ShutterOpen [0.5] ShutterClose [0.5]").is_ok());
+        assert!(render_settings("RollingShutter [1.0]").is_ok());
+
+        // The errors should point at the setting that's out of range.
+        let leaf_offset = |contents: &str, leaf: &'static str| {
+            let text = render_settings_text(contents);
+            let tree = DataTree::from_str(&text).unwrap();
+            let settings = tree
+                .iter_children_with_type("RenderSettings")
+                .next()
+                .unwrap();
+            let offset = settings
+                .iter_children_with_type(leaf)
+                .next()
+                .unwrap()
+                .byte_offset();
+            offset
+        };
+        for &(contents, leaf) in &[
+            ("ShutterOpen [0.5] ShutterClose [0.25]", "ShutterClose"),
+            ("RollingShutter [-0.1]", "RollingShutter"),
+            (
+                "ShutterOpen [-1.0] ShutterClose [1.0] RollingShutter [1.5]",
+                "RollingShutter",
+            ),
+            ("ShutterClose [0.5] RollingShutter [0.75]", "RollingShutter"),
+        ] {
+            match render_settings(contents) {
+                Err(PsyParseError::IncorrectLeafData(offset, _)) => {
+                    assert_eq!(offset, leaf_offset(contents, leaf))
+                }
+                _ => panic!("'{}' should be rejected.", contents),
+            }
+        }
+    }
+}
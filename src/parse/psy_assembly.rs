@@ -1,26 +1,88 @@
 #![allow(dead_code)]
 
-use std::result::Result;
+use std::{collections::HashMap, result::Result};
 
 use kioku::Arena;
 
-use crate::scene::{Assembly, AssemblyBuilder, Object};
+use crate::{
+    accel::SplitMode,
+    math::Matrix4x4,
+    paging::PageFiles,
+    scene::{Assembly, AssemblyBuilder, Object},
+    shading::DisplacementShader,
+    surface::{dicing::DicingContext, micropoly_batch::MicropolyBatch, triangle_mesh::MeshStorage},
+};
 
 use super::{
-    psy::{parse_matrix, PsyParseError},
+    psy::{parse_matrix, parse_medium_bind, parse_split_mode, PsyParseError},
+    psy_curve_surface::parse_curve_surface,
+    psy_displacement_shader::parse_displacement_shader,
     psy_light::{parse_rectangle_light, parse_sphere_light},
-    psy_mesh_surface::parse_mesh_surface,
+    psy_mesh_surface::{parse_mesh_storage, parse_mesh_surface},
+    psy_patch::{parse_bicubic_patch, parse_bilinear_patch},
+    psy_points_surface::parse_points_surface,
+    psy_quadric::{parse_cylinder, parse_disk, parse_sphere},
+    psy_subdivision_surface::parse_subdivision_surface,
     psy_surface_shader::parse_surface_shader,
     DataTree,
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn parse_assembly<'a>(
     arena: &'a Arena,
+    page_files: &'a PageFiles,
     tree: &'a DataTree,
+    dicing_ctx: &DicingContext,
+    direct_shading: bool,
+    split_mode: SplitMode,
+    mesh_storage: MeshStorage,
+    medium_map: &HashMap<&str, usize>,
 ) -> Result<Assembly<'a>, PsyParseError> {
     let mut builder = AssemblyBuilder::new(arena);
 
+    // The BVH split mode is inherited by sub-assemblies, unless they
+    // specify their own.
+    let split_mode = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("BVHSplits").nth(0)
+    {
+        parse_split_mode(contents, byte_offset)?
+    } else {
+        split_mode
+    };
+    builder.set_split_mode(split_mode);
+    let dicing_ctx = &dicing_ctx.with_split_mode(split_mode);
+
     if tree.is_internal() {
+        // Collect the transforms of all instances up-front, so that data
+        // that gets diced knows where it will be placed.
+        let mut instance_xforms: HashMap<&str, Vec<Vec<_>>> = HashMap::new();
+        for child in tree.iter_children_with_type("Instance") {
+            if let Some((_, name, _)) = child.iter_leaf_children_with_type("Data").nth(0) {
+                let mut xforms = Vec::new();
+                for (_, contents, _) in child.iter_leaf_children_with_type("Transform") {
+                    xforms.push(parse_matrix(contents)?);
+                }
+                instance_xforms.entry(name).or_default().push(xforms);
+            }
+        }
+        let instanced_ctx = |name: &str| {
+            dicing_ctx.instanced(instance_xforms.get(name).map_or(&[][..], |x| &x[..]))
+        };
+
+        // Diced geometry, which gets pre-shaded when it's instanced unless
+        // the scene is shaded directly.
+        let mut micropoly_batches: HashMap<&str, &MicropolyBatch> = HashMap::new();
+
+        // The pre-shaded copies of that geometry made so far, as (data
+        // name, shader name, world-to-local transform, object name).  A copy
+        // can only be shared between instances placed in exactly the same
+        // place.
+        let mut preshaded: Vec<(&str, &str, Vec<Matrix4x4>, String)> = Vec::new();
+
+        // Displacement shaders are applied while dicing, so they're kept
+        // here rather than in the assembly.
+        let mut displacement_shaders: HashMap<&str, &dyn DisplacementShader> = HashMap::new();
+
         for child in tree.iter_children() {
             match child.type_name() {
                 // Sub-Assembly
@@ -29,7 +91,19 @@ pub fn parse_assembly<'a>(
                         ident: Some(ident), ..
                     } = *child
                     {
-                        builder.add_assembly(ident, parse_assembly(arena, child)?);
+                        builder.add_assembly(
+                            ident,
+                            parse_assembly(
+                                arena,
+                                page_files,
+                                child,
+                                &instanced_ctx(ident),
+                                direct_shading,
+                                split_mode,
+                                mesh_storage,
+                                medium_map,
+                            )?,
+                        );
                     } else {
                         return Err(PsyParseError::UnknownError(child.byte_offset()));
                     }
@@ -51,22 +125,25 @@ pub fn parse_assembly<'a>(
                     };
 
                     // Get surface shader binding, if any.
-                    let surface_shader_name = if child
+                    let surface_shader_name = if let Some((_, shader_name, byte_offset)) = child
                         .iter_leaf_children_with_type("SurfaceShaderBind")
-                        .count()
-                        > 0
+                        .nth(0)
                     {
-                        Some(
-                            child
-                                .iter_leaf_children_with_type("SurfaceShaderBind")
-                                .nth(0)
-                                .unwrap()
-                                .1,
-                        )
+                        if builder.surface_shader(shader_name).is_none() {
+                            return Err(PsyParseError::IncorrectLeafData(
+                                byte_offset,
+                                "SurfaceShaderBind refers to a surface shader \
+                                 that doesn't exist.",
+                            ));
+                        }
+                        Some(shader_name)
                     } else {
                         None
                     };
 
+                    // Get medium binding, if any.
+                    let medium_index = parse_medium_bind(child, medium_map)?;
+
                     // Get xforms
                     let mut xforms = Vec::new();
                     for (_, contents, _) in child.iter_leaf_children_with_type("Transform") {
@@ -75,7 +152,57 @@ pub fn parse_assembly<'a>(
 
                     // Add instance
                     if builder.name_exists(name) {
-                        builder.add_instance(name, surface_shader_name, Some(&xforms));
+                        // Pre-shading is done in world space, so it needs
+                        // this instance to have a single placement.
+                        let space = dicing_ctx.instance_space(&xforms);
+                        match (micropoly_batches.get(name), surface_shader_name, space) {
+                            (Some(batch), Some(shader_name), Some(space))
+                                if !direct_shading && medium_index.is_none() =>
+                            {
+                                let existing = preshaded
+                                    .iter()
+                                    .find(|p| p.0 == name && p.1 == shader_name && p.2 == space);
+                                let preshaded_name = if let Some(p) = existing {
+                                    p.3.clone()
+                                } else {
+                                    // Checked when parsing the binding above.
+                                    let shader = builder.surface_shader(shader_name).unwrap();
+                                    let preshaded_name =
+                                        format!("{}|{}|{}", name, shader_name, preshaded.len());
+                                    builder.add_object(
+                                        &preshaded_name,
+                                        Object::Surface(arena.alloc(batch.preshaded(
+                                            arena,
+                                            shader,
+                                            &space,
+                                            dicing_ctx.camera(),
+                                        ))),
+                                    );
+                                    preshaded.push((
+                                        name,
+                                        shader_name,
+                                        space,
+                                        preshaded_name.clone(),
+                                    ));
+                                    preshaded_name
+                                };
+                                builder.add_instance(
+                                    &preshaded_name,
+                                    surface_shader_name,
+                                    medium_index,
+                                    Some(&xforms),
+                                );
+                            }
+
+                            _ => {
+                                builder.add_instance(
+                                    name,
+                                    surface_shader_name,
+                                    medium_index,
+                                    Some(&xforms),
+                                );
+                            }
+                        }
                     } else {
                         return Err(PsyParseError::InstancedMissingData(
                             child.iter_leaf_children_with_type("Data").nth(0).unwrap().2,
@@ -105,16 +232,52 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // DisplacementShader
+                "DisplacementShader" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        displacement_shaders
+                            .insert(ident, parse_displacement_shader(arena, child)?);
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 // MeshSurface
                 "MeshSurface" => {
                     if let DataTree::Internal {
                         ident: Some(ident), ..
                     } = *child
                     {
-                        builder.add_object(
-                            ident,
-                            Object::Surface(arena.alloc(parse_mesh_surface(arena, child)?)),
-                        );
+                        // When the scene is shaded directly, meshes are only
+                        // diced if they need it for displacement.
+                        let mesh = parse_mesh_surface(child)?;
+                        let displacement = bound_displacement_shader(child, &displacement_shaders)?;
+                        if !direct_shading || displacement.is_some() {
+                            let batch =
+                                arena.alloc(mesh.dice(arena, &instanced_ctx(ident), displacement));
+                            micropoly_batches.insert(ident, batch);
+                            builder.add_object(ident, Object::Surface(batch));
+                        } else {
+                            let tri_mesh = mesh
+                                .triangulate(
+                                    arena,
+                                    page_files,
+                                    split_mode,
+                                    parse_mesh_storage(child, mesh_storage)?,
+                                )
+                                .map_err(|e| {
+                                    PsyParseError::IoError(
+                                        child.byte_offset(),
+                                        "Failed to write the mesh's page file.",
+                                        e.to_string(),
+                                    )
+                                })?;
+                            builder.add_object(ident, Object::Surface(arena.alloc(tri_mesh)));
+                        }
                     } else {
                         // TODO: error condition of some kind, because no ident
                         panic!(
@@ -125,6 +288,149 @@ pub fn parse_assembly<'a>(
                     }
                 }
 
+                // SubdivisionSurface
+                "SubdivisionSurface" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        let batch = arena.alloc(parse_subdivision_surface(child)?.dice(
+                            arena,
+                            &instanced_ctx(ident),
+                            bound_displacement_shader(child, &displacement_shaders)?,
+                        ));
+                        micropoly_batches.insert(ident, batch);
+                        builder.add_object(ident, Object::Surface(batch));
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Bilinear Patch
+                "BilinearPatch" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        let batch = arena.alloc(parse_bilinear_patch(
+                            arena,
+                            child,
+                            &instanced_ctx(ident),
+                            bound_displacement_shader(child, &displacement_shaders)?,
+                        )?);
+                        micropoly_batches.insert(ident, batch);
+                        builder.add_object(ident, Object::Surface(batch));
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Bicubic Patch
+                "BicubicPatch" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        let batch = arena.alloc(parse_bicubic_patch(
+                            arena,
+                            child,
+                            &instanced_ctx(ident),
+                            bound_displacement_shader(child, &displacement_shaders)?,
+                        )?);
+                        micropoly_batches.insert(ident, batch);
+                        builder.add_object(ident, Object::Surface(batch));
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // CurveSurface
+                "CurveSurface" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(
+                                arena.alloc(parse_curve_surface(arena, child, split_mode)?),
+                            ),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // PointsSurface
+                "PointsSurface" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(
+                                arena.alloc(parse_points_surface(arena, child, split_mode)?),
+                            ),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Sphere
+                "Sphere" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_sphere(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Disk
+                "Disk" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_disk(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
+                // Cylinder
+                "Cylinder" => {
+                    if let DataTree::Internal {
+                        ident: Some(ident), ..
+                    } = *child
+                    {
+                        builder.add_object(
+                            ident,
+                            Object::Surface(arena.alloc(parse_cylinder(arena, child)?)),
+                        );
+                    } else {
+                        // No ident
+                        return Err(PsyParseError::UnknownError(child.byte_offset()));
+                    }
+                }
+
                 // Sphere Light
                 "SphereLight" => {
                     if let DataTree::Internal {
@@ -159,25 +465,7 @@ pub fn parse_assembly<'a>(
 
                 _ => {
                     // TODO: some kind of error, because not a known type name
-                } // // Bilinear Patch
-                  // "BilinearPatch" => {
-                  //     assembly->add_object(child.name, parse_bilinear_patch(child));
-                  // }
-                  //
-                  // // Bicubic Patch
-                  // else if (child.type == "BicubicPatch") {
-                  //     assembly->add_object(child.name, parse_bicubic_patch(child));
-                  // }
-                  //
-                  // // Subdivision surface
-                  // else if (child.type == "SubdivisionSurface") {
-                  //     assembly->add_object(child.name, parse_subdivision_surface(child));
-                  // }
-                  //
-                  // // Sphere
-                  // else if (child.type == "Sphere") {
-                  //     assembly->add_object(child.name, parse_sphere(child));
-                  // }
+                }
             }
         }
     } else {
@@ -186,3 +474,26 @@ pub fn parse_assembly<'a>(
 
     return Ok(builder.build());
 }
+
+/// Looks up the displacement shader bound to the given geometry, if any.
+fn bound_displacement_shader<'a>(
+    tree: &DataTree,
+    displacement_shaders: &HashMap<&str, &'a dyn DisplacementShader>,
+) -> Result<Option<&'a dyn DisplacementShader>, PsyParseError> {
+    if let Some((_, name, byte_offset)) = tree
+        .iter_leaf_children_with_type("DisplacementShaderBind")
+        .nth(0)
+    {
+        if let Some(&shader) = displacement_shaders.get(name.trim()) {
+            Ok(Some(shader))
+        } else {
+            Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "DisplacementShaderBind refers to a displacement shader \
+                 that doesn't exist.",
+            ))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,156 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, multi::many1, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    accel::SplitMode,
+    math::Point,
+    surface::curve_surface::{CurveBasis, CurveShape, CurveSurface},
+};
+
+use super::{
+    basics::{ws_f32, ws_usize},
+    psy::PsyParseError,
+    DataTree,
+};
+
+pub fn parse_curve_surface<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    split_mode: SplitMode,
+) -> Result<CurveSurface<'a>, PsyParseError> {
+    // Basis
+    let basis =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Basis").nth(0) {
+            match text.trim() {
+                "Bezier" => CurveBasis::Bezier,
+                "BSpline" => CurveBasis::BSpline,
+                _ => {
+                    return Err(PsyParseError::UnknownVariant(
+                        byte_offset,
+                        "Basis should be either Bezier or BSpline.",
+                    ));
+                }
+            }
+        } else {
+            CurveBasis::BSpline
+        };
+
+    // Shape
+    let shape =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Shape").nth(0) {
+            match text.trim() {
+                "Ribbon" => CurveShape::Ribbon,
+                "Round" => CurveShape::Round,
+                _ => {
+                    return Err(PsyParseError::UnknownVariant(
+                        byte_offset,
+                        "Shape should be either Ribbon or Round.",
+                    ));
+                }
+            }
+        } else {
+            CurveShape::Round
+        };
+
+    // Get verts, one leaf for each time sample
+    let mut verts = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Vertices") {
+        if let IResult::Ok((_, tverts)) =
+            all_consuming(many1(tuple((ws_f32, ws_f32, ws_f32))))(text)
+        {
+            verts.push(
+                tverts
+                    .iter()
+                    .map(|v| Point::new(v.0, v.1, v.2))
+                    .collect::<Vec<_>>(),
+            );
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "CurveSurface Vertices should be a list of points \
+                 in the form '[x y z x y z ...]'.",
+            ));
+        }
+    }
+    if verts.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "CurveSurface must have at least one Vertices field.",
+        ));
+    }
+    let vert_count = verts[0].len();
+    if verts.iter().any(|tverts| tverts.len() != vert_count) {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "All CurveSurface Vertices fields must have the same number of points.",
+        ));
+    }
+
+    // Get widths, either one leaf for each time sample or just one
+    let mut widths = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Widths") {
+        match all_consuming(many1(ws_f32))(text) {
+            IResult::Ok((_, twidths)) if twidths.len() == vert_count => widths.push(twidths),
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "CurveSurface Widths should be a list of decimal numbers \
+                     with one for each vertex.",
+                ));
+            }
+        }
+    }
+    if widths.len() != 1 && widths.len() != verts.len() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "CurveSurface must have either one Widths field, or one for each \
+             Vertices field.",
+        ));
+    }
+
+    // Get curve vert counts
+    let curve_vert_counts = if let Some((_, text, byte_offset)) =
+        tree.iter_leaf_children_with_type("CurveVertCounts").nth(0)
+    {
+        if let IResult::Ok((_, counts)) = all_consuming(many1(ws_usize))(text) {
+            let valid = counts.iter().all(|&count| match basis {
+                CurveBasis::Bezier => count >= 4 && (count - 1) % 3 == 0,
+                CurveBasis::BSpline => count >= 4,
+            });
+            if !valid || counts.iter().sum::<usize>() != vert_count {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "CurveSurface CurveVertCounts don't add up to the number of \
+                     vertices, or there's a curve with an invalid number of \
+                     vertices for its basis.",
+                ));
+            }
+            counts
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "CurveSurface CurveVertCounts should be a list of integers.",
+            ));
+        }
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "CurveSurface must have a CurveVertCounts field.",
+        ));
+    };
+
+    Ok(CurveSurface::new(
+        arena,
+        basis,
+        shape,
+        &verts,
+        &widths,
+        &curve_vert_counts,
+        split_mode,
+    ))
+}
@@ -0,0 +1,134 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    math::Vector,
+    shading::{DisplacementShader, SimpleDisplacementShader},
+};
+
+use super::{
+    basics::{ws_f32, ws_u32},
+    psy::PsyParseError,
+    DataTree,
+};
+
+pub fn parse_displacement_shader<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<&'a dyn DisplacementShader, PsyParseError> {
+    let type_name = if let Some((_, text, _)) = tree.iter_leaf_children_with_type("Type").nth(0) {
+        text.trim()
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Type field in DisplacementShader.",
+        ));
+    };
+
+    // Frequency
+    let frequency = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Frequency").nth(0)
+    {
+        if let IResult::Ok((_, frequency)) = all_consuming(ws_f32)(contents) {
+            frequency
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Frequency should be a decimal number specified \
+                 in the form '[frequency]'.",
+            ));
+        }
+    } else {
+        1.0
+    };
+
+    // Octaves
+    let octaves = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Octaves").nth(0)
+    {
+        if let IResult::Ok((_, octaves)) = all_consuming(ws_u32)(contents) {
+            octaves
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Octaves should be an integer specified in the \
+                 form '[octaves]'.",
+            ));
+        }
+    } else {
+        1
+    };
+
+    let shader = match type_name {
+        "Scalar" => {
+            let height = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Height").nth(0)
+            {
+                if let IResult::Ok((_, height)) = all_consuming(ws_f32)(contents) {
+                    height
+                } else {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "Height should be a decimal number specified \
+                         in the form '[height]'.",
+                    ));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Height field in Scalar DisplacementShader.",
+                ));
+            };
+
+            arena.alloc(SimpleDisplacementShader::Scalar {
+                height: height,
+                frequency: frequency,
+                octaves: octaves,
+            })
+        }
+
+        "Vector" => {
+            let amplitude = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Amplitude").nth(0)
+            {
+                if let IResult::Ok((_, a)) =
+                    all_consuming(tuple((ws_f32, ws_f32, ws_f32)))(contents)
+                {
+                    Vector::new(a.0, a.1, a.2)
+                } else {
+                    return Err(PsyParseError::IncorrectLeafData(
+                        byte_offset,
+                        "Amplitude should be three decimal numbers \
+                         specified in the form '[x y z]'.",
+                    ));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected an Amplitude field in Vector DisplacementShader.",
+                ));
+            };
+
+            arena.alloc(SimpleDisplacementShader::Vector {
+                amplitude: amplitude,
+                frequency: frequency,
+                octaves: octaves,
+            })
+        }
+
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                tree.byte_offset(),
+                "DisplacementShader Type should be either Scalar \
+                 or Vector.",
+            ));
+        }
+    };
+
+    Ok(shader)
+}
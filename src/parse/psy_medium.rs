@@ -0,0 +1,227 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    color::Color,
+    math::Point,
+    medium::{grid::read_raw_grid, GridMedium, HomogeneousMedium, Medium},
+};
+
+use super::{
+    basics::{ws_f32, ws_usize},
+    psy::{parse_color, parse_file_path, PsyParseError},
+    DataTree,
+};
+
+pub fn parse_medium<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<&'a dyn Medium, PsyParseError> {
+    let type_name = if let Some((_, text, _)) = tree.iter_leaf_children_with_type("Type").nth(0) {
+        text.trim()
+    } else {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Expected a Type field in Medium.",
+        ));
+    };
+
+    // Properties common to all media
+    let absorption = parse_coefficient(
+        tree,
+        "Absorption",
+        "Expected an Absorption field in Medium.",
+    )?;
+    let scattering =
+        parse_coefficient(tree, "Scattering", "Expected a Scattering field in Medium.")?;
+
+    // Density
+    let density = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Density").nth(0)
+    {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, density)) if density >= 0.0 => density,
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Density should be a single non-negative decimal number.",
+                ));
+            }
+        }
+    } else {
+        1.0
+    };
+
+    // Anisotropy
+    let anisotropy = if let Some((_, contents, byte_offset)) =
+        tree.iter_leaf_children_with_type("Anisotropy").nth(0)
+    {
+        if let IResult::Ok((_, anisotropy)) = all_consuming(ws_f32)(contents) {
+            // Keep it away from -1 and 1, where the phase function
+            // becomes a delta distribution.
+            anisotropy.max(-0.99).min(0.99)
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Anisotropy should be a single decimal number from -1 to 1.",
+            ));
+        }
+    } else {
+        0.0
+    };
+
+    let medium: &dyn Medium = match type_name {
+        "Homogeneous" => arena.alloc(HomogeneousMedium {
+            absorption: absorption,
+            scattering: scattering,
+            density: density,
+            anisotropy: anisotropy,
+        }),
+
+        "Grid" => {
+            // Resolution
+            let resolution = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Resolution").nth(0)
+            {
+                match all_consuming(tuple((ws_usize, ws_usize, ws_usize)))(contents) {
+                    IResult::Ok((_, (x, y, z))) if x > 0 && y > 0 && z > 0 => [x, y, z],
+                    _ => {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Resolution should be three positive integers in \
+                             the form '[x y z]'.",
+                        ));
+                    }
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Resolution field in Grid Medium.",
+                ));
+            };
+
+            // Bounds
+            let bounds = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Bounds").nth(0)
+            {
+                match all_consuming(tuple((ws_f32, ws_f32, ws_f32, ws_f32, ws_f32, ws_f32)))(
+                    contents,
+                ) {
+                    IResult::Ok((_, (x1, y1, z1, x2, y2, z2))) if x1 < x2 && y1 < y2 && z1 < z2 => {
+                        BBox::from_points(Point::new(x1, y1, z1), Point::new(x2, y2, z2))
+                    }
+                    _ => {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Bounds should be the min and max corners of a box \
+                             in the form '[x1 y1 z1 x2 y2 z2]'.",
+                        ));
+                    }
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Bounds field in Grid Medium.",
+                ));
+            };
+
+            // Voxel data
+            let density_grid = if let Some(grid) = parse_grid_file(tree, "DensityFile", resolution)?
+            {
+                grid
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a DensityFile field in Grid Medium.",
+                ));
+            };
+            let temperature_grid = parse_grid_file(tree, "TemperatureFile", resolution)?;
+
+            // Emission
+            let emission = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Emission").nth(0)
+            {
+                match all_consuming(ws_f32)(contents) {
+                    IResult::Ok((_, emission)) if emission >= 0.0 => emission,
+                    _ => {
+                        return Err(PsyParseError::IncorrectLeafData(
+                            byte_offset,
+                            "Emission should be a single non-negative decimal number.",
+                        ));
+                    }
+                }
+            } else {
+                1.0
+            };
+
+            arena.alloc(GridMedium::new(
+                arena,
+                bounds,
+                resolution,
+                &density_grid,
+                temperature_grid.as_ref().map(|grid| &grid[..]),
+                absorption,
+                scattering,
+                density,
+                emission,
+                anisotropy,
+            ))
+        }
+
+        _ => {
+            return Err(PsyParseError::UnknownVariant(
+                tree.byte_offset(),
+                "Unknown Medium type.",
+            ));
+        }
+    };
+
+    Ok(medium)
+}
+
+/// Reads the voxel grid file given by the first leaf of type `leaf_type`,
+/// if there is one.
+fn parse_grid_file(
+    tree: &DataTree,
+    leaf_type: &'static str,
+    resolution: [usize; 3],
+) -> Result<Option<Vec<f32>>, PsyParseError> {
+    if let Some((_, contents, byte_offset)) = tree.iter_leaf_children_with_type(leaf_type).nth(0) {
+        let path = parse_file_path(contents, byte_offset)?;
+        if let Ok(grid) = read_raw_grid(std::path::Path::new(path), resolution) {
+            Ok(Some(grid))
+        } else {
+            Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Voxel grid files should be readable raw grids of 32-bit floats \
+                 matching the Resolution.",
+            ))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+/// Parses the first leaf of type `leaf_type` as a color.
+fn parse_coefficient(
+    tree: &DataTree,
+    leaf_type: &'static str,
+    missing_msg: &'static str,
+) -> Result<Color, PsyParseError> {
+    if let Some((_, contents, byte_offset)) = tree.iter_leaf_children_with_type(leaf_type).nth(0) {
+        if let Ok(color) = parse_color(contents) {
+            Ok(color)
+        } else {
+            // Found color, but its contents is not in the right format
+            Err(PsyParseError::UnknownError(byte_offset))
+        }
+    } else {
+        Err(PsyParseError::MissingNode(tree.byte_offset(), missing_msg))
+    }
+}
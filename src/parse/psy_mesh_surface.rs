@@ -4,11 +4,9 @@ use std::result::Result;
 
 use nom::{sequence::tuple, IResult};
 
-use kioku::Arena;
-
 use crate::{
     math::{Normal, Point},
-    surface::triangle_mesh::TriangleMesh,
+    surface::{polygon_mesh::PolygonMesh, triangle_mesh::MeshStorage},
 };
 
 use super::{
@@ -24,10 +22,7 @@ use super::{
 //    accel: BVH,
 // }
 
-pub fn parse_mesh_surface<'a>(
-    arena: &'a Arena,
-    tree: &'a DataTree,
-) -> Result<TriangleMesh<'a>, PsyParseError> {
+pub fn parse_mesh_surface(tree: &DataTree) -> Result<PolygonMesh, PsyParseError> {
     let mut verts = Vec::new(); // Vec of vecs, one for each time sample
     let mut normals = Vec::new(); // Vec of vecs, on for each time sample
     let mut face_vert_counts = Vec::new();
@@ -92,36 +87,44 @@ pub fn parse_mesh_surface<'a>(
         }
     }
 
-    // Build triangle mesh
-    let mut tri_vert_indices = Vec::new();
-    let mut ii = 0;
+    // Validate faces
     for fvc in &face_vert_counts {
-        if *fvc >= 3 {
-            // Store the polygon, split up into triangles if >3 verts
-            let v1 = ii;
-            for vi in 0..(fvc - 2) {
-                tri_vert_indices.push((
-                    face_vert_indices[v1],
-                    face_vert_indices[v1 + vi + 1],
-                    face_vert_indices[v1 + vi + 2],
-                ));
-            }
-        } else {
+        if *fvc < 3 {
             // TODO: proper error
             panic!("Cannot handle polygons with less than three vertices.");
         }
-
-        ii += *fvc;
     }
 
-    Ok(TriangleMesh::from_verts_and_indices(
-        arena,
-        &verts,
-        &if normals.is_empty() {
+    Ok(PolygonMesh::new(
+        verts,
+        if normals.is_empty() {
             None
         } else {
             Some(normals)
         },
-        &tri_vert_indices,
+        &face_vert_counts,
+        &face_vert_indices,
     ))
 }
+
+/// Parses how a mesh's geometry should be stored when it's rendered
+/// directly, falling back to `default` if it doesn't say.
+pub fn parse_mesh_storage(
+    tree: &DataTree,
+    default: MeshStorage,
+) -> Result<MeshStorage, PsyParseError> {
+    if let Some((_, contents, byte_offset)) = tree.iter_leaf_children_with_type("Storage").nth(0) {
+        match contents.trim() {
+            "Full" => Ok(MeshStorage::Full),
+            "Compressed" => Ok(MeshStorage::Compressed),
+            "Paged" => Ok(MeshStorage::Paged),
+            _ => Err(PsyParseError::UnknownVariant(
+                byte_offset,
+                "Storage should be one of Full, \
+                 Compressed, or Paged.",
+            )),
+        }
+    } else {
+        Ok(default)
+    }
+}
@@ -0,0 +1,120 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, multi::many1, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    math::Point,
+    shading::DisplacementShader,
+    surface::{
+        bicubic_patch::BicubicPatch, bilinear_patch::BilinearPatch, dicing::dice_patches,
+        dicing::DicingContext, micropoly_batch::MicropolyBatch,
+    },
+};
+
+use super::{
+    basics::{ws_f32, ws_u32},
+    psy::PsyParseError,
+    DataTree,
+};
+
+pub fn parse_bilinear_patch<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    dicing_ctx: &DicingContext,
+    displacement: Option<&dyn DisplacementShader>,
+) -> Result<MicropolyBatch<'a>, PsyParseError> {
+    let control_points: Vec<[Point; 4]> = parse_control_points(tree, 4)?
+        .iter()
+        .map(|cps| [cps[0], cps[1], cps[2], cps[3]])
+        .collect();
+    let must_split = parse_must_split(tree)?;
+
+    Ok(dice_patches(
+        arena,
+        &[BilinearPatch::new(&control_points, must_split)],
+        dicing_ctx,
+        displacement,
+    ))
+}
+
+pub fn parse_bicubic_patch<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    dicing_ctx: &DicingContext,
+    displacement: Option<&dyn DisplacementShader>,
+) -> Result<MicropolyBatch<'a>, PsyParseError> {
+    let control_points: Vec<[Point; 16]> = parse_control_points(tree, 16)?
+        .iter()
+        .map(|cps| {
+            let mut patch = [Point::new(0.0, 0.0, 0.0); 16];
+            patch.copy_from_slice(cps);
+            patch
+        })
+        .collect();
+    let must_split = parse_must_split(tree)?;
+
+    Ok(dice_patches(
+        arena,
+        &[BicubicPatch::new(&control_points, must_split)],
+        dicing_ctx,
+        displacement,
+    ))
+}
+
+/// Parses the `Vertices` leaves of a patch, one for each time sample, each
+/// with exactly `count` control points.
+fn parse_control_points(tree: &DataTree, count: usize) -> Result<Vec<Vec<Point>>, PsyParseError> {
+    let mut control_points = Vec::new();
+
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Vertices") {
+        if let IResult::Ok((_, verts)) = all_consuming(many1(tuple((ws_f32, ws_f32, ws_f32))))(text)
+        {
+            if verts.len() != count {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Patch Vertices have the wrong number of control points.",
+                ));
+            }
+            control_points.push(verts.iter().map(|v| Point::new(v.0, v.1, v.2)).collect());
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Patch Vertices should be a list of control points \
+                 in the form '[x y z x y z ...]'.",
+            ));
+        }
+    }
+
+    if control_points.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "Patches must have at least one Vertices field.",
+        ));
+    }
+
+    Ok(control_points)
+}
+
+/// Parses the optional `MustSplit` leaf of a patch, which flags each of
+/// the four edges with a 0 or 1.
+fn parse_must_split(tree: &DataTree) -> Result<[bool; 4], PsyParseError> {
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("MustSplit").nth(0) {
+        match all_consuming(tuple((ws_u32, ws_u32, ws_u32, ws_u32)))(text) {
+            IResult::Ok((_, flags))
+                if [flags.0, flags.1, flags.2, flags.3].iter().all(|&f| f <= 1) =>
+            {
+                Ok([flags.0 == 1, flags.1 == 1, flags.2 == 1, flags.3 == 1])
+            }
+            _ => Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "MustSplit should be four edge flags in the form '[0 1 0 0]'.",
+            )),
+        }
+    } else {
+        Ok([false; 4])
+    }
+}
@@ -0,0 +1,135 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, multi::many1, sequence::tuple, IResult};
+
+use kioku::Arena;
+
+use crate::{
+    accel::SplitMode,
+    color::{rec709_e_to_xyz, Color},
+    math::Point,
+    surface::points_surface::{PointShape, PointsSurface},
+};
+
+use super::{basics::ws_f32, psy::PsyParseError, DataTree};
+
+pub fn parse_points_surface<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+    split_mode: SplitMode,
+) -> Result<PointsSurface<'a>, PsyParseError> {
+    // Shape
+    let shape =
+        if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("Shape").nth(0) {
+            match text.trim() {
+                "Sphere" => PointShape::Sphere,
+                "Disc" => PointShape::Disc,
+                _ => {
+                    return Err(PsyParseError::UnknownVariant(
+                        byte_offset,
+                        "Shape should be either Sphere or Disc.",
+                    ));
+                }
+            }
+        } else {
+            PointShape::Sphere
+        };
+
+    // Get positions, one leaf for each time sample
+    let mut positions = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Points") {
+        if let IResult::Ok((_, tpoints)) =
+            all_consuming(many1(tuple((ws_f32, ws_f32, ws_f32))))(text)
+        {
+            positions.push(
+                tpoints
+                    .iter()
+                    .map(|p| Point::new(p.0, p.1, p.2))
+                    .collect::<Vec<_>>(),
+            );
+        } else {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "PointsSurface Points should be a list of points \
+                 in the form '[x y z x y z ...]'.",
+            ));
+        }
+    }
+    if positions.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "PointsSurface must have at least one Points field.",
+        ));
+    }
+    let point_count = positions[0].len();
+    if positions.iter().any(|tpoints| tpoints.len() != point_count) {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "All PointsSurface Points fields must have the same number of points.",
+        ));
+    }
+
+    // Get radii, either one leaf for each time sample or just one
+    let mut radii = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Radii") {
+        match all_consuming(many1(ws_f32))(text) {
+            IResult::Ok((_, tradii))
+                if tradii.len() == point_count && tradii.iter().all(|&r| r >= 0.0) =>
+            {
+                radii.push(tradii)
+            }
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "PointsSurface Radii should be a list of non-negative \
+                     decimal numbers with one for each point.",
+                ));
+            }
+        }
+    }
+    if radii.len() != 1 && radii.len() != positions.len() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "PointsSurface must have either one Radii field, or one for each \
+             Points field.",
+        ));
+    }
+
+    // Get colors, if any, either one leaf for each time sample or just one
+    let mut colors = Vec::new();
+    for (_, text, byte_offset) in tree.iter_leaf_children_with_type("Colors") {
+        match all_consuming(many1(tuple((ws_f32, ws_f32, ws_f32))))(text) {
+            IResult::Ok((_, tcolors)) if tcolors.len() == point_count => colors.push(
+                tcolors
+                    .iter()
+                    .map(|&c| Color::new_xyz(rec709_e_to_xyz(c)))
+                    .collect::<Vec<_>>(),
+            ),
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "PointsSurface Colors should be a list of rec709 colors \
+                     in the form '[r g b r g b ...]', with one for each point.",
+                ));
+            }
+        }
+    }
+    if colors.len() > 1 && colors.len() != positions.len() {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "PointsSurface must have either no Colors field, one Colors \
+             field, or one for each Points field.",
+        ));
+    }
+    let colors = if colors.is_empty() {
+        None
+    } else {
+        Some(colors)
+    };
+
+    Ok(PointsSurface::new(
+        arena, shape, &positions, &radii, &colors, split_mode,
+    ))
+}
@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+
+use std::result::Result;
+
+use nom::{combinator::all_consuming, IResult};
+
+use kioku::Arena;
+
+use crate::surface::quadric::{Cylinder, Disk, Sphere};
+
+use super::{basics::ws_f32, psy::PsyParseError, DataTree};
+
+pub fn parse_sphere<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Sphere<'a>, PsyParseError> {
+    let radii = parse_time_samples(
+        tree,
+        "Radius",
+        "Sphere must have at least one Radius field.",
+    )?;
+
+    Ok(Sphere::new(arena, &radii))
+}
+
+pub fn parse_disk<'a>(arena: &'a Arena, tree: &'a DataTree) -> Result<Disk<'a>, PsyParseError> {
+    let radii = parse_time_samples(tree, "Radius", "Disk must have at least one Radius field.")?;
+
+    Ok(Disk::new(arena, &radii))
+}
+
+pub fn parse_cylinder<'a>(
+    arena: &'a Arena,
+    tree: &'a DataTree,
+) -> Result<Cylinder<'a>, PsyParseError> {
+    let radii = parse_time_samples(
+        tree,
+        "Radius",
+        "Cylinder must have at least one Radius field.",
+    )?;
+    let heights = parse_time_samples(
+        tree,
+        "Height",
+        "Cylinder must have at least one Height field.",
+    )?;
+
+    Ok(Cylinder::new(arena, &radii, &heights))
+}
+
+/// Parses the leaves of type `leaf_type`, one for each time sample, each
+/// with a single non-negative number.  `missing_msg` is the error message
+/// for when there are none.
+fn parse_time_samples(
+    tree: &DataTree,
+    leaf_type: &'static str,
+    missing_msg: &'static str,
+) -> Result<Vec<f32>, PsyParseError> {
+    let mut samples = Vec::new();
+
+    for (_, contents, byte_offset) in tree.iter_leaf_children_with_type(leaf_type) {
+        match all_consuming(ws_f32)(contents) {
+            IResult::Ok((_, value)) if value >= 0.0 => samples.push(value),
+            _ => {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "Quadric surface dimensions should be a single \
+                     non-negative decimal number.",
+                ));
+            }
+        }
+    }
+
+    if samples.is_empty() {
+        return Err(PsyParseError::MissingNode(tree.byte_offset(), missing_msg));
+    }
+
+    Ok(samples)
+}
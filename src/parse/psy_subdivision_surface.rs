@@ -0,0 +1,215 @@
+#![allow(dead_code)]
+
+use std::{collections::HashMap, result::Result};
+
+use nom::{sequence::tuple, IResult};
+
+use crate::{
+    math::Point,
+    surface::subdivision_surface::{BoundaryRule, SubdivisionSurface},
+};
+
+use super::{
+    basics::{ws_f32, ws_usize},
+    psy::PsyParseError,
+    DataTree,
+};
+
+pub fn parse_subdivision_surface(tree: &DataTree) -> Result<SubdivisionSurface, PsyParseError> {
+    let mut verts = Vec::new(); // Vec of vecs, one for each time sample
+    let mut face_vert_counts = Vec::new();
+    let mut face_vert_indices = Vec::new();
+    let mut creases = Vec::new();
+    let mut boundary_rule = BoundaryRule::EdgesAndCorners;
+
+    // Get verts
+    for (_, mut text, _) in tree.iter_leaf_children_with_type("Vertices") {
+        // Collect verts for this time sample
+        let mut tverts = Vec::new();
+        while let IResult::Ok((remaining, vert)) = tuple((ws_f32, ws_f32, ws_f32))(text) {
+            text = remaining;
+
+            tverts.push(Point::new(vert.0, vert.1, vert.2));
+        }
+        verts.push(tverts);
+    }
+
+    if verts.is_empty() {
+        return Err(PsyParseError::MissingNode(
+            tree.byte_offset(),
+            "SubdivisionSurface must have at least one Vertices field.",
+        ));
+    }
+
+    // Make sure all time samples have same vert count
+    let vert_count = verts[0].len();
+    for vs in &verts {
+        if vs.len() != vert_count {
+            return Err(PsyParseError::IncorrectLeafData(
+                tree.byte_offset(),
+                "All Vertices time samples of a SubdivisionSurface \
+                 must have the same number of vertices.",
+            ));
+        }
+    }
+
+    // Get face vert counts
+    if let Some((_, mut text, _)) = tree.iter_leaf_children_with_type("FaceVertCounts").nth(0) {
+        while let IResult::Ok((remaining, count)) = ws_usize(text) {
+            text = remaining;
+
+            face_vert_counts.push(count);
+        }
+    }
+
+    // Get face vert indices
+    if let Some((_, mut text, _)) = tree.iter_leaf_children_with_type("FaceVertIndices").nth(0) {
+        while let IResult::Ok((remaining, index)) = ws_usize(text) {
+            text = remaining;
+
+            face_vert_indices.push(index);
+        }
+    }
+
+    // Validate faces
+    if face_vert_counts.iter().any(|&fvc| fvc < 3) {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "SubdivisionSurface faces must have at least three vertices.",
+        ));
+    }
+    if face_vert_counts.iter().sum::<usize>() != face_vert_indices.len()
+        || face_vert_indices.iter().any(|&i| i >= vert_count)
+    {
+        return Err(PsyParseError::IncorrectLeafData(
+            tree.byte_offset(),
+            "SubdivisionSurface FaceVertIndices don't match its \
+             FaceVertCounts and Vertices.",
+        ));
+    }
+
+    // Make sure the faces are manifold: no face can use a vertex more than
+    // once, and no edge can be shared by more than two faces.
+    let mut edge_face_counts = HashMap::new();
+    let mut ii = 0;
+    for &fvc in &face_vert_counts {
+        let face = &face_vert_indices[ii..(ii + fvc)];
+        ii += fvc;
+        for (i, &v1) in face.iter().enumerate() {
+            if face[..i].contains(&v1) {
+                return Err(PsyParseError::IncorrectLeafData(
+                    tree.byte_offset(),
+                    "SubdivisionSurface faces can't use the same vertex \
+                     more than once.",
+                ));
+            }
+            let v2 = face[(i + 1) % fvc];
+            let count = edge_face_counts
+                .entry((v1.min(v2), v1.max(v2)))
+                .or_insert(0);
+            *count += 1;
+            if *count > 2 {
+                return Err(PsyParseError::IncorrectLeafData(
+                    tree.byte_offset(),
+                    "SubdivisionSurface edges can't be shared by more than \
+                     two faces.",
+                ));
+            }
+        }
+    }
+
+    // Get creases, if any
+    if let Some((_, mut text, byte_offset)) = tree.iter_leaf_children_with_type("Creases").nth(0) {
+        while let IResult::Ok((remaining, crease)) = tuple((ws_usize, ws_usize, ws_f32))(text) {
+            text = remaining;
+
+            if crease.0 >= vert_count || crease.1 >= vert_count {
+                return Err(PsyParseError::IncorrectLeafData(
+                    byte_offset,
+                    "SubdivisionSurface Creases refer to vertices that \
+                     don't exist.",
+                ));
+            }
+            creases.push(crease);
+        }
+        if !text.trim().is_empty() {
+            return Err(PsyParseError::IncorrectLeafData(
+                byte_offset,
+                "Creases should be specified as a list of \
+                 '[vert_index vert_index sharpness ...]'.",
+            ));
+        }
+    }
+
+    // Get boundary rule, if any
+    if let Some((_, text, byte_offset)) = tree.iter_leaf_children_with_type("BoundaryRule").nth(0) {
+        boundary_rule = match text.trim() {
+            "EdgesOnly" => BoundaryRule::EdgesOnly,
+            "EdgesAndCorners" => BoundaryRule::EdgesAndCorners,
+            _ => {
+                return Err(PsyParseError::UnknownVariant(
+                    byte_offset,
+                    "BoundaryRule should be either EdgesOnly or \
+                     EdgesAndCorners.",
+                ));
+            }
+        };
+    }
+
+    Ok(SubdivisionSurface::new(
+        verts,
+        &face_vert_counts,
+        &face_vert_indices,
+        &creases,
+        boundary_rule,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<SubdivisionSurface, PsyParseError> {
+        let text = format!("SubdivisionSurface {{ {} }}", contents);
+        let tree = DataTree::from_str(&text).unwrap();
+        parse_subdivision_surface(
+            tree.iter_children_with_type("SubdivisionSurface")
+                .next()
+                .unwrap(),
+        )
+    }
+
+    const VERTICES: &str = "Vertices [0 0 0  1 0 0  1 1 0  0 1 0  0 0 1  1 0 1]";
+
+    #[test]
+    fn manifold_faces() {
+        assert!(parse(&format!(
+            "{} FaceVertCounts [4 4] FaceVertIndices [0 1 2 3  1 0 4 5]",
+            VERTICES
+        ))
+        .is_ok());
+    }
+
+    #[test]
+    fn repeated_face_vertex_is_an_error() {
+        match parse(&format!(
+            "{} FaceVertCounts [4] FaceVertIndices [0 1 2 1]",
+            VERTICES
+        )) {
+            Err(PsyParseError::IncorrectLeafData(_, _)) => {}
+            r => panic!("Expected a parse error, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn non_manifold_edge_is_an_error() {
+        // Three faces sharing the edge between vertices 0 and 1.
+        match parse(&format!(
+            "{} FaceVertCounts [4 3 3] FaceVertIndices [0 1 2 3  1 0 4  0 1 5]",
+            VERTICES
+        )) {
+            Err(PsyParseError::IncorrectLeafData(_, _)) => {}
+            r => panic!("Expected a parse error, got {:?}", r),
+        }
+    }
+}
@@ -132,6 +132,88 @@ pub fn parse_surface_shader<'a>(
             arena.alloc(SimpleSurfaceShader::Emit { color: color })
         }
 
+        "Hair" => {
+            // Color
+            let color = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Color").nth(0)
+            {
+                if let Ok(color) = parse_color(contents) {
+                    color
+                } else {
+                    // Found color, but its contents is not in the right format
+                    return Err(PsyParseError::UnknownError(byte_offset));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Color field in Hair SurfaceShader.",
+                ));
+            };
+
+            // Roughness
+            let roughness = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Roughness").nth(0)
+            {
+                if let IResult::Ok((_, roughness)) = all_consuming(ws_f32)(contents) {
+                    roughness
+                } else {
+                    return Err(PsyParseError::UnknownError(byte_offset));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Roughness field in Hair SurfaceShader.",
+                ));
+            };
+
+            arena.alloc(SimpleSurfaceShader::Hair {
+                color: color,
+                roughness: roughness,
+            })
+        }
+
+        "Subsurface" => {
+            // Color
+            let color = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("Color").nth(0)
+            {
+                if let Ok(color) = parse_color(contents) {
+                    color
+                } else {
+                    // Found color, but its contents is not in the right format
+                    return Err(PsyParseError::UnknownError(byte_offset));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a Color field in Subsurface SurfaceShader.",
+                ));
+            };
+
+            // Mean free path
+            let mfp = if let Some((_, contents, byte_offset)) =
+                tree.iter_leaf_children_with_type("MeanFreePath").nth(0)
+            {
+                if let Ok(mfp) = parse_color(contents) {
+                    mfp
+                } else {
+                    // Found mean free path, but its contents is not in the
+                    // right format
+                    return Err(PsyParseError::UnknownError(byte_offset));
+                }
+            } else {
+                return Err(PsyParseError::MissingNode(
+                    tree.byte_offset(),
+                    "Expected a MeanFreePath field in Subsurface SurfaceShader.",
+                ));
+            };
+
+            arena.alloc(SimpleSurfaceShader::Subsurface {
+                color: color,
+                mfp: mfp,
+            })
+        }
+
         _ => unimplemented!(),
     };
 
@@ -1,7 +1,5 @@
 #![allow(dead_code)]
 
-use glam::Vec4Mask;
-
 use crate::math::{Matrix4x4, Point, Vector};
 
 type RayIndexType = u16;
@@ -36,6 +34,7 @@ struct RayCold {
     orig: Point, // World-space ray origin
     dir: Vector, // World-space ray direction
     wavelength: f32,
+    object_filter: Option<usize>, // If set, the ray only intersects the object with this id
 }
 
 /// A batch of rays, separated into hot and cold parts.
@@ -43,6 +42,7 @@ struct RayCold {
 pub struct RayBatch {
     hot: Vec<RayHot>,
     cold: Vec<RayCold>,
+    filtered_count: usize, // Number of rays with an object filter
 }
 
 impl RayBatch {
@@ -51,6 +51,7 @@ impl RayBatch {
         RayBatch {
             hot: Vec::new(),
             cold: Vec::new(),
+            filtered_count: 0,
         }
     }
 
@@ -60,6 +61,7 @@ impl RayBatch {
         RayBatch {
             hot: Vec::with_capacity(n),
             cold: Vec::with_capacity(n),
+            filtered_count: 0,
         }
     }
 
@@ -75,6 +77,7 @@ impl RayBatch {
             orig: ray.orig,
             dir: ray.dir,
             wavelength: ray.wavelength,
+            object_filter: None,
         });
     }
 
@@ -83,6 +86,21 @@ impl RayBatch {
         self.cold.swap(a, b);
     }
 
+    /// Appends a copy of the ray at index `idx` of another batch.
+    pub fn push_from(&mut self, other: &RayBatch, idx: usize) {
+        self.hot.push(other.hot[idx]);
+        self.cold.push(other.cold[idx]);
+        self.filtered_count += other.cold[idx].object_filter.is_some() as usize;
+    }
+
+    /// Overwrites the ray at index `idx` with a copy of the ray at index
+    /// `other_idx` of another batch.
+    pub fn copy_from(&mut self, idx: usize, other: &RayBatch, other_idx: usize) {
+        self.hot[idx] = other.hot[other_idx];
+        self.set_object_filter(idx, other.cold[other_idx].object_filter);
+        self.cold[idx] = other.cold[other_idx];
+    }
+
     pub fn set_from_ray(&mut self, ray: &Ray, is_occlusion: bool, idx: usize) {
         self.hot[idx].orig_local = ray.orig;
         self.hot[idx].dir_inv_local = Vector {
@@ -95,9 +113,16 @@ impl RayBatch {
         self.cold[idx].orig = ray.orig;
         self.cold[idx].dir = ray.dir;
         self.cold[idx].wavelength = ray.wavelength;
+        self.set_object_filter(idx, None);
     }
 
     pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            self.filtered_count -= self.cold[len..]
+                .iter()
+                .filter(|ray| ray.object_filter.is_some())
+                .count();
+        }
         self.hot.truncate(len);
         self.cold.truncate(len);
     }
@@ -108,6 +133,7 @@ impl RayBatch {
     pub fn clear(&mut self) {
         self.hot.clear();
         self.cold.clear();
+        self.filtered_count = 0;
     }
 
     pub fn len(&self) -> usize {
@@ -169,6 +195,31 @@ impl RayBatch {
         self.cold[idx].wavelength
     }
 
+    /// Returns the id of the only object the given ray (at index `idx`)
+    /// intersects, if it's restricted to one.
+    #[inline(always)]
+    pub fn object_filter(&self, idx: usize) -> Option<usize> {
+        self.cold[idx].object_filter
+    }
+
+    /// Restricts the given ray (at index `idx`) to only intersect the
+    /// object with the given id, or lifts the restriction if `None`.
+    ///
+    /// This is reset by `set_from_ray()`, so should be called after it.
+    #[inline(always)]
+    pub fn set_object_filter(&mut self, idx: usize, object_id: Option<usize>) {
+        self.filtered_count -= self.cold[idx].object_filter.is_some() as usize;
+        self.filtered_count += object_id.is_some() as usize;
+        self.cold[idx].object_filter = object_id;
+    }
+
+    /// Returns whether any of the rays are restricted to one object by
+    /// `set_object_filter()`.
+    #[inline(always)]
+    pub fn has_object_filters(&self) -> bool {
+        self.filtered_count > 0
+    }
+
     /// Returns whether the given ray (at index `idx`) is an occlusion ray.
     #[inline(always)]
     pub fn is_occlusion(&self, idx: usize) -> bool {
@@ -192,6 +243,13 @@ impl RayBatch {
     pub fn mark_done(&mut self, idx: usize) {
         self.hot[idx].flags |= DONE_FLAG
     }
+
+    /// Marks the given ray (at index `idx`) as not having finished
+    /// traversal after all.
+    #[inline(always)]
+    pub fn unmark_done(&mut self, idx: usize) {
+        self.hot[idx].flags &= !DONE_FLAG
+    }
 }
 
 /// A structure used for tracking traversal of a ray batch through a scene.
@@ -346,10 +404,10 @@ impl RayStack {
 
     /// Pops the next task off the stack, executes the provided closure for
     /// each ray index in the task, and pushes the ray indices back onto the
-    /// indicated lanes.
+    /// lanes indicated by the bitmask that the closure returns.
     pub fn pop_do_next_task_and_push_rays<F>(&mut self, output_lane_count: usize, mut handle_ray: F)
     where
-        F: FnMut(usize) -> Vec4Mask,
+        F: FnMut(usize) -> u32,
     {
         // Pop the task and do necessary bookkeeping.
         let task = self.tasks.pop().unwrap();
@@ -361,7 +419,9 @@ impl RayStack {
         // to the start of our task range, but will continue to access it's
         // elements beyond that range via `get_unchecked()` below.  Because the
         // memory is not freed nor altered, this is safe.  However, again, the
-        // Vec apis don't promise this behavior.  So:
+        // Vec apis don't promise this behavior.  The elements are read through
+        // a raw pointer rather than `get_unchecked()`, since the latter checks
+        // the index against the truncated length in debug builds.  So:
         //
         // TODO: build a slightly different lane abstraction to get this same
         // efficiency without depending on implicit Vec behavior.
@@ -371,8 +431,8 @@ impl RayStack {
 
         // Execute task.
         for i in task_range.0..task_range.1 {
-            let ray_idx = *unsafe { self.lanes[task.lane].idxs.get_unchecked(i) };
-            let push_mask = handle_ray(ray_idx as usize).bitmask();
+            let ray_idx = unsafe { *self.lanes[task.lane].idxs.as_ptr().add(i) };
+            let push_mask = handle_ray(ray_idx as usize);
             for l in 0..output_lane_count {
                 if (push_mask & (1 << l)) != 0 {
                     self.lanes[l as usize].idxs.push(ray_idx);
@@ -399,3 +459,55 @@ struct RayTask {
     lane: usize,
     start_idx: usize,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_ray() -> Ray {
+        Ray {
+            orig: Point::new(0.0, 0.0, 0.0),
+            dir: Vector::new(0.0, 0.0, 1.0),
+            time: 0.0,
+            wavelength: 500.0,
+            max_t: f32::INFINITY,
+        }
+    }
+
+    #[test]
+    fn object_filters_are_counted() {
+        let mut rays = RayBatch::new();
+        for _ in 0..4 {
+            rays.push(test_ray(), false);
+        }
+        assert!(!rays.has_object_filters());
+
+        rays.set_object_filter(1, Some(5));
+        rays.set_object_filter(1, Some(6));
+        rays.set_object_filter(3, Some(5));
+        assert!(rays.has_object_filters());
+
+        // Clearing one filter leaves the other.
+        rays.set_object_filter(1, None);
+        assert!(rays.has_object_filters());
+
+        // Copies carry their filters with them.
+        let mut rays_2 = RayBatch::new();
+        rays_2.push_from(&rays, 3);
+        rays_2.push_from(&rays, 0);
+        assert!(rays_2.has_object_filters());
+        rays_2.copy_from(0, &rays, 2);
+        assert!(!rays_2.has_object_filters());
+        rays_2.copy_from(1, &rays, 3);
+        rays_2.truncate(1);
+        assert!(!rays_2.has_object_filters());
+
+        // Resetting the ray clears its filter.
+        rays.set_from_ray(&test_ray(), false, 3);
+        assert!(!rays.has_object_filters());
+
+        rays.set_object_filter(0, Some(1));
+        rays.clear();
+        assert!(!rays.has_object_filters());
+    }
+}
@@ -13,15 +13,20 @@ use glam::Vec4;
 
 use crate::{
     accel::ACCEL_NODE_RAY_TESTS,
-    color::{map_0_1_to_wavelength, SpectralSample, XYZ},
+    color::{map_0_1_to_wavelength, Color, SpectralSample, XYZ},
     fp_utils::robust_ray_origin,
     hash::hash_u32,
     hilbert,
     image::Image,
-    math::{fast_logit, upper_power_of_two},
+    math::{dot, fast_logit, upper_power_of_two, zup_to_vec, Matrix4x4, Vector},
+    medium::{delta_track, MediumEvent},
     mis::power_heuristic,
+    paging::PAGE_FAULTS,
     ray::{Ray, RayBatch},
+    sampling::{cosine_sample_hemisphere, uniform_sample_sphere},
     scene::{Scene, SceneLightSample},
+    shading::surface_closure::subsurface_coefficients,
+    shutter::Shutter,
     surface,
     timer::Timer,
     tracer::Tracer,
@@ -34,6 +39,8 @@ pub struct Renderer<'a> {
     pub resolution: (usize, usize),
     pub spp: usize,
     pub seed: u32,
+    pub shutter: Shutter,
+    pub sort_rays: bool,
     pub scene: Scene<'a>,
 }
 
@@ -41,6 +48,7 @@ pub struct Renderer<'a> {
 pub struct RenderStats {
     pub trace_time: f64,
     pub accel_node_visits: u64,
+    pub page_faults: u64,
     pub ray_count: u64,
     pub initial_ray_generation_time: f64,
     pub ray_generation_time: f64,
@@ -53,6 +61,7 @@ impl RenderStats {
         RenderStats {
             trace_time: 0.0,
             accel_node_visits: 0,
+            page_faults: 0,
             ray_count: 0,
             initial_ray_generation_time: 0.0,
             ray_generation_time: 0.0,
@@ -64,6 +73,7 @@ impl RenderStats {
     fn collect(&mut self, other: RenderStats) {
         self.trace_time += other.trace_time;
         self.accel_node_visits += other.accel_node_visits;
+        self.page_faults += other.page_faults;
         self.ray_count += other.ray_count;
         self.initial_ray_generation_time += other.initial_ray_generation_time;
         self.ray_generation_time += other.ray_generation_time;
@@ -207,7 +217,7 @@ impl<'a> Renderer<'a> {
 
         let mut paths = Vec::new();
         let mut rays = RayBatch::new();
-        let mut tracer = Tracer::from_assembly(&self.scene.root);
+        let mut tracer = Tracer::from_assembly(&self.scene.root, self.sort_rays);
         let mut xform_stack = TransformStack::new();
 
         // Pre-calculate some useful values related to the image plane
@@ -243,13 +253,11 @@ impl<'a> Renderer<'a> {
                     let offset = hash_u32(((x as u32) << 16) ^ (y as u32), self.seed);
                     for si in 0..self.spp {
                         // Calculate image plane x and y coordinates
-                        let (img_x, img_y) = {
-                            let filter_x = fast_logit(get_sample(4, offset + si as u32), 1.5) + 0.5;
-                            let filter_y = fast_logit(get_sample(5, offset + si as u32), 1.5) + 0.5;
-                            let samp_x = (filter_x + x as f32) * cmpx;
-                            let samp_y = (filter_y + y as f32) * cmpy;
-                            ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent)
-                        };
+                        let filter_x = fast_logit(get_sample(4, offset + si as u32), 1.5) + 0.5;
+                        let filter_y = fast_logit(get_sample(5, offset + si as u32), 1.5) + 0.5;
+                        let samp_x = (filter_x + x as f32) * cmpx;
+                        let samp_y = (filter_y + y as f32) * cmpy;
+                        let (img_x, img_y) = ((samp_x - 0.5) * x_extent, (0.5 - samp_y) * y_extent);
 
                         // Create the light path and initial ray for this sample
                         let (path, ray) = LightPath::new(
@@ -260,12 +268,16 @@ impl<'a> Renderer<'a> {
                                 get_sample(0, offset + si as u32),
                                 get_sample(1, offset + si as u32),
                             ),
-                            get_sample(2, offset + si as u32),
+                            self.shutter
+                                .sample_time(get_sample(2, offset + si as u32), samp_y),
                             map_0_1_to_wavelength(get_sample(3, offset + si as u32)),
                             offset + si as u32,
                         );
-                        paths.push(path);
-                        rays.push(ray, false);
+                        // Samples the camera doesn't see are left black.
+                        if let Some(ray) = ray {
+                            paths.push(path);
+                            rays.push(ray, false);
+                        }
                     }
                 }
             }
@@ -349,6 +361,10 @@ impl<'a> Renderer<'a> {
             stats.accel_node_visits = anv.get();
             anv.set(0);
         });
+        PAGE_FAULTS.with(|pf| {
+            stats.page_faults = pf.get();
+            pf.set(0);
+        });
 
         // Collect stats
         collected_stats.write().unwrap().collect(stats);
@@ -360,8 +376,22 @@ enum LightPathEvent {
     CameraRay,
     BounceRay,
     ShadowRay,
+    SubsurfaceRay,
+}
+
+/// The state of a subsurface scattering random walk.
+#[derive(Debug, Copy, Clone)]
+struct SubsurfaceWalk {
+    object_id: usize, // The placement of the object being walked through
+    sigma_a: Vec4,    // Absorption coefficients
+    sigma_s: Vec4,    // Scattering coefficients
+    step_count: u32,
 }
 
+/// The maximum number of scattering events in a subsurface random walk,
+/// after which the path is terminated.
+const SUBSURFACE_MAX_STEPS: u32 = 256;
+
 #[derive(Debug)]
 pub struct LightPath {
     event: LightPathEvent,
@@ -380,6 +410,12 @@ pub struct LightPath {
     light_attenuation: Vec4,
     pending_color_addition: Vec4,
     color: Vec4,
+
+    medium: Option<usize>,        // The medium the path is currently in
+    shadow_medium: Option<usize>, // The medium the shadow ray is currently in
+    shadow_max_t: f32,            // The remaining max t of the shadow ray
+    subsurface: Option<SubsurfaceWalk>,
+    rand_count: Cell<u32>,
 }
 
 #[allow(clippy::new_ret_no_self)]
@@ -392,7 +428,7 @@ impl LightPath {
         time: f32,
         wavelength: f32,
         lds_offset: u32,
-    ) -> (LightPath, Ray) {
+    ) -> (LightPath, Option<Ray>) {
         (
             LightPath {
                 event: LightPathEvent::CameraRay,
@@ -411,6 +447,12 @@ impl LightPath {
                 light_attenuation: Vec4::splat(1.0),
                 pending_color_addition: Vec4::splat(0.0),
                 color: Vec4::splat(0.0),
+
+                medium: scene.world.medium,
+                shadow_medium: None,
+                shadow_max_t: 0.0,
+                subsurface: None,
+                rand_count: Cell::new(0),
             },
             scene.camera.generate_ray(
                 image_plane_co.0,
@@ -429,6 +471,15 @@ impl LightPath {
         get_sample(dimension, self.lds_offset)
     }
 
+    /// Returns the next random number for medium tracking, which can take
+    /// an unbounded number of them per path.
+    fn next_random(&self) -> f32 {
+        use crate::hash::hash_u32_to_f32;
+        let n = self.rand_count.get();
+        self.rand_count.set(n + 1);
+        hash_u32_to_f32(n, hash_u32(self.lds_offset, 0x6d65_6469))
+    }
+
     fn next(
         &mut self,
         xform_stack: &mut TransformStack,
@@ -441,6 +492,38 @@ impl LightPath {
             //--------------------------------------------------------------------
             // Result of Camera or bounce ray, prepare next bounce and light rays
             LightPathEvent::CameraRay | LightPathEvent::BounceRay => {
+                // If we're in a medium, track through it up to whatever
+                // the ray hit, since the ray may scatter or be absorbed
+                // before getting there.
+                if let Some(medium_index) = self.medium {
+                    let mut weight = Vec4::splat(1.0);
+                    let event = scene.media[medium_index].track(
+                        rays.orig(ray_idx),
+                        rays.dir(ray_idx),
+                        rays.max_t(ray_idx),
+                        self.time,
+                        self.wavelength,
+                        &mut weight,
+                        &mut || self.next_random(),
+                    );
+                    self.light_attenuation *= weight;
+
+                    match event {
+                        MediumEvent::Scatter(t) => {
+                            let isect =
+                                self.medium_intersection(scene, medium_index, rays, ray_idx, t);
+                            return self.scatter(xform_stack, scene, &isect, rays, ray_idx);
+                        }
+                        MediumEvent::Absorb => {
+                            // Collect any light emitted by the medium where
+                            // the path was absorbed.
+                            self.color += self.light_attenuation / self.closure_sample_pdf;
+                            return false;
+                        }
+                        MediumEvent::Pass => {}
+                    }
+                }
+
                 if let surface::SurfaceIntersection::Hit {
                     intersection_data: ref idata,
                     ref closure,
@@ -448,14 +531,40 @@ impl LightPath {
                 {
                     // Hit something!  Do the stuff
 
+                    use crate::shading::surface_closure::SurfaceClosure;
+
+                    // If it's a medium boundary, pass through it into or out
+                    // of the medium, and continue on.
+                    if let SurfaceClosure::MediumBoundary(medium_index) = *closure {
+                        self.medium =
+                            Self::medium_across_boundary(scene, self.medium, medium_index);
+                        let offset_pos = robust_ray_origin(
+                            idata.pos,
+                            idata.pos_err,
+                            idata.nor_g.normalized(),
+                            idata.incoming,
+                        );
+                        rays.set_from_ray(
+                            &Ray {
+                                orig: offset_pos,
+                                dir: idata.incoming,
+                                time: self.time,
+                                wavelength: self.wavelength,
+                                max_t: std::f32::INFINITY,
+                            },
+                            false,
+                            ray_idx,
+                        );
+                        return true;
+                    }
+
                     // If it's an emission closure, handle specially:
                     // - Collect light from the emission.
                     // - Terminate the path.
-                    use crate::shading::surface_closure::SurfaceClosure;
                     if let SurfaceClosure::Emit(color) = *closure {
                         let color = color.to_spectral_sample(self.wavelength).e;
                         if let LightPathEvent::CameraRay = self.event {
-                            self.color += color;
+                            self.color += color * self.light_attenuation;
                         } else {
                             let mis_pdf =
                                 power_heuristic(self.closure_sample_pdf, idata.sample_pdf);
@@ -465,182 +574,13 @@ impl LightPath {
                         return false;
                     }
 
-                    // Roll the previous closure pdf into the attenauation
-                    self.light_attenuation /= self.closure_sample_pdf;
-
-                    // Prepare light ray
-                    let light_n = self.next_lds_samp();
-                    let light_uvw = (
-                        self.next_lds_samp(),
-                        self.next_lds_samp(),
-                        self.next_lds_samp(),
-                    );
-                    xform_stack.clear();
-                    let light_info = scene.sample_lights(
-                        xform_stack,
-                        light_n,
-                        light_uvw,
-                        self.wavelength,
-                        self.time,
-                        isect,
-                    );
-                    let found_light = if light_info.is_none()
-                        || light_info.pdf() <= 0.0
-                        || light_info.selection_pdf() <= 0.0
-                    {
-                        false
-                    } else {
-                        let light_pdf = light_info.pdf();
-                        let light_sel_pdf = light_info.selection_pdf();
-
-                        // Calculate the shadow ray and surface closure stuff
-                        let (attenuation, closure_pdf, shadow_ray) = match light_info {
-                            SceneLightSample::None => unreachable!(),
-
-                            // Distant light
-                            SceneLightSample::Distant { direction, .. } => {
-                                let (attenuation, closure_pdf) = closure.evaluate(
-                                    rays.dir(ray_idx),
-                                    direction,
-                                    idata.nor,
-                                    idata.nor_g,
-                                    self.wavelength,
-                                );
-                                let shadow_ray = {
-                                    // Calculate the shadow ray for testing if the light is
-                                    // in shadow or not.
-                                    let offset_pos = robust_ray_origin(
-                                        idata.pos,
-                                        idata.pos_err,
-                                        idata.nor_g.normalized(),
-                                        direction,
-                                    );
-                                    Ray {
-                                        orig: offset_pos,
-                                        dir: direction,
-                                        time: self.time,
-                                        wavelength: self.wavelength,
-                                        max_t: std::f32::INFINITY,
-                                    }
-                                };
-                                (attenuation, closure_pdf, shadow_ray)
-                            }
-
-                            // Surface light
-                            SceneLightSample::Surface { sample_geo, .. } => {
-                                let dir = sample_geo.0 - idata.pos;
-                                let (attenuation, closure_pdf) = closure.evaluate(
-                                    rays.dir(ray_idx),
-                                    dir,
-                                    idata.nor,
-                                    idata.nor_g,
-                                    self.wavelength,
-                                );
-                                let shadow_ray = {
-                                    // Calculate the shadow ray for testing if the light is
-                                    // in shadow or not.
-                                    let offset_pos = robust_ray_origin(
-                                        idata.pos,
-                                        idata.pos_err,
-                                        idata.nor_g.normalized(),
-                                        dir,
-                                    );
-                                    let offset_end = robust_ray_origin(
-                                        sample_geo.0,
-                                        sample_geo.2,
-                                        sample_geo.1.normalized(),
-                                        -dir,
-                                    );
-                                    Ray {
-                                        orig: offset_pos,
-                                        dir: offset_end - offset_pos,
-                                        time: self.time,
-                                        wavelength: self.wavelength,
-                                        max_t: 1.0,
-                                    }
-                                };
-                                (attenuation, closure_pdf, shadow_ray)
-                            }
-                        };
-
-                        // If there's any possible contribution, set up for a
-                        // light ray.
-                        if attenuation.e.max_element() <= 0.0 {
-                            false
-                        } else {
-                            // Calculate and store the light that will be contributed
-                            // to the film plane if the light is not in shadow.
-                            let light_mis_pdf = power_heuristic(light_pdf, closure_pdf);
-                            self.pending_color_addition =
-                                light_info.color().e * attenuation.e * self.light_attenuation
-                                    / (light_mis_pdf * light_sel_pdf);
-
-                            rays.set_from_ray(&shadow_ray, true, ray_idx);
-
-                            true
-                        }
-                    };
-
-                    // Prepare bounce ray
-                    let do_bounce = if self.bounce_count < 2 {
-                        self.bounce_count += 1;
-
-                        // Sample closure
-                        let (dir, filter, pdf) = {
-                            let u = self.next_lds_samp();
-                            let v = self.next_lds_samp();
-                            closure.sample(
-                                idata.incoming,
-                                idata.nor,
-                                idata.nor_g,
-                                (u, v),
-                                self.wavelength,
-                            )
-                        };
-
-                        // Check if pdf is zero, to avoid NaN's.
-                        if (pdf > 0.0) && (filter.e.max_element() > 0.0) {
-                            // Account for the additional light attenuation from
-                            // this bounce
-                            self.next_attenuation_fac = filter.e;
-                            self.closure_sample_pdf = pdf;
-
-                            // Calculate the ray for this bounce
-                            let offset_pos = robust_ray_origin(
-                                idata.pos,
-                                idata.pos_err,
-                                idata.nor_g.normalized(),
-                                dir,
-                            );
-                            self.next_bounce_ray = Some(Ray {
-                                orig: offset_pos,
-                                dir: dir,
-                                time: self.time,
-                                wavelength: self.wavelength,
-                                max_t: std::f32::INFINITY,
-                            });
-
-                            true
-                        } else {
-                            false
-                        }
-                    } else {
-                        self.next_bounce_ray = None;
-                        false
-                    };
-
-                    // Book keeping for next event
-                    if found_light {
-                        self.event = LightPathEvent::ShadowRay;
-                        return true;
-                    } else if do_bounce {
-                        rays.set_from_ray(&self.next_bounce_ray.unwrap(), false, ray_idx);
-                        self.event = LightPathEvent::BounceRay;
-                        self.light_attenuation *= self.next_attenuation_fac;
-                        return true;
-                    } else {
-                        return false;
+                    // If it's a subsurface closure, start a random walk
+                    // into the surface.
+                    if let SurfaceClosure::Subsurface { color, mfp } = *closure {
+                        return self.enter_subsurface(idata, color, mfp, rays, ray_idx);
                     }
+
+                    self.scatter(xform_stack, scene, isect, rays, ray_idx)
                 } else {
                     // Didn't hit anything, so background color
                     self.color += scene
@@ -654,13 +594,150 @@ impl LightPath {
                 }
             }
 
+            //--------------------------------------------------------------------
+            // Result of a step of a subsurface random walk
+            LightPathEvent::SubsurfaceRay => {
+                let mut walk = self.subsurface.unwrap();
+
+                // The walk only traces against the object it's in, so a
+                // miss means the object isn't closed.  Nothing sensible
+                // can be done about that, so just terminate the path.
+                let idata = if let surface::SurfaceIntersection::Hit {
+                    intersection_data: ref idata,
+                    ..
+                } = *isect
+                {
+                    idata
+                } else {
+                    return false;
+                };
+
+                // Track through the inside of the object up to the
+                // surface, to see if the path scatters before reaching it.
+                let dir = rays.dir(ray_idx);
+                let dir_len = dir.length();
+                let mut weight = Vec4::splat(1.0);
+                let event = delta_track(
+                    (0.0, idata.t * dir_len),
+                    (walk.sigma_a + walk.sigma_s).max_element(),
+                    |_| (walk.sigma_a, walk.sigma_s, Vec4::splat(0.0)),
+                    &mut weight,
+                    &mut || self.next_random(),
+                );
+                self.light_attenuation *= weight;
+
+                match event {
+                    Some(MediumEvent::Scatter(d)) => {
+                        walk.step_count += 1;
+                        if walk.step_count > SUBSURFACE_MAX_STEPS {
+                            return false;
+                        }
+                        self.subsurface = Some(walk);
+
+                        // Scatter isotropically and continue the walk.
+                        let new_dir = uniform_sample_sphere(self.next_random(), self.next_random());
+                        rays.set_from_ray(
+                            &Ray {
+                                orig: rays.orig(ray_idx) + (dir * (d / dir_len)),
+                                dir: new_dir,
+                                time: self.time,
+                                wavelength: self.wavelength,
+                                max_t: std::f32::INFINITY,
+                            },
+                            false,
+                            ray_idx,
+                        );
+                        rays.set_object_filter(ray_idx, Some(walk.object_id));
+                        true
+                    }
+
+                    Some(_) => false,
+
+                    None => {
+                        // Made it back out of the surface, so leave it
+                        // diffusely, as if from a white Lambert surface.
+                        // The incoming direction is flipped, so the
+                        // outgoing light goes to the outside.
+                        use crate::shading::surface_closure::SurfaceClosure;
+                        self.subsurface = None;
+                        let exit_isect = surface::SurfaceIntersection::Hit {
+                            intersection_data: surface::SurfaceIntersectionData {
+                                incoming: -dir,
+                                ..*idata
+                            },
+                            closure: SurfaceClosure::Lambert(Color::new_xyz((1.0, 1.0, 1.0))),
+                        };
+                        self.scatter(xform_stack, scene, &exit_isect, rays, ray_idx)
+                    }
+                }
+            }
+
             //--------------------------------------------------------------------
             // Result of shadow ray from sampling a light
             LightPathEvent::ShadowRay => {
-                // If the light was not in shadow, add it's light to the film
-                // plane.
-                if let surface::SurfaceIntersection::Miss = *isect {
-                    self.color += self.pending_color_addition;
+                use crate::shading::surface_closure::SurfaceClosure;
+
+                match *isect {
+                    // If the light was not in shadow, add it's light to the
+                    // film plane.
+                    surface::SurfaceIntersection::Miss => {
+                        if let Some(medium_index) = self.shadow_medium {
+                            self.pending_color_addition *= scene.media[medium_index].transmittance(
+                                rays.orig(ray_idx),
+                                rays.dir(ray_idx),
+                                self.shadow_max_t,
+                                self.time,
+                                self.wavelength,
+                                &mut || self.next_random(),
+                            );
+                        }
+                        self.color += self.pending_color_addition;
+                    }
+
+                    // If it hit a medium boundary, account for the
+                    // transmittance up to it and continue on through it.
+                    surface::SurfaceIntersection::Hit {
+                        intersection_data: ref idata,
+                        closure: SurfaceClosure::MediumBoundary(medium_index),
+                    } => {
+                        if let Some(medium_index) = self.shadow_medium {
+                            self.pending_color_addition *= scene.media[medium_index].transmittance(
+                                rays.orig(ray_idx),
+                                rays.dir(ray_idx),
+                                idata.t,
+                                self.time,
+                                self.wavelength,
+                                &mut || self.next_random(),
+                            );
+                        }
+                        self.shadow_medium =
+                            Self::medium_across_boundary(scene, self.shadow_medium, medium_index);
+
+                        if self.pending_color_addition.max_element() > 0.0 {
+                            let offset_pos = robust_ray_origin(
+                                idata.pos,
+                                idata.pos_err,
+                                idata.nor_g.normalized(),
+                                idata.incoming,
+                            );
+                            self.shadow_max_t -= idata.t;
+                            rays.set_from_ray(
+                                &Ray {
+                                    orig: offset_pos,
+                                    dir: idata.incoming,
+                                    time: self.time,
+                                    wavelength: self.wavelength,
+                                    max_t: self.shadow_max_t,
+                                },
+                                false,
+                                ray_idx,
+                            );
+                            return true;
+                        }
+                    }
+
+                    // Otherwise the light is in shadow.
+                    _ => {}
                 }
 
                 // Set up for the next bounce, if any
@@ -675,6 +752,307 @@ impl LightPath {
             }
         }
     }
+
+    /// Scatters the path off of the given intersection, either with a
+    /// surface or within a medium, setting up the light and bounce rays.
+    fn scatter(
+        &mut self,
+        xform_stack: &mut TransformStack,
+        scene: &Scene,
+        isect: &surface::SurfaceIntersection,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        let (idata, closure) = if let surface::SurfaceIntersection::Hit {
+            intersection_data: ref idata,
+            ref closure,
+        } = *isect
+        {
+            (idata, closure)
+        } else {
+            unreachable!()
+        };
+
+        // Roll the previous closure pdf into the attenauation
+        self.light_attenuation /= self.closure_sample_pdf;
+
+        // Prepare light ray
+        let light_n = self.next_lds_samp();
+        let light_uvw = (
+            self.next_lds_samp(),
+            self.next_lds_samp(),
+            self.next_lds_samp(),
+        );
+        xform_stack.clear();
+        let light_info = scene.sample_lights(
+            xform_stack,
+            light_n,
+            light_uvw,
+            self.wavelength,
+            self.time,
+            isect,
+        );
+        let found_light =
+            if light_info.is_none() || light_info.pdf() <= 0.0 || light_info.selection_pdf() <= 0.0
+            {
+                false
+            } else {
+                let light_pdf = light_info.pdf();
+                let light_sel_pdf = light_info.selection_pdf();
+
+                // Calculate the shadow ray and surface closure stuff
+                let (attenuation, closure_pdf, shadow_ray) = match light_info {
+                    SceneLightSample::None => unreachable!(),
+
+                    // Distant light
+                    SceneLightSample::Distant { direction, .. } => {
+                        let (attenuation, closure_pdf) = closure.evaluate(
+                            idata.incoming,
+                            direction,
+                            idata.nor,
+                            idata.nor_g,
+                            self.wavelength,
+                        );
+                        let shadow_ray = {
+                            // Calculate the shadow ray for testing if the light is
+                            // in shadow or not.
+                            let offset_pos = robust_ray_origin(
+                                idata.pos,
+                                idata.pos_err,
+                                idata.nor_g.normalized(),
+                                direction,
+                            );
+                            Ray {
+                                orig: offset_pos,
+                                dir: direction,
+                                time: self.time,
+                                wavelength: self.wavelength,
+                                max_t: std::f32::INFINITY,
+                            }
+                        };
+                        (attenuation, closure_pdf, shadow_ray)
+                    }
+
+                    // Surface light
+                    SceneLightSample::Surface { sample_geo, .. } => {
+                        let dir = sample_geo.0 - idata.pos;
+                        let (attenuation, closure_pdf) = closure.evaluate(
+                            idata.incoming,
+                            dir,
+                            idata.nor,
+                            idata.nor_g,
+                            self.wavelength,
+                        );
+                        let shadow_ray = {
+                            // Calculate the shadow ray for testing if the light is
+                            // in shadow or not.
+                            let offset_pos = robust_ray_origin(
+                                idata.pos,
+                                idata.pos_err,
+                                idata.nor_g.normalized(),
+                                dir,
+                            );
+                            let offset_end = robust_ray_origin(
+                                sample_geo.0,
+                                sample_geo.2,
+                                sample_geo.1.normalized(),
+                                -dir,
+                            );
+                            Ray {
+                                orig: offset_pos,
+                                dir: offset_end - offset_pos,
+                                time: self.time,
+                                wavelength: self.wavelength,
+                                max_t: 1.0,
+                            }
+                        };
+                        (attenuation, closure_pdf, shadow_ray)
+                    }
+                };
+
+                // If there's any possible contribution, set up for a
+                // light ray.
+                if attenuation.e.max_element() <= 0.0 {
+                    false
+                } else {
+                    // Calculate and store the light that will be contributed
+                    // to the film plane if the light is not in shadow.
+                    let light_mis_pdf = power_heuristic(light_pdf, closure_pdf);
+                    self.pending_color_addition =
+                        light_info.color().e * attenuation.e * self.light_attenuation
+                            / (light_mis_pdf * light_sel_pdf);
+
+                    // If there are any media in the scene, the shadow ray
+                    // needs to be able to pass through medium boundaries, so
+                    // it can't be an occlusion ray.
+                    self.shadow_medium = self.medium;
+                    self.shadow_max_t = shadow_ray.max_t;
+                    rays.set_from_ray(&shadow_ray, scene.media.is_empty(), ray_idx);
+
+                    true
+                }
+            };
+
+        // Prepare bounce ray
+        let do_bounce = if self.bounce_count < 2 {
+            self.bounce_count += 1;
+
+            // Sample closure
+            let (dir, filter, pdf) = {
+                let u = self.next_lds_samp();
+                let v = self.next_lds_samp();
+                closure.sample(
+                    idata.incoming,
+                    idata.nor,
+                    idata.nor_g,
+                    (u, v),
+                    self.wavelength,
+                )
+            };
+
+            // Check if pdf is zero, to avoid NaN's.
+            if (pdf > 0.0) && (filter.e.max_element() > 0.0) {
+                // Account for the additional light attenuation from
+                // this bounce
+                self.next_attenuation_fac = filter.e;
+                self.closure_sample_pdf = pdf;
+
+                // Calculate the ray for this bounce
+                let offset_pos =
+                    robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+                self.next_bounce_ray = Some(Ray {
+                    orig: offset_pos,
+                    dir: dir,
+                    time: self.time,
+                    wavelength: self.wavelength,
+                    max_t: std::f32::INFINITY,
+                });
+
+                true
+            } else {
+                false
+            }
+        } else {
+            self.next_bounce_ray = None;
+            false
+        };
+
+        // Book keeping for next event
+        if found_light {
+            self.event = LightPathEvent::ShadowRay;
+            return true;
+        } else if do_bounce {
+            rays.set_from_ray(&self.next_bounce_ray.unwrap(), false, ray_idx);
+            self.event = LightPathEvent::BounceRay;
+            self.light_attenuation *= self.next_attenuation_fac;
+            return true;
+        } else {
+            return false;
+        }
+    }
+
+    /// Starts a subsurface random walk from the given intersection, with
+    /// a diffuse transmission into the surface.
+    fn enter_subsurface(
+        &mut self,
+        idata: &surface::SurfaceIntersectionData,
+        color: Color,
+        mfp: Color,
+        rays: &mut RayBatch,
+        ray_idx: usize,
+    ) -> bool {
+        // Roll the previous closure pdf into the attenuation, since the
+        // walk itself doesn't have one.
+        self.light_attenuation /= self.closure_sample_pdf;
+        self.closure_sample_pdf = 1.0;
+
+        let (sigma_a, sigma_s) = subsurface_coefficients(color, mfp, self.wavelength);
+        self.subsurface = Some(SubsurfaceWalk {
+            object_id: idata.object_id,
+            sigma_a: sigma_a,
+            sigma_s: sigma_s,
+            step_count: 0,
+        });
+
+        // Cosine-weighted direction into the surface, which exactly
+        // cancels out with the diffuse transmission.
+        let nor_in = if dot(idata.nor_g.into_vector(), idata.incoming) >= 0.0 {
+            idata.nor_g.normalized().into_vector()
+        } else {
+            -idata.nor_g.normalized().into_vector()
+        };
+        let dir = zup_to_vec(
+            cosine_sample_hemisphere(self.next_lds_samp(), self.next_lds_samp()),
+            nor_in,
+        );
+
+        let offset_pos = robust_ray_origin(idata.pos, idata.pos_err, idata.nor_g.normalized(), dir);
+        rays.set_from_ray(
+            &Ray {
+                orig: offset_pos,
+                dir: dir,
+                time: self.time,
+                wavelength: self.wavelength,
+                max_t: std::f32::INFINITY,
+            },
+            false,
+            ray_idx,
+        );
+        rays.set_object_filter(ray_idx, Some(idata.object_id));
+        self.event = LightPathEvent::SubsurfaceRay;
+
+        true
+    }
+
+    /// Returns the medium a path that's currently in `current` is in after
+    /// crossing the boundary of the medium with the given index.
+    ///
+    /// Since surface orientation isn't reliable, crossing a boundary simply
+    /// toggles between its medium and the world's.  So media can't be
+    /// nested, and the camera must start outside of all of them.
+    fn medium_across_boundary(
+        scene: &Scene,
+        current: Option<usize>,
+        medium_index: usize,
+    ) -> Option<usize> {
+        if current == Some(medium_index) {
+            scene.world.medium
+        } else {
+            Some(medium_index)
+        }
+    }
+
+    /// Builds the intersection for a scattering event at `t` within the
+    /// medium with the given index.
+    fn medium_intersection(
+        &self,
+        scene: &Scene,
+        medium_index: usize,
+        rays: &RayBatch,
+        ray_idx: usize,
+        t: f32,
+    ) -> surface::SurfaceIntersection {
+        use crate::shading::surface_closure::SurfaceClosure;
+
+        let dir = rays.dir(ray_idx);
+        let nor = (-dir).normalized().into_normal();
+        surface::SurfaceIntersection::Hit {
+            intersection_data: surface::SurfaceIntersectionData {
+                incoming: dir,
+                t: t,
+                pos: rays.orig(ray_idx) + (dir * t),
+                pos_err: 0.0,
+                nor: nor,
+                nor_g: nor,
+                tangent: Vector::new(0.0, 0.0, 0.0),
+                color: None,
+                object_id: 0,
+                local_space: Matrix4x4::new(),
+                sample_pdf: 0.0,
+            },
+            closure: SurfaceClosure::HenyeyGreenstein(scene.media[medium_index].anisotropy()),
+        }
+    }
 }
 
 /// Gets a sample, using LDS samples for lower dimensions,
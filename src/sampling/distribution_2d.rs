@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+
+use std::mem::{transmute, MaybeUninit};
+
+use kioku::Arena;
+
+/// A piecewise-constant 2D distribution over the unit square, e.g. for
+/// importance sampling an image.
+///
+/// Cell (0, 0) is at the minimum corner of the square, and cells are stored
+/// in row-major order.
+#[derive(Copy, Clone, Debug)]
+pub struct Distribution2D<'a> {
+    width: usize,
+    height: usize,
+    marginal_cdf: &'a [f32],     // Per-row cdf, `height + 1` entries
+    conditional_cdfs: &'a [f32], // Per-cell cdfs within each row, `width + 1` entries per row
+}
+
+impl<'a> Distribution2D<'a> {
+    /// Creates a new distribution from a grid of non-negative weights.
+    ///
+    /// If all of the weights are zero, the distribution is uniform.
+    pub fn new<'b>(
+        arena: &'b Arena,
+        width: usize,
+        height: usize,
+        weights: &[f32],
+    ) -> Distribution2D<'b> {
+        assert!(width > 0 && height > 0);
+        assert_eq!(weights.len(), width * height);
+
+        // Build the conditional cdfs and the row sums.
+        let mut row_sums = Vec::with_capacity(height);
+        let conditional_cdfs = arena.alloc_array_uninit::<f32>((width + 1) * height);
+        for y in 0..height {
+            let row = &weights[(y * width)..((y + 1) * width)];
+            let cdf = &mut conditional_cdfs[(y * (width + 1))..((y + 1) * (width + 1))];
+            let sum = build_cdf(row, cdf);
+            row_sums.push(sum);
+        }
+
+        // Build the marginal cdf.
+        let marginal_cdf = arena.alloc_array_uninit::<f32>(height + 1);
+        build_cdf(&row_sums, marginal_cdf);
+
+        unsafe {
+            Distribution2D {
+                width: width,
+                height: height,
+                marginal_cdf: transmute(marginal_cdf),
+                conditional_cdfs: transmute(conditional_cdfs),
+            }
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Maps uniformly distributed `u` and `v` in [0, 1) to a point in the
+    /// unit square, distributed according to the weights.
+    pub fn sample(&self, u: f32, v: f32) -> (f32, f32) {
+        let (y, v_rem) = sample_cdf(self.marginal_cdf, v);
+        let cdf = &self.conditional_cdfs[(y * (self.width + 1))..((y + 1) * (self.width + 1))];
+        let (x, u_rem) = sample_cdf(cdf, u);
+
+        (
+            (x as f32 + u_rem) / self.width as f32,
+            (y as f32 + v_rem) / self.height as f32,
+        )
+    }
+
+    /// Returns the pdf (with respect to area on the unit square) of
+    /// sampling the point (x, y).
+    pub fn pdf(&self, x: f32, y: f32) -> f32 {
+        if x < 0.0 || x >= 1.0 || y < 0.0 || y >= 1.0 {
+            return 0.0;
+        }
+
+        let xi = ((x * self.width as f32) as usize).min(self.width - 1);
+        let yi = ((y * self.height as f32) as usize).min(self.height - 1);
+        let cdf = &self.conditional_cdfs[(yi * (self.width + 1))..((yi + 1) * (self.width + 1))];
+
+        let row_p = self.marginal_cdf[yi + 1] - self.marginal_cdf[yi];
+        let cell_p = cdf[xi + 1] - cdf[xi];
+
+        row_p * cell_p * (self.width * self.height) as f32
+    }
+}
+
+/// Fills in `cdf` (which must be one longer than `weights`) with the
+/// normalized cdf of `weights`, and returns the sum of the weights.
+///
+/// If the weights sum to zero, the cdf is made uniform.
+fn build_cdf(weights: &[f32], cdf: &mut [MaybeUninit<f32>]) -> f32 {
+    debug_assert_eq!(cdf.len(), weights.len() + 1);
+
+    let sum: f32 = weights.iter().map(|w| w.max(0.0)).sum();
+    let n = weights.len() as f32;
+
+    unsafe {
+        *cdf[0].as_mut_ptr() = 0.0;
+    }
+    let mut acc = 0.0;
+    for (i, w) in weights.iter().enumerate() {
+        acc += if sum > 0.0 { w.max(0.0) / sum } else { 1.0 / n };
+        unsafe {
+            *cdf[i + 1].as_mut_ptr() = acc;
+        }
+    }
+    unsafe {
+        *cdf[weights.len()].as_mut_ptr() = 1.0;
+    }
+
+    sum
+}
+
+/// Finds the interval of `cdf` that `n` lands in, and returns its index
+/// along with `n` remapped to [0, 1) within that interval.  Zero-width
+/// intervals are never selected.
+fn sample_cdf(cdf: &[f32], n: f32) -> (usize, f32) {
+    let count = cdf.len() - 1;
+
+    // Binary search for the last entry <= n.
+    let mut lo = 0;
+    let mut hi = count;
+    while hi - lo > 1 {
+        let mid = (lo + hi) / 2;
+        if cdf[mid] <= n {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    // Skip past any zero-width intervals.
+    while lo < (count - 1) && cdf[lo + 1] <= cdf[lo] {
+        lo += 1;
+    }
+
+    let width = cdf[lo + 1] - cdf[lo];
+    let rem = if width > 0.0 {
+        ((n - cdf[lo]) / width).max(0.0).min(0.999_999_94)
+    } else {
+        0.5
+    };
+
+    (lo, rem)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uniform_sample() {
+        let arena = Arena::new();
+        let dist = Distribution2D::new(&arena, 2, 2, &[1.0, 1.0, 1.0, 1.0]);
+
+        let (x, y) = dist.sample(0.25, 0.75);
+        assert!((x - 0.25).abs() < 0.0001);
+        assert!((y - 0.75).abs() < 0.0001);
+        assert!((dist.pdf(x, y) - 1.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn all_zero_is_uniform() {
+        let arena = Arena::new();
+        let dist = Distribution2D::new(&arena, 2, 2, &[0.0, 0.0, 0.0, 0.0]);
+
+        let (x, y) = dist.sample(0.6, 0.1);
+        assert!((x - 0.6).abs() < 0.0001);
+        assert!((y - 0.1).abs() < 0.0001);
+    }
+
+    #[test]
+    fn skips_zero_cells() {
+        let arena = Arena::new();
+        let dist = Distribution2D::new(&arena, 3, 1, &[0.0, 1.0, 0.0]);
+
+        for &u in &[0.0, 0.3, 0.5, 0.9, 0.999] {
+            let (x, _) = dist.sample(u, 0.5);
+            assert!(x >= 1.0 / 3.0 && x < 2.0 / 3.0);
+        }
+        assert_eq!(dist.pdf(0.1, 0.5), 0.0);
+        assert!((dist.pdf(0.5, 0.5) - 3.0).abs() < 0.0001);
+    }
+
+    #[test]
+    fn weighted_rows() {
+        let arena = Arena::new();
+        let dist = Distribution2D::new(&arena, 1, 2, &[1.0, 3.0]);
+
+        let (_, y1) = dist.sample(0.5, 0.2);
+        let (_, y2) = dist.sample(0.5, 0.3);
+        assert!(y1 < 0.5);
+        assert!(y2 >= 0.5);
+        assert!((dist.pdf(0.5, 0.25) - 0.5).abs() < 0.0001);
+        assert!((dist.pdf(0.5, 0.75) - 1.5).abs() < 0.0001);
+    }
+}
@@ -1,7 +1,9 @@
+mod distribution_2d;
 mod monte_carlo;
 
+pub use self::distribution_2d::Distribution2D;
 pub use self::monte_carlo::{
-    cosine_sample_hemisphere, spherical_triangle_solid_angle, square_to_circle,
+    cosine_sample_hemisphere, spherical_triangle_solid_angle, square_to_circle, square_to_polygon,
     triangle_surface_area, uniform_sample_cone, uniform_sample_cone_pdf, uniform_sample_hemisphere,
     uniform_sample_sphere, uniform_sample_spherical_triangle, uniform_sample_triangle,
 };
@@ -31,6 +31,34 @@ pub fn square_to_circle(x: f32, y: f32) -> (f32, f32) {
     (radius * angle.cos(), radius * angle.sin())
 }
 
+/// Maps the unit square to a regular polygon inscribed in the unit circle.
+///
+/// - `u`, `v`: Uniformly distributed in [0, 1).
+/// - `side_count`: Number of sides of the polygon.  Must be at least 3.
+/// - `rotation`: Angle of the first vertex from the x axis, in radians.
+///
+/// The polygon is split into `side_count` triangles around the center,
+/// with `u` selecting the triangle.
+pub fn square_to_polygon(u: f32, v: f32, side_count: u32, rotation: f32) -> (f32, f32) {
+    debug_assert!(side_count >= 3);
+
+    let n = side_count as f32;
+    let side = (u * n).floor().min(n - 1.0);
+    let u = (u * n) - side;
+
+    // Corners of the selected triangle, other than the center.
+    let angle_1 = rotation + (side * 2.0 * PI_32 / n);
+    let angle_2 = angle_1 + (2.0 * PI_32 / n);
+    let (x1, y1) = (angle_1.cos(), angle_1.sin());
+    let (x2, y2) = (angle_2.cos(), angle_2.sin());
+
+    // Uniformly sample the triangle.
+    let su = u.sqrt();
+    let b1 = su * (1.0 - v);
+    let b2 = su * v;
+    ((x1 * b1) + (x2 * b2), (y1 * b1) + (y2 * b2))
+}
+
 pub fn cosine_sample_hemisphere(u: f32, v: f32) -> Vector {
     let (u, v) = square_to_circle((u * 2.0) - 1.0, (v * 2.0) - 1.0);
     let z = (1.0 - ((u * u) + (v * v))).max(0.0).sqrt();
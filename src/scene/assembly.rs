@@ -3,14 +3,15 @@ use std::collections::HashMap;
 use kioku::Arena;
 
 use crate::{
-    accel::BVH4,
-    accel::{LightAccel, LightTree},
+    accel::WideBVH,
+    accel::{LightAccel, LightTree, SplitMode},
     bbox::{transform_bbox_slice_from, BBox},
     boundable::Boundable,
     color::SpectralSample,
     lerp::lerp_slice,
     light::SurfaceLight,
     math::{Matrix4x4, Normal, Point},
+    normal_cone::NormalCone,
     shading::SurfaceShader,
     surface::{Surface, SurfaceIntersection},
     transform_stack::TransformStack,
@@ -33,10 +34,14 @@ pub struct Assembly<'a> {
     pub assemblies: &'a [Assembly<'a>],
 
     // Object accel
-    pub object_accel: BVH4<'a>,
+    pub object_accel: WideBVH<'a>,
 
     // Light accel
     pub light_accel: LightTree<'a>,
+
+    // Number of object placements within the assembly, counting each
+    // placement of an object through nested instances separately
+    pub placement_count: usize,
 }
 
 // TODO: actually fix this clippy warning, rather than `allow`ing it.
@@ -140,6 +145,72 @@ impl<'a> Assembly<'a> {
     }
 }
 
+impl<'a> Assembly<'a> {
+    /// Creates a copy of the assembly with its instances' transforms
+    /// replaced by `xforms`, refitting its object and light accels to the
+    /// new transforms rather than building them again.
+    ///
+    /// `xforms` must be laid out the same as the assembly's current
+    /// transforms, with the same number of time samples for each instance.
+    /// The objects and sub-assemblies, along with their accels, are shared
+    /// with this assembly rather than copied, so only the new transforms and
+    /// accels are allocated in `arena`.  This is meant for rendering
+    /// animation, where most assets only change their transforms between
+    /// frames.
+    ///
+    /// Refitting keeps the trees of the accels, so they get worse the
+    /// further the instances move from where they were when the assembly was
+    /// built.
+    ///
+    /// Only the transforms of this assembly's own instances can be replaced.
+    /// Nested updates, of the instances within sub-assemblies, aren't
+    /// supported: the sub-assemblies are shared as they are, and there's no
+    /// way to give them new transforms short of building this assembly again.
+    #[allow(dead_code)]
+    pub fn with_transforms<'b>(&self, arena: &'b Arena, xforms: &[Matrix4x4]) -> Assembly<'b>
+    where
+        'a: 'b,
+    {
+        assert_eq!(
+            xforms.len(),
+            self.xforms.len(),
+            "New transforms don't match the layout of the assembly's transforms."
+        );
+
+        // Calculate the new instance bounds, indexed by instance id.  The
+        // object accel can refer to an instance more than once, so each
+        // instance's bounds are only calculated the first time it's seen.
+        let instances = self.instances.iter().chain(self.light_instances.iter());
+        let id_count = instances.clone().map(|inst| inst.id + 1).max().unwrap_or(0);
+        let mut bbs: Vec<Option<Vec<BBox>>> = vec![None; id_count];
+        for inst in instances {
+            if bbs[inst.id].is_none() {
+                bbs[inst.id] = Some(instance_bounds(inst, self.objects, self.assemblies, xforms));
+            }
+        }
+        let bounder = |inst: &Instance| &bbs[inst.id].as_ref().unwrap()[..];
+
+        // Refit accels
+        let object_accel = self.object_accel.refit(arena, self.instances, bounder);
+        let light_accel = self.light_accel.refit(arena, self.light_instances, |inst| {
+            let (energy, cone) = light_instance_info(inst, self.objects, self.assemblies, xforms);
+            (bounder(inst), energy, cone)
+        });
+
+        Assembly {
+            instances: self.instances,
+            light_instances: self.light_instances,
+            xforms: arena.copy_slice(xforms),
+            surface_shaders: self.surface_shaders,
+            objects: self.objects,
+            assemblies: self.assemblies,
+            object_accel: object_accel,
+            light_accel: light_accel,
+            placement_count: self.placement_count,
+        }
+    }
+}
+
 impl<'a> Boundable for Assembly<'a> {
     fn bounds(&self) -> &[BBox] {
         self.object_accel.bounds()
@@ -165,6 +236,12 @@ pub struct AssemblyBuilder<'a> {
     // Assembly list
     assemblies: Vec<Assembly<'a>>,
     assembly_map: HashMap<String, usize>, // map Name -> Index
+
+    // How to split the nodes of the object accel
+    split_mode: SplitMode,
+
+    // Number of object placements added so far
+    placement_count: usize,
 }
 
 impl<'a> AssemblyBuilder<'a> {
@@ -179,9 +256,17 @@ impl<'a> AssemblyBuilder<'a> {
             object_map: HashMap::new(),
             assemblies: Vec::new(),
             assembly_map: HashMap::new(),
+            split_mode: SplitMode::Object,
+            placement_count: 0,
         }
     }
 
+    /// Sets how the nodes of the assembly's object accel are split when
+    /// it's built.  Defaults to `SplitMode::Object`.
+    pub fn set_split_mode(&mut self, split_mode: SplitMode) {
+        self.split_mode = split_mode;
+    }
+
     pub fn add_surface_shader(&mut self, name: &str, shader: &'a dyn SurfaceShader) {
         // Make sure the name hasn't already been used.
         if self.surface_shader_map.contains_key(name) {
@@ -224,6 +309,7 @@ impl<'a> AssemblyBuilder<'a> {
         &mut self,
         name: &str,
         surface_shader_name: Option<&str>,
+        medium_index: Option<usize>,
         xforms: Option<&[Matrix4x4]>,
     ) {
         // Make sure name exists
@@ -253,7 +339,9 @@ impl<'a> AssemblyBuilder<'a> {
                         .get(name)
                         .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
                 }),
+                medium_index: medium_index,
                 id: self.instances.len(),
+                placement_offset: self.placement_count,
                 transform_indices: xforms
                     .map(|xf| (self.xforms.len(), self.xforms.len() + xf.len())),
             }
@@ -267,12 +355,24 @@ impl<'a> AssemblyBuilder<'a> {
                         .get(name)
                         .unwrap_or_else(|| panic!("Unknown surface shader '{}'.", name))
                 }),
+                medium_index: medium_index,
                 id: self.instances.len(),
+                placement_offset: self.placement_count,
                 transform_indices: xforms
                     .map(|xf| (self.xforms.len(), self.xforms.len() + xf.len())),
             }
         };
 
+        // Count the object placements made by the instance
+        let placement_count = match instance.instance_type {
+            InstanceType::Object => 1,
+            InstanceType::Assembly => self.assemblies[instance.data_index].placement_count,
+        };
+        self.placement_count = self
+            .placement_count
+            .checked_add(placement_count)
+            .expect("Too many object placements in assembly.");
+
         self.instances.push(instance);
 
         // Store transforms
@@ -281,18 +381,31 @@ impl<'a> AssemblyBuilder<'a> {
         }
     }
 
+    pub fn surface_shader(&self, name: &str) -> Option<&'a dyn SurfaceShader> {
+        self.surface_shader_map
+            .get(name)
+            .map(|&i| self.surface_shaders[i])
+    }
+
     pub fn name_exists(&self, name: &str) -> bool {
         self.object_map.contains_key(name) || self.assembly_map.contains_key(name)
     }
 
-    pub fn build(mut self) -> Assembly<'a> {
+    pub fn build(self) -> Assembly<'a> {
         // Calculate instance bounds, used for building object accel and light accel.
         let (bis, bbs) = self.instance_bounds();
 
-        // Build object accel
-        let object_accel = BVH4::from_objects(self.arena, &mut self.instances[..], 1, |inst| {
-            &bbs[bis[inst.id]..bis[inst.id + 1]]
-        });
+        // Build object accel.  With spatial splits, instances can end up in
+        // more than one leaf, so the instances the accel refers to are what
+        // get stored in the assembly.
+        let (object_accel, accel_instances) = WideBVH::from_objects_with_splits(
+            self.arena,
+            &self.instances,
+            1,
+            self.split_mode,
+            |inst| &bbs[bis[inst.id]..bis[inst.id + 1]],
+            |_, bounds, axis, pos| bounds.split(axis, pos),
+        );
 
         // Get list of instances that are for light sources or assemblies that contain light
         // sources.
@@ -321,24 +434,13 @@ impl<'a> AssemblyBuilder<'a> {
         // Build light accel
         let light_accel = LightTree::from_objects(self.arena, &mut light_instances[..], |inst| {
             let bounds = &bbs[bis[inst.id]..bis[inst.id + 1]];
-            let energy = match inst.instance_type {
-                InstanceType::Object => {
-                    if let Object::SurfaceLight(light) = self.objects[inst.data_index] {
-                        light.approximate_energy()
-                    } else {
-                        0.0
-                    }
-                }
-
-                InstanceType::Assembly => self.assemblies[inst.data_index]
-                    .light_accel
-                    .approximate_energy(),
-            };
-            (bounds, energy)
+            let (energy, cone) =
+                light_instance_info(inst, &self.objects, &self.assemblies, &self.xforms);
+            (bounds, energy, cone)
         });
 
         Assembly {
-            instances: self.arena.copy_slice(&self.instances),
+            instances: self.arena.copy_slice(&accel_instances),
             light_instances: self.arena.copy_slice(&light_instances),
             xforms: self.arena.copy_slice(&self.xforms),
             surface_shaders: self.arena.copy_slice(&self.surface_shaders),
@@ -346,53 +448,109 @@ impl<'a> AssemblyBuilder<'a> {
             assemblies: self.arena.copy_slice(&self.assemblies),
             object_accel: object_accel,
             light_accel: light_accel,
+            placement_count: self.placement_count,
         }
     }
 
     /// Returns a pair of vectors with the bounds of all instances.
-    /// This is used for building the assembly's BVH4.
+    /// This is used for building the assembly's BVH.
     fn instance_bounds(&self) -> (Vec<usize>, Vec<BBox>) {
         let mut indices = vec![0];
         let mut bounds = Vec::new();
 
         for inst in &self.instances {
-            let mut bbs = Vec::new();
-            let mut bbs2 = Vec::new();
+            bounds.extend(instance_bounds(
+                inst,
+                &self.objects,
+                &self.assemblies,
+                &self.xforms,
+            ));
+            indices.push(bounds.len());
+        }
 
-            // Get bounding boxes
-            match inst.instance_type {
-                InstanceType::Object => {
-                    // Push bounds onto bbs
-                    let obj = &self.objects[inst.data_index];
-                    match *obj {
-                        Object::Surface(s) => bbs.extend(s.bounds()),
-                        Object::SurfaceLight(l) => bbs.extend(l.bounds()),
-                    }
-                }
+        (indices, bounds)
+    }
+}
 
-                InstanceType::Assembly => {
-                    // Push bounds onto bbs
-                    let asmb = &self.assemblies[inst.data_index];
-                    bbs.extend(asmb.bounds());
-                }
+/// Returns the bounds of an instance in its assembly's space.
+fn instance_bounds(
+    inst: &Instance,
+    objects: &[Object],
+    assemblies: &[Assembly],
+    xforms: &[Matrix4x4],
+) -> Vec<BBox> {
+    let mut bbs = Vec::new();
+    let mut bbs2 = Vec::new();
+
+    // Get bounding boxes
+    match inst.instance_type {
+        InstanceType::Object => {
+            // Push bounds onto bbs
+            let obj = &objects[inst.data_index];
+            match *obj {
+                Object::Surface(s) => bbs.extend(s.bounds()),
+                Object::SurfaceLight(l) => bbs.extend(l.bounds()),
             }
+        }
 
-            // Transform the bounding boxes, if necessary
-            if let Some((xstart, xend)) = inst.transform_indices {
-                let xf = &self.xforms[xstart..xend];
-                transform_bbox_slice_from(&bbs, xf, &mut bbs2);
+        InstanceType::Assembly => {
+            // Push bounds onto bbs
+            let asmb = &assemblies[inst.data_index];
+            bbs.extend(asmb.bounds());
+        }
+    }
+
+    // Transform the bounding boxes, if necessary
+    if let Some((xstart, xend)) = inst.transform_indices {
+        let xf = &xforms[xstart..xend];
+        transform_bbox_slice_from(&bbs, xf, &mut bbs2);
+    } else {
+        bbs2.clear();
+        bbs2.extend(bbs);
+    }
+
+    bbs2
+}
+
+/// Returns the approximate energy and the normal cone of a light instance,
+/// in its assembly's space.  This is used for building the assembly's light
+/// accel.
+fn light_instance_info(
+    inst: &Instance,
+    objects: &[Object],
+    assemblies: &[Assembly],
+    xforms: &[Matrix4x4],
+) -> (f32, NormalCone) {
+    let (energy, cone) = match inst.instance_type {
+        InstanceType::Object => {
+            if let Object::SurfaceLight(light) = objects[inst.data_index] {
+                (light.approximate_energy(), light.normal_cone())
             } else {
-                bbs2.clear();
-                bbs2.extend(bbs);
+                (0.0, NormalCone::full_sphere())
             }
+        }
 
-            // Push transformed bounds onto vec
-            bounds.extend(bbs2);
-            indices.push(bounds.len());
+        InstanceType::Assembly => {
+            let accel = &assemblies[inst.data_index].light_accel;
+            (accel.approximate_energy(), accel.normal_cone())
         }
+    };
 
-        (indices, bounds)
-    }
+    // Transform the cone into the assembly's space, covering all of the
+    // instance's transform time samples.
+    let cone = if let Some((xstart, xend)) = inst.transform_indices {
+        xforms[xstart..xend]
+            .iter()
+            .map(|xf| cone.transformed(xf.inverse()))
+            .fold(None, |acc: Option<NormalCone>, c| {
+                Some(acc.map_or(c, |acc| acc.union(&c)))
+            })
+            .unwrap()
+    } else {
+        cone
+    };
+
+    (energy, cone)
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -406,7 +564,9 @@ pub struct Instance {
     pub instance_type: InstanceType,
     pub data_index: usize,
     pub surface_shader_index: Option<usize>,
+    pub medium_index: Option<usize>, // Index of the scene medium, if the instance bounds one
     pub id: usize,
+    pub placement_offset: usize, // Index of the instance's first object placement in its assembly
     pub transform_indices: Option<(usize, usize)>,
 }
 
@@ -415,3 +575,236 @@ pub enum InstanceType {
     Object,
     Assembly,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        math::Vector,
+        paging::PageFiles,
+        ray::{Ray, RayBatch},
+        surface::triangle_mesh::{MeshStorage, TriangleMesh},
+        tracer::Tracer,
+    };
+
+    const INSTANCE_COUNT: usize = 12;
+
+    // A unit square in the xy plane.
+    fn build_square<'a>(arena: &'a Arena, page_files: &'a PageFiles) -> TriangleMesh<'a> {
+        let verts = vec![
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(1.0, 1.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+        ];
+        TriangleMesh::from_verts_and_indices(
+            arena,
+            page_files,
+            &[verts],
+            &None,
+            &[(0, 1, 2), (0, 2, 3)],
+            SplitMode::Object,
+            MeshStorage::Full,
+        )
+        .unwrap()
+    }
+
+    // The transforms of all of the instances of `build_assembly()` in the
+    // given frame, with two time samples each.  The instances are laid out
+    // in a grid that's reversed and stacked in z in the second frame, so
+    // they end up far from where they were.
+    fn frame_xforms(frame: usize) -> Vec<Matrix4x4> {
+        let mut xforms = Vec::new();
+        for i in 0..=INSTANCE_COUNT {
+            let cell = if frame == 0 { i } else { INSTANCE_COUNT - i };
+            let loc = Point::new((cell % 4) as f32 * 2.0, (cell / 4) as f32 * 2.0, 0.0);
+            let (loc, motion) = if frame == 0 {
+                (loc, Point::new(0.5, 0.0, 0.0))
+            } else {
+                (
+                    Point::new(loc.x(), loc.y(), i as f32 * 0.25),
+                    Point::new(0.0, 0.75, 0.0),
+                )
+            };
+            xforms.push(Matrix4x4::from_location(loc));
+            xforms.push(Matrix4x4::from_location(Point::new(
+                loc.x() + motion.x(),
+                loc.y() + motion.y(),
+                loc.z(),
+            )));
+        }
+        xforms
+    }
+
+    // Builds an assembly of `INSTANCE_COUNT` instances of a square, plus an
+    // instance of a sub-assembly with two more squares, using `xforms`.
+    fn build_assembly<'a>(
+        arena: &'a Arena,
+        square: &'a TriangleMesh<'a>,
+        xforms: &[Matrix4x4],
+    ) -> Assembly<'a> {
+        let mut sub_builder = AssemblyBuilder::new(arena);
+        sub_builder.add_object("square", Object::Surface(square));
+        sub_builder.add_instance("square", None, None, None);
+        sub_builder.add_instance(
+            "square",
+            None,
+            None,
+            Some(&[Matrix4x4::from_location(Point::new(1.0, 0.0, 0.0))]),
+        );
+
+        let mut builder = AssemblyBuilder::new(arena);
+        builder.add_object("square", Object::Surface(square));
+        builder.add_assembly("sub", sub_builder.build());
+        for i in 0..INSTANCE_COUNT {
+            builder.add_instance("square", None, None, Some(&xforms[(i * 2)..(i * 2 + 2)]));
+        }
+        builder.add_instance("sub", None, None, Some(&xforms[(INSTANCE_COUNT * 2)..]));
+        builder.build()
+    }
+
+    fn trace_grid(assembly: &Assembly) -> Vec<SurfaceIntersection> {
+        let mut rays = RayBatch::new();
+        let res = 32;
+        for y in 0..res {
+            for x in 0..res {
+                rays.push(
+                    Ray {
+                        orig: Point::new(
+                            -4.0 + (x as f32 + 0.5) * 16.0 / res as f32,
+                            -4.0 + (y as f32 + 0.5) * 12.0 / res as f32,
+                            20.0,
+                        ),
+                        dir: Vector::new(0.0, 0.0, -1.0),
+                        time: ((x + y) % 5) as f32 / 4.0,
+                        wavelength: 500.0,
+                        max_t: f32::INFINITY,
+                    },
+                    false,
+                );
+            }
+        }
+        let mut tracer = Tracer::from_assembly(assembly, false);
+        tracer.trace(&mut rays).to_vec()
+    }
+
+    #[test]
+    fn with_transforms_matches_fresh_build() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let square = build_square(&arena, &page_files);
+        let xforms = frame_xforms(1);
+
+        let refit =
+            build_assembly(&arena, &square, &frame_xforms(0)).with_transforms(&arena, &xforms);
+        let fresh = build_assembly(&arena, &square, &xforms);
+
+        // Bounds.  The refit bounds should contain every instance, but the
+        // fresh build can store the union of an instance's time samples in
+        // place of the samples, so its bounds can be looser.
+        let contains = |a: BBox, b: BBox| {
+            (0..3).all(|i| {
+                a.min.get_n(i) <= b.min.get_n(i) + 0.0001
+                    && a.max.get_n(i) >= b.max.get_n(i) - 0.0001
+            })
+        };
+        for i in 0..=4 {
+            let time = i as f32 / 4.0;
+            let refit_bounds = lerp_slice(refit.bounds(), time);
+            assert!(contains(lerp_slice(fresh.bounds(), time), refit_bounds));
+            for inst in refit.instances {
+                let inst_bounds = instance_bounds(inst, refit.objects, refit.assemblies, &xforms);
+                assert!(contains(refit_bounds, lerp_slice(&inst_bounds, time)));
+            }
+        }
+
+        // Traversal
+        let refit_isects = trace_grid(&refit);
+        let fresh_isects = trace_grid(&fresh);
+        let mut hit_count = 0;
+        for (a, b) in refit_isects.iter().zip(fresh_isects.iter()) {
+            match (a, b) {
+                (SurfaceIntersection::Miss, SurfaceIntersection::Miss) => {}
+                (
+                    SurfaceIntersection::Hit {
+                        intersection_data: a,
+                        ..
+                    },
+                    SurfaceIntersection::Hit {
+                        intersection_data: b,
+                        ..
+                    },
+                ) => {
+                    assert!((a.pos - b.pos).length() < 0.0001);
+                    assert_eq!(a.object_id, b.object_id);
+                    hit_count += 1;
+                }
+                _ => panic!("Refit and fresh assemblies disagree on a hit."),
+            }
+        }
+        assert!(hit_count > 0);
+    }
+
+    #[test]
+    fn placement_ids_are_unique() {
+        let arena = Arena::new();
+        let page_files = PageFiles::new();
+        let square = build_square(&arena, &page_files);
+
+        // A sub-assembly of two squares side by side, placed three times in
+        // a column, next to a single square.
+        let mut sub_builder = AssemblyBuilder::new(&arena);
+        sub_builder.add_object("square", Object::Surface(&square));
+        sub_builder.add_instance("square", None, None, None);
+        sub_builder.add_instance(
+            "square",
+            None,
+            None,
+            Some(&[Matrix4x4::from_location(Point::new(-2.0, 0.0, 0.0))]),
+        );
+        let mut builder = AssemblyBuilder::new(&arena);
+        builder.add_object("square", Object::Surface(&square));
+        builder.add_assembly("sub", sub_builder.build());
+        builder.add_instance("square", None, None, None);
+        for i in 1..4 {
+            let xform = Matrix4x4::from_location(Point::new(0.0, i as f32 * -2.0, 0.0));
+            builder.add_instance("sub", None, None, Some(&[xform]));
+        }
+        let assembly = builder.build();
+        assert_eq!(assembly.placement_count, 7);
+
+        // Trace a ray through the middle of each square.
+        let mut rays = RayBatch::new();
+        for &x in &[0.5, 2.5] {
+            for y in 0..4 {
+                if x > 1.0 && y == 0 {
+                    continue;
+                }
+                rays.push(
+                    Ray {
+                        orig: Point::new(x, y as f32 * 2.0 + 0.5, 10.0),
+                        dir: Vector::new(0.0, 0.0, -1.0),
+                        time: 0.0,
+                        wavelength: 500.0,
+                        max_t: f32::INFINITY,
+                    },
+                    false,
+                );
+            }
+        }
+        let mut tracer = Tracer::from_assembly(&assembly, false);
+        let mut ids: Vec<usize> = tracer
+            .trace(&mut rays)
+            .iter()
+            .map(|isect| match *isect {
+                SurfaceIntersection::Hit {
+                    intersection_data, ..
+                } => intersection_data.object_id,
+                _ => panic!("Ray missed its square."),
+            })
+            .collect();
+        ids.sort_unstable();
+        assert_eq!(ids, (0..7).collect::<Vec<_>>());
+    }
+}
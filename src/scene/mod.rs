@@ -7,6 +7,7 @@ use crate::{
     camera::Camera,
     color::SpectralSample,
     math::{Normal, Point, Vector},
+    medium::Medium,
     surface::SurfaceIntersection,
     transform_stack::TransformStack,
 };
@@ -21,6 +22,7 @@ pub struct Scene<'a> {
     pub name: Option<String>,
     pub camera: Camera<'a>,
     pub world: World<'a>,
+    pub media: &'a [&'a dyn Medium],
     pub root: Assembly<'a>,
 }
 
@@ -34,28 +36,59 @@ impl<'a> Scene<'a> {
         time: f32,
         intr: &SurfaceIntersection,
     ) -> SceneLightSample {
-        // TODO: this just selects between world lights and local lights
-        // with a 50/50 chance.  We should do something more sophisticated
-        // than this, accounting for the estimated impact of the lights
-        // on the point being lit.
-
         // Calculate relative probabilities of traversing into world lights
-        // or local lights.
-        let wl_energy = if self
-            .world
-            .lights
-            .iter()
-            .fold(0.0, |energy, light| energy + light.approximate_energy())
-            <= 0.0
+        // or local lights, based on their estimated contribution to the
+        // point being lit.
+        let (wl_energy, ll_energy) = if let SurfaceIntersection::Hit {
+            intersection_data: idata,
+            closure,
+        } = *intr
         {
-            0.0
+            let wl_energy = self.world.lights.iter().fold(0.0, |energy, light| {
+                energy
+                    + light.approximate_contribution(
+                        idata.incoming,
+                        idata.nor,
+                        idata.nor_g,
+                        &closure,
+                        time,
+                    )
+            });
+            let ll_energy = self.root.light_accel.approximate_contribution(
+                idata.incoming,
+                idata.pos,
+                idata.nor,
+                idata.nor_g,
+                &closure,
+                time,
+            );
+            (wl_energy, ll_energy)
         } else {
-            1.0
+            (0.0, 0.0)
         };
-        let ll_energy = if self.root.light_accel.approximate_energy() <= 0.0 {
-            0.0
+
+        // If the estimates don't give us anything to go on, fall back to
+        // an even choice between whichever kinds of lights exist at all.
+        let (wl_energy, ll_energy) = if (wl_energy + ll_energy) > 0.0 {
+            (wl_energy, ll_energy)
         } else {
-            1.0
+            let wl_energy = if self
+                .world
+                .lights
+                .iter()
+                .fold(0.0, |energy, light| energy + light.approximate_energy())
+                <= 0.0
+            {
+                0.0
+            } else {
+                1.0
+            };
+            let ll_energy = if self.root.light_accel.approximate_energy() <= 0.0 {
+                0.0
+            } else {
+                1.0
+            };
+            (wl_energy, ll_energy)
         };
         let tot_energy = wl_energy + ll_energy;
 
@@ -4,4 +4,5 @@ use crate::{color::Color, light::WorldLightSource};
 pub struct World<'a> {
     pub background_color: Color,
     pub lights: &'a [&'a dyn WorldLightSource],
+    pub medium: Option<usize>, // Index of the scene medium filling the world, e.g. fog
 }
@@ -1,8 +1,13 @@
+pub mod noise;
 pub mod surface_closure;
 
 use std::fmt::Debug;
 
-use crate::{color::Color, surface::SurfaceIntersectionData};
+use crate::{
+    color::Color,
+    math::{Normal, Point, Vector},
+    surface::SurfaceIntersectionData,
+};
 
 pub use self::surface_closure::SurfaceClosure;
 
@@ -13,6 +18,24 @@ pub trait SurfaceShader: Debug + Sync {
     fn shade(&self, data: &SurfaceIntersectionData, time: f32) -> SurfaceClosure;
 }
 
+/// Trait for displacement shaders, which are applied to geometry when
+/// it's diced.
+pub trait DisplacementShader: Debug + Sync {
+    /// Takes a point on the undisplaced surface and its normal, in the
+    /// local space of the geometry, and returns the offset to move the
+    /// point by.
+    ///
+    /// `nor` is normalized.
+    fn displace(&self, pos: Point, nor: Normal, time: f32) -> Vector;
+
+    /// Returns the maximum length of the offsets returned by `displace()`.
+    ///
+    /// Offsets are clamped to this, so anything bounding the undisplaced
+    /// geometry can be safely expanded by it to bound the displaced
+    /// geometry.
+    fn bound(&self) -> f32;
+}
+
 /// Clearly we must eat this brownie before the world ends, lest it
 /// go uneaten before the world ends.  But to do so we must trek
 /// far--much like in Lord of the Rings--to fetch the golden fork with
@@ -37,26 +60,125 @@ pub enum SimpleSurfaceShader {
         roughness: f32,
         fresnel: f32,
     },
+    Hair {
+        color: Color,
+        roughness: f32,
+    },
+    Subsurface {
+        color: Color,
+        mfp: Color,
+    },
 }
 
 impl SurfaceShader for SimpleSurfaceShader {
     fn shade(&self, data: &SurfaceIntersectionData, time: f32) -> SurfaceClosure {
-        let _ = (data, time); // Silence "unused" compiler warning
+        let _ = time; // Silence "unused" compiler warning
+
+        // Per-primitive colors override the shader's color.
+        let color_or = |color: Color| data.color.unwrap_or(color);
 
         match *self {
-            SimpleSurfaceShader::Emit { color } => SurfaceClosure::Emit(color),
+            SimpleSurfaceShader::Emit { color } => SurfaceClosure::Emit(color_or(color)),
 
-            SimpleSurfaceShader::Lambert { color } => SurfaceClosure::Lambert(color),
+            SimpleSurfaceShader::Lambert { color } => SurfaceClosure::Lambert(color_or(color)),
 
             SimpleSurfaceShader::GGX {
                 color,
                 roughness,
                 fresnel,
             } => SurfaceClosure::GGX {
-                color: color,
+                color: color_or(color),
                 roughness: roughness,
                 fresnel: fresnel,
             },
+
+            SimpleSurfaceShader::Hair { color, roughness } => SurfaceClosure::Hair {
+                color: color_or(color),
+                roughness: roughness,
+                tangent: data.tangent,
+            },
+
+            SimpleSurfaceShader::Subsurface { color, mfp } => SurfaceClosure::Subsurface {
+                color: color_or(color),
+                mfp: mfp,
+            },
+        }
+    }
+}
+
+/// The shader for surfaces that bound a medium.
+///
+/// The surfaces themselves are invisible, and just mark where rays enter
+/// and leave the medium.
+#[derive(Debug, Copy, Clone)]
+pub struct MediumBoundaryShader {
+    pub medium_index: usize,
+}
+
+impl SurfaceShader for MediumBoundaryShader {
+    fn shade(&self, data: &SurfaceIntersectionData, time: f32) -> SurfaceClosure {
+        let _ = (data, time); // Silence "unused" compiler warning
+
+        SurfaceClosure::MediumBoundary(self.medium_index)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub enum SimpleDisplacementShader {
+    /// Displaces along the surface normal by fractal noise scaled by
+    /// `height`.
+    Scalar {
+        height: f32,
+        frequency: f32,
+        octaves: u32,
+    },
+
+    /// Displaces in an arbitrary direction, with each axis driven by its
+    /// own fractal noise scaled by the corresponding component of
+    /// `amplitude`.
+    Vector {
+        amplitude: Vector,
+        frequency: f32,
+        octaves: u32,
+    },
+}
+
+impl DisplacementShader for SimpleDisplacementShader {
+    fn displace(&self, pos: Point, nor: Normal, time: f32) -> Vector {
+        let _ = time; // Silence "unused" compiler warning
+
+        let noise_at = |frequency: f32, octaves: u32, seed: u32| {
+            let p = (
+                pos.x() * frequency,
+                pos.y() * frequency,
+                pos.z() * frequency,
+            );
+            noise::fbm(p, octaves, seed)
+        };
+
+        match *self {
+            SimpleDisplacementShader::Scalar {
+                height,
+                frequency,
+                octaves,
+            } => nor.into_vector() * (height * noise_at(frequency, octaves, 0)),
+
+            SimpleDisplacementShader::Vector {
+                amplitude,
+                frequency,
+                octaves,
+            } => Vector::new(
+                amplitude.x() * noise_at(frequency, octaves, 0),
+                amplitude.y() * noise_at(frequency, octaves, 1000),
+                amplitude.z() * noise_at(frequency, octaves, 2000),
+            ),
+        }
+    }
+
+    fn bound(&self) -> f32 {
+        match *self {
+            SimpleDisplacementShader::Scalar { height, .. } => height.abs(),
+            SimpleDisplacementShader::Vector { amplitude, .. } => amplitude.length(),
         }
     }
 }
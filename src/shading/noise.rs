@@ -0,0 +1,113 @@
+//! Procedural noise functions for use in shaders.
+
+use crate::hash::hash_u32;
+
+/// 3D gradient noise, following Ken Perlin's "Improving Noise".
+///
+/// Returns a value in roughly [-1, 1] that varies smoothly with `p`, and
+/// is zero at integer coordinates.
+pub fn gradient_noise(p: (f32, f32, f32), seed: u32) -> f32 {
+    let (x0, y0, z0) = (p.0.floor(), p.1.floor(), p.2.floor());
+    let (fx, fy, fz) = (p.0 - x0, p.1 - y0, p.2 - z0);
+    let (ix, iy, iz) = (x0 as i32, y0 as i32, z0 as i32);
+
+    // Dot product of the offset from a lattice point with that lattice
+    // point's pseudo-random gradient.
+    let grad = |dx: i32, dy: i32, dz: i32| {
+        let hash = hash_u32(
+            (ix + dx) as u32 ^ hash_u32((iy + dy) as u32 ^ hash_u32((iz + dz) as u32, seed), seed),
+            seed,
+        );
+        let (x, y, z) = (fx - dx as f32, fy - dy as f32, fz - dz as f32);
+
+        // Pick one of the 12 directions to the edges of a cube, with four
+        // of them doubled up to make 16.
+        let h = hash & 15;
+        let u = if h < 8 { x } else { y };
+        let v = if h < 4 {
+            y
+        } else if h == 12 || h == 14 {
+            x
+        } else {
+            z
+        };
+        (if h & 1 == 0 { u } else { -u }) + (if h & 2 == 0 { v } else { -v })
+    };
+
+    let fade = |t: f32| t * t * t * ((t * ((t * 6.0) - 15.0)) + 10.0);
+    let (u, v, w) = (fade(fx), fade(fy), fade(fz));
+    let lerp = |a: f32, b: f32, t: f32| a + ((b - a) * t);
+
+    lerp(
+        lerp(
+            lerp(grad(0, 0, 0), grad(1, 0, 0), u),
+            lerp(grad(0, 1, 0), grad(1, 1, 0), u),
+            v,
+        ),
+        lerp(
+            lerp(grad(0, 0, 1), grad(1, 0, 1), u),
+            lerp(grad(0, 1, 1), grad(1, 1, 1), u),
+            v,
+        ),
+        w,
+    )
+}
+
+/// Fractal sum of `octaves` octaves of gradient noise, each at twice the
+/// frequency and half the amplitude of the previous one.
+///
+/// The result is normalized and clamped to [-1, 1].
+pub fn fbm(p: (f32, f32, f32), octaves: u32, seed: u32) -> f32 {
+    let mut sum = 0.0;
+    let mut total_amplitude = 0.0;
+    let mut amplitude = 1.0;
+    let mut frequency = 1.0;
+    for i in 0..octaves.max(1) {
+        sum += gradient_noise(
+            (p.0 * frequency, p.1 * frequency, p.2 * frequency),
+            seed.wrapping_add(i),
+        ) * amplitude;
+        total_amplitude += amplitude;
+        amplitude *= 0.5;
+        frequency *= 2.0;
+    }
+
+    (sum / total_amplitude).max(-1.0).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gradient_noise_zero_at_lattice_points() {
+        for &p in &[(0.0, 0.0, 0.0), (1.0, -3.0, 7.0), (-12.0, 5.0, -2.0)] {
+            assert_eq!(gradient_noise(p, 0), 0.0);
+        }
+    }
+
+    #[test]
+    fn gradient_noise_range() {
+        for i in 0..1000 {
+            let p = (i as f32 * 0.173, i as f32 * -0.291, i as f32 * 0.057);
+            let n = gradient_noise(p, 3);
+            assert!(n >= -1.1 && n <= 1.1);
+        }
+    }
+
+    #[test]
+    fn gradient_noise_continuous() {
+        let a = gradient_noise((2.999_99, 0.5, 0.25), 1);
+        let b = gradient_noise((3.000_01, 0.5, 0.25), 1);
+        assert!((a - b).abs() < 0.001);
+    }
+
+    #[test]
+    fn fbm_range() {
+        for i in 0..1000 {
+            let p = (i as f32 * 0.731, i as f32 * 0.119, i as f32 * -0.377);
+            let n = fbm(p, 5, 7);
+            assert!(n >= -1.0 && n <= 1.0);
+        }
+    }
+}
@@ -24,9 +24,22 @@ pub enum SurfaceClosure {
         roughness: f32,
         fresnel: f32, // [0.0, 1.0] determines how much fresnel reflection comes into play
     },
+    Hair {
+        color: Color,
+        roughness: f32,
+        tangent: Vector, // Direction along the fiber
+    },
+
+    // Phase function for scattering inside of participating media.
+    HenyeyGreenstein(f32), // Anisotropy, from -1 (backward) to 1 (forward)
 
     // Special closures that need special handling by the renderer.
     Emit(Color),
+    MediumBoundary(usize), // Index of the scene medium that this bounds
+    Subsurface {
+        color: Color, // Overall color after multiple scattering
+        mfp: Color,   // Mean free path within the surface, per wavelength
+    },
 }
 
 use self::SurfaceClosure::*;
@@ -40,7 +53,11 @@ impl SurfaceClosure {
         match *self {
             Lambert(_) => false,
             GGX { roughness, .. } => roughness == 0.0,
+            Hair { .. } => false,
+            HenyeyGreenstein(_) => false,
             Emit(_) => false,
+            MediumBoundary(_) => true,
+            Subsurface { .. } => false,
         }
     }
 
@@ -71,7 +88,25 @@ impl SurfaceClosure {
                 fresnel,
             } => ggx_closure::sample(color, roughness, fresnel, inc, nor, nor_g, uv, wavelength),
 
+            Hair {
+                color,
+                roughness,
+                tangent,
+            } => hair_closure::sample(color, roughness, tangent, inc, nor, nor_g, uv, wavelength),
+
+            HenyeyGreenstein(g) => hg_closure::sample(g, inc, uv, wavelength),
+
             Emit(color) => emit_closure::sample(color, inc, nor, nor_g, uv, wavelength),
+
+            // Medium boundaries don't scatter, they just let light through.
+            MediumBoundary(_) => (inc, SpectralSample::from_value(1.0, wavelength), 1.0),
+
+            // Subsurface scattering is done by a random walk in the
+            // renderer, but it's approximately diffuse for anything else
+            // that's interested.
+            Subsurface { color, .. } => {
+                lambert_closure::sample(color, inc, nor, nor_g, uv, wavelength)
+            }
         }
     }
 
@@ -102,7 +137,23 @@ impl SurfaceClosure {
                 fresnel,
             } => ggx_closure::evaluate(color, roughness, fresnel, inc, out, nor, nor_g, wavelength),
 
+            Hair {
+                color,
+                roughness,
+                tangent,
+            } => {
+                hair_closure::evaluate(color, roughness, tangent, inc, out, nor, nor_g, wavelength)
+            }
+
+            HenyeyGreenstein(g) => hg_closure::evaluate(g, inc, out, wavelength),
+
             Emit(color) => emit_closure::evaluate(color, inc, out, nor, nor_g, wavelength),
+
+            MediumBoundary(_) => (SpectralSample::from_value(0.0, wavelength), 0.0),
+
+            Subsurface { color, .. } => {
+                lambert_closure::evaluate(color, inc, out, nor, nor_g, wavelength)
+            }
         }
     }
 
@@ -143,6 +194,20 @@ impl SurfaceClosure {
                 nor,
                 nor_g,
             ),
+            Hair { color, .. } => hair_closure::estimate_eval_over_sphere_light(
+                color,
+                inc,
+                to_light_center,
+                light_radius_squared,
+                nor,
+                nor_g,
+            ),
+            HenyeyGreenstein(g) => hg_closure::estimate_eval_over_sphere_light(
+                g,
+                inc,
+                to_light_center,
+                light_radius_squared,
+            ),
             Emit(color) => emit_closure::estimate_eval_over_sphere_light(
                 color,
                 inc,
@@ -151,6 +216,15 @@ impl SurfaceClosure {
                 nor,
                 nor_g,
             ),
+            MediumBoundary(_) => 0.0,
+            Subsurface { color, .. } => lambert_closure::estimate_eval_over_sphere_light(
+                color,
+                inc,
+                to_light_center,
+                light_radius_squared,
+                nor,
+                nor_g,
+            ),
         }
     }
 
@@ -163,7 +237,15 @@ impl SurfaceClosure {
                 + 2 // Fresnel
                 + color.compressed_size() // Color
             }
+            Hair { color, .. } => {
+                2 // Roughness
+                + 12 // Tangent
+                + color.compressed_size() // Color
+            }
+            HenyeyGreenstein(_) => 4,
             Emit(color) => color.compressed_size(),
+            MediumBoundary(_) => 4,
+            Subsurface { color, mfp } => color.compressed_size() + mfp.compressed_size(),
         }
     }
 
@@ -204,6 +286,38 @@ impl SurfaceClosure {
                 out_data[0] = 2; // Discriminant
                 color.write_compressed(&mut out_data[1..]);
             }
+            Hair {
+                color,
+                roughness,
+                tangent,
+            } => {
+                out_data[0] = 3; // Discriminant
+
+                // Roughness and tangent, constant-size like with GGX.
+                let rgh =
+                    ((roughness.max(0.0).min(1.0) * std::u16::MAX as f32) as u16).to_le_bytes();
+                out_data[1] = rgh[0];
+                out_data[2] = rgh[1];
+                for (i, v) in [tangent.x(), tangent.y(), tangent.z()].iter().enumerate() {
+                    out_data[(3 + (i * 4))..(7 + (i * 4))].copy_from_slice(&v.to_le_bytes());
+                }
+
+                // Color
+                color.write_compressed(&mut out_data[15..]);
+            }
+            HenyeyGreenstein(g) => {
+                out_data[0] = 4; // Discriminant
+                out_data[1..5].copy_from_slice(&g.to_le_bytes());
+            }
+            MediumBoundary(index) => {
+                out_data[0] = 5; // Discriminant
+                out_data[1..5].copy_from_slice(&(index as u32).to_le_bytes());
+            }
+            Subsurface { color, mfp } => {
+                out_data[0] = 6; // Discriminant
+                let size = color.write_compressed(&mut out_data[1..]);
+                mfp.write_compressed(&mut out_data[(1 + size)..]);
+            }
         }
         self.compressed_size()
     }
@@ -245,6 +359,57 @@ impl SurfaceClosure {
                 (SurfaceClosure::Emit(col), 1 + size)
             }
 
+            3 => {
+                // Hair
+                let rgh = u16::from_le_bytes([in_data[1], in_data[2]]) as f32
+                    * (1.0 / std::u16::MAX as f32);
+                let mut tangent = [0.0f32; 3];
+                for (i, v) in tangent.iter_mut().enumerate() {
+                    let mut bytes = [0u8; 4];
+                    bytes.copy_from_slice(&in_data[(3 + (i * 4))..(7 + (i * 4))]);
+                    *v = f32::from_le_bytes(bytes);
+                }
+                let (col, size) = Color::from_compressed(&in_data[15..]);
+                (
+                    SurfaceClosure::Hair {
+                        color: col,
+                        roughness: rgh,
+                        tangent: Vector::new(tangent[0], tangent[1], tangent[2]),
+                    },
+                    15 + size,
+                )
+            }
+
+            4 => {
+                // HenyeyGreenstein
+                let mut g = [0u8; 4];
+                g.copy_from_slice(&in_data[1..5]);
+                (SurfaceClosure::HenyeyGreenstein(f32::from_le_bytes(g)), 5)
+            }
+
+            5 => {
+                // MediumBoundary
+                let mut index = [0u8; 4];
+                index.copy_from_slice(&in_data[1..5]);
+                (
+                    SurfaceClosure::MediumBoundary(u32::from_le_bytes(index) as usize),
+                    5,
+                )
+            }
+
+            6 => {
+                // Subsurface
+                let (col, col_size) = Color::from_compressed(&in_data[1..]);
+                let (mfp, mfp_size) = Color::from_compressed(&in_data[(1 + col_size)..]);
+                (
+                    SurfaceClosure::Subsurface {
+                        color: col,
+                        mfp: mfp,
+                    },
+                    1 + col_size + mfp_size,
+                )
+            }
+
             _ => unreachable!(),
         }
     }
@@ -270,13 +435,78 @@ impl Lerp for SurfaceClosure {
                 roughness: lerp(rgh1, rgh2, alpha),
                 fresnel: lerp(frs1, frs2, alpha),
             },
+            (
+                Hair {
+                    color: col1,
+                    roughness: rgh1,
+                    tangent: tan1,
+                },
+                Hair {
+                    color: col2,
+                    roughness: rgh2,
+                    tangent: tan2,
+                },
+            ) => Hair {
+                color: lerp(col1, col2, alpha),
+                roughness: lerp(rgh1, rgh2, alpha),
+                tangent: lerp(tan1, tan2, alpha),
+            },
+            (HenyeyGreenstein(g1), HenyeyGreenstein(g2)) => HenyeyGreenstein(lerp(g1, g2, alpha)),
             (Emit(col1), Emit(col2)) => Emit(lerp(col1, col2, alpha)),
+            (MediumBoundary(i1), MediumBoundary(i2)) if i1 == i2 => MediumBoundary(i1),
+            (
+                Subsurface {
+                    color: col1,
+                    mfp: mfp1,
+                },
+                Subsurface {
+                    color: col2,
+                    mfp: mfp2,
+                },
+            ) => Subsurface {
+                color: lerp(col1, col2, alpha),
+                mfp: lerp(mfp1, mfp2, alpha),
+            },
 
             _ => panic!("Cannot lerp between different surface closure types."),
         }
     }
 }
 
+/// Returns the absorption and scattering coefficients of a subsurface
+/// random walk for the given hero wavelength.
+///
+/// The single-scattering albedo is found from the desired multiple
+/// scattering color with the fit from "Practical and Controllable
+/// Subsurface Scattering for Production Path Tracing" by Chiang et al.
+pub fn subsurface_coefficients(color: Color, mfp: Color, wavelength: f32) -> (Vec4, Vec4) {
+    let color = color.to_spectral_sample(wavelength).e;
+    let mfp = mfp.to_spectral_sample(wavelength).e;
+
+    let albedo = |a: f32| {
+        let a = clamp(a, 0.0, 0.999);
+        let tmp = 4.09712 + (4.20863 * a) - (9.59217 + (41.6808 * a) + (17.7126 * a * a)).sqrt();
+        1.0 - (tmp * tmp)
+    };
+    let extinction = |mfp: f32| 1.0 / mfp.max(0.000_001);
+
+    let sigma_t = Vec4::new(
+        extinction(mfp.x()),
+        extinction(mfp.y()),
+        extinction(mfp.z()),
+        extinction(mfp.w()),
+    );
+    let sigma_s = sigma_t
+        * Vec4::new(
+            albedo(color.x()),
+            albedo(color.y()),
+            albedo(color.z()),
+            albedo(color.w()),
+        );
+
+    (sigma_t - sigma_s, sigma_s)
+}
+
 /// Lambert closure code.
 mod lambert_closure {
     use super::*;
@@ -632,10 +862,511 @@ mod ggx_closure {
     }
 }
 
+/// Hair closure code.
+///
+/// This is the model from "A Practical and Controllable Hair and Fur Model
+/// for Production Path Tracing" by Chiang et al., following the
+/// implementation in "Physically Based Rendering" 3rd edition by Pharr
+/// et al.  The longitudinal and azimuthal roughness are both set from the
+/// single roughness parameter.
+///
+/// The model needs to know where across the fiber the hit is, which is
+/// derived from the shading normal: on a round fiber the normal tilts
+/// towards the fiber's edges.
+mod hair_closure {
+    use super::*;
+
+    use crate::math::{coordinate_system_from_vector, cross};
+
+    const ETA: f32 = 1.55; // Index of refraction of the fiber
+    const ALPHA: f32 = 2.0 * PI_32 / 180.0; // Tilt of the cuticle scales
+    const P_MAX: usize = 3; // Number of explicitly modeled scattering events
+    const SQRT_PI_OVER_8: f32 = 0.626_657_07;
+
+    pub fn sample(
+        color: Color,
+        roughness: f32,
+        tangent: Vector,
+        inc: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        uv: (f32, f32),
+        wavelength: f32,
+    ) -> (Vector, SpectralSample, f32) {
+        let frame = Frame::new(inc, nor, tangent);
+        let hair = Hair::new(color, roughness, frame.h, wavelength);
+        let wo = frame.to_local(-inc);
+
+        // Split the two sample values into four.
+        let u0 = demux_float(uv.0);
+        let u1 = demux_float(uv.1);
+
+        // Choose which scattering event to sample.
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(wo);
+        let ap_pdf = hair.ap_pdf(cos_theta_o);
+        let mut p = 0;
+        let mut u_p = u0.0;
+        while p < P_MAX && u_p >= ap_pdf[p] {
+            u_p -= ap_pdf[p];
+            p += 1;
+        }
+        let (sin_theta_op, cos_theta_op) = hair.tilt(p, sin_theta_o, cos_theta_o);
+
+        // Sample the longitudinal scattering.
+        let u = u1.0.max(1.0e-5);
+        let cos_theta = 1.0 + (hair.v[p] * (u + ((1.0 - u) * (-2.0 / hair.v[p]).exp())).ln());
+        let sin_theta = (1.0 - (cos_theta * cos_theta)).max(0.0).sqrt();
+        let cos_phi = (2.0 * PI_32 * u1.1).cos();
+        let sin_theta_i = (-cos_theta * sin_theta_op) + (sin_theta * cos_phi * cos_theta_op);
+        let cos_theta_i = (1.0 - (sin_theta_i * sin_theta_i)).max(0.0).sqrt();
+
+        // Sample the azimuthal scattering.
+        let dphi = if p < P_MAX {
+            phi(p, hair.gamma_o, hair.gamma_t(cos_theta_o))
+                + sample_trimmed_logistic(u0.1, hair.s, -PI_32, PI_32)
+        } else {
+            2.0 * PI_32 * u0.1
+        };
+        let phi_i = phi_o + dphi;
+
+        let out = frame.from_local(Vector::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        ));
+
+        let (filter, pdf) = evaluate(color, roughness, tangent, inc, out, nor, nor_g, wavelength);
+        (out, filter, pdf)
+    }
+
+    pub fn evaluate(
+        color: Color,
+        roughness: f32,
+        tangent: Vector,
+        inc: Vector,
+        out: Vector,
+        nor: Normal,
+        nor_g: Normal,
+        wavelength: f32,
+    ) -> (SpectralSample, f32) {
+        let _ = nor_g; // Not using this, silence warning
+
+        let frame = Frame::new(inc, nor, tangent);
+        let hair = Hair::new(color, roughness, frame.h, wavelength);
+        let (sin_theta_o, cos_theta_o, phi_o) = angles(frame.to_local(-inc));
+        let (sin_theta_i, cos_theta_i, phi_i) = angles(frame.to_local(out));
+        let dphi = phi_i - phi_o;
+
+        let gamma_t = hair.gamma_t(cos_theta_o);
+        let ap = hair.ap(cos_theta_o);
+        let ap_pdf = hair.ap_pdf(cos_theta_o);
+
+        let mut f = Vec4::splat(0.0);
+        let mut pdf = 0.0;
+        for p in 0..=P_MAX {
+            let (sin_theta_op, cos_theta_op) = hair.tilt(p, sin_theta_o, cos_theta_o);
+            let mp = mp(
+                cos_theta_i,
+                cos_theta_op.abs(),
+                sin_theta_i,
+                sin_theta_op,
+                hair.v[p],
+            );
+            let np = if p < P_MAX {
+                np(dphi, p, hair.s, hair.gamma_o, gamma_t)
+            } else {
+                1.0 / (2.0 * PI_32)
+            };
+            f += ap[p] * (mp * np);
+            pdf += mp * ap_pdf[p] * np;
+        }
+
+        // Note that unlike in PBRT, this isn't divided by the cosine
+        // factor, because our closures include it.
+        (SpectralSample::from_parts(f, wavelength), pdf)
+    }
+
+    pub fn estimate_eval_over_sphere_light(
+        _color: Color,
+        _inc: Vector,
+        to_light_center: Vector,
+        light_radius_squared: f32,
+        _nor: Normal,
+        _nor_g: Normal,
+    ) -> f32 {
+        // Hair scatters light in all directions, so just treat it as
+        // isotropic and use the solid angle of the light.
+        let dist2 = to_light_center.length2();
+        if dist2 <= light_radius_squared {
+            1.0
+        } else {
+            let sin_theta_max2 = (light_radius_squared / dist2).min(1.0);
+            let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
+            (1.0 - cos_theta_max) * 0.5
+        }
+    }
+
+    /// The shading frame of a hit on a fiber.
+    ///
+    /// x runs along the fiber, and z is perpendicular to the fiber,
+    /// pointing as much towards the viewer as possible.  `h` is where
+    /// across the fiber the hit is, from -1 to 1.
+    struct Frame {
+        x: Vector,
+        y: Vector,
+        z: Vector,
+        h: f32,
+    }
+
+    impl Frame {
+        fn new(inc: Vector, nor: Normal, tangent: Vector) -> Frame {
+            let nor = nor.normalized().into_vector();
+            let x = if tangent.length2() > 0.0 {
+                tangent.normalized()
+            } else {
+                // No tangent, e.g. on a surface that isn't a curve, so
+                // just pick one.
+                coordinate_system_from_vector(nor).1
+            };
+
+            let wo = -inc.normalized();
+            let z = {
+                let wo_perp = wo - (x * dot(wo, x));
+                if wo_perp.length2() > 0.0 {
+                    wo_perp.normalized()
+                } else {
+                    cross(x, coordinate_system_from_vector(x).1).normalized()
+                }
+            };
+            let y = cross(z, x);
+
+            Frame {
+                x: x,
+                y: y,
+                z: z,
+                h: dot(nor, y).max(-1.0).min(1.0),
+            }
+        }
+
+        fn to_local(&self, v: Vector) -> Vector {
+            let v = v.normalized();
+            Vector::new(dot(v, self.x), dot(v, self.y), dot(v, self.z))
+        }
+
+        fn from_local(&self, v: Vector) -> Vector {
+            (self.x * v.x()) + (self.y * v.y()) + (self.z * v.z())
+        }
+    }
+
+    /// Parameters of the model for a given hit.
+    struct Hair {
+        h: f32,
+        gamma_o: f32,
+        sigma_a: Vec4,
+        v: [f32; P_MAX + 1], // Longitudinal variance for each scattering event
+        s: f32,              // Azimuthal logistic scale
+        sin_2k_alpha: [f32; 3],
+        cos_2k_alpha: [f32; 3],
+    }
+
+    impl Hair {
+        fn new(color: Color, roughness: f32, h: f32, wavelength: f32) -> Hair {
+            let beta = roughness.max(0.01).min(1.0);
+
+            let v0 = {
+                let tmp = (0.726 * beta) + (0.812 * beta * beta) + (3.7 * beta.powi(20));
+                tmp * tmp
+            };
+            let s =
+                SQRT_PI_OVER_8 * ((0.265 * beta) + (1.194 * beta * beta) + (5.372 * beta.powi(22)));
+
+            // Absorption coefficient that produces roughly the given color
+            // after multiple scattering.
+            let sigma_a = {
+                let c = color.to_spectral_sample(wavelength).e;
+                let denom = 5.969 - (0.215 * beta) + (2.532 * beta.powi(2))
+                    - (10.73 * beta.powi(3))
+                    + (5.574 * beta.powi(4))
+                    + (0.245 * beta.powi(5));
+                let sa = |c: f32| {
+                    let tmp = c.max(1.0e-4).min(1.0).ln() / denom;
+                    tmp * tmp
+                };
+                Vec4::new(sa(c.x()), sa(c.y()), sa(c.z()), sa(c.w()))
+            };
+
+            let mut sin_2k_alpha = [ALPHA.sin(), 0.0, 0.0];
+            let mut cos_2k_alpha = [ALPHA.cos(), 0.0, 0.0];
+            for i in 1..3 {
+                sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+                cos_2k_alpha[i] = (cos_2k_alpha[i - 1] * cos_2k_alpha[i - 1])
+                    - (sin_2k_alpha[i - 1] * sin_2k_alpha[i - 1]);
+            }
+
+            Hair {
+                h: h,
+                gamma_o: h.asin(),
+                sigma_a: sigma_a,
+                v: [v0, v0 * 0.25, v0 * 4.0, v0 * 4.0],
+                s: s,
+                sin_2k_alpha: sin_2k_alpha,
+                cos_2k_alpha: cos_2k_alpha,
+            }
+        }
+
+        /// Returns the outgoing angle rotated by the cuticle tilt for the
+        /// given scattering event.
+        fn tilt(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+            let (s2a, c2a) = (&self.sin_2k_alpha, &self.cos_2k_alpha);
+            match p {
+                0 => (
+                    (sin_theta_o * c2a[1]) - (cos_theta_o * s2a[1]),
+                    (cos_theta_o * c2a[1]) + (sin_theta_o * s2a[1]),
+                ),
+                1 => (
+                    (sin_theta_o * c2a[0]) + (cos_theta_o * s2a[0]),
+                    (cos_theta_o * c2a[0]) - (sin_theta_o * s2a[0]),
+                ),
+                2 => (
+                    (sin_theta_o * c2a[2]) + (cos_theta_o * s2a[2]),
+                    (cos_theta_o * c2a[2]) - (sin_theta_o * s2a[2]),
+                ),
+                _ => (sin_theta_o, cos_theta_o),
+            }
+        }
+
+        /// Returns the angle of the refracted ray inside the fiber,
+        /// projected onto the plane perpendicular to it.
+        fn gamma_t(&self, cos_theta_o: f32) -> f32 {
+            let sin_theta_o = (1.0 - (cos_theta_o * cos_theta_o)).max(0.0).sqrt();
+            let etap = ((ETA * ETA) - (sin_theta_o * sin_theta_o)).sqrt() / cos_theta_o;
+            (self.h / etap).max(-1.0).min(1.0).asin()
+        }
+
+        /// Returns the attenuation for each scattering event.
+        fn ap(&self, cos_theta_o: f32) -> [Vec4; P_MAX + 1] {
+            // Transmittance of a single path through the fiber.
+            let transmittance = {
+                let sin_theta_o = (1.0 - (cos_theta_o * cos_theta_o)).max(0.0).sqrt();
+                let sin_theta_t = sin_theta_o / ETA;
+                let cos_theta_t = (1.0 - (sin_theta_t * sin_theta_t)).max(0.0).sqrt();
+                let cos_gamma_t = self.gamma_t(cos_theta_o).cos();
+                let d = self.sigma_a * (-2.0 * cos_gamma_t / cos_theta_t);
+                Vec4::new(d.x().exp(), d.y().exp(), d.z().exp(), d.w().exp())
+            };
+
+            let cos_gamma_o = (1.0 - (self.h * self.h)).max(0.0).sqrt();
+            let f = dielectric_fresnel(ETA * ETA, (cos_theta_o * cos_gamma_o).max(0.0).min(1.0));
+
+            let mut ap = [Vec4::splat(0.0); P_MAX + 1];
+            ap[0] = Vec4::splat(f);
+            ap[1] = transmittance * ((1.0 - f) * (1.0 - f));
+            for p in 2..P_MAX {
+                ap[p] = ap[p - 1] * transmittance * f;
+            }
+            let tf = transmittance * f;
+            let denom = Vec4::splat(1.0) - tf;
+            ap[P_MAX] = Vec4::new(
+                (ap[P_MAX - 1].x() * tf.x()) / denom.x(),
+                (ap[P_MAX - 1].y() * tf.y()) / denom.y(),
+                (ap[P_MAX - 1].z() * tf.z()) / denom.z(),
+                (ap[P_MAX - 1].w() * tf.w()) / denom.w(),
+            );
+
+            ap
+        }
+
+        /// Returns the probabilities of sampling each scattering event,
+        /// proportional to their average attenuation.
+        fn ap_pdf(&self, cos_theta_o: f32) -> [f32; P_MAX + 1] {
+            let ap = self.ap(cos_theta_o);
+            let mut ap_pdf = [0.0; P_MAX + 1];
+            let mut sum = 0.0;
+            for p in 0..=P_MAX {
+                ap_pdf[p] = (ap[p].x() + ap[p].y() + ap[p].z() + ap[p].w()) * 0.25;
+                sum += ap_pdf[p];
+            }
+            for pdf in &mut ap_pdf {
+                *pdf /= sum;
+            }
+            ap_pdf
+        }
+    }
+
+    /// Returns the sine and cosine of the longitudinal angle and the
+    /// azimuthal angle of a local space direction.
+    fn angles(v: Vector) -> (f32, f32, f32) {
+        let sin_theta = v.x().max(-1.0).min(1.0);
+        let cos_theta = (1.0 - (sin_theta * sin_theta)).max(0.0).sqrt();
+        (sin_theta, cos_theta, v.z().atan2(v.y()))
+    }
+
+    /// Longitudinal scattering function.
+    fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+        let a = cos_theta_i * cos_theta_o / v;
+        let b = sin_theta_i * sin_theta_o / v;
+        if v <= 0.1 {
+            // Computed in log space to avoid overflow.
+            (log_i0(a) - b - (1.0 / v) + 0.6931 + (1.0 / (2.0 * v)).ln()).exp()
+        } else {
+            ((-b).exp() * i0(a)) / ((1.0 / v).sinh() * 2.0 * v)
+        }
+    }
+
+    /// Azimuthal scattering function.
+    fn np(dphi: f32, p: usize, s: f32, gamma_o: f32, gamma_t: f32) -> f32 {
+        let mut d = dphi - phi(p, gamma_o, gamma_t);
+        while d > PI_32 {
+            d -= 2.0 * PI_32;
+        }
+        while d < -PI_32 {
+            d += 2.0 * PI_32;
+        }
+        trimmed_logistic(d, s, -PI_32, PI_32)
+    }
+
+    /// The azimuthal exit angle of the given scattering event.
+    fn phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+        (2.0 * p as f32 * gamma_t) - (2.0 * gamma_o) + (p as f32 * PI_32)
+    }
+
+    /// Modified Bessel function of the first kind.
+    fn i0(x: f32) -> f32 {
+        let mut val = 0.0;
+        let mut x2i = 1.0;
+        let mut ifact: f32 = 1.0;
+        let mut i4 = 1.0;
+        for i in 0..10 {
+            if i > 1 {
+                ifact *= i as f32;
+            }
+            val += x2i / (i4 * ifact * ifact);
+            x2i *= x * x;
+            i4 *= 4.0;
+        }
+        val
+    }
+
+    fn log_i0(x: f32) -> f32 {
+        if x > 12.0 {
+            x + (0.5 * (-(2.0 * PI_32).ln() + (1.0 / x).ln() + (1.0 / (8.0 * x))))
+        } else {
+            i0(x).ln()
+        }
+    }
+
+    fn logistic(x: f32, s: f32) -> f32 {
+        let x = x.abs();
+        let e = (-x / s).exp();
+        e / (s * (1.0 + e) * (1.0 + e))
+    }
+
+    fn logistic_cdf(x: f32, s: f32) -> f32 {
+        1.0 / (1.0 + (-x / s).exp())
+    }
+
+    fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+        logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+    }
+
+    fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+        let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+        let x = -s * ((1.0 / ((u * k) + logistic_cdf(a, s))) - 1.0).ln();
+        x.max(a).min(b)
+    }
+
+    /// Splits one sample value in [0, 1) into two, by de-interleaving its
+    /// bits.
+    fn demux_float(f: f32) -> (f32, f32) {
+        fn compact_1_by_1(mut x: u32) -> u32 {
+            x &= 0x5555_5555;
+            x = (x ^ (x >> 1)) & 0x3333_3333;
+            x = (x ^ (x >> 2)) & 0x0f0f_0f0f;
+            x = (x ^ (x >> 4)) & 0x00ff_00ff;
+            x = (x ^ (x >> 8)) & 0x0000_ffff;
+            x
+        }
+
+        let bits = (f.max(0.0) as f64 * (1u64 << 32) as f64).min(std::u32::MAX as f64) as u32;
+        (
+            compact_1_by_1(bits) as f32 / (1u32 << 16) as f32,
+            compact_1_by_1(bits >> 1) as f32 / (1u32 << 16) as f32,
+        )
+    }
+}
+
 /// Emit closure code.
 ///
 /// NOTE: this needs to be handled specially by the integrator!  It does not
 /// behave like a standard closure!
+/// Henyey-Greenstein phase function code.
+///
+/// Unlike the surface closures, there's no cosine factor here, since
+/// scattering inside a medium isn't relative to any surface.
+mod hg_closure {
+    use super::*;
+
+    use crate::math::coordinate_system_from_vector;
+
+    const INV_4_PI: f32 = 1.0 / (4.0 * PI_32);
+
+    pub fn sample(
+        g: f32,
+        inc: Vector,
+        uv: (f32, f32),
+        wavelength: f32,
+    ) -> (Vector, SpectralSample, f32) {
+        // Angle from the incoming direction.
+        let cos_theta = if g.abs() < 1.0e-3 {
+            1.0 - (2.0 * uv.0)
+        } else {
+            let sqr_term = (1.0 - (g * g)) / (1.0 - g + (2.0 * g * uv.0));
+            (1.0 + (g * g) - (sqr_term * sqr_term)) / (2.0 * g)
+        }
+        .max(-1.0)
+        .min(1.0);
+        let sin_theta = (1.0 - (cos_theta * cos_theta)).max(0.0).sqrt();
+        let phi = 2.0 * PI_32 * uv.1;
+
+        let (z, x, y) = coordinate_system_from_vector(inc.normalized());
+        let out = (x * (sin_theta * phi.cos())) + (y * (sin_theta * phi.sin())) + (z * cos_theta);
+
+        let p = hg(g, cos_theta);
+        (out, SpectralSample::from_value(p, wavelength), p)
+    }
+
+    pub fn evaluate(g: f32, inc: Vector, out: Vector, wavelength: f32) -> (SpectralSample, f32) {
+        let p = hg(g, dot(inc.normalized(), out.normalized()));
+        (SpectralSample::from_value(p, wavelength), p)
+    }
+
+    pub fn estimate_eval_over_sphere_light(
+        g: f32,
+        inc: Vector,
+        to_light_center: Vector,
+        light_radius_squared: f32,
+    ) -> f32 {
+        let dist2 = to_light_center.length2();
+        if dist2 <= light_radius_squared {
+            return 1.0;
+        }
+
+        // The phase function towards the light's center times the light's
+        // solid angle.
+        let sin_theta_max2 = (light_radius_squared / dist2).min(1.0);
+        let cos_theta_max = (1.0 - sin_theta_max2).sqrt();
+        let cos_theta = dot(inc.normalized(), to_light_center.normalized());
+        hg(g, cos_theta) * 2.0 * PI_32 * (1.0 - cos_theta_max)
+    }
+
+    /// The phase function for light scattered at an angle with the given
+    /// cosine from its original direction.
+    fn hg(g: f32, cos_theta: f32) -> f32 {
+        let denom = 1.0 + (g * g) - (2.0 * g * cos_theta);
+        INV_4_PI * (1.0 - (g * g)) / (denom * denom.sqrt())
+    }
+}
+
 mod emit_closure {
     use super::*;
 
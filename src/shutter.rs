@@ -0,0 +1,157 @@
+#![allow(dead_code)]
+
+/// The shape of the shutter's opening and closing over time, which
+/// determines how ray times are distributed within the shutter interval.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ShutterCurve {
+    /// Opens and closes instantly.
+    Box,
+
+    /// Opens and closes linearly, with each ramp taking up the given
+    /// fraction of the shutter interval.  The fraction is in [0, 0.5].
+    Trapezoid(f32),
+}
+
+/// Describes when and how the camera's shutter is open.
+///
+/// The scene's time samples span the time range [0, 1], and all of the
+/// times here are relative to that span rather than to the frame.  The
+/// span of time the samples cover is up to whoever writes the scene, so
+/// that is also who converts shutter times to this convention.
+#[derive(Debug, Copy, Clone)]
+pub struct Shutter {
+    /// When the shutter starts opening, as a fraction of the time sample
+    /// span.
+    pub open: f32,
+
+    /// When the shutter is fully closed again, as a fraction of the time
+    /// sample span.
+    pub close: f32,
+
+    pub curve: ShutterCurve,
+
+    /// The time the shutter takes to sweep from the top to the bottom of
+    /// the image, for rolling shutter.  Each scanline is exposed for
+    /// `close - open - rolling_duration`, starting at an offset
+    /// proportional to its distance from the top of the image.  Zero
+    /// means a global shutter.
+    pub rolling_duration: f32,
+}
+
+impl Shutter {
+    pub fn new() -> Shutter {
+        Shutter {
+            open: 0.0,
+            close: 1.0,
+            curve: ShutterCurve::Box,
+            rolling_duration: 0.0,
+        }
+    }
+
+    /// Maps `n` in [0, 1) to a ray time.
+    ///
+    /// - `n`: Uniformly distributed sample value.
+    /// - `image_y`: Vertical position on the image, from 0.0 at the top to
+    ///   1.0 at the bottom.  Only used for rolling shutter.
+    pub fn sample_time(&self, n: f32, image_y: f32) -> f32 {
+        let t = match self.curve {
+            ShutterCurve::Box => n,
+            ShutterCurve::Trapezoid(ramp) => sample_trapezoid(n, ramp),
+        };
+
+        let exposure = (self.close - self.open - self.rolling_duration).max(0.0);
+        let row_open = self.open + (self.rolling_duration * image_y.max(0.0).min(1.0));
+
+        row_open + (t * exposure)
+    }
+}
+
+impl Default for Shutter {
+    fn default() -> Shutter {
+        Shutter::new()
+    }
+}
+
+/// Maps `n` in [0, 1) to [0, 1) distributed according to a trapezoid
+/// with linear ramps of width `ramp` at either end.
+fn sample_trapezoid(n: f32, ramp: f32) -> f32 {
+    let ramp = ramp.max(0.0).min(0.5);
+    if ramp <= 0.0 {
+        return n;
+    }
+
+    // Height of the plateau, normalizing the total area to 1.
+    let h = 1.0 / (1.0 - ramp);
+
+    // Area under each ramp.
+    let ramp_area = h * ramp * 0.5;
+
+    if n < ramp_area {
+        (2.0 * ramp * n / h).sqrt()
+    } else if n > (1.0 - ramp_area) {
+        1.0 - (2.0 * ramp * (1.0 - n) / h).sqrt()
+    } else {
+        ramp + ((n - ramp_area) / h)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn box_default() {
+        let shutter = Shutter::new();
+        assert_eq!(shutter.sample_time(0.0, 0.5), 0.0);
+        assert_eq!(shutter.sample_time(0.25, 0.5), 0.25);
+        assert_eq!(shutter.sample_time(0.75, 0.0), 0.75);
+    }
+
+    #[test]
+    fn open_close() {
+        let shutter = Shutter {
+            open: 0.25,
+            close: 0.75,
+            ..Shutter::new()
+        };
+        assert_eq!(shutter.sample_time(0.0, 0.5), 0.25);
+        assert_eq!(shutter.sample_time(0.5, 0.5), 0.5);
+        assert_eq!(shutter.sample_time(1.0, 0.5), 0.75);
+    }
+
+    #[test]
+    fn rolling() {
+        let shutter = Shutter {
+            rolling_duration: 0.5,
+            ..Shutter::new()
+        };
+        assert_eq!(shutter.sample_time(0.0, 0.0), 0.0);
+        assert_eq!(shutter.sample_time(1.0, 0.0), 0.5);
+        assert_eq!(shutter.sample_time(0.0, 1.0), 0.5);
+        assert_eq!(shutter.sample_time(1.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn trapezoid_endpoints_and_symmetry() {
+        for &ramp in &[0.0, 0.1, 0.25, 0.5] {
+            assert!(sample_trapezoid(0.0, ramp).abs() < 0.0001);
+            assert!((sample_trapezoid(1.0, ramp) - 1.0).abs() < 0.0001);
+            assert!((sample_trapezoid(0.5, ramp) - 0.5).abs() < 0.0001);
+            for &n in &[0.05, 0.2, 0.4] {
+                let a = sample_trapezoid(n, ramp);
+                let b = sample_trapezoid(1.0 - n, ramp);
+                assert!((a - (1.0 - b)).abs() < 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn trapezoid_monotonic() {
+        let mut prev = 0.0;
+        for i in 1..100 {
+            let t = sample_trapezoid(i as f32 / 100.0, 0.3);
+            assert!(t > prev);
+            prev = t;
+        }
+    }
+}
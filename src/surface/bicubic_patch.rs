@@ -0,0 +1,139 @@
+use super::{point_order, Patch, PointOrder};
+use crate::{
+    lerp::{lerp, lerp_slice_with},
+    math::{cross, Normal, Point, Vector},
+};
+
+/// A bicubic Bezier patch.
+#[derive(Debug, Copy, Clone)]
+pub struct BicubicPatch<'a> {
+    // The 16 control points for each time sample, stored in rows along u,
+    // with the corners in the same order as `BilinearPatch`:
+    //  u ----->
+    // v  0  .  .  3
+    // |  .  .  .  .
+    // |  .  .  .  .
+    // |  12 .  .  15
+    // \/
+    control_points: &'a [[Point; 16]],
+
+    // Indicates if any of the edges *must* be split, for example if there
+    // are adjacent patches that were split for non-dicing reasons.  Uses
+    // the same edge numbering as `BilinearPatch`.
+    must_split: [bool; 4],
+}
+
+impl<'a> BicubicPatch<'a> {
+    pub fn new(control_points: &'a [[Point; 16]], must_split: [bool; 4]) -> BicubicPatch<'a> {
+        BicubicPatch {
+            control_points: control_points,
+            must_split: must_split,
+        }
+    }
+}
+
+/// Cubic Bernstein basis functions and their derivatives at `t`.
+fn bernstein(t: f32) -> ([f32; 4], [f32; 4]) {
+    let s = 1.0 - t;
+    (
+        [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t],
+        [
+            -3.0 * s * s,
+            (3.0 * s * s) - (6.0 * t * s),
+            (6.0 * t * s) - (3.0 * t * t),
+            3.0 * t * t,
+        ],
+    )
+}
+
+/// Evaluates the position and partial derivatives of a single time sample
+/// of a bicubic Bezier patch.
+fn eval_bezier(patch: &[Point; 16], uv: (f32, f32)) -> (Point, Vector, Vector) {
+    let (bu, dbu) = bernstein(uv.0);
+    let (bv, dbv) = bernstein(uv.1);
+
+    let mut pos = Vector::new(0.0, 0.0, 0.0);
+    let mut dpdu = Vector::new(0.0, 0.0, 0.0);
+    let mut dpdv = Vector::new(0.0, 0.0, 0.0);
+    for j in 0..4 {
+        for i in 0..4 {
+            let p = patch[(j * 4) + i].into_vector();
+            pos = pos + (p * (bu[i] * bv[j]));
+            dpdu = dpdu + (p * (dbu[i] * bv[j]));
+            dpdv = dpdv + (p * (bu[i] * dbv[j]));
+        }
+    }
+
+    // Points on the edges of the patch are evaluated from just the edge's
+    // curve, so that adjacent patches get precisely the same points.
+    let edge = |a: usize, b: usize, c: usize, d: usize, t: f32| {
+        eval_edge([patch[a], patch[b], patch[c], patch[d]], t)
+    };
+    let pos = if uv.1 == 0.0 {
+        edge(0, 1, 2, 3, uv.0)
+    } else if uv.1 == 1.0 {
+        edge(12, 13, 14, 15, uv.0)
+    } else if uv.0 == 0.0 {
+        edge(0, 4, 8, 12, uv.1)
+    } else if uv.0 == 1.0 {
+        edge(3, 7, 11, 15, uv.1)
+    } else {
+        pos.into_point()
+    };
+
+    (pos, dpdu, dpdv)
+}
+
+/// Evaluates a cubic Bezier curve, always in the same direction regardless
+/// of the order of the control points.
+fn eval_edge(curve: [Point; 4], t: f32) -> Point {
+    let (curve, t) = match point_order(curve[0], curve[3]) {
+        PointOrder::AsIs => (curve, t),
+        PointOrder::Flip => ([curve[3], curve[2], curve[1], curve[0]], 1.0 - t),
+    };
+
+    let (b, _) = bernstein(t);
+    ((curve[0].into_vector() * b[0])
+        + (curve[1].into_vector() * b[1])
+        + (curve[2].into_vector() * b[2])
+        + (curve[3].into_vector() * b[3]))
+        .into_point()
+}
+
+impl<'a> Patch for BicubicPatch<'a> {
+    fn time_sample_count(&self) -> usize {
+        self.control_points.len()
+    }
+
+    fn eval(&self, uv: (f32, f32), time_sample: usize) -> (Point, Normal) {
+        let (pos, dpdu, dpdv) = eval_bezier(&self.control_points[time_sample], uv);
+        let nor = cross(dpdu, dpdv);
+
+        if nor.length2() > 0.0 {
+            (pos, nor.into_normal())
+        } else {
+            // Degenerate derivatives, which happens e.g. at collapsed
+            // corners.  Nudge towards the center of the patch to get a
+            // usable normal.
+            let uv2 = (lerp(uv.0, 0.5, 0.001), lerp(uv.1, 0.5, 0.001));
+            let (_, dpdu, dpdv) = eval_bezier(&self.control_points[time_sample], uv2);
+            (pos, cross(dpdu, dpdv).into_normal())
+        }
+    }
+
+    fn eval_at_time(&self, uv: (f32, f32), time: f32) -> Point {
+        let patch = lerp_slice_with(self.control_points, time, |a, b, alpha| {
+            let mut patch = a;
+            for i in 0..16 {
+                patch[i] = lerp(a[i], b[i], alpha);
+            }
+            patch
+        });
+
+        eval_bezier(&patch, uv).0
+    }
+
+    fn must_split(&self) -> [bool; 4] {
+        self.must_split
+    }
+}
@@ -1,7 +1,7 @@
-use super::{point_order, PointOrder, Splitable, MAX_EDGE_DICE};
+use super::{point_order, Patch, PointOrder};
 use crate::{
     lerp::{lerp, lerp_slice},
-    math::Point,
+    math::{cross, Normal, Point},
 };
 
 #[derive(Debug, Copy, Clone)]
@@ -13,6 +13,10 @@ pub struct BilinearPatch<'a> {
     // \/
     control_points: &'a [[Point; 4]],
 
+    // Optional shading normals at the control points, in the same layout.
+    // When not present, the true surface normal is used.
+    control_normals: Option<&'a [[Normal; 4]]>,
+
     // Indicates if any of the edges *must* be split, for example if there
     // are adjacent patches that were split for non-dicing reasons.
     //
@@ -26,114 +30,108 @@ pub struct BilinearPatch<'a> {
     must_split: [bool; 4],
 }
 
+impl<'a> BilinearPatch<'a> {
+    pub fn new(control_points: &'a [[Point; 4]], must_split: [bool; 4]) -> BilinearPatch<'a> {
+        BilinearPatch {
+            control_points: control_points,
+            control_normals: None,
+            must_split: must_split,
+        }
+    }
+
+    pub fn with_normals(
+        control_points: &'a [[Point; 4]],
+        control_normals: &'a [[Normal; 4]],
+        must_split: [bool; 4],
+    ) -> BilinearPatch<'a> {
+        assert_eq!(control_points.len(), control_normals.len());
+        BilinearPatch {
+            control_points: control_points,
+            control_normals: Some(control_normals),
+            must_split: must_split,
+        }
+    }
+}
+
 fn bilerp_point(patch: [Point; 4], uv: (f32, f32)) -> Point {
-    let a = lerp(patch[0], patch[1], uv.0);
-    let b = lerp(patch[3], patch[2], uv.0);
-    lerp(a, b, uv.1)
+    // Points on the edges of the patch are interpolated from just the
+    // edge's end points, so that adjacent patches get precisely the same
+    // points.
+    if uv.1 == 0.0 {
+        lerp_edge(patch[0], patch[1], uv.0)
+    } else if uv.1 == 1.0 {
+        lerp_edge(patch[3], patch[2], uv.0)
+    } else if uv.0 == 0.0 {
+        lerp_edge(patch[0], patch[3], uv.1)
+    } else if uv.0 == 1.0 {
+        lerp_edge(patch[1], patch[2], uv.1)
+    } else {
+        let a = lerp(patch[0], patch[1], uv.0);
+        let b = lerp(patch[3], patch[2], uv.0);
+        lerp(a, b, uv.1)
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub struct BilinearSubPatch<'a> {
-    original: &'a BilinearPatch<'a>,
-    clip: [(f32, f32); 4],
-    must_split: [bool; 4],
+/// Like `bilerp_point()`, but for the normals at the control points.
+///
+/// Normals on the edges of the patch are interpolated in the same
+/// direction as the points, so that adjacent patches agree on them too.
+/// This matters for displacement.
+fn bilerp_normal(patch: [Point; 4], nors: [Normal; 4], uv: (f32, f32)) -> Normal {
+    let lerp_edge_nor = |i1: usize, i2: usize, t: f32| match point_order(patch[i1], patch[i2]) {
+        PointOrder::AsIs => lerp(nors[i1], nors[i2], t),
+        PointOrder::Flip => lerp(nors[i2], nors[i1], 1.0 - t),
+    };
+
+    if uv.1 == 0.0 {
+        lerp_edge_nor(0, 1, uv.0)
+    } else if uv.1 == 1.0 {
+        lerp_edge_nor(3, 2, uv.0)
+    } else if uv.0 == 0.0 {
+        lerp_edge_nor(0, 3, uv.1)
+    } else if uv.0 == 1.0 {
+        lerp_edge_nor(1, 2, uv.1)
+    } else {
+        let a = lerp(nors[0], nors[1], uv.0);
+        let b = lerp(nors[3], nors[2], uv.0);
+        lerp(a, b, uv.1)
+    }
 }
 
-impl<'a> Splitable for BilinearSubPatch<'a> {
-    fn split<F>(&self, metric: F) -> Option<(Self, Self)>
-    where
-        F: Fn(Point, Point) -> f32,
-    {
-        // Get the points of the sub-patch at time 0.5.
-        let patch = lerp_slice(self.original.control_points, 0.5);
-        let points = [
-            bilerp_point(patch, self.clip[0]),
-            bilerp_point(patch, self.clip[1]),
-            bilerp_point(patch, self.clip[2]),
-            bilerp_point(patch, self.clip[3]),
-        ];
-
-        // Calculate edge metrics.
-        let edge_metric = [
-            metric(points[0], points[1]),
-            metric(points[1], points[2]),
-            metric(points[2], points[3]),
-            metric(points[3], points[0]),
-        ];
-
-        // Find an edge to split, if any.
-        let split_edge_index = {
-            // Find the "longest" edge in terms of the metric.
-            let (edge_i, m) = edge_metric
-                .iter()
-                .enumerate()
-                .max_by(|a, b| {
-                    if a.1 > b.1 {
-                        std::cmp::Ordering::Greater
-                    } else {
-                        std::cmp::Ordering::Less
-                    }
-                })
-                .unwrap();
-
-            // Return an edge to split, if a split is needed.
-            if *m > MAX_EDGE_DICE as f32 {
-                // Split needed because of over-long edge.
-                Some(edge_i)
-            } else {
-                // Return the the first edge with "must_split" set, if any.
-                // Otherwise returns `None`.
-                self.must_split
-                    .iter()
-                    .enumerate()
-                    .find(|a| *a.1)
-                    .map(|a| a.0)
-            }
-        };
-
-        // Do the split if needed
-        if let Some(i) = split_edge_index {
-            let edge_1 = (i, (i + 1) % 4);
-            let edge_2 = ((i + 2) % 4, (i + 3) % 4);
-            let new_must_split = {
-                let mut new_must_split = self.must_split;
-                new_must_split[edge_1.0] = false;
-                new_must_split[edge_2.0] = false;
-                new_must_split
-            };
-
-            let midpoint_1 = lerp(self.clip[edge_1.0], self.clip[edge_1.1], 0.5);
-            let midpoint_2 = {
-                let alpha =
-                    if self.must_split[edge_2.0] || edge_metric[edge_2.0] > MAX_EDGE_DICE as f32 {
-                        0.5
-                    } else {
-                        let edge_2_dice_rate = edge_metric[edge_2.0].ceil();
-                        (edge_2_dice_rate * 0.5).floor() / edge_2_dice_rate
-                    };
-
-                match point_order(points[edge_2.0], points[edge_2.1]) {
-                    PointOrder::AsIs => lerp(self.clip[edge_2.0], self.clip[edge_2.1], alpha),
-                    PointOrder::Flip => lerp(self.clip[edge_2.1], self.clip[edge_2.0], alpha),
-                }
-            };
-
-            // Build the new sub-patches
-            let mut patch_1 = BilinearSubPatch {
-                original: self.original,
-                clip: self.clip,
-                must_split: new_must_split,
-            };
-            let mut patch_2 = patch_1;
-            patch_1.clip[edge_1.1] = midpoint_1;
-            patch_1.clip[edge_2.0] = midpoint_2;
-            patch_2.clip[edge_1.0] = midpoint_1;
-            patch_2.clip[edge_2.1] = midpoint_2;
-
-            Some((patch_1, patch_2))
-        } else {
-            // No split
-            None
+/// Interpolates along an edge, always in the same direction regardless of
+/// the order of the end points.
+pub fn lerp_edge(p1: Point, p2: Point, t: f32) -> Point {
+    match point_order(p1, p2) {
+        PointOrder::AsIs => lerp(p1, p2, t),
+        PointOrder::Flip => lerp(p2, p1, 1.0 - t),
+    }
+}
+
+impl<'a> Patch for BilinearPatch<'a> {
+    fn time_sample_count(&self) -> usize {
+        self.control_points.len()
+    }
+
+    fn eval(&self, uv: (f32, f32), time_sample: usize) -> (Point, Normal) {
+        let patch = self.control_points[time_sample];
+        let pos = bilerp_point(patch, uv);
+
+        if let Some(normals) = self.control_normals {
+            return (pos, bilerp_normal(patch, normals[time_sample], uv));
         }
+
+        // Partial derivatives.
+        let dpdu = lerp(patch[1] - patch[0], patch[2] - patch[3], uv.1);
+        let dpdv = lerp(patch[3] - patch[0], patch[2] - patch[1], uv.0);
+
+        (pos, cross(dpdu, dpdv).into_normal())
+    }
+
+    fn eval_at_time(&self, uv: (f32, f32), time: f32) -> Point {
+        bilerp_point(lerp_slice(self.control_points, time), uv)
+    }
+
+    fn must_split(&self) -> [bool; 4] {
+        self.must_split
     }
 }
@@ -0,0 +1,426 @@
+//! Cubic curves with varying width, for hair, fur, grass and the like.
+//!
+//! Curves are stored as cubic Bezier segments, and are intersected as flat
+//! strips that always face the ray, by recursively subdividing the
+//! segments in ray space until they're close enough to straight.  This is
+//! the method from "Ray Tracing for Curves Primitive" by Nakamaru and Ohno,
+//! as also used in "Physically Based Rendering" by Pharr et al.
+
+use glam::Vec4;
+use kioku::Arena;
+
+use crate::{
+    accel::{SplitMode, WideBVH},
+    bbox::BBox,
+    boundable::Boundable,
+    fp_utils::transformed_pos_err,
+    lerp::lerp_slice,
+    math::{coordinate_system_from_vector, cross, dot, Matrix4x4, Point, Vector},
+    ray::{RayBatch, RayStack},
+    shading::SurfaceShader,
+};
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+
+const MAX_LEAF_SEGMENT_COUNT: usize = 2;
+const MAX_SUBDIVISION_DEPTH: i32 = 10;
+
+/// The basis the control points of a curve are specified in.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CurveBasis {
+    /// Four control points per segment, with neighboring segments sharing
+    /// their end points.
+    Bezier,
+
+    /// Uniform cubic B-spline, with one segment per control point beyond
+    /// the third.
+    BSpline,
+}
+
+/// How curves are shaded.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CurveShape {
+    /// Flat strips facing the ray.
+    Ribbon,
+
+    /// Strips facing the ray, but with normals that make them shade as if
+    /// they were round tubes.
+    Round,
+}
+
+/// A cubic Bezier segment.  Each control point is (x, y, z, width).
+type Segment = [Vec4; 4];
+
+#[derive(Copy, Clone, Debug)]
+pub struct CurveSurface<'a> {
+    shape: CurveShape,
+    time_sample_count: usize,
+    segments: &'a [Segment], // Segments, with the time samples for each segment stored contiguously
+    indices: &'a [u32],
+    accel: WideBVH<'a>,
+}
+
+impl<'a> CurveSurface<'a> {
+    /// Creates a new set of curves.
+    ///
+    /// - `verts`: The control points for each time sample.
+    /// - `widths`: The width at each control point, either for each time
+    ///   sample or once for all of them.
+    /// - `curve_vert_counts`: The number of control points in each curve.
+    pub fn new<'b>(
+        arena: &'b Arena,
+        basis: CurveBasis,
+        shape: CurveShape,
+        verts: &[Vec<Point>],
+        widths: &[Vec<f32>],
+        curve_vert_counts: &[usize],
+        split_mode: SplitMode,
+    ) -> CurveSurface<'b> {
+        let time_sample_count = verts.len();
+
+        // Convert the curves to Bezier segments, with the time samples of
+        // each segment next to each other.
+        let mut segments: Vec<Segment> = Vec::new();
+        let mut first_vert = 0;
+        for &count in curve_vert_counts {
+            let segment_count = match basis {
+                CurveBasis::Bezier => (count - 1) / 3,
+                CurveBasis::BSpline => count - 3,
+            };
+            for si in 0..segment_count {
+                for ti in 0..time_sample_count {
+                    let tws = &widths[ti.min(widths.len() - 1)];
+                    let cp = |i: usize| {
+                        let p = verts[ti][first_vert + i];
+                        Vec4::new(p.x(), p.y(), p.z(), tws[first_vert + i])
+                    };
+                    segments.push(match basis {
+                        CurveBasis::Bezier => {
+                            [cp(si * 3), cp(si * 3 + 1), cp(si * 3 + 2), cp(si * 3 + 3)]
+                        }
+                        CurveBasis::BSpline => {
+                            let (p0, p1, p2, p3) = (cp(si), cp(si + 1), cp(si + 2), cp(si + 3));
+                            [
+                                (p0 + (p1 * 4.0) + p2) * (1.0 / 6.0),
+                                ((p1 * 2.0) + p2) * (1.0 / 3.0),
+                                (p1 + (p2 * 2.0)) * (1.0 / 3.0),
+                                (p1 + (p2 * 4.0) + p3) * (1.0 / 6.0),
+                            ]
+                        }
+                    });
+                }
+            }
+            first_vert += count;
+        }
+        let segment_count = segments.len() / time_sample_count.max(1);
+
+        // Create bounds array for use during BVH construction
+        let bounds: Vec<_> = segments.iter().map(segment_bounds).collect();
+
+        // Build BVH.  With spatial splits, segments can end up in more than
+        // one leaf, so the segment indices the BVH refers to are what get
+        // stored.  The segments' bounds are clipped conservatively, since
+        // they're curved.
+        let indices: Vec<u32> = (0..segment_count as u32).collect();
+        let (accel, indices) = WideBVH::from_objects_with_splits(
+            arena,
+            &indices,
+            MAX_LEAF_SEGMENT_COUNT,
+            split_mode,
+            |&i| &bounds[(i as usize * time_sample_count)..((i as usize + 1) * time_sample_count)],
+            |_, bounds, axis, pos| bounds.split(axis, pos),
+        );
+        let indices = arena.copy_slice(&indices);
+
+        CurveSurface {
+            shape: shape,
+            time_sample_count: time_sample_count,
+            segments: arena.copy_slice(&segments),
+            indices: indices,
+            accel: accel,
+        }
+    }
+}
+
+impl<'a> Boundable for CurveSurface<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+impl<'a> Surface for CurveSurface<'a> {
+    fn intersect_rays(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        object_id: usize,
+    ) {
+        self.accel
+            .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
+                ray_stack.do_next_task(|ray_idx| {
+                    if rays.is_done(ray_idx) {
+                        return;
+                    }
+
+                    let ray_time = rays.time(ray_idx);
+
+                    // Get the transform space
+                    let xform = if space.is_empty() {
+                        Matrix4x4::new()
+                    } else {
+                        lerp_slice(space, ray_time)
+                    };
+
+                    // Get the ray origin and direction in local space, and
+                    // the transform into ray space.
+                    let orig = rays.orig_local(ray_idx).into_vector();
+                    let dir = rays.dir(ray_idx) * xform;
+                    let dir_len = dir.length();
+                    let (ray_z, ray_x, ray_y) = coordinate_system_from_vector(dir / dir_len);
+                    let to_ray_space = |cp: Vec4| {
+                        let v = Vector::new(cp.x(), cp.y(), cp.z()) - orig;
+                        Vec4::new(dot(v, ray_x), dot(v, ray_y), dot(v, ray_z), cp.w())
+                    };
+
+                    // Iterate through the segments and test the ray against
+                    // them.
+                    let mut hit = None;
+                    for &seg_idx in &self.indices[idx_range.clone()] {
+                        let seg = if self.time_sample_count == 1 {
+                            self.segments[seg_idx as usize]
+                        } else {
+                            lerp_slice(
+                                &self.segments[(seg_idx as usize * self.time_sample_count)
+                                    ..((seg_idx as usize + 1) * self.time_sample_count)],
+                                ray_time,
+                            )
+                        };
+
+                        let ray_seg = [
+                            to_ray_space(seg[0]),
+                            to_ray_space(seg[1]),
+                            to_ray_space(seg[2]),
+                            to_ray_space(seg[3]),
+                        ];
+                        let depth = subdivision_depth(&ray_seg);
+                        let max_z = rays.max_t(ray_idx) * dir_len;
+
+                        if let Some((z, u)) = intersect_segment(&ray_seg, (0.0, 1.0), depth, max_z)
+                        {
+                            if rays.is_occlusion(ray_idx) {
+                                isects[ray_idx] = SurfaceIntersection::Occlude;
+                                rays.mark_done(ray_idx);
+                                return;
+                            } else {
+                                let t = z / dir_len;
+                                rays.set_max_t(ray_idx, t);
+                                hit = Some((seg, t, u));
+                            }
+                        }
+                    }
+
+                    // Calculate intersection data if necessary.
+                    if let Some((seg, t, u)) = hit {
+                        let (center, width) = eval(&seg, u);
+                        let tangent = {
+                            let tangent = eval_derivative(&seg, u);
+                            if tangent.length2() > 0.0 {
+                                tangent
+                            } else {
+                                // Degenerate end point, so use the chord.
+                                let d = seg[3] - seg[0];
+                                Vector::new(d.x(), d.y(), d.z())
+                            }
+                        };
+                        let pos = orig + (dir * t);
+
+                        // The direction across the strip, and the signed offset
+                        // of the hit point from the center of the strip in
+                        // that direction, from -1 to 1.
+                        let side = cross(tangent, dir).normalized();
+                        let offset = if width > 0.0 {
+                            (dot(pos - center.into_vector(), side) / (width * 0.5))
+                                .max(-1.0)
+                                .min(1.0)
+                        } else {
+                            0.0
+                        };
+
+                        let facing = {
+                            let n = cross(side, tangent).normalized();
+                            if dot(n, dir) > 0.0 {
+                                -n
+                            } else {
+                                n
+                            }
+                        };
+                        let nor = match self.shape {
+                            CurveShape::Ribbon => facing,
+                            CurveShape::Round => {
+                                (facing * (1.0 - (offset * offset)).max(0.0).sqrt())
+                                    + (side * offset)
+                            }
+                        };
+
+                        // Transform everything into world space.
+                        let inv_xform = xform.inverse();
+                        let nor = nor.into_normal() * inv_xform;
+
+                        let intersection_data = SurfaceIntersectionData {
+                            incoming: rays.dir(ray_idx),
+                            t: t,
+                            pos: pos.into_point() * inv_xform,
+                            // The strip only approximates the curve's actual
+                            // surface, so this is conservative.
+                            pos_err: transformed_pos_err(pos.into_point(), width * 2.0, &inv_xform),
+                            nor: nor,
+                            nor_g: nor,
+                            tangent: tangent * inv_xform,
+                            color: None,
+                            object_id: object_id,
+                            local_space: xform,
+                            sample_pdf: 0.0,
+                        };
+
+                        // Fill in intersection data
+                        isects[ray_idx] = SurfaceIntersection::Hit {
+                            intersection_data: intersection_data,
+                            closure: shader.shade(&intersection_data, ray_time),
+                        };
+                    }
+                });
+                ray_stack.pop_task();
+            });
+    }
+}
+
+/// Intersects a ray-space segment with the ray, which starts at the origin
+/// and points down +z.
+///
+/// Returns the ray-space z and the curve parameter of the nearest hit
+/// that's less than `max_z`, if any.
+fn intersect_segment(
+    seg: &Segment,
+    u_range: (f32, f32),
+    depth: i32,
+    max_z: f32,
+) -> Option<(f32, f32)> {
+    // Cull the segment if its bounds, expanded by its width, miss the ray.
+    let half_width = seg[0].w().max(seg[1].w()).max(seg[2].w()).max(seg[3].w()) * 0.5;
+    let min = seg[0].min(seg[1]).min(seg[2].min(seg[3]));
+    let max = seg[0].max(seg[1]).max(seg[2].max(seg[3]));
+    if (min.x() - half_width) > 0.0
+        || (max.x() + half_width) < 0.0
+        || (min.y() - half_width) > 0.0
+        || (max.y() + half_width) < 0.0
+        || (min.z() - half_width) > max_z
+        || (max.z() + half_width) < 0.0
+    {
+        return None;
+    }
+
+    if depth > 0 {
+        // Split and recurse, nearest hit wins.
+        let (seg1, seg2) = split(seg);
+        let u_mid = (u_range.0 + u_range.1) * 0.5;
+        let hit1 = intersect_segment(&seg1, (u_range.0, u_mid), depth - 1, max_z);
+        let max_z = hit1.map_or(max_z, |hit| hit.0);
+        let hit2 = intersect_segment(&seg2, (u_mid, u_range.1), depth - 1, max_z);
+        return hit2.or(hit1);
+    }
+
+    // The segment is close enough to straight, so intersect it as a line
+    // segment.  First make sure the ray is between the lines perpendicular
+    // to the curve at its ends.
+    let (p0, p1, p2, p3) = (seg[0], seg[1], seg[2], seg[3]);
+    if ((p1.y() - p0.y()) * -p0.y()) + (p0.x() * (p0.x() - p1.x())) < 0.0
+        || ((p2.y() - p3.y()) * -p3.y()) + (p3.x() * (p3.x() - p2.x())) < 0.0
+    {
+        return None;
+    }
+
+    // Find the closest point on the segment to the ray.
+    let (dx, dy) = (p3.x() - p0.x(), p3.y() - p0.y());
+    let denom = (dx * dx) + (dy * dy);
+    if denom == 0.0 {
+        return None;
+    }
+    let w = (((-p0.x() * dx) + (-p0.y() * dy)) / denom)
+        .max(0.0)
+        .min(1.0);
+    let (pc, width) = eval(seg, w);
+
+    // Check if that's within the curve's width and the ray's extent.
+    let dist2 = (pc.x() * pc.x()) + (pc.y() * pc.y());
+    if dist2 > (width * width * 0.25) || pc.z() < 0.0 || pc.z() > max_z {
+        return None;
+    }
+
+    Some((pc.z(), u_range.0 + ((u_range.1 - u_range.0) * w)))
+}
+
+/// Returns the number of times a ray-space segment should be split in
+/// half to be close enough to straight for intersection.
+fn subdivision_depth(seg: &Segment) -> i32 {
+    let mut l0: f32 = 0.0;
+    for i in 0..2 {
+        let d = (seg[i] - (seg[i + 1] * 2.0) + seg[i + 2]).abs();
+        l0 = l0.max(d.x()).max(d.y()).max(d.z());
+    }
+
+    let eps = seg[0].w().max(seg[1].w()).max(seg[2].w()).max(seg[3].w()) * 0.05;
+    if eps <= 0.0 {
+        return 0;
+    }
+
+    let r0 = (std::f32::consts::SQRT_2 * 6.0 * l0 / (8.0 * eps)).log2() * 0.5;
+    (r0.round() as i32).max(0).min(MAX_SUBDIVISION_DEPTH)
+}
+
+/// Splits a segment in half.
+fn split(seg: &Segment) -> (Segment, Segment) {
+    let p01 = (seg[0] + seg[1]) * 0.5;
+    let p12 = (seg[1] + seg[2]) * 0.5;
+    let p23 = (seg[2] + seg[3]) * 0.5;
+    let p012 = (p01 + p12) * 0.5;
+    let p123 = (p12 + p23) * 0.5;
+    let mid = (p012 + p123) * 0.5;
+
+    ([seg[0], p01, p012, mid], [mid, p123, p23, seg[3]])
+}
+
+/// Returns the position and width of a segment at `u`.
+fn eval(seg: &Segment, u: f32) -> (Point, f32) {
+    let iu = 1.0 - u;
+    let p = (seg[0] * (iu * iu * iu))
+        + (seg[1] * (3.0 * iu * iu * u))
+        + (seg[2] * (3.0 * iu * u * u))
+        + (seg[3] * (u * u * u));
+
+    (Point::new(p.x(), p.y(), p.z()), p.w())
+}
+
+/// Returns the derivative of a segment's position at `u`.
+fn eval_derivative(seg: &Segment, u: f32) -> Vector {
+    let iu = 1.0 - u;
+    let d = ((seg[1] - seg[0]) * (3.0 * iu * iu))
+        + ((seg[2] - seg[1]) * (6.0 * iu * u))
+        + ((seg[3] - seg[2]) * (3.0 * u * u));
+
+    Vector::new(d.x(), d.y(), d.z())
+}
+
+/// Returns the bounds of a segment, including its width.
+fn segment_bounds(seg: &Segment) -> BBox {
+    let half_width = seg[0].w().max(seg[1].w()).max(seg[2].w()).max(seg[3].w()) * 0.5;
+    let min = seg[0].min(seg[1]).min(seg[2].min(seg[3]));
+    let max = seg[0].max(seg[1]).max(seg[2].max(seg[3]));
+
+    BBox::from_points(
+        Point::new(min.x(), min.y(), min.z()) - Vector::new(half_width, half_width, half_width),
+        Point::new(max.x(), max.y(), max.z()) + Vector::new(half_width, half_width, half_width),
+    )
+}
@@ -0,0 +1,567 @@
+//! Adaptive splitting and dicing of parametric patches into micropolygons.
+//!
+//! The splitting follows "DiagSplit: Parallel, Crack-free, Adaptive
+//! Tessellation for Micropolygon Rendering" by Fisher et al.: each edge
+//! gets a dice rate based on its length in camera space, and patches are
+//! split until all of their edges can be diced with at most
+//! `MAX_EDGE_DICE` segments.  When an edge whose dice rate is already
+//! decided gets split, it's split exactly on one of its dice points, so
+//! that it still matches up with the un-split neighbor on the other side.
+
+use kioku::Arena;
+
+use crate::{
+    accel::SplitMode,
+    algorithm::merge_slices_append,
+    camera::Camera,
+    lerp::{lerp, lerp_slice},
+    math::{Matrix4x4, Normal, Point},
+    shading::DisplacementShader,
+};
+
+use super::{
+    displaced_patch::DisplacedPatch, micropoly_batch::MicropolyBatch, point_order, Patch,
+    PointOrder, Splitable, MAX_EDGE_DICE,
+};
+
+// Limit on how many times a patch can be recursively split, so that
+// geometry very close to the camera doesn't blow up.
+const MAX_SPLIT_DEPTH: u32 = 24;
+
+/// Information needed to dice geometry: how finely to dice it, and how to
+/// build the acceleration structure of the result.
+#[derive(Debug, Clone)]
+pub struct DicingContext<'a> {
+    camera: Camera<'a>,
+    image_width: usize,
+
+    // Target micropolygon edge length, in pixels.
+    dicing_rate: f32,
+
+    // How to split the BVH nodes of the diced micropolygons.
+    split_mode: SplitMode,
+
+    // Local-to-world transforms for each place the geometry currently
+    // being diced is instanced, at the middle of the shutter.
+    local_to_world: Vec<Matrix4x4>,
+
+    // The time samples of the world-to-local transform of the current
+    // space, composed the same way as during tracing, if the space is
+    // placed exactly once.  An empty list means world space.
+    space: Option<Vec<Matrix4x4>>,
+}
+
+impl<'a> DicingContext<'a> {
+    pub fn new(camera: Camera<'a>, image_width: usize, dicing_rate: f32) -> DicingContext<'a> {
+        DicingContext {
+            camera: camera,
+            image_width: image_width,
+            dicing_rate: dicing_rate,
+            split_mode: SplitMode::Object,
+            local_to_world: vec![Matrix4x4::new()],
+            space: Some(Vec::new()),
+        }
+    }
+
+    pub fn camera(&self) -> &Camera<'a> {
+        &self.camera
+    }
+
+    /// Creates a copy of the context that builds the acceleration
+    /// structures of diced geometry with `split_mode`.
+    pub fn with_split_mode(&self, split_mode: SplitMode) -> DicingContext<'a> {
+        DicingContext {
+            split_mode: split_mode,
+            ..self.clone()
+        }
+    }
+
+    /// Creates a new context for data instanced within the current space.
+    ///
+    /// `instance_xforms` contains the time samples of each instance's
+    /// transform, as specified in the scene file.  Instances without any
+    /// transform should be given an empty list.  If there are no instances
+    /// at all, the current space is used as-is.
+    pub fn instanced(&self, instance_xforms: &[Vec<Matrix4x4>]) -> DicingContext<'a> {
+        if instance_xforms.is_empty() {
+            return self.clone();
+        }
+
+        let mut local_to_world = Vec::new();
+        for parent in &self.local_to_world {
+            let parent_xform = parent.inverse();
+            for xforms in instance_xforms {
+                if xforms.is_empty() {
+                    local_to_world.push(*parent);
+                } else {
+                    local_to_world.push((parent_xform * lerp_slice(xforms, 0.5)).inverse());
+                }
+            }
+        }
+
+        let space = match instance_xforms {
+            [xforms] => self.instance_space(xforms),
+            _ => None,
+        };
+
+        DicingContext {
+            local_to_world: local_to_world,
+            space: space,
+            ..self.clone()
+        }
+    }
+
+    /// Returns the time samples of the world-to-local transform of an
+    /// instance with transform `xforms` in the current space, or `None` if
+    /// the current space is placed more than once.
+    pub fn instance_space(&self, xforms: &[Matrix4x4]) -> Option<Vec<Matrix4x4>> {
+        let parent = self.space.as_ref()?;
+        if parent.is_empty() {
+            Some(xforms.to_vec())
+        } else if xforms.is_empty() {
+            Some(parent.clone())
+        } else {
+            let mut space = Vec::new();
+            merge_slices_append(parent, xforms, &mut space, |xf1, xf2| *xf1 * *xf2);
+            Some(space)
+        }
+    }
+
+    /// Returns how many micropolygons the edge from `p1` to `p2` should be
+    /// diced into, where the points are in the local space of the
+    /// geometry.
+    ///
+    /// The result is not rounded.  For geometry that's instanced multiple
+    /// times, the finest dicing of all the instances is used.
+    pub fn edge_metric(&self, p1: Point, p2: Point) -> f32 {
+        let mut metric = 0.0f32;
+        for xform in &self.local_to_world {
+            let p1 = p1 * *xform;
+            let p2 = p2 * *xform;
+            let pixel_width = self
+                .camera
+                .pixel_width_at(lerp(p1, p2, 0.5), 0.5, self.image_width)
+                * self.dicing_rate;
+            let length = (p2 - p1).length();
+
+            if pixel_width > 0.0 {
+                metric = metric.max(length / pixel_width);
+            } else if length > 0.0 {
+                return std::f32::INFINITY;
+            }
+        }
+        metric
+    }
+}
+
+/// Splits and dices `patches` into a single micropolygon batch, applying
+/// `displacement` if given.
+///
+/// The bounds of displaced batches are padded by the displacement's bound.
+pub fn dice_patches<'b, P: Patch>(
+    arena: &'b Arena,
+    patches: &[P],
+    ctx: &DicingContext,
+    displacement: Option<&dyn DisplacementShader>,
+) -> MicropolyBatch<'b> {
+    if let Some(shader) = displacement {
+        let displaced: Vec<_> = patches
+            .iter()
+            .map(|patch| DisplacedPatch::new(patch, shader))
+            .collect();
+        split_and_dice(arena, &displaced, ctx).with_bounds_padding(arena, shader.bound())
+    } else {
+        split_and_dice(arena, patches, ctx)
+    }
+}
+
+fn split_and_dice<'b, P: Patch>(
+    arena: &'b Arena,
+    patches: &[P],
+    ctx: &DicingContext,
+) -> MicropolyBatch<'b> {
+    let metric = |p1: Point, p2: Point| ctx.edge_metric(p1, p2);
+    let time_sample_count = patches.first().map_or(1, |p| p.time_sample_count());
+
+    let mut grids = MicropolyGrids {
+        verts: vec![Vec::new(); time_sample_count],
+        normals: vec![Vec::new(); time_sample_count],
+        tris: Vec::new(),
+    };
+
+    let mut stack = Vec::new();
+    for patch in patches {
+        assert_eq!(patch.time_sample_count(), time_sample_count);
+
+        stack.push(SubPatch::new(patch));
+        while let Some(sub_patch) = stack.pop() {
+            if let Some((a, b)) = sub_patch.split(&metric) {
+                stack.push(a);
+                stack.push(b);
+            } else {
+                sub_patch.dice(&metric, &mut grids);
+            }
+        }
+    }
+
+    MicropolyBatch::from_verts_and_indices(
+        arena,
+        &grids.verts,
+        &grids.normals,
+        &grids.tris,
+        ctx.split_mode,
+    )
+}
+
+/// A quadrilateral region of a patch in the patch's parametric space.
+pub struct SubPatch<'a, P: Patch> {
+    patch: &'a P,
+
+    // The corners of the sub-patch in the patch's uv space, in the same
+    // order as the patch's corners.
+    clip: [(f32, f32); 4],
+
+    // The dice rate of each edge, if it has already been decided by the
+    // split of a parent.  A rate of zero means the edge has collapsed
+    // to a single point.
+    rates: [Option<u32>; 4],
+
+    must_split: [bool; 4],
+    depth: u32,
+}
+
+// Implemented by hand because deriving them would require `P: Copy`.
+impl<'a, P: Patch> Copy for SubPatch<'a, P> {}
+impl<'a, P: Patch> Clone for SubPatch<'a, P> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, P: Patch> SubPatch<'a, P> {
+    pub fn new(patch: &'a P) -> SubPatch<'a, P> {
+        SubPatch {
+            patch: patch,
+            clip: [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)],
+            rates: [None; 4],
+            must_split: patch.must_split(),
+            depth: 0,
+        }
+    }
+
+    /// Returns the corner points of the sub-patch at time 0.5.
+    fn corner_points(&self) -> [Point; 4] {
+        [
+            self.patch.eval_at_time(self.clip[0], 0.5),
+            self.patch.eval_at_time(self.clip[1], 0.5),
+            self.patch.eval_at_time(self.clip[2], 0.5),
+            self.patch.eval_at_time(self.clip[3], 0.5),
+        ]
+    }
+
+    /// Returns the metric of each edge, and the dice rate of each edge if
+    /// it's short enough to be diced.
+    ///
+    /// To account for curvature, each edge is measured through its
+    /// midpoint rather than just between its end points.
+    fn edge_rates<F>(&self, points: &[Point; 4], metric: F) -> ([f32; 4], [Option<u32>; 4])
+    where
+        F: Fn(Point, Point) -> f32,
+    {
+        let mut metrics = [0.0f32; 4];
+        let mut rates = self.rates;
+        for i in 0..4 {
+            let i2 = (i + 1) % 4;
+            let (p1, p2) = match point_order(points[i], points[i2]) {
+                PointOrder::AsIs => (points[i], points[i2]),
+                PointOrder::Flip => (points[i2], points[i]),
+            };
+            let mid = self.patch.eval_at_time(self.edge_uv(points, i, 1, 2), 0.5);
+            metrics[i] = metric(p1, mid) + metric(mid, p2);
+
+            if rates[i].is_none() && metrics[i] <= MAX_EDGE_DICE as f32 {
+                rates[i] = Some((metrics[i].ceil() as u32).max(1));
+            }
+        }
+
+        (metrics, rates)
+    }
+
+    /// Returns the uv coordinates of the `k`th of the `rate` dice points of
+    /// edge `i`.
+    ///
+    /// The points are always computed in the same direction along the
+    /// edge regardless of which patch the edge belongs to, so that
+    /// neighboring patches get precisely the same points.
+    fn edge_uv(&self, points: &[Point; 4], i: usize, k: u32, rate: u32) -> (f32, f32) {
+        let i2 = (i + 1) % 4;
+        match point_order(points[i], points[i2]) {
+            PointOrder::AsIs => lerp_uv(self.clip[i], self.clip[i2], k as f32 / rate as f32),
+            PointOrder::Flip => {
+                lerp_uv(self.clip[i2], self.clip[i], (rate - k) as f32 / rate as f32)
+            }
+        }
+    }
+
+    /// Dices the sub-patch into micropolygons, adding them to `grids`.
+    fn dice<F>(&self, metric: F, grids: &mut MicropolyGrids)
+    where
+        F: Fn(Point, Point) -> f32,
+    {
+        let points = self.corner_points();
+        let (_, rates) = self.edge_rates(&points, metric);
+
+        // Any edges that still don't have a rate are too long, but we've
+        // run out of splits, so just clamp them.
+        let rates = [
+            rates[0].unwrap_or(MAX_EDGE_DICE),
+            rates[1].unwrap_or(MAX_EDGE_DICE),
+            rates[2].unwrap_or(MAX_EDGE_DICE),
+            rates[3].unwrap_or(MAX_EDGE_DICE),
+        ];
+
+        // Corner vertices, sharing vertices between corners that have
+        // collapsed together.
+        let mut corners = [0; 4];
+        for i in 0..4 {
+            corners[i] = match (0..i).find(|&j| self.clip[j] == self.clip[i]) {
+                Some(j) => corners[j],
+                None => grids.add_vertex(self.patch, self.clip[i]),
+            };
+        }
+
+        // The vertices along each edge, along with their parametric
+        // position along the edge.
+        let mut edges: [Vec<(usize, f32)>; 4] = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+        for i in 0..4 {
+            let rate = rates[i];
+            edges[i].push((corners[i], 0.0));
+            for k in 1..rate {
+                let vi = grids.add_vertex(self.patch, self.edge_uv(&points, i, k, rate));
+                edges[i].push((vi, k as f32 / rate as f32));
+            }
+            if rate > 0 {
+                edges[i].push((corners[(i + 1) % 4], 1.0));
+            }
+        }
+
+        // Dice rates of the interior.
+        let nu = rates[0].max(rates[2]).max(1);
+        let nv = rates[1].max(rates[3]).max(1);
+
+        if nv == 1 {
+            // No interior vertices, so just zip the two long edges
+            // together.
+            let edge_2: Vec<_> = edges[2].iter().rev().map(|&(v, t)| (v, 1.0 - t)).collect();
+            grids.zip(&edges[0], &edge_2);
+        } else if nu == 1 {
+            let edge_3: Vec<_> = edges[3].iter().rev().map(|&(v, t)| (v, 1.0 - t)).collect();
+            grids.zip(&edges[1], &edge_3);
+        } else {
+            // Interior grid vertices, excluding the outer ring.
+            let interior_uv = |i: u32, j: u32| {
+                let s = i as f32 / nu as f32;
+                let t = j as f32 / nv as f32;
+                lerp_uv(
+                    lerp_uv(self.clip[0], self.clip[1], s),
+                    lerp_uv(self.clip[3], self.clip[2], s),
+                    t,
+                )
+            };
+            let row_len = (nu - 1) as usize;
+            let mut interior = Vec::with_capacity(row_len * (nv - 1) as usize);
+            for j in 1..nv {
+                for i in 1..nu {
+                    interior.push(grids.add_vertex(self.patch, interior_uv(i, j)));
+                }
+            }
+            let grid = |i: u32, j: u32| interior[((j - 1) as usize * row_len) + (i - 1) as usize];
+
+            // Interior quads.
+            for j in 1..(nv - 1) {
+                for i in 1..(nu - 1) {
+                    let v00 = grid(i, j);
+                    let v10 = grid(i + 1, j);
+                    let v11 = grid(i + 1, j + 1);
+                    let v01 = grid(i, j + 1);
+                    grids.add_tri(v00, v10, v11);
+                    grids.add_tri(v00, v11, v01);
+                }
+            }
+
+            // Stitch the edges to the outer ring of the interior grid.
+            let ring = |verts: Vec<usize>| -> Vec<(usize, f32)> {
+                let n = (verts.len() + 1) as f32;
+                verts
+                    .iter()
+                    .enumerate()
+                    .map(|(i, &v)| (v, (i + 1) as f32 / n))
+                    .collect()
+            };
+            let inner = [
+                ring((1..nu).map(|i| grid(i, 1)).collect()),
+                ring((1..nv).map(|j| grid(nu - 1, j)).collect()),
+                ring((1..nu).rev().map(|i| grid(i, nv - 1)).collect()),
+                ring((1..nv).rev().map(|j| grid(1, j)).collect()),
+            ];
+            for i in 0..4 {
+                grids.zip(&edges[i], &inner[i]);
+            }
+        }
+    }
+}
+
+impl<'a, P: Patch> Splitable for SubPatch<'a, P> {
+    fn split<F>(&self, metric: F) -> Option<(Self, Self)>
+    where
+        F: Fn(Point, Point) -> f32,
+    {
+        if self.depth >= MAX_SPLIT_DEPTH {
+            return None;
+        }
+
+        let points = self.corner_points();
+        let (metrics, rates) = self.edge_rates(&points, metric);
+        let needs_split = |i: usize| rates[i].is_none() || self.must_split[i];
+
+        // Pick which pair of opposite edges to split, if any.
+        let split_u = needs_split(0) || needs_split(2);
+        let split_v = needs_split(1) || needs_split(3);
+        let e = match (split_u, split_v) {
+            (false, false) => return None,
+            (true, false) => 0,
+            (false, true) => 1,
+            (true, true) => {
+                if (metrics[0] + metrics[2]) >= (metrics[1] + metrics[3]) {
+                    0
+                } else {
+                    1
+                }
+            }
+        };
+
+        // Splits edge `i`, returning the split point and the dice rates of
+        // the halves adjacent to the edge's first and second corners.
+        let split_edge = |i: usize| -> ((f32, f32), Option<u32>, Option<u32>) {
+            let rate = if self.must_split[i] { None } else { rates[i] };
+            match rate {
+                None => (self.edge_uv(&points, i, 1, 2), None, None),
+                Some(0) => (self.clip[i], Some(0), Some(0)),
+                Some(rate) => {
+                    // Split on a dice point, so the halves' rates add up
+                    // to the original rate.
+                    let half = rate / 2;
+                    match point_order(points[i], points[(i + 1) % 4]) {
+                        PointOrder::AsIs => (
+                            self.edge_uv(&points, i, half, rate),
+                            Some(half),
+                            Some(rate - half),
+                        ),
+                        PointOrder::Flip => (
+                            self.edge_uv(&points, i, rate - half, rate),
+                            Some(rate - half),
+                            Some(half),
+                        ),
+                    }
+                }
+            }
+        };
+        let edge_1 = (e, e + 1);
+        let edge_2 = (e + 2, (e + 3) % 4);
+        let (midpoint_1, rate_1a, rate_1b) = split_edge(edge_1.0);
+        let (midpoint_2, rate_2a, rate_2b) = split_edge(edge_2.0);
+
+        let mut must_split = self.must_split;
+        must_split[edge_1.0] = false;
+        must_split[edge_2.0] = false;
+
+        // Build the new sub-patches.
+        let mut patch_1 = SubPatch {
+            patch: self.patch,
+            clip: self.clip,
+            rates: rates,
+            must_split: must_split,
+            depth: self.depth + 1,
+        };
+        let mut patch_2 = patch_1;
+
+        // The edge shared between the new sub-patches takes the place of
+        // one of the un-split edges in each, so it's important to not
+        // carry that edge's rate or must-split flag over.
+        patch_1.clip[edge_1.1] = midpoint_1;
+        patch_1.clip[edge_2.0] = midpoint_2;
+        patch_1.rates[edge_1.0] = rate_1a;
+        patch_1.rates[edge_1.1] = None;
+        patch_1.rates[edge_2.0] = rate_2b;
+        patch_1.must_split[edge_1.1] = false;
+
+        patch_2.clip[edge_1.0] = midpoint_1;
+        patch_2.clip[edge_2.1] = midpoint_2;
+        patch_2.rates[edge_1.0] = rate_1b;
+        patch_2.rates[edge_2.0] = rate_2a;
+        patch_2.rates[edge_2.1] = None;
+        patch_2.must_split[edge_2.1] = false;
+
+        Some((patch_1, patch_2))
+    }
+}
+
+/// Interpolates between two uv coordinates.
+///
+/// Unlike `lerp()`, this is exact for coordinate components that are the
+/// same in `a` and `b`, so that points on the edges of a patch stay
+/// precisely on its edges.  It's also exact at the end points.
+fn lerp_uv(a: (f32, f32), b: (f32, f32), alpha: f32) -> (f32, f32) {
+    if alpha >= 1.0 {
+        b
+    } else {
+        (a.0 + ((b.0 - a.0) * alpha), a.1 + ((b.1 - a.1) * alpha))
+    }
+}
+
+/// Accumulates diced micropolygons.
+struct MicropolyGrids {
+    verts: Vec<Vec<Point>>,    // One Vec per time sample
+    normals: Vec<Vec<Normal>>, // One Vec per time sample
+    tris: Vec<(usize, usize, usize)>,
+}
+
+impl MicropolyGrids {
+    fn add_vertex<P: Patch>(&mut self, patch: &P, uv: (f32, f32)) -> usize {
+        for ti in 0..self.verts.len() {
+            let (pos, nor) = patch.eval(uv, ti);
+            self.verts[ti].push(pos);
+            self.normals[ti].push(nor.normalized());
+        }
+        self.verts[0].len() - 1
+    }
+
+    fn add_tri(&mut self, v0: usize, v1: usize, v2: usize) {
+        // Skip triangles that have collapsed.
+        if v0 != v1 && v1 != v2 && v2 != v0 {
+            self.tris.push((v0, v1, v2));
+        }
+    }
+
+    /// Stitches together two parallel rows of vertices with triangles.
+    ///
+    /// Each vertex is paired with its parametric position along the rows,
+    /// which is used to keep the triangles well shaped.  The rows must run
+    /// in the same direction, with `b` on the inside of the patch relative
+    /// to `a`.
+    fn zip(&mut self, a: &[(usize, f32)], b: &[(usize, f32)]) {
+        let mut ai = 0;
+        let mut bi = 0;
+        while (ai + 1) < a.len() || (bi + 1) < b.len() {
+            let advance_a =
+                (bi + 1) >= b.len() || ((ai + 1) < a.len() && a[ai + 1].1 <= b[bi + 1].1);
+            if advance_a {
+                self.add_tri(a[ai].0, a[ai + 1].0, b[bi].0);
+                ai += 1;
+            } else {
+                self.add_tri(a[ai].0, b[bi + 1].0, b[bi].0);
+                bi += 1;
+            }
+        }
+    }
+}
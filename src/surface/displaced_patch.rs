@@ -0,0 +1,131 @@
+use crate::{
+    lerp::lerp,
+    math::{cross, Normal, Point, Vector},
+    shading::DisplacementShader,
+};
+
+use super::Patch;
+
+// Parametric offset used to compute the normals of the displaced surface.
+const NORMAL_EPSILON: f32 = 1.0e-4;
+
+/// Wraps a patch with a displacement shader.
+///
+/// Since the displacement is applied in `eval()` and `eval_at_time()`,
+/// the dicing both measures and dices the displaced surface.
+///
+/// Note that scalar displacement moves points along the undisplaced
+/// surface normal, so neighboring patches only stay watertight where
+/// their normals agree along the shared edge.
+#[derive(Debug, Copy, Clone)]
+pub struct DisplacedPatch<'a, P: Patch> {
+    patch: &'a P,
+    shader: &'a dyn DisplacementShader,
+}
+
+impl<'a, P: Patch> DisplacedPatch<'a, P> {
+    pub fn new(patch: &'a P, shader: &'a dyn DisplacementShader) -> DisplacedPatch<'a, P> {
+        DisplacedPatch {
+            patch: patch,
+            shader: shader,
+        }
+    }
+
+    /// Returns the displacement offset of `pos`, clamped to the shader's
+    /// bound.
+    fn offset(&self, pos: Point, nor: Normal, time: f32) -> Vector {
+        let offset = self.shader.displace(pos, nor.normalized(), time);
+        let bound = self.shader.bound();
+        let length = offset.length();
+        if length > bound {
+            offset * (bound / length)
+        } else {
+            offset
+        }
+    }
+
+    /// Returns the displaced position at `uv` for the given time sample.
+    ///
+    /// `uv` may be slightly outside of the patch's parametric domain, in
+    /// which case the undisplaced surface is extrapolated linearly from
+    /// the nearest point on the patch's edge.
+    fn displaced_point(&self, uv: (f32, f32), time_sample: usize) -> Point {
+        let clamped = (uv.0.clamp(0.0, 1.0), uv.1.clamp(0.0, 1.0));
+        let (pos, nor) = if clamped == uv {
+            self.patch.eval(uv, time_sample)
+        } else {
+            // Reflect the point that's just as far inside the patch
+            // through the point on its edge.
+            let mirrored = ((2.0 * clamped.0) - uv.0, (2.0 * clamped.1) - uv.1);
+            let (pos1, nor1) = self.patch.eval(clamped, time_sample);
+            let (pos2, nor2) = self.patch.eval(mirrored, time_sample);
+            (
+                pos1 + (pos1 - pos2),
+                (nor1.normalized() * 2.0) - nor2.normalized(),
+            )
+        };
+        pos + self.offset(pos, nor, self.sample_time(time_sample))
+    }
+
+    fn sample_time(&self, time_sample: usize) -> f32 {
+        let count = self.patch.time_sample_count();
+        if count > 1 {
+            time_sample as f32 / (count - 1) as f32
+        } else {
+            0.5
+        }
+    }
+}
+
+impl<'a, P: Patch> Patch for DisplacedPatch<'a, P> {
+    fn time_sample_count(&self) -> usize {
+        self.patch.time_sample_count()
+    }
+
+    fn eval(&self, uv: (f32, f32), time_sample: usize) -> (Point, Normal) {
+        let pos = self.displaced_point(uv, time_sample);
+
+        // Recompute the normal from central differences of the displaced
+        // surface.  At the patch's edges this reaches slightly past them,
+        // so that the displacement is sampled the same way on both sides
+        // of an edge and neighboring patches get the same normal along it.
+        let dpdu = self.displaced_point((uv.0 + NORMAL_EPSILON, uv.1), time_sample)
+            - self.displaced_point((uv.0 - NORMAL_EPSILON, uv.1), time_sample);
+        let dpdv = self.displaced_point((uv.0, uv.1 + NORMAL_EPSILON), time_sample)
+            - self.displaced_point((uv.0, uv.1 - NORMAL_EPSILON), time_sample);
+        let nor = cross(dpdu, dpdv);
+
+        if nor.length2() > 0.0 {
+            (pos, nor.into_normal())
+        } else {
+            // Degenerate, e.g. at a collapsed corner, so fall back to the
+            // undisplaced normal.
+            (pos, self.patch.eval(uv, time_sample).1)
+        }
+    }
+
+    fn eval_at_time(&self, uv: (f32, f32), time: f32) -> Point {
+        let pos = self.patch.eval_at_time(uv, time);
+
+        // Patches only provide normals at their time samples, so
+        // interpolate between those.
+        let count = self.patch.time_sample_count();
+        let nor = if count > 1 {
+            let t = time.max(0.0).min(1.0) * (count - 1) as f32;
+            let i = (t as usize).min(count - 2);
+            lerp(
+                self.patch.eval(uv, i).1.normalized(),
+                self.patch.eval(uv, i + 1).1.normalized(),
+                t - i as f32,
+            )
+        } else {
+            self.patch.eval(uv, 0).1
+        };
+
+        pos + self.offset(pos, nor, time)
+    }
+
+    fn must_split(&self) -> [bool; 4] {
+        self.patch.must_split()
+    }
+}
@@ -5,18 +5,23 @@ use std::collections::HashMap;
 use kioku::Arena;
 
 use crate::{
-    accel::BVH4,
+    accel::{SplitMode, WideBVH},
     bbox::BBox,
     boundable::Boundable,
-    lerp::lerp_slice,
-    math::{cross, dot, Matrix4x4, Normal, Point},
+    camera::Camera,
+    lerp::{lerp, lerp_slice},
+    math::{cross, dot, Matrix4x4, Normal, Point, Vector},
     ray::{RayBatch, RayStack},
-    shading::SurfaceClosure,
+    shading::{SurfaceClosure, SurfaceShader},
 };
 
-use super::{triangle, SurfaceIntersection, SurfaceIntersectionData};
+use super::{
+    triangle::{self, Triangle4},
+    Surface, SurfaceIntersection, SurfaceIntersectionData,
+};
 
-const MAX_LEAF_TRIANGLE_COUNT: usize = 3;
+// Leaves hold one `Triangle4` worth of triangles, for the SIMD kernel.
+const MAX_LEAF_TRIANGLE_COUNT: usize = 4;
 
 /// This is the core surface primitive for rendering: all surfaces are
 /// ultimately processed into pre-shaded micropolygon batches for rendering.
@@ -41,8 +46,17 @@ pub struct MicropolyBatch<'a> {
     // of a vertex, which indexes into all of the arrays above.
     indices: &'a [(u32, u32, u32)],
 
+    // The triangles of each BVH leaf, with the time samples for each leaf
+    // stored contiguously, and the index of each leaf's first triangle.
+    triangles: &'a [Triangle4],
+    leaf_starts: &'a [u32],
+
     // Acceleration structure for fast ray intersection testing.
-    accel: BVH4<'a>,
+    accel: WideBVH<'a>,
+
+    // Bounds of the batch at each time sample, which can be looser than
+    // those of the acceleration structure.
+    bounds: &'a [BBox],
 }
 
 impl<'a> MicropolyBatch<'a> {
@@ -51,6 +65,7 @@ impl<'a> MicropolyBatch<'a> {
         verts: &[Vec<Point>],
         vert_normals: &[Vec<Normal>],
         tri_indices: &[(usize, usize, usize)],
+        split_mode: SplitMode,
     ) -> MicropolyBatch<'b> {
         let vert_count = verts[0].len();
         let time_sample_count = verts.len();
@@ -87,16 +102,11 @@ impl<'a> MicropolyBatch<'a> {
             unsafe { std::mem::transmute(&normals[..]) }
         };
 
-        // Copy triangle vertex indices over, appending the triangle index itself to the tuple
-        let indices: &mut [(u32, u32, u32)] = {
-            let indices = arena.alloc_array_uninit(tri_indices.len());
-            for (i, tri_i) in tri_indices.iter().enumerate() {
-                unsafe {
-                    *indices[i].as_mut_ptr() = (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32);
-                }
-            }
-            unsafe { std::mem::transmute(indices) }
-        };
+        // Copy triangle vertex indices over, reordering their vertices
+        let indices: Vec<(u32, u32, u32)> = tri_indices
+            .iter()
+            .map(|tri_i| (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32))
+            .collect();
 
         // Create bounds array for use during BVH construction
         let (bounds, bounds_map) = {
@@ -114,16 +124,57 @@ impl<'a> MicropolyBatch<'a> {
                     bounds.push(BBox::from_points(minimum, maximum));
                 }
                 let end = bounds.len();
-                bounds_map.insert((tri.0 as u32, tri.1 as u32, tri.2 as u32), (start, end));
+                // Keyed the same way as the (reordered) `indices` above.
+                bounds_map.insert((tri.0 as u32, tri.2 as u32, tri.1 as u32), (start, end));
             }
             (bounds, bounds_map)
         };
 
-        // Build BVH
-        let accel = BVH4::from_objects(arena, &mut indices[..], MAX_LEAF_TRIANGLE_COUNT, |tri| {
-            let (start, end) = bounds_map[tri];
-            &bounds[start..end]
-        });
+        // Build BVH.  With spatial splits, triangles can end up in more
+        // than one leaf, so the indices the BVH refers to are what get
+        // stored.
+        let (accel, indices) = WideBVH::from_objects_with_splits(
+            arena,
+            &indices,
+            MAX_LEAF_TRIANGLE_COUNT,
+            split_mode,
+            |tri| {
+                let (start, end) = bounds_map[tri];
+                &bounds[start..end]
+            },
+            |tri, bounds, axis, pos| {
+                let tri_verts = (
+                    verts[0][tri.0 as usize],
+                    verts[0][tri.1 as usize],
+                    verts[0][tri.2 as usize],
+                );
+                triangle::split_bounds(tri_verts, bounds, axis, pos)
+            },
+        );
+
+        // Store the triangles of each leaf together in SoA layout.
+        let mut leaf_ranges = accel.leaf_object_ranges();
+        leaf_ranges.sort_unstable();
+        leaf_ranges.dedup();
+        leaf_ranges.retain(|&(start, end)| start < end);
+        let mut leaf_starts = Vec::with_capacity(leaf_ranges.len());
+        let mut triangles = Vec::with_capacity(leaf_ranges.len() * time_sample_count);
+        for &(start, end) in &leaf_ranges {
+            leaf_starts.push(start as u32);
+            for time_sample in verts {
+                let tris: Vec<_> = indices[start..end]
+                    .iter()
+                    .map(|tri| {
+                        (
+                            time_sample[tri.0 as usize],
+                            time_sample[tri.1 as usize],
+                            time_sample[tri.2 as usize],
+                        )
+                    })
+                    .collect();
+                triangles.push(Triangle4::from_triangles(&tris));
+            }
+        }
 
         MicropolyBatch {
             time_sample_count: time_sample_count,
@@ -132,25 +183,180 @@ impl<'a> MicropolyBatch<'a> {
             compressed_vertex_closure_size: 0,
             vertex_closure_time_sample_count: 1,
             compressed_vertex_closures: &[],
-            indices: indices,
+            indices: arena.copy_slice(&indices),
+            triangles: arena.copy_slice(&triangles),
+            leaf_starts: arena.copy_slice(&leaf_starts),
             accel: accel,
+            bounds: arena.copy_slice(accel.bounds()),
+        }
+    }
+
+    /// Creates a copy of the batch with its bounds grown by `padding` on
+    /// all sides, e.g. by the bound of the displacement it was diced with,
+    /// so that the bounds stay conservative for anything that assumes that
+    /// bound.
+    ///
+    /// Only the bounds the batch reports to the acceleration structures
+    /// it's placed in are padded.  Its own acceleration structure stays as
+    /// tight as its micropolygons.
+    pub fn with_bounds_padding<'b>(&self, arena: &'b Arena, padding: f32) -> MicropolyBatch<'b>
+    where
+        'a: 'b,
+    {
+        let bounds: Vec<_> = self.bounds.iter().map(|b| b.expanded(padding)).collect();
+        MicropolyBatch {
+            bounds: arena.copy_slice(&bounds),
+            ..*self
+        }
+    }
+}
+
+impl<'a> MicropolyBatch<'a> {
+    /// Creates a copy of the batch with its vertices pre-shaded by
+    /// `shader`, for a single placement of the batch in the scene.
+    ///
+    /// `space` is the world-to-local transform of that placement, as it
+    /// will be when tracing rays, and the shader is evaluated in world space
+    /// with the same inputs it would get when shading at hit time.  The
+    /// incoming direction is that of the camera ray reaching the vertex.
+    ///
+    /// The geometry and acceleration structure are shared with the
+    /// original batch, and only the closures are newly allocated.  The
+    /// shader is evaluated at as many times as there are time samples in
+    /// either the geometry or `space`, spread evenly over [0, 1].
+    pub fn preshaded<'b>(
+        &self,
+        arena: &'b Arena,
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        camera: &Camera,
+    ) -> MicropolyBatch<'b>
+    where
+        'a: 'b,
+    {
+        let vert_count = self.vertices.len() / self.time_sample_count;
+        let closure_time_sample_count = self.time_sample_count.max(space.len());
+
+        let closures: Vec<SurfaceClosure> = (0..(vert_count * closure_time_sample_count))
+            .map(|i| {
+                let vi = i / closure_time_sample_count;
+                let ti = i % closure_time_sample_count;
+                let time = if closure_time_sample_count > 1 {
+                    ti as f32 / (closure_time_sample_count - 1) as f32
+                } else {
+                    0.5
+                };
+
+                let samples = (vi * self.time_sample_count)..((vi + 1) * self.time_sample_count);
+                let mat_space = if space.is_empty() {
+                    Matrix4x4::new()
+                } else {
+                    lerp_slice(space, time).inverse()
+                };
+                let pos = lerp_slice(&self.vertices[samples.clone()], time) * mat_space;
+                let nor = (lerp_slice(&self.normals[samples], time).normalized() * mat_space)
+                    .normalized();
+
+                let data = SurfaceIntersectionData {
+                    incoming: camera.direction_to(pos, time),
+                    t: 0.0,
+                    pos: pos,
+                    pos_err: 0.0,
+                    nor: nor,
+                    nor_g: nor,
+                    tangent: Vector::new(0.0, 0.0, 0.0),
+                    color: None,
+                    object_id: 0,
+                    local_space: mat_space,
+                    sample_pdf: 0.0,
+                };
+                shader.shade(&data, time)
+            })
+            .collect();
+
+        // The closures can compress to different sizes, so pad them all
+        // out to the largest one so they can be indexed directly.
+        let closure_size = closures
+            .iter()
+            .map(|c| c.compressed_size())
+            .max()
+            .unwrap_or(0);
+        let compressed_vertex_closures = {
+            let bytes = arena.alloc_array_uninit(closures.len() * closure_size);
+            for byte in bytes.iter_mut() {
+                unsafe {
+                    *byte.as_mut_ptr() = 0u8;
+                }
+            }
+            let bytes: &mut [u8] = unsafe { std::mem::transmute(bytes) };
+            for (i, closure) in closures.iter().enumerate() {
+                closure.write_compressed(&mut bytes[(i * closure_size)..]);
+            }
+            bytes
+        };
+
+        MicropolyBatch {
+            time_sample_count: self.time_sample_count,
+            vertices: self.vertices,
+            normals: self.normals,
+            compressed_vertex_closure_size: closure_size,
+            vertex_closure_time_sample_count: closure_time_sample_count,
+            compressed_vertex_closures: compressed_vertex_closures,
+            indices: self.indices,
+            triangles: self.triangles,
+            leaf_starts: self.leaf_starts,
+            accel: self.accel,
+            bounds: self.bounds,
+        }
+    }
+
+    /// Returns the triangles of the BVH leaf whose triangles start at
+    /// `tri_idx`, at the given time.
+    fn leaf_triangles(&self, tri_idx: usize, time: f32) -> Triangle4 {
+        let leaf_i = self
+            .leaf_starts
+            .partition_point(|&start| start as usize <= tri_idx)
+            - 1;
+        let tsc = self.time_sample_count;
+        lerp_slice(&self.triangles[(leaf_i * tsc)..((leaf_i + 1) * tsc)], time)
+    }
+
+    /// Returns the pre-shaded closure of a vertex, interpolated to `time`.
+    fn vertex_closure(&self, vert_index: usize, time: f32) -> SurfaceClosure {
+        let closure = |ti: usize| {
+            let start_byte = ((vert_index * self.vertex_closure_time_sample_count) + ti)
+                * self.compressed_vertex_closure_size;
+            let end_byte = start_byte + self.compressed_vertex_closure_size;
+            SurfaceClosure::from_compressed(&self.compressed_vertex_closures[start_byte..end_byte])
+                .0
+        };
+
+        if self.vertex_closure_time_sample_count == 1 {
+            closure(0)
+        } else {
+            let tdiff = 1.0 / (self.vertex_closure_time_sample_count - 1) as f32;
+            let ti = ((time / tdiff) as usize).min(self.vertex_closure_time_sample_count - 2);
+            let alpha = (time - (ti as f32 * tdiff)) / tdiff;
+            lerp(closure(ti), closure(ti + 1), alpha)
         }
     }
 }
 
 impl<'a> Boundable for MicropolyBatch<'a> {
     fn bounds(&self) -> &[BBox] {
-        self.accel.bounds()
+        self.bounds
     }
 }
 
-impl<'a> MicropolyBatch<'a> {
+impl<'a> Surface for MicropolyBatch<'a> {
     fn intersect_rays(
         &self,
         rays: &mut RayBatch,
         ray_stack: &mut RayStack,
         isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
         space: &[Matrix4x4],
+        object_id: usize,
     ) {
         // Precalculate transform for non-motion blur cases
         let static_mat_space = if space.len() == 1 {
@@ -167,30 +373,17 @@ impl<'a> MicropolyBatch<'a> {
                 let is_cached = ray_stack.ray_count_in_next_task() >= tri_count
                     && self.time_sample_count == 1
                     && space.len() <= 1;
-                let mut tri_cache = [std::mem::MaybeUninit::uninit(); MAX_LEAF_TRIANGLE_COUNT];
-                if is_cached {
-                    for tri_idx in idx_range.clone() {
-                        let i = tri_idx - idx_range.start;
-                        let tri_indices = self.indices[tri_idx];
-
-                        // For static triangles with static transforms, cache them.
-                        unsafe {
-                            *tri_cache[i].as_mut_ptr() = (
-                                self.vertices[tri_indices.0 as usize],
-                                self.vertices[tri_indices.1 as usize],
-                                self.vertices[tri_indices.2 as usize],
-                            );
-                            if !space.is_empty() {
-                                (*tri_cache[i].as_mut_ptr()).0 =
-                                    (*tri_cache[i].as_mut_ptr()).0 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).1 =
-                                    (*tri_cache[i].as_mut_ptr()).1 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).2 =
-                                    (*tri_cache[i].as_mut_ptr()).2 * static_mat_space;
-                            }
-                        }
+                let tri_cache = if is_cached {
+                    // For static triangles with static transforms, cache them.
+                    let tris = self.leaf_triangles(idx_range.start, 0.0);
+                    if space.is_empty() {
+                        tris
+                    } else {
+                        tris.transformed(static_mat_space)
                     }
-                }
+                } else {
+                    Triangle4::new()
+                };
 
                 // Test each ray against the triangles.
                 ray_stack.do_next_task(|ray_idx| {
@@ -210,74 +403,38 @@ impl<'a> MicropolyBatch<'a> {
                         static_mat_space
                     };
 
-                    // Iterate through the triangles and test the ray against them.
+                    // Get the triangles if necessary.
+                    let tris = if is_cached {
+                        tri_cache
+                    } else {
+                        let tris = self.leaf_triangles(idx_range.start, ray_time);
+                        if space.is_empty() {
+                            tris
+                        } else {
+                            tris.transformed(mat_space)
+                        }
+                    };
+
+                    // Test the ray against the triangles, all four at once.
                     let mut non_shadow_hit = false;
                     let mut hit_tri = std::mem::MaybeUninit::uninit();
                     let mut hit_tri_indices = std::mem::MaybeUninit::uninit();
                     let mut hit_tri_data = std::mem::MaybeUninit::uninit();
                     let ray_pre = triangle::RayTriPrecompute::new(rays.dir(ray_idx));
-                    for tri_idx in idx_range.clone() {
-                        let tri_indices = self.indices[tri_idx];
-
-                        // Get triangle if necessary
-                        let tri = if is_cached {
-                            let i = tri_idx - idx_range.start;
-                            unsafe { tri_cache[i].assume_init() }
+                    if let Some((lane, t, b0, b1, b2)) =
+                        tris.intersect_ray(rays.orig(ray_idx), ray_pre, rays.max_t(ray_idx))
+                    {
+                        if rays.is_occlusion(ray_idx) {
+                            isects[ray_idx] = SurfaceIntersection::Occlude;
+                            rays.mark_done(ray_idx);
                         } else {
-                            let mut tri = if self.time_sample_count == 1 {
-                                // No deformation motion blur, so fast-path it.
-                                (
-                                    self.vertices[tri_indices.0 as usize],
-                                    self.vertices[tri_indices.1 as usize],
-                                    self.vertices[tri_indices.2 as usize],
-                                )
-                            } else {
-                                // Deformation motion blur, need to interpolate.
-                                let p0_slice = &self.vertices[(tri_indices.0 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.0 as usize + 1) * self.time_sample_count)];
-                                let p1_slice = &self.vertices[(tri_indices.1 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.1 as usize + 1) * self.time_sample_count)];
-                                let p2_slice = &self.vertices[(tri_indices.2 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.2 as usize + 1) * self.time_sample_count)];
-
-                                let p0 = lerp_slice(p0_slice, ray_time);
-                                let p1 = lerp_slice(p1_slice, ray_time);
-                                let p2 = lerp_slice(p2_slice, ray_time);
-
-                                (p0, p1, p2)
-                            };
-
-                            if !space.is_empty() {
-                                tri.0 = tri.0 * mat_space;
-                                tri.1 = tri.1 * mat_space;
-                                tri.2 = tri.2 * mat_space;
-                            }
-
-                            tri
-                        };
-
-                        // Test ray against triangle
-                        if let Some((t, b0, b1, b2)) = triangle::intersect_ray(
-                            rays.orig(ray_idx),
-                            ray_pre,
-                            rays.max_t(ray_idx),
-                            tri,
-                        ) {
-                            if rays.is_occlusion(ray_idx) {
-                                isects[ray_idx] = SurfaceIntersection::Occlude;
-                                rays.mark_done(ray_idx);
-                                break;
-                            } else {
-                                non_shadow_hit = true;
-                                rays.set_max_t(ray_idx, t);
-                                unsafe {
-                                    *hit_tri.as_mut_ptr() = tri;
-                                    *hit_tri_indices.as_mut_ptr() = tri_indices;
-                                    *hit_tri_data.as_mut_ptr() = (t, b0, b1, b2);
-                                }
+                            non_shadow_hit = true;
+                            rays.set_max_t(ray_idx, t);
+                            unsafe {
+                                *hit_tri.as_mut_ptr() = tris.triangle(lane);
+                                *hit_tri_indices.as_mut_ptr() =
+                                    self.indices[idx_range.start + lane];
+                                *hit_tri_data.as_mut_ptr() = (t, b0, b1, b2);
                             }
                         }
                     }
@@ -319,19 +476,6 @@ impl<'a> MicropolyBatch<'a> {
                             }
                         };
 
-                        // Calculate interpolated surface closure.
-                        // TODO: actually interpolate.
-                        let closure = {
-                            let start_byte = hit_tri_indices.0 as usize
-                                * self.compressed_vertex_closure_size
-                                * self.vertex_closure_time_sample_count;
-                            let end_byte = start_byte + self.compressed_vertex_closure_size;
-                            let (closure, _) = SurfaceClosure::from_compressed(
-                                &self.compressed_vertex_closures[start_byte..end_byte],
-                            );
-                            closure
-                        };
-
                         let intersection_data = SurfaceIntersectionData {
                             incoming: rays.dir(ray_idx),
                             t: t,
@@ -339,10 +483,30 @@ impl<'a> MicropolyBatch<'a> {
                             pos_err: pos_err,
                             nor: shading_normal,
                             nor_g: geo_normal,
+                            tangent: Vector::new(0.0, 0.0, 0.0),
+                            color: None,
+                            object_id: object_id,
                             local_space: mat_space,
                             sample_pdf: 0.0,
                         };
 
+                        // Calculate interpolated surface closure.
+                        let closure = if self.compressed_vertex_closures.is_empty() {
+                            // No pre-shaded closures, so shade directly.
+                            shader.shade(&intersection_data, ray_time)
+                        } else {
+                            let c0 = self.vertex_closure(hit_tri_indices.0 as usize, ray_time);
+                            let c1 = self.vertex_closure(hit_tri_indices.1 as usize, ray_time);
+                            let c2 = self.vertex_closure(hit_tri_indices.2 as usize, ray_time);
+                            let b01 = b0 + b1;
+                            let c01 = if b01 > 0.0 {
+                                lerp(c0, c1, b1 / b01)
+                            } else {
+                                c0
+                            };
+                            lerp(c01, c2, b2)
+                        };
+
                         // Fill in intersection data
                         isects[ray_idx] = SurfaceIntersection::Hit {
                             intersection_data: intersection_data,
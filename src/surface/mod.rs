@@ -1,8 +1,16 @@
 #![allow(dead_code)]
 
 // pub mod micropoly_batch;
+pub mod bicubic_patch;
 pub mod bilinear_patch;
+pub mod curve_surface;
+pub mod dicing;
+pub mod displaced_patch;
 pub mod micropoly_batch;
+pub mod points_surface;
+pub mod polygon_mesh;
+pub mod quadric;
+pub mod subdivision_surface;
 pub mod triangle;
 pub mod triangle_mesh;
 
@@ -10,6 +18,7 @@ use std::fmt::Debug;
 
 use crate::{
     boundable::Boundable,
+    color::Color,
     math::{Matrix4x4, Normal, Point, Vector},
     ray::{RayBatch, RayStack},
     shading::surface_closure::SurfaceClosure,
@@ -19,6 +28,11 @@ use crate::{
 const MAX_EDGE_DICE: u32 = 128;
 
 pub trait Surface: Boundable + Debug + Sync {
+    /// Intersects the rays of the next task on `ray_stack` with the surface,
+    /// filling in `isects` for the rays that hit it closer than before.
+    ///
+    /// `object_id` identifies the placement of the surface being traced,
+    /// and is what the hits' `object_id` is set to.
     fn intersect_rays(
         &self,
         rays: &mut RayBatch,
@@ -26,6 +40,7 @@ pub trait Surface: Boundable + Debug + Sync {
         isects: &mut [SurfaceIntersection],
         shader: &dyn SurfaceShader,
         space: &[Matrix4x4],
+        object_id: usize,
     );
 }
 
@@ -36,6 +51,24 @@ pub trait Splitable: Copy {
         F: Fn(Point, Point) -> f32;
 }
 
+/// A parametric quad patch that can be split and diced into micropolygons.
+///
+/// The patch is parameterized over [0, 1] x [0, 1], with its corners in
+/// the same order as `BilinearPatch`'s control points.
+pub trait Patch: Debug + Sync {
+    fn time_sample_count(&self) -> usize;
+
+    /// Returns the position and (not necessarily normalized) surface normal
+    /// at `uv` for the given time sample.
+    fn eval(&self, uv: (f32, f32), time_sample: usize) -> (Point, Normal);
+
+    /// Returns the position at `uv`, interpolated to `time`.
+    fn eval_at_time(&self, uv: (f32, f32), time: f32) -> Point;
+
+    /// Returns which edges must be split regardless of their dice rate.
+    fn must_split(&self) -> [bool; 4];
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum PointOrder {
     AsIs,
@@ -86,6 +119,9 @@ pub struct SurfaceIntersectionData {
     // a cube centered around `pos` with dimensions of `2 * pos_err`.
     pub nor: Normal,            // Shading normal
     pub nor_g: Normal,          // True geometric normal
+    pub tangent: Vector,        // Surface tangent, e.g. along a curve, or zero if there isn't one
+    pub color: Option<Color>,   // Per-primitive color, e.g. of a point, overriding the shader's
+    pub object_id: usize,       // Which placement of an object was hit
     pub local_space: Matrix4x4, // Matrix from global space to local space
     pub t: f32,                 // Ray t-value at the intersection point
     pub sample_pdf: f32,        // The PDF of getting this point by explicitly sampling the surface
@@ -0,0 +1,316 @@
+//! Point clouds, for particles like sand, sparks and debris.
+//!
+//! Each point is rendered either as a sphere or as a disc that always faces
+//! the ray.  Since scenes with these can easily have millions of points,
+//! the per-point data is kept as compact as possible: a single `Vec4` of
+//! position and radius for each time sample, and optionally a color.
+
+use glam::Vec4;
+use kioku::Arena;
+
+use crate::{
+    accel::{SplitMode, WideBVH},
+    bbox::BBox,
+    boundable::Boundable,
+    color::Color,
+    fp_utils::{fp_gamma, solve_quadratic, transformed_pos_err},
+    lerp::lerp_slice,
+    math::{dot, Matrix4x4, Point, Vector},
+    ray::{RayBatch, RayStack},
+    shading::SurfaceShader,
+};
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+
+const MAX_LEAF_POINT_COUNT: usize = 4;
+
+/// The shape each point is rendered as.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PointShape {
+    Sphere,
+
+    /// A flat disc facing the ray.
+    Disc,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct PointsSurface<'a> {
+    shape: PointShape,
+    time_sample_count: usize,
+    points: &'a [Vec4], // (x, y, z, radius), with the time samples for each point stored contiguously
+    color_time_sample_count: usize,
+    colors: Option<&'a [Color]>, // Per-point colors, with the time samples for each point stored contiguously
+    indices: &'a [u32],
+    accel: WideBVH<'a>,
+}
+
+impl<'a> PointsSurface<'a> {
+    /// Creates a new point cloud.
+    ///
+    /// - `positions`: The point positions for each time sample.
+    /// - `radii`: The point radii, either for each time sample or once for
+    ///   all of them.
+    /// - `colors`: Optional per-point colors, either for each time sample
+    ///   or once for all of them.
+    pub fn new<'b>(
+        arena: &'b Arena,
+        shape: PointShape,
+        positions: &[Vec<Point>],
+        radii: &[Vec<f32>],
+        colors: &Option<Vec<Vec<Color>>>,
+        split_mode: SplitMode,
+    ) -> PointsSurface<'b> {
+        let point_count = positions[0].len();
+        let time_sample_count = positions.len();
+
+        // Copy points over to a contiguous area of memory, reorganizing them
+        // so that each points' time samples are contiguous in memory.
+        let points: &mut [Vec4] = {
+            let points = arena.alloc_array_uninit(point_count * time_sample_count);
+
+            for pi in 0..point_count {
+                for ti in 0..time_sample_count {
+                    let p = positions[ti][pi];
+                    let r = radii[ti.min(radii.len() - 1)][pi];
+                    unsafe {
+                        *points[(pi * time_sample_count) + ti].as_mut_ptr() =
+                            Vec4::new(p.x(), p.y(), p.z(), r);
+                    }
+                }
+            }
+
+            unsafe { std::mem::transmute(points) }
+        };
+
+        // Copy colors, if any, organizing them the same as points above.
+        let color_time_sample_count = colors.as_ref().map_or(0, |cols| cols.len());
+        let colors = match colors {
+            Some(ref cols) => {
+                let colors = arena.alloc_array_uninit(point_count * color_time_sample_count);
+
+                for pi in 0..point_count {
+                    for ti in 0..color_time_sample_count {
+                        unsafe {
+                            *colors[(pi * color_time_sample_count) + ti].as_mut_ptr() =
+                                cols[ti][pi];
+                        }
+                    }
+                }
+
+                unsafe { Some(std::mem::transmute(&colors[..])) }
+            }
+
+            None => None,
+        };
+
+        // Create bounds array for use during BVH construction
+        let bounds: Vec<_> = points
+            .iter()
+            .map(|p| {
+                let r = p.w();
+                BBox::from_points(
+                    Point::new(p.x() - r, p.y() - r, p.z() - r),
+                    Point::new(p.x() + r, p.y() + r, p.z() + r),
+                )
+            })
+            .collect();
+
+        // Build BVH.  With spatial splits, points can end up in more than
+        // one leaf, so the point indices the BVH refers to are what get
+        // stored.
+        let indices: Vec<u32> = (0..point_count as u32).collect();
+        let (accel, indices) = WideBVH::from_objects_with_splits(
+            arena,
+            &indices,
+            MAX_LEAF_POINT_COUNT,
+            split_mode,
+            |&i| &bounds[(i as usize * time_sample_count)..((i as usize + 1) * time_sample_count)],
+            |_, bounds, axis, pos| bounds.split(axis, pos),
+        );
+        let indices = arena.copy_slice(&indices);
+
+        PointsSurface {
+            shape: shape,
+            time_sample_count: time_sample_count,
+            points: points,
+            color_time_sample_count: color_time_sample_count,
+            colors: colors,
+            indices: indices,
+            accel: accel,
+        }
+    }
+}
+
+impl<'a> Boundable for PointsSurface<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.accel.bounds()
+    }
+}
+
+impl<'a> Surface for PointsSurface<'a> {
+    fn intersect_rays(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        object_id: usize,
+    ) {
+        self.accel
+            .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
+                ray_stack.do_next_task(|ray_idx| {
+                    if rays.is_done(ray_idx) {
+                        return;
+                    }
+
+                    let ray_time = rays.time(ray_idx);
+
+                    // Get the transform space
+                    let xform = if space.is_empty() {
+                        Matrix4x4::new()
+                    } else {
+                        lerp_slice(space, ray_time)
+                    };
+
+                    // Get the ray origin and direction in local space
+                    let orig = rays.orig_local(ray_idx).into_vector();
+                    let dir = rays.dir(ray_idx) * xform;
+
+                    // Iterate through the points and test the ray against
+                    // them.
+                    let mut hit = None;
+                    for &point_idx in &self.indices[idx_range.clone()] {
+                        let point = if self.time_sample_count == 1 {
+                            self.points[point_idx as usize]
+                        } else {
+                            lerp_slice(
+                                &self.points[(point_idx as usize * self.time_sample_count)
+                                    ..((point_idx as usize + 1) * self.time_sample_count)],
+                                ray_time,
+                            )
+                        };
+                        let center = Vector::new(point.x(), point.y(), point.z());
+                        let radius = point.w();
+
+                        let point_hit = match self.shape {
+                            PointShape::Sphere => {
+                                intersect_sphere(orig, dir, rays.max_t(ray_idx), center, radius)
+                            }
+                            PointShape::Disc => {
+                                intersect_disc(orig, dir, rays.max_t(ray_idx), center, radius)
+                            }
+                        };
+
+                        if let Some((t, pos, pos_err, nor)) = point_hit {
+                            if rays.is_occlusion(ray_idx) {
+                                isects[ray_idx] = SurfaceIntersection::Occlude;
+                                rays.mark_done(ray_idx);
+                                return;
+                            } else {
+                                rays.set_max_t(ray_idx, t);
+                                hit = Some((point_idx as usize, t, pos, pos_err, nor));
+                            }
+                        }
+                    }
+
+                    // Calculate intersection data if necessary.
+                    if let Some((point_idx, t, pos, pos_err, nor)) = hit {
+                        let color = self.colors.map(|colors| {
+                            lerp_slice(
+                                &colors[(point_idx * self.color_time_sample_count)
+                                    ..((point_idx + 1) * self.color_time_sample_count)],
+                                ray_time,
+                            )
+                        });
+
+                        // Transform everything into world space.
+                        let inv_xform = xform.inverse();
+                        let nor = nor.into_normal() * inv_xform;
+
+                        let intersection_data = SurfaceIntersectionData {
+                            incoming: rays.dir(ray_idx),
+                            t: t,
+                            pos: pos.into_point() * inv_xform,
+                            pos_err: transformed_pos_err(pos.into_point(), pos_err, &inv_xform),
+                            nor: nor,
+                            nor_g: nor,
+                            tangent: Vector::new(0.0, 0.0, 0.0),
+                            color: color,
+                            object_id: object_id,
+                            local_space: xform,
+                            sample_pdf: 0.0,
+                        };
+
+                        // Fill in intersection data
+                        isects[ray_idx] = SurfaceIntersection::Hit {
+                            intersection_data: intersection_data,
+                            closure: shader.shade(&intersection_data, ray_time),
+                        };
+                    }
+                });
+                ray_stack.pop_task();
+            });
+    }
+}
+
+/// Intersects a ray with a sphere.
+///
+/// Returns the t, position, position error and (unnormalized) normal of the
+/// hit, if any.
+fn intersect_sphere(
+    orig: Vector,
+    dir: Vector,
+    max_t: f32,
+    center: Vector,
+    radius: f32,
+) -> Option<(f32, Vector, f32, Vector)> {
+    let rel_orig = orig - center;
+    let (t0, t1) = solve_quadratic(
+        dir.length2(),
+        2.0 * dot(dir, rel_orig),
+        rel_orig.length2() - (radius * radius),
+    )?;
+
+    let t = if t0 > 0.0 { t0 } else { t1 };
+    if t <= 0.0 || t > max_t {
+        return None;
+    }
+
+    // Re-project the hit point onto the surface of the sphere, which
+    // leaves only the error of the re-projection and of adding the center
+    // back.
+    let unit_pos = (rel_orig + (dir * t)).normalized();
+    let pos = center + (unit_pos * radius);
+    let pos_err = (center.abs().co.max_element() + radius) * fp_gamma(5);
+
+    Some((t, pos, pos_err, unit_pos))
+}
+
+/// Intersects a ray with a disc that's facing the ray.
+///
+/// Returns the same things as `intersect_sphere()`.
+fn intersect_disc(
+    orig: Vector,
+    dir: Vector,
+    max_t: f32,
+    center: Vector,
+    radius: f32,
+) -> Option<(f32, Vector, f32, Vector)> {
+    let t = dot(center - orig, dir) / dir.length2();
+    if t <= 0.0 || t > max_t {
+        return None;
+    }
+
+    let pos = orig + (dir * t);
+    if (pos - center).length2() > (radius * radius) {
+        return None;
+    }
+
+    // Since the disc faces whatever ray hits it, rays leaving it in
+    // other directions can see it at a different depth.  So this is
+    // conservative, to keep them from hitting it again.
+    let pos_err = radius * 2.0;
+
+    Some((t, pos, pos_err, -dir))
+}
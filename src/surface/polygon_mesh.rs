@@ -0,0 +1,207 @@
+//! Polygon meshes, which are either rendered directly as triangles or
+//! diced into micropolygons.
+//!
+//! When diced, quads are diced directly as bilinear patches.  Other polygons are
+//! split into one quad per corner, meeting at the polygon's center and at
+//! its edge midpoints.  The edges of neighboring quads that border such
+//! polygons are then forced to split at their midpoints as well, so that
+//! everything stays watertight.
+
+use std::{collections::HashSet, io};
+
+use kioku::Arena;
+
+use crate::{
+    accel::SplitMode,
+    lerp::lerp,
+    math::{Normal, Point, Vector},
+    paging::PageFiles,
+    shading::DisplacementShader,
+};
+
+use super::{
+    bilinear_patch::{lerp_edge, BilinearPatch},
+    dicing::{dice_patches, DicingContext},
+    micropoly_batch::MicropolyBatch,
+    triangle_mesh::{MeshStorage, TriangleMesh},
+};
+
+#[derive(Debug, Clone)]
+pub struct PolygonMesh {
+    verts: Vec<Vec<Point>>,            // Vec of vecs, one for each time sample
+    normals: Option<Vec<Vec<Normal>>>, // Organized the same as `verts`
+    faces: Vec<Vec<usize>>,
+}
+
+impl PolygonMesh {
+    /// Creates a new polygon mesh.
+    ///
+    /// - `verts`: The vertices for each time sample.
+    /// - `normals`: Optional vertex normals for each time sample, for
+    ///   smooth shading.
+    /// - `face_vert_counts`: The number of vertices in each face.
+    /// - `face_vert_indices`: The vertex indices of each face, one after
+    ///   the other.
+    pub fn new(
+        verts: Vec<Vec<Point>>,
+        normals: Option<Vec<Vec<Normal>>>,
+        face_vert_counts: &[usize],
+        face_vert_indices: &[usize],
+    ) -> PolygonMesh {
+        let mut faces = Vec::with_capacity(face_vert_counts.len());
+        let mut ii = 0;
+        for &fvc in face_vert_counts {
+            faces.push(face_vert_indices[ii..(ii + fvc)].to_vec());
+            ii += fvc;
+        }
+
+        PolygonMesh {
+            verts: verts,
+            normals: normals,
+            faces: faces,
+        }
+    }
+
+    /// Tessellates the mesh into micropolygons.
+    pub fn dice<'b>(
+        &self,
+        arena: &'b Arena,
+        ctx: &DicingContext,
+        displacement: Option<&dyn DisplacementShader>,
+    ) -> MicropolyBatch<'b> {
+        let time_sample_count = self.verts.len();
+
+        // Edges of non-quad faces get split at their midpoints.
+        let split_edges: HashSet<(usize, usize)> = self
+            .faces
+            .iter()
+            .filter(|face| face.len() != 4)
+            .flat_map(|face| {
+                (0..face.len()).map(move |i| edge_key(face[i], face[(i + 1) % face.len()]))
+            })
+            .collect();
+
+        // Build the bilinear patch control points, with the time samples of
+        // each patch next to each other.
+        let mut control_points: Vec<[Point; 4]> = Vec::new();
+        let mut control_normals: Vec<[Normal; 4]> = Vec::new();
+        let mut must_split = Vec::new();
+        for face in &self.faces {
+            let n = face.len();
+
+            if n == 4 {
+                for ti in 0..time_sample_count {
+                    let p = |i: usize| self.verts[ti][face[i]];
+                    control_points.push([p(0), p(1), p(2), p(3)]);
+                    if let Some(ref normals) = self.normals {
+                        let nor = |i: usize| normals[ti][face[i]];
+                        control_normals.push([nor(0), nor(1), nor(2), nor(3)]);
+                    }
+                }
+                must_split.push([
+                    split_edges.contains(&edge_key(face[0], face[1])),
+                    split_edges.contains(&edge_key(face[1], face[2])),
+                    split_edges.contains(&edge_key(face[2], face[3])),
+                    split_edges.contains(&edge_key(face[3], face[0])),
+                ]);
+            } else {
+                // Center and edge midpoints for each time sample.
+                let mut centers = Vec::with_capacity(time_sample_count);
+                let mut mids = Vec::with_capacity(time_sample_count);
+                for ti in 0..time_sample_count {
+                    let p = |i: usize| self.verts[ti][face[i % n]];
+                    centers.push(
+                        ((0..n).fold(Vector::new(0.0, 0.0, 0.0), |a, i| a + p(i).into_vector())
+                            / n as f32)
+                            .into_point(),
+                    );
+                    mids.push(
+                        (0..n)
+                            .map(|i| lerp_edge(p(i), p(i + 1), 0.5))
+                            .collect::<Vec<_>>(),
+                    );
+                }
+
+                for i in 0..n {
+                    let i_prev = (i + n - 1) % n;
+                    for ti in 0..time_sample_count {
+                        control_points.push([
+                            self.verts[ti][face[i]],
+                            mids[ti][i],
+                            centers[ti],
+                            mids[ti][i_prev],
+                        ]);
+                        if let Some(ref normals) = self.normals {
+                            let nor = |i: usize| normals[ti][face[i % n]];
+                            let center = (0..n)
+                                .fold(Vector::new(0.0, 0.0, 0.0), |a, i| a + nor(i).into_vector())
+                                .into_normal();
+                            control_normals.push([
+                                nor(i),
+                                lerp(nor(i), nor(i + 1), 0.5),
+                                center.normalized(),
+                                lerp(nor(i_prev), nor(i), 0.5),
+                            ]);
+                        }
+                    }
+                    must_split.push([false; 4]);
+                }
+            }
+        }
+
+        // Dice.
+        if self.normals.is_some() {
+            let patches: Vec<_> = control_points
+                .chunks(time_sample_count)
+                .zip(control_normals.chunks(time_sample_count))
+                .zip(must_split.iter())
+                .map(|((cps, nors), &ms)| BilinearPatch::with_normals(cps, nors, ms))
+                .collect();
+            dice_patches(arena, &patches, ctx, displacement)
+        } else {
+            let patches: Vec<_> = control_points
+                .chunks(time_sample_count)
+                .zip(must_split.iter())
+                .map(|(cps, &ms)| BilinearPatch::new(cps, ms))
+                .collect();
+            dice_patches(arena, &patches, ctx, displacement)
+        }
+    }
+
+    /// Splits the mesh's polygons into triangle fans, for rendering it
+    /// directly without dicing.
+    ///
+    /// Returns an error if the page file of a paged mesh can't be written.
+    pub fn triangulate<'b>(
+        &self,
+        arena: &'b Arena,
+        page_files: &'b PageFiles,
+        split_mode: SplitMode,
+        storage: MeshStorage,
+    ) -> io::Result<TriangleMesh<'b>> {
+        let mut tri_vert_indices = Vec::new();
+        for face in &self.faces {
+            for vi in 0..(face.len() - 2) {
+                tri_vert_indices.push((face[0], face[vi + 1], face[vi + 2]));
+            }
+        }
+
+        TriangleMesh::from_verts_and_indices(
+            arena,
+            page_files,
+            &self.verts,
+            &self.normals,
+            &tri_vert_indices,
+            split_mode,
+            storage,
+        )
+    }
+}
+
+fn edge_key(v1: usize, v2: usize) -> (usize, usize) {
+    if v1 < v2 {
+        (v1, v2)
+    } else {
+        (v2, v1)
+    }
+}
@@ -0,0 +1,320 @@
+//! Analytic quadric surfaces: spheres, disks and cylinders.
+//!
+//! These are intersected directly rather than diced into micropolygons,
+//! which keeps scenes with many small round things (e.g. particles) from
+//! needing huge amounts of tessellated geometry.
+//!
+//! All of them are centered on the origin of their local space.  Their
+//! parameters can be time sampled for deformation motion blur, and the
+//! number of time samples of different parameters needn't match.
+
+use kioku::Arena;
+
+use crate::{
+    bbox::BBox,
+    boundable::Boundable,
+    fp_utils::{fp_gamma, solve_quadratic, transformed_pos_err},
+    lerp::lerp_slice,
+    math::{dot, Matrix4x4, Normal, Point, Vector},
+    ray::{RayBatch, RayStack},
+    shading::SurfaceShader,
+};
+
+use super::{Surface, SurfaceIntersection, SurfaceIntersectionData};
+
+/// A sphere.
+#[derive(Copy, Clone, Debug)]
+pub struct Sphere<'a> {
+    radii: &'a [f32],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> Sphere<'a> {
+    pub fn new<'b>(arena: &'b Arena, radii: &[f32]) -> Sphere<'b> {
+        let bbs: Vec<_> = radii
+            .iter()
+            .map(|&r| BBox::from_points(Point::new(-r, -r, -r), Point::new(r, r, r)))
+            .collect();
+        Sphere {
+            radii: arena.copy_slice(&radii),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+}
+
+impl<'a> Boundable for Sphere<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
+
+impl<'a> Surface for Sphere<'a> {
+    fn intersect_rays(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        object_id: usize,
+    ) {
+        intersect_quadric(
+            rays,
+            ray_stack,
+            isects,
+            shader,
+            space,
+            object_id,
+            |orig, dir, max_t, time| {
+                let radius = lerp_slice(self.radii, time);
+
+                let (t0, t1) = solve_quadratic(
+                    dir.length2(),
+                    2.0 * dot(dir, orig),
+                    orig.length2() - (radius * radius),
+                )?;
+
+                let t = if t0 > 0.0 { t0 } else { t1 };
+                if t <= 0.0 || t > max_t {
+                    return None;
+                }
+
+                // Re-project the hit point onto the surface of the sphere,
+                // which leaves only the error of the re-projection itself.
+                let unit_pos = (orig + (dir * t)).normalized();
+                let pos = unit_pos * radius;
+                let pos_err = pos.abs().co.max_element() * fp_gamma(5);
+
+                Some((t, pos.into_point(), pos_err, unit_pos.into_normal()))
+            },
+        );
+    }
+}
+
+/// A disk in the xy plane, facing +z.
+#[derive(Copy, Clone, Debug)]
+pub struct Disk<'a> {
+    radii: &'a [f32],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> Disk<'a> {
+    pub fn new<'b>(arena: &'b Arena, radii: &[f32]) -> Disk<'b> {
+        let bbs: Vec<_> = radii
+            .iter()
+            .map(|&r| BBox::from_points(Point::new(-r, -r, 0.0), Point::new(r, r, 0.0)))
+            .collect();
+        Disk {
+            radii: arena.copy_slice(&radii),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+}
+
+impl<'a> Boundable for Disk<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
+
+impl<'a> Surface for Disk<'a> {
+    fn intersect_rays(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        object_id: usize,
+    ) {
+        intersect_quadric(
+            rays,
+            ray_stack,
+            isects,
+            shader,
+            space,
+            object_id,
+            |orig, dir, max_t, time| {
+                let radius = lerp_slice(self.radii, time);
+
+                if dir.z() == 0.0 {
+                    return None;
+                }
+                let t = -orig.z() / dir.z();
+                if t <= 0.0 || t > max_t {
+                    return None;
+                }
+
+                let t_pos = orig + (dir * t);
+                if ((t_pos.x() * t_pos.x()) + (t_pos.y() * t_pos.y())) > (radius * radius) {
+                    return None;
+                }
+
+                // The hit point is snapped exactly onto the plane of the disk,
+                // so only x and y have error.
+                let pos = Point::new(t_pos.x(), t_pos.y(), 0.0);
+                let abs_sum = orig.abs() + (dir * t).abs();
+                let pos_err = abs_sum.x().max(abs_sum.y()) * fp_gamma(5);
+
+                Some((t, pos, pos_err, Normal::new(0.0, 0.0, 1.0)))
+            },
+        );
+    }
+}
+
+/// An open cylinder around the z axis, extending from `-height / 2` to
+/// `height / 2`.
+///
+/// It has no end caps, but those can be added with disks.
+#[derive(Copy, Clone, Debug)]
+pub struct Cylinder<'a> {
+    radii: &'a [f32],
+    heights: &'a [f32],
+    bounds_: &'a [BBox],
+}
+
+impl<'a> Cylinder<'a> {
+    pub fn new<'b>(arena: &'b Arena, radii: &[f32], heights: &[f32]) -> Cylinder<'b> {
+        let sample_count = radii.len().max(heights.len());
+        let bbs: Vec<_> = (0..sample_count)
+            .map(|i| {
+                let time = if sample_count > 1 {
+                    i as f32 / (sample_count - 1) as f32
+                } else {
+                    0.0
+                };
+                let r = lerp_slice(radii, time);
+                let h = lerp_slice(heights, time) * 0.5;
+                BBox::from_points(Point::new(-r, -r, -h), Point::new(r, r, h))
+            })
+            .collect();
+        Cylinder {
+            radii: arena.copy_slice(&radii),
+            heights: arena.copy_slice(&heights),
+            bounds_: arena.copy_slice(&bbs),
+        }
+    }
+}
+
+impl<'a> Boundable for Cylinder<'a> {
+    fn bounds(&self) -> &[BBox] {
+        self.bounds_
+    }
+}
+
+impl<'a> Surface for Cylinder<'a> {
+    fn intersect_rays(
+        &self,
+        rays: &mut RayBatch,
+        ray_stack: &mut RayStack,
+        isects: &mut [SurfaceIntersection],
+        shader: &dyn SurfaceShader,
+        space: &[Matrix4x4],
+        object_id: usize,
+    ) {
+        intersect_quadric(
+            rays,
+            ray_stack,
+            isects,
+            shader,
+            space,
+            object_id,
+            |orig, dir, max_t, time| {
+                let radius = lerp_slice(self.radii, time);
+                let half_height = lerp_slice(self.heights, time) * 0.5;
+
+                let (t0, t1) = solve_quadratic(
+                    (dir.x() * dir.x()) + (dir.y() * dir.y()),
+                    2.0 * ((dir.x() * orig.x()) + (dir.y() * orig.y())),
+                    (orig.x() * orig.x()) + (orig.y() * orig.y()) - (radius * radius),
+                )?;
+
+                // Take the nearest hit that's within both the ray's extents
+                // and the height of the cylinder.
+                let t = [t0, t1].iter().cloned().find(|&t| {
+                    t > 0.0 && t <= max_t && (orig.z() + (dir.z() * t)).abs() <= half_height
+                })?;
+
+                // Re-project the hit point onto the surface of the cylinder.
+                let t_pos = orig + (dir * t);
+                let hit_radius = ((t_pos.x() * t_pos.x()) + (t_pos.y() * t_pos.y())).sqrt();
+                let nor = Vector::new(t_pos.x() / hit_radius, t_pos.y() / hit_radius, 0.0);
+                let pos = Point::new(nor.x() * radius, nor.y() * radius, t_pos.z());
+                let pos_err = (nor.x().abs().max(nor.y().abs()) * radius * fp_gamma(3))
+                    .max((orig.z().abs() + (dir.z() * t).abs()) * fp_gamma(5));
+
+                Some((t, pos, pos_err, nor.into_normal()))
+            },
+        );
+    }
+}
+
+/// Intersects the rays of the next task on the ray stack with a quadric.
+///
+/// `hit` takes the local space ray origin, direction, max t and time, and
+/// returns the t, local space position, position error and normal of the
+/// nearest hit, if any.
+fn intersect_quadric<F>(
+    rays: &mut RayBatch,
+    ray_stack: &mut RayStack,
+    isects: &mut [SurfaceIntersection],
+    shader: &dyn SurfaceShader,
+    space: &[Matrix4x4],
+    object_id: usize,
+    hit: F,
+) where
+    F: Fn(Vector, Vector, f32, f32) -> Option<(f32, Point, f32, Normal)>,
+{
+    ray_stack.pop_do_next_task(|ray_idx| {
+        if rays.is_done(ray_idx) {
+            return;
+        }
+
+        let time = rays.time(ray_idx);
+
+        // Get the transform space
+        let xform = if space.is_empty() {
+            Matrix4x4::new()
+        } else {
+            lerp_slice(space, time)
+        };
+
+        // Get the ray origin and direction in local space
+        let orig = rays.orig_local(ray_idx).into_vector();
+        let dir = rays.dir(ray_idx) * xform;
+
+        let (t, pos, pos_err, nor) = match hit(orig, dir, rays.max_t(ray_idx), time) {
+            Some(h) => h,
+            None => return,
+        };
+
+        if rays.is_occlusion(ray_idx) {
+            isects[ray_idx] = SurfaceIntersection::Occlude;
+            rays.mark_done(ray_idx);
+        } else {
+            // Transform the hit back into world space.
+            let inv_xform = xform.inverse();
+            let nor = nor * inv_xform;
+
+            let intersection_data = SurfaceIntersectionData {
+                incoming: rays.dir(ray_idx),
+                t: t,
+                pos: pos * inv_xform,
+                pos_err: transformed_pos_err(pos, pos_err, &inv_xform),
+                nor: nor,
+                nor_g: nor,
+                tangent: Vector::new(0.0, 0.0, 0.0),
+                color: None,
+                object_id: object_id,
+                local_space: xform,
+                sample_pdf: 0.0,
+            };
+
+            isects[ray_idx] = SurfaceIntersection::Hit {
+                intersection_data: intersection_data,
+                closure: shader.shade(&intersection_data, time),
+            };
+            rays.set_max_t(ray_idx, t);
+        }
+    });
+}
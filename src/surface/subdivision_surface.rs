@@ -0,0 +1,673 @@
+//! Catmull-Clark subdivision surfaces.
+//!
+//! The control mesh is first refined with the usual Catmull-Clark rules,
+//! including semi-sharp creases ("Subdivision Surfaces in Character
+//! Animation" by DeRose et al.), until all faces are quads and any
+//! semi-sharp creases have been resolved.  Each resulting quad is then
+//! approximated by a bicubic Bezier patch following "Approximating
+//! Catmull-Clark Subdivision Surfaces with Bicubic Patches" by Loop and
+//! Schaefer, which is exact in regular regions and watertight everywhere.
+//! Those patches are then diced into micropolygons.
+
+use std::collections::HashMap;
+
+use kioku::Arena;
+
+use crate::{
+    lerp::lerp,
+    math::{Point, Vector},
+    shading::DisplacementShader,
+};
+
+use super::{
+    bicubic_patch::BicubicPatch,
+    dicing::{dice_patches, DicingContext},
+    micropoly_batch::MicropolyBatch,
+};
+
+// The most refinement levels that will be done to resolve semi-sharp
+// creases.  Creases sharper than this are treated as infinitely sharp.
+const MAX_CREASE_LEVELS: usize = 4;
+
+/// How the boundaries of a subdivision surface are handled.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BoundaryRule {
+    /// Boundary edges are infinitely sharp creases.
+    EdgesOnly,
+
+    /// Boundary edges are infinitely sharp creases, and boundary vertices
+    /// with only one face are additionally kept as sharp corners.
+    EdgesAndCorners,
+}
+
+/// A Catmull-Clark subdivision surface control mesh.
+#[derive(Debug, Clone)]
+pub struct SubdivisionSurface {
+    verts: Vec<Vec<Point>>, // Vec of vecs, one for each time sample
+    faces: Vec<Vec<usize>>,
+    creases: HashMap<(usize, usize), f32>, // Keyed by (low, high) vertex index
+    boundary_rule: BoundaryRule,
+}
+
+impl SubdivisionSurface {
+    /// Creates a new subdivision surface.
+    ///
+    /// - `verts`: The control vertices for each time sample.
+    /// - `face_vert_counts`: The number of vertices in each face.
+    /// - `face_vert_indices`: The vertex indices of each face, one after
+    ///   the other.
+    /// - `creases`: Edges with their crease sharpness, as
+    ///   `(vert_index_1, vert_index_2, sharpness)`.
+    ///
+    /// The faces should be manifold: no face should use a vertex more than
+    /// once, and no edge should be shared by more than two faces.
+    pub fn new(
+        verts: Vec<Vec<Point>>,
+        face_vert_counts: &[usize],
+        face_vert_indices: &[usize],
+        creases: &[(usize, usize, f32)],
+        boundary_rule: BoundaryRule,
+    ) -> SubdivisionSurface {
+        let mut faces = Vec::with_capacity(face_vert_counts.len());
+        let mut ii = 0;
+        for &fvc in face_vert_counts {
+            faces.push(face_vert_indices[ii..(ii + fvc)].to_vec());
+            ii += fvc;
+        }
+
+        let creases = creases
+            .iter()
+            .filter(|c| c.2 > 0.0)
+            .map(|&(v1, v2, sharpness)| (edge_key(v1, v2), sharpness))
+            .collect();
+
+        SubdivisionSurface {
+            verts: verts,
+            faces: faces,
+            creases: creases,
+            boundary_rule: boundary_rule,
+        }
+    }
+
+    /// Tessellates the surface into micropolygons.
+    pub fn dice<'b>(
+        &self,
+        arena: &'b Arena,
+        ctx: &DicingContext,
+        displacement: Option<&dyn DisplacementShader>,
+    ) -> MicropolyBatch<'b> {
+        // Refine until we have all quads and the semi-sharp creases are
+        // resolved.
+        let levels = {
+            let max_sharpness = self.creases.values().fold(0.0f32, |a, &b| a.max(b));
+            (max_sharpness.ceil() as usize)
+                .max(1)
+                .min(MAX_CREASE_LEVELS)
+        };
+        let mut mesh = self.clone();
+        for _ in 0..levels {
+            mesh = mesh.refine();
+        }
+
+        // Build and dice the patches.
+        let control_points = mesh.bezier_patches();
+        let time_sample_count = mesh.verts.len();
+        let patches: Vec<_> = control_points
+            .chunks(time_sample_count)
+            .map(|cps| BicubicPatch::new(cps, [false; 4]))
+            .collect();
+
+        dice_patches(arena, &patches, ctx, displacement)
+    }
+
+    /// Does a single level of Catmull-Clark refinement.  The result has
+    /// only quads.
+    fn refine(&self) -> SubdivisionSurface {
+        let topo = Topology::new(self);
+        let vert_count = self.verts[0].len();
+        let edge_count = topo.edges.len();
+
+        // New vertices are laid out with the refined original vertices
+        // first, then the edge points, then the face points.
+        let edge_offset = vert_count;
+        let face_offset = vert_count + edge_count;
+
+        let mut new_verts = Vec::with_capacity(self.verts.len());
+        for verts in &self.verts {
+            let face_points: Vec<Vector> = self
+                .faces
+                .iter()
+                .map(|face| average(face.iter().map(|&v| verts[v].into_vector())))
+                .collect();
+
+            // Edge points.
+            let edge_points: Vec<Vector> = topo
+                .edges
+                .iter()
+                .enumerate()
+                .map(|(ei, &(v1, v2))| {
+                    let sharp = (verts[v1].into_vector() + verts[v2].into_vector()) * 0.5;
+                    let sharpness = topo.edge_sharpness[ei];
+                    if sharpness >= 1.0 {
+                        sharp
+                    } else {
+                        let faces = &topo.edge_faces[ei];
+                        let smooth = (verts[v1].into_vector()
+                            + verts[v2].into_vector()
+                            + face_points[faces[0]]
+                            + face_points[faces[1]])
+                            * 0.25;
+                        lerp(smooth, sharp, sharpness)
+                    }
+                })
+                .collect();
+
+            // Vertex points.
+            let vert_points = (0..vert_count).map(|vi| {
+                let v = verts[vi].into_vector();
+                let edges = &topo.vert_edges[vi];
+                if edges.is_empty() {
+                    return v;
+                }
+
+                let n = edges.len() as f32;
+                let smooth = {
+                    let q = average(topo.vert_faces[vi].iter().map(|&f| face_points[f]));
+                    let r = average(edges.iter().map(|&e| {
+                        let (v1, v2) = topo.edges[e];
+                        (verts[v1].into_vector() + verts[v2].into_vector()) * 0.5
+                    }));
+                    (q + (r * 2.0) + (v * (n - 3.0))) / n
+                };
+
+                // Semi-sharp vertices with a sharpness of less than one
+                // blend between the smooth and sharp rules.
+                let (sharp, sharpness) = match topo.vert_kind(vi, self.boundary_rule) {
+                    VertKind::Smooth => return smooth,
+                    VertKind::Crease(sharpness, v1, v2) => (
+                        ((v * 6.0) + verts[v1].into_vector() + verts[v2].into_vector())
+                            * (1.0 / 8.0),
+                        sharpness,
+                    ),
+                    VertKind::Corner(sharpness) => (v, sharpness),
+                };
+                if sharpness >= 1.0 {
+                    sharp
+                } else {
+                    lerp(smooth, sharp, sharpness)
+                }
+            });
+
+            let mut tverts: Vec<Point> = vert_points.map(|v| v.into_point()).collect();
+            tverts.extend(edge_points.iter().map(|v| v.into_point()));
+            tverts.extend(face_points.iter().map(|v| v.into_point()));
+            new_verts.push(tverts);
+        }
+
+        // New faces.
+        let mut new_faces = Vec::new();
+        for (fi, face) in self.faces.iter().enumerate() {
+            let n = face.len();
+            for i in 0..n {
+                let e_next = topo.edge_map[&edge_key(face[i], face[(i + 1) % n])];
+                let e_prev = topo.edge_map[&edge_key(face[(i + n - 1) % n], face[i])];
+                new_faces.push(vec![
+                    face[i],
+                    edge_offset + e_next,
+                    face_offset + fi,
+                    edge_offset + e_prev,
+                ]);
+            }
+        }
+
+        // New creases, which are one less sharp than their parents.
+        let mut new_creases = HashMap::new();
+        for (ei, &(v1, v2)) in topo.edges.iter().enumerate() {
+            if let Some(&sharpness) = self.creases.get(&(v1, v2)) {
+                if sharpness > 1.0 {
+                    new_creases.insert(edge_key(v1, edge_offset + ei), sharpness - 1.0);
+                    new_creases.insert(edge_key(v2, edge_offset + ei), sharpness - 1.0);
+                }
+            }
+        }
+
+        SubdivisionSurface {
+            verts: new_verts,
+            faces: new_faces,
+            creases: new_creases,
+            boundary_rule: self.boundary_rule,
+        }
+    }
+
+    /// Computes the bicubic Bezier control points approximating each face
+    /// of the mesh, which must be all quads.
+    ///
+    /// The control points are returned in a flat list, with all of the
+    /// time samples of the first face, then of the second, etc.
+    fn bezier_patches(&self) -> Vec<[Point; 16]> {
+        let topo = Topology::new(self);
+        let vert_count = self.verts[0].len();
+
+        // Which edges are treated as sharp.  Any remaining semi-sharp
+        // creases are treated as infinitely sharp.
+        let is_sharp = |e: usize| topo.edge_sharpness[e] > 0.0;
+
+        // The index of vertex `v` within face `f`.
+        let corner_index =
+            |f: usize, v: usize| self.faces[f].iter().position(|&fv| fv == v).unwrap();
+
+        let mut patches = Vec::with_capacity(self.faces.len() * self.verts.len());
+        let mut patches_by_time = Vec::with_capacity(self.verts.len());
+        for verts in &self.verts {
+            let p = |v: usize| verts[v].into_vector();
+
+            // Interior control points, one for each face corner.
+            let interior: Vec<[Vector; 4]> = self
+                .faces
+                .iter()
+                .map(|face| {
+                    let mut points = [Vector::new(0.0, 0.0, 0.0); 4];
+                    for i in 0..4 {
+                        let n = topo.vert_edges[face[i]].len() as f32;
+                        points[i] = ((p(face[i]) * n)
+                            + ((p(face[(i + 1) % 4]) + p(face[(i + 3) % 4])) * 2.0)
+                            + p(face[(i + 2) % 4]))
+                            / (n + 5.0);
+                    }
+                    points
+                })
+                .collect();
+
+            // Corner control points, which are on the limit surface.
+            let corners: Vec<Vector> = (0..vert_count)
+                .map(|vi| match topo.vert_kind(vi, self.boundary_rule) {
+                    VertKind::Smooth => average(
+                        topo.vert_faces[vi]
+                            .iter()
+                            .map(|&f| interior[f][corner_index(f, vi)]),
+                    ),
+                    VertKind::Crease(_, v1, v2) => (p(vi) * 4.0 + p(v1) + p(v2)) * (1.0 / 6.0),
+                    VertKind::Corner(_) => p(vi),
+                })
+                .collect();
+
+            // The edge control point near vertex `v` on the edge to `w`,
+            // with `f` being one of the faces sharing the edge.
+            let edge_point = |f: usize, v: usize, w: usize| -> Vector {
+                let e = topo.edge_map[&edge_key(v, w)];
+                if is_sharp(e) {
+                    ((p(v) * 2.0) + p(w)) * (1.0 / 3.0)
+                } else {
+                    let faces = &topo.edge_faces[e];
+                    let g = if faces[0] == f { faces[1] } else { faces[0] };
+                    (interior[f][corner_index(f, v)] + interior[g][corner_index(g, v)]) * 0.5
+                }
+            };
+
+            let mut face_patches = Vec::with_capacity(self.faces.len());
+            for (fi, face) in self.faces.iter().enumerate() {
+                let (v0, v1, v2, v3) = (face[0], face[1], face[2], face[3]);
+                let patch = [
+                    corners[v0],
+                    edge_point(fi, v0, v1),
+                    edge_point(fi, v1, v0),
+                    corners[v1],
+                    //
+                    edge_point(fi, v0, v3),
+                    interior[fi][0],
+                    interior[fi][1],
+                    edge_point(fi, v1, v2),
+                    //
+                    edge_point(fi, v3, v0),
+                    interior[fi][3],
+                    interior[fi][2],
+                    edge_point(fi, v2, v1),
+                    //
+                    corners[v3],
+                    edge_point(fi, v3, v2),
+                    edge_point(fi, v2, v3),
+                    corners[v2],
+                ];
+
+                let mut points = [Point::new(0.0, 0.0, 0.0); 16];
+                for i in 0..16 {
+                    points[i] = patch[i].into_point();
+                }
+                face_patches.push(points);
+            }
+            patches_by_time.push(face_patches);
+        }
+
+        // Re-arrange so that each patch's time samples are together.
+        for fi in 0..self.faces.len() {
+            for face_patches in &patches_by_time {
+                patches.push(face_patches[fi]);
+            }
+        }
+
+        patches
+    }
+}
+
+fn edge_key(v1: usize, v2: usize) -> (usize, usize) {
+    if v1 < v2 {
+        (v1, v2)
+    } else {
+        (v2, v1)
+    }
+}
+
+fn average<I: Iterator<Item = Vector>>(vecs: I) -> Vector {
+    let mut sum = Vector::new(0.0, 0.0, 0.0);
+    let mut count = 0;
+    for v in vecs {
+        sum = sum + v;
+        count += 1;
+    }
+    sum / count.max(1) as f32
+}
+
+#[derive(Debug, Copy, Clone)]
+enum VertKind {
+    Smooth,
+
+    /// On a crease, with the crease sharpness and the neighboring vertices
+    /// along the crease.
+    Crease(f32, usize, usize),
+
+    /// A corner, with its sharpness.
+    Corner(f32),
+}
+
+/// Adjacency information of a control mesh.
+struct Topology {
+    edges: Vec<(usize, usize)>, // (low, high) vertex index
+    edge_map: HashMap<(usize, usize), usize>,
+    edge_faces: Vec<Vec<usize>>,
+    edge_sharpness: Vec<f32>, // Infinity for boundary and non-manifold edges
+    vert_edges: Vec<Vec<usize>>,
+    vert_faces: Vec<Vec<usize>>,
+}
+
+impl Topology {
+    fn new(mesh: &SubdivisionSurface) -> Topology {
+        let vert_count = mesh.verts[0].len();
+        let mut topo = Topology {
+            edges: Vec::new(),
+            edge_map: HashMap::new(),
+            edge_faces: Vec::new(),
+            edge_sharpness: Vec::new(),
+            vert_edges: vec![Vec::new(); vert_count],
+            vert_faces: vec![Vec::new(); vert_count],
+        };
+
+        for (fi, face) in mesh.faces.iter().enumerate() {
+            for i in 0..face.len() {
+                let key = edge_key(face[i], face[(i + 1) % face.len()]);
+                let ei = match topo.edge_map.get(&key) {
+                    Some(&ei) => ei,
+                    None => {
+                        let ei = topo.edges.len();
+                        topo.edges.push(key);
+                        topo.edge_map.insert(key, ei);
+                        topo.edge_faces.push(Vec::new());
+                        topo.vert_edges[key.0].push(ei);
+                        topo.vert_edges[key.1].push(ei);
+                        ei
+                    }
+                };
+                topo.edge_faces[ei].push(fi);
+                topo.vert_faces[face[i]].push(fi);
+            }
+        }
+
+        topo.edge_sharpness = topo
+            .edges
+            .iter()
+            .enumerate()
+            .map(|(ei, key)| {
+                if topo.edge_faces[ei].len() != 2 {
+                    std::f32::INFINITY
+                } else {
+                    mesh.creases.get(key).cloned().unwrap_or(0.0)
+                }
+            })
+            .collect();
+
+        topo
+    }
+
+    fn vert_kind(&self, vi: usize, boundary_rule: BoundaryRule) -> VertKind {
+        let sharp_edges: Vec<usize> = self.vert_edges[vi]
+            .iter()
+            .cloned()
+            .filter(|&e| self.edge_sharpness[e] > 0.0)
+            .collect();
+        let sharpness = sharp_edges
+            .iter()
+            .map(|&e| self.edge_sharpness[e])
+            .sum::<f32>()
+            / sharp_edges.len().max(1) as f32;
+
+        let is_boundary_corner =
+            boundary_rule == BoundaryRule::EdgesAndCorners && self.vert_faces[vi].len() == 1;
+
+        if sharp_edges.len() > 2 || is_boundary_corner {
+            VertKind::Corner(if is_boundary_corner {
+                std::f32::INFINITY
+            } else {
+                sharpness
+            })
+        } else if sharp_edges.len() == 2 {
+            let other = |e: usize| {
+                let (v1, v2) = self.edges[e];
+                if v1 == vi {
+                    v2
+                } else {
+                    v1
+                }
+            };
+            VertKind::Crease(sharpness, other(sharp_edges[0]), other(sharp_edges[1]))
+        } else {
+            VertKind::Smooth
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near(a: Point, b: Point) {
+        assert!((a - b).length() < 0.000_01, "{:?} != {:?}", a, b);
+    }
+
+    // The patch control point indices along each side of a face, from
+    // `face[i]` to `face[i + 1]`.
+    const PATCH_SIDES: [[usize; 4]; 4] = [
+        [0, 1, 2, 3],
+        [3, 7, 11, 15],
+        [15, 14, 13, 12],
+        [12, 8, 4, 0],
+    ];
+
+    /// A cube from -1 to 1 on each axis.
+    fn cube() -> SubdivisionSurface {
+        let verts = (0..8)
+            .map(|i| {
+                let coord = |bit| if i & bit == 0 { -1.0 } else { 1.0 };
+                Point::new(coord(1), coord(2), coord(4))
+            })
+            .collect();
+        let faces = [
+            0, 2, 6, 4, // -x
+            1, 5, 7, 3, // +x
+            0, 4, 5, 1, // -y
+            2, 3, 7, 6, // +y
+            0, 1, 3, 2, // -z
+            4, 6, 7, 5, // +z
+        ];
+        SubdivisionSurface::new(vec![verts], &[4; 6], &faces, &[], BoundaryRule::EdgesOnly)
+    }
+
+    /// A grid of quads with `res` by `res` vertices, with the vertex at
+    /// column `x` and row `y` at `pos(x, y)`.
+    fn grid<F: Fn(usize, usize) -> Point>(
+        res: usize,
+        pos: F,
+        creases: &[(usize, usize, f32)],
+        boundary_rule: BoundaryRule,
+    ) -> SubdivisionSurface {
+        let verts = (0..(res * res)).map(|i| pos(i % res, i / res)).collect();
+        let mut faces = Vec::new();
+        for y in 0..(res - 1) {
+            for x in 0..(res - 1) {
+                let i = y * res + x;
+                faces.extend_from_slice(&[i, i + 1, i + res + 1, i + res]);
+            }
+        }
+        let face_count = faces.len() / 4;
+        SubdivisionSurface::new(
+            vec![verts],
+            &vec![4; face_count],
+            &faces,
+            creases,
+            boundary_rule,
+        )
+    }
+
+    #[test]
+    fn cube_refined_once() {
+        let cube = cube();
+        let topo = Topology::new(&cube);
+        let refined = cube.refine();
+        let verts = &refined.verts[0];
+        assert_eq!(verts.len(), 8 + 12 + 6);
+        assert_eq!(refined.faces.len(), 24);
+        assert!(refined.faces.iter().all(|f| f.len() == 4));
+
+        // The original vertices move 4/9 of the way to the center.
+        for i in 0..8 {
+            assert_near(
+                verts[i],
+                (cube.verts[0][i].into_vector() * (5.0 / 9.0)).into_point(),
+            );
+        }
+
+        // The edge points are the average of the edge's endpoints and the
+        // centers of its two faces, which works out to 3/4 of the way out
+        // to the edge's midpoint.
+        for (ei, &(v1, v2)) in topo.edges.iter().enumerate() {
+            let mid = (cube.verts[0][v1].into_vector() + cube.verts[0][v2].into_vector()) * 0.5;
+            assert_near(verts[8 + ei], (mid * 0.75).into_point());
+        }
+
+        // The face points are the face centers.
+        for (fi, face) in cube.faces.iter().enumerate() {
+            let center = average(face.iter().map(|&v| cube.verts[0][v].into_vector()));
+            assert_near(verts[20 + fi], center.into_point());
+            assert_eq!(center.length(), 1.0);
+        }
+    }
+
+    #[test]
+    fn sharp_crease_stays_on_crease_line() {
+        // A bumpy grid with an infinitely sharp crease running straight
+        // across it along its second row, on the line y = 1, z = 0.  The
+        // rows on either side are spaced unevenly, so that the smooth rules
+        // would pull the crease off of the line.
+        let rows = [0.0, 1.0, 3.0, 3.5];
+        let mesh = grid(
+            4,
+            |x, y| {
+                let z = if y == 1 {
+                    0.0
+                } else {
+                    (x as f32 + y as f32 * 0.7).sin()
+                };
+                Point::new(x as f32 * x as f32, rows[y], z)
+            },
+            &[
+                (4, 5, f32::INFINITY),
+                (5, 6, f32::INFINITY),
+                (6, 7, f32::INFINITY),
+            ],
+            BoundaryRule::EdgesOnly,
+        );
+        let on_line = |p: Point| (p.y() - 1.0).abs() < 0.000_01 && p.z().abs() < 0.000_01;
+
+        let mut mesh = mesh;
+        for level in 1..=3 {
+            mesh = mesh.refine();
+            let verts = &mesh.verts[0];
+
+            // The crease is still there, split into twice as many edges,
+            // and all of its vertices are on the line.
+            assert_eq!(mesh.creases.len(), 3 << level);
+            for (&(v1, v2), &sharpness) in &mesh.creases {
+                assert_eq!(sharpness, f32::INFINITY);
+                assert!(on_line(verts[v1]), "{:?}", verts[v1]);
+                assert!(on_line(verts[v2]), "{:?}", verts[v2]);
+            }
+
+            // The patch control points along the crease are on the line
+            // too.
+            let patches = mesh.bezier_patches();
+            let mut side_count = 0;
+            for (fi, face) in mesh.faces.iter().enumerate() {
+                for i in 0..4 {
+                    if mesh
+                        .creases
+                        .contains_key(&edge_key(face[i], face[(i + 1) % 4]))
+                    {
+                        side_count += 1;
+                        for &pi in &PATCH_SIDES[i] {
+                            assert!(on_line(patches[fi][pi]), "{:?}", patches[fi][pi]);
+                        }
+                    }
+                }
+            }
+            assert_eq!(side_count, 2 * mesh.creases.len());
+        }
+    }
+
+    #[test]
+    fn boundary_corners() {
+        let pos = |x: usize, y: usize| Point::new(x as f32, y as f32, ((x * y) as f32).sin());
+        let corners = [0, 2, 6, 8];
+
+        for &boundary_rule in &[BoundaryRule::EdgesAndCorners, BoundaryRule::EdgesOnly] {
+            let mesh = grid(3, pos, &[], boundary_rule);
+            let refined = mesh.refine().refine();
+            let patches = refined.bezier_patches();
+
+            for &vi in &corners {
+                // Find the corner's patch control point.
+                let (fi, face) = refined
+                    .faces
+                    .iter()
+                    .enumerate()
+                    .find(|(_, face)| face.contains(&vi))
+                    .unwrap();
+                let i = face.iter().position(|&v| v == vi).unwrap();
+                let patch_corner = patches[fi][PATCH_SIDES[i][0]];
+
+                let original = mesh.verts[0][vi];
+                match boundary_rule {
+                    // Corners stay put.
+                    BoundaryRule::EdgesAndCorners => {
+                        assert_eq!(refined.verts[0][vi], original);
+                        assert_eq!(patch_corner, original);
+                    }
+
+                    // Corners are rounded off like any other boundary
+                    // vertex.
+                    BoundaryRule::EdgesOnly => {
+                        assert!((refined.verts[0][vi] - original).length() > 0.01);
+                        assert!((patch_corner - original).length() > 0.01);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
 #![allow(dead_code)]
 
+use glam::Vec4;
+
 use crate::{
+    bbox::BBox,
     fp_utils::fp_gamma,
-    math::{Point, Vector},
+    lerp::Lerp,
+    math::{Matrix4x4, Point, Vector},
 };
 
 #[derive(Debug, Copy, Clone)]
@@ -148,6 +152,236 @@ pub fn intersect_ray(
     Some((t, b0, b1, b2))
 }
 
+/// Four triangles, stored in SoA layout for testing a ray against all of
+/// them at once with `intersect_ray()`.
+///
+/// Lanes without a triangle have NaN vertices, which never hit.
+#[derive(Debug, Copy, Clone)]
+pub struct Triangle4 {
+    p0: [Vec4; 3], // x, y, and z of the first vertex of each triangle
+    p1: [Vec4; 3],
+    p2: [Vec4; 3],
+}
+
+impl Triangle4 {
+    /// Creates a `Triangle4` with no triangles in it.
+    pub fn new() -> Triangle4 {
+        let empty = [Vec4::splat(f32::NAN); 3];
+        Triangle4 {
+            p0: empty,
+            p1: empty,
+            p2: empty,
+        }
+    }
+
+    /// Creates a `Triangle4` from up to four triangles.
+    pub fn from_triangles(tris: &[(Point, Point, Point)]) -> Triangle4 {
+        assert!(tris.len() <= 4);
+        let mut p = [[[f32::NAN; 4]; 3]; 3];
+        for (lane, tri) in tris.iter().enumerate() {
+            for (vert, v) in [tri.0, tri.1, tri.2].iter().enumerate() {
+                p[vert][0][lane] = v.x();
+                p[vert][1][lane] = v.y();
+                p[vert][2][lane] = v.z();
+            }
+        }
+
+        let to_vec4 = |a: [f32; 4]| Vec4::new(a[0], a[1], a[2], a[3]);
+        let to_vec4s = |p: [[f32; 4]; 3]| [to_vec4(p[0]), to_vec4(p[1]), to_vec4(p[2])];
+        Triangle4 {
+            p0: to_vec4s(p[0]),
+            p1: to_vec4s(p[1]),
+            p2: to_vec4s(p[2]),
+        }
+    }
+
+    /// Returns the triangle in the given lane.
+    pub fn triangle(&self, lane: usize) -> (Point, Point, Point) {
+        let point = |p: &[Vec4; 3]| {
+            let x: [f32; 4] = p[0].into();
+            let y: [f32; 4] = p[1].into();
+            let z: [f32; 4] = p[2].into();
+            Point::new(x[lane], y[lane], z[lane])
+        };
+        (point(&self.p0), point(&self.p1), point(&self.p2))
+    }
+
+    /// Returns the triangles transformed by `mat`.
+    ///
+    /// Each vertex is transformed the same way as a lone `Point` is, so that
+    /// the results are exactly the same.
+    pub fn transformed(&self, mat: Matrix4x4) -> Triangle4 {
+        let mut tris = [self.triangle(0); 4];
+        for (lane, tri) in tris.iter_mut().enumerate() {
+            let t = self.triangle(lane);
+            *tri = (t.0 * mat, t.1 * mat, t.2 * mat);
+        }
+        Triangle4::from_triangles(&tris)
+    }
+
+    /// Intersects `ray` with the four triangles, returning the lane of the
+    /// closest hit along with its `(t, b0, b1, b2)`, as described for the
+    /// single-triangle `intersect_ray()`, or `None` if no triangle was hit.
+    ///
+    /// This gives exactly the same result as testing the triangles one at a
+    /// time with `intersect_ray()`, in lane order, shortening the ray at each
+    /// hit.
+    pub fn intersect_ray(
+        &self,
+        ray_orig: Point,
+        ray_pre: RayTriPrecompute,
+        ray_max_t: f32,
+    ) -> Option<(usize, f32, f32, f32, f32)> {
+        let (xi, yi, zi) = ray_pre.i;
+        let zero = Vec4::splat(0.0);
+
+        // Calculate vertices in ray space.
+        let orig = [
+            Vec4::splat(ray_orig.x()),
+            Vec4::splat(ray_orig.y()),
+            Vec4::splat(ray_orig.z()),
+        ];
+        let sx = Vec4::splat(ray_pre.s.0);
+        let sy = Vec4::splat(ray_pre.s.1);
+        let sz = Vec4::splat(ray_pre.s.2);
+        let ray_space = |p: &[Vec4; 3]| {
+            let px = p[xi] - orig[xi];
+            let py = p[yi] - orig[yi];
+            let pz = p[zi] - orig[zi];
+            (px - (sx * pz), py - (sy * pz), sz * pz)
+        };
+        let (p0x, p0y, p0z) = ray_space(&self.p0);
+        let (p1x, p1y, p1z) = ray_space(&self.p1);
+        let (p2x, p2y, p2z) = ray_space(&self.p2);
+
+        // Calculate scaled barycentric coordinates.
+        let mut e0 = (p1x * p2y) - (p1y * p2x);
+        let mut e1 = (p2x * p0y) - (p2y * p0x);
+        let mut e2 = (p0x * p1y) - (p0y * p1x);
+
+        // Fallback to test against edges using double precision, in the
+        // lanes that need it.
+        let on_edge = e0.cmpeq(zero) | e1.cmpeq(zero) | e2.cmpeq(zero);
+        if on_edge.any() {
+            let lanes = |v: Vec4| -> [f32; 4] { v.into() };
+            let (p0x, p0y) = (lanes(p0x), lanes(p0y));
+            let (p1x, p1y) = (lanes(p1x), lanes(p1y));
+            let (p2x, p2y) = (lanes(p2x), lanes(p2y));
+            let (mut e0s, mut e1s, mut e2s) = (lanes(e0), lanes(e1), lanes(e2));
+            let edge_mask = on_edge.bitmask();
+            for i in 0..4 {
+                if edge_mask & (1 << i) != 0 {
+                    e0s[i] =
+                        ((p1x[i] as f64 * p2y[i] as f64) - (p1y[i] as f64 * p2x[i] as f64)) as f32;
+                    e1s[i] =
+                        ((p2x[i] as f64 * p0y[i] as f64) - (p2y[i] as f64 * p0x[i] as f64)) as f32;
+                    e2s[i] =
+                        ((p0x[i] as f64 * p1y[i] as f64) - (p0y[i] as f64 * p1x[i] as f64)) as f32;
+                }
+            }
+            e0 = Vec4::new(e0s[0], e0s[1], e0s[2], e0s[3]);
+            e1 = Vec4::new(e1s[0], e1s[1], e1s[2], e1s[3]);
+            e2 = Vec4::new(e2s[0], e2s[1], e2s[2], e2s[3]);
+        }
+
+        // Check if the ray hit the triangles.  The tests are all written so
+        // that they fail for NaNs, so that empty lanes never hit.
+        let hit = (e0.cmpge(zero) & e1.cmpge(zero) & e2.cmpge(zero))
+            | (e0.cmple(zero) & e1.cmple(zero) & e2.cmple(zero));
+
+        // Determinant
+        let det = e0 + e1 + e2;
+        let hit = hit & det.cmpne(zero);
+        if !hit.any() {
+            return None;
+        }
+
+        // Calculate t of hitpoint, and check if it's within ray min/max t.
+        let t_scaled = (e0 * p0z) + (e1 * p1z) + (e2 * p2z);
+        let max_t_scaled = Vec4::splat(ray_max_t) * det;
+        let hit = hit
+            & ((det.cmpgt(zero) & t_scaled.cmpgt(zero) & t_scaled.cmple(max_t_scaled))
+                | (det.cmplt(zero) & t_scaled.cmplt(zero) & t_scaled.cmpge(max_t_scaled)));
+        if !hit.any() {
+            return None;
+        }
+
+        // Calculate t and the hitpoint barycentric coordinates.
+        let inv_det = Vec4::splat(1.0) / det;
+        let b0 = e0 * inv_det;
+        let b1 = e1 * inv_det;
+        let b2 = e2 * inv_det;
+        let t = t_scaled * inv_det;
+
+        // Check error bounds on t for very close hit points, the same as in
+        // the single-triangle version.
+        let dt = {
+            // Calculate delta z
+            let max_zt = max_abs_3_4(p0z, p1z, p2z);
+            let dz = Vec4::splat(fp_gamma(3)) * max_zt;
+
+            // Calculate delta x and y
+            let max_xt = max_abs_3_4(p0x, p1x, p2x);
+            let max_yt = max_abs_3_4(p0y, p1y, p2y);
+            let dx = Vec4::splat(fp_gamma(5)) * (max_xt + max_zt);
+            let dy = Vec4::splat(fp_gamma(5)) * (max_yt + max_zt);
+
+            // Calculate delta e
+            let de = Vec4::splat(2.0)
+                * ((Vec4::splat(fp_gamma(2)) * max_xt * max_yt) + (dy * max_xt + dx * max_yt));
+
+            // Calculate delta t
+            let max_e = max_abs_3_4(e0, e1, e2);
+            Vec4::splat(3.0)
+                * ((Vec4::splat(fp_gamma(3)) * max_e * max_zt) + (de * max_zt + dz * max_e))
+                * inv_det.abs()
+        };
+        let hit = hit & t.cmpgt(dt);
+
+        // Pick the hit the sequential version would have ended up with: each
+        // hit shortens the ray, and later lanes have to hit within that.
+        let hit_mask = hit.bitmask();
+        if hit_mask == 0 {
+            return None;
+        }
+        let t: [f32; 4] = t.into();
+        let t_scaled: [f32; 4] = t_scaled.into();
+        let det: [f32; 4] = det.into();
+        let mut closest = None;
+        let mut max_t = ray_max_t;
+        for i in 0..4 {
+            if hit_mask & (1 << i) != 0 {
+                let in_range = if det[i] > 0.0 {
+                    t_scaled[i] <= (max_t * det[i])
+                } else {
+                    t_scaled[i] >= (max_t * det[i])
+                };
+                if in_range {
+                    closest = Some(i);
+                    max_t = t[i];
+                }
+            }
+        }
+
+        closest.map(|i| {
+            let b0: [f32; 4] = b0.into();
+            let b1: [f32; 4] = b1.into();
+            let b2: [f32; 4] = b2.into();
+            (i, t[i], b0[i], b1[i], b2[i])
+        })
+    }
+}
+
+impl Lerp for Triangle4 {
+    fn lerp(self, other: Triangle4, alpha: f32) -> Triangle4 {
+        Triangle4 {
+            p0: self.p0.lerp(other.p0, alpha),
+            p1: self.p1.lerp(other.p1, alpha),
+            p2: self.p2.lerp(other.p2, alpha),
+        }
+    }
+}
+
 /// Calculates a point on a triangle's surface at the given barycentric
 /// coordinates.
 ///
@@ -181,3 +415,278 @@ fn max_abs_3(a: f32, b: f32, c: f32) -> f32 {
         c
     }
 }
+
+fn max_abs_3_4(a: Vec4, b: Vec4, c: Vec4) -> Vec4 {
+    a.abs().max(b.abs()).max(c.abs())
+}
+
+/// Splits a triangle with a plane at `pos` along the given axis (0 = x,
+/// 1 = y, 2 = z), returning the bounds of the parts of it below and above
+/// the plane, clipped to `bounds`.
+///
+/// This is for building BVHs with spatial splits.  Parts that would be
+/// empty are returned as degenerate BBoxes, the same as from `BBox::new()`.
+pub fn split_bounds(
+    tri: (Point, Point, Point),
+    bounds: BBox,
+    axis: usize,
+    pos: f32,
+) -> (BBox, BBox) {
+    let mut below = BBox::new();
+    let mut above = BBox::new();
+
+    let verts = [tri.0, tri.1, tri.2];
+    for i in 0..3 {
+        let a = verts[i];
+        let b = verts[(i + 1) % 3];
+        let a_n = a.get_n(axis);
+        let b_n = b.get_n(axis);
+
+        if a_n <= pos {
+            below |= a;
+        }
+        if a_n >= pos {
+            above |= a;
+        }
+
+        // Add where the edge crosses the plane, if it does.
+        if (a_n < pos && b_n > pos) || (a_n > pos && b_n < pos) {
+            let t = (pos - a_n) / (b_n - a_n);
+            let p = a.lerp(b, t.max(0.0).min(1.0));
+            below |= p;
+            above |= p;
+        }
+    }
+
+    let (bounds_below, bounds_above) = bounds.split(axis, pos);
+    let below = below & bounds_below;
+    let above = above & bounds_above;
+    (
+        if below.is_empty() { BBox::new() } else { below },
+        if above.is_empty() { BBox::new() } else { above },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hash::hash_u32_to_f32;
+
+    /// Tests the triangles one at a time with the single-triangle
+    /// `intersect_ray()`, in order, shortening the ray at each hit.
+    fn intersect_sequential(
+        tris: &[(Point, Point, Point)],
+        ray_orig: Point,
+        ray_pre: RayTriPrecompute,
+        ray_max_t: f32,
+    ) -> Option<(usize, f32, f32, f32, f32)> {
+        let mut closest = None;
+        let mut max_t = ray_max_t;
+        for (i, &tri) in tris.iter().enumerate() {
+            if let Some((t, b0, b1, b2)) = intersect_ray(ray_orig, ray_pre, max_t, tri) {
+                closest = Some((i, t, b0, b1, b2));
+                max_t = t;
+            }
+        }
+        closest
+    }
+
+    fn check_against_sequential(tris: &[(Point, Point, Point)], orig: Point, dir: Vector) {
+        let pre = RayTriPrecompute::new(dir);
+        let tri4 = Triangle4::from_triangles(tris);
+        for &max_t in &[f32::INFINITY, 2.0] {
+            assert_eq!(
+                tri4.intersect_ray(orig, pre, max_t),
+                intersect_sequential(tris, orig, pre, max_t),
+                "{:?} {:?} {:?} {}",
+                tris,
+                orig,
+                dir,
+                max_t,
+            );
+        }
+    }
+
+    fn random_point(n: u32, seed: u32) -> Point {
+        Point::new(
+            hash_u32_to_f32(n * 3, seed) * 2.0 - 1.0,
+            hash_u32_to_f32(n * 3 + 1, seed) * 2.0 - 1.0,
+            hash_u32_to_f32(n * 3 + 2, seed) * 2.0 - 1.0,
+        )
+    }
+
+    #[test]
+    fn triangle4_matches_sequential_with_empty_lanes() {
+        let mut hit_count = 0;
+        for seed in 0..500 {
+            let tris: Vec<_> = (0..4)
+                .map(|i| {
+                    (
+                        random_point(i * 3, seed),
+                        random_point(i * 3 + 1, seed),
+                        random_point(i * 3 + 2, seed),
+                    )
+                })
+                .collect();
+            let orig = (random_point(12, seed).into_vector() * 2.0).into_point();
+            let dir = random_point(13, seed) - orig;
+
+            // Full and partial groups, the latter with NaN lanes.
+            for len in 0..=4 {
+                check_against_sequential(&tris[..len], orig, dir);
+            }
+            if intersect_sequential(&tris, orig, RayTriPrecompute::new(dir), f32::INFINITY)
+                .is_some()
+            {
+                hit_count += 1;
+            }
+        }
+        assert!(hit_count > 50);
+
+        // An empty group never hits.
+        let pre = RayTriPrecompute::new(Vector::new(0.0, 0.0, 1.0));
+        assert_eq!(
+            Triangle4::new().intersect_ray(Point::new(0.0, 0.0, -1.0), pre, f32::INFINITY),
+            None
+        );
+    }
+
+    #[test]
+    fn triangle4_matches_sequential_on_shared_edges_and_vertices() {
+        // A fan of triangles around the origin in the z = 1 plane, sharing
+        // edges along the axes and the vertex at the origin.
+        let c = Point::new(0.0, 0.0, 1.0);
+        let px = Point::new(1.0, 0.0, 1.0);
+        let py = Point::new(0.0, 1.0, 1.0);
+        let nx = Point::new(-1.0, 0.0, 1.0);
+        let ny = Point::new(0.0, -1.0, 1.0);
+        let fan = [(c, px, py), (c, py, nx), (c, nx, ny), (c, ny, px)];
+        let dir = Vector::new(0.0, 0.0, 1.0);
+
+        // The shared vertex, points on the shared edges, and on the outer
+        // edges and vertices.
+        let targets = [
+            (0.0, 0.0),
+            (0.5, 0.0),
+            (0.0, 0.5),
+            (-0.25, 0.0),
+            (0.0, -0.75),
+            (0.5, 0.5),
+            (1.0, 0.0),
+            (0.0, -1.0),
+        ];
+        for &(x, y) in &targets {
+            let orig = Point::new(x, y, 0.0);
+            let pre = RayTriPrecompute::new(dir);
+
+            // The tests are exact on the edges, so the ray has to hit
+            // something.
+            assert!(intersect_sequential(&fan, orig, pre, f32::INFINITY).is_some());
+            for len in 1..=4 {
+                check_against_sequential(&fan[..len], orig, dir);
+            }
+
+            // Same with the winding flipped, and the triangles reordered.
+            let flipped: Vec<_> = fan.iter().rev().map(|&(a, b, c)| (a, c, b)).collect();
+            check_against_sequential(&flipped, orig, dir);
+        }
+
+        // Pairs of random triangles sharing an edge, with rays aimed at
+        // points along it, where the edge tests round to zero.
+        for seed in 0..500 {
+            let (a, b) = (random_point(0, seed), random_point(1, seed));
+            let tris = [(a, b, random_point(2, seed)), (b, a, random_point(3, seed))];
+            let target = a.lerp(b, hash_u32_to_f32(4, seed));
+            let orig = (random_point(5, seed).into_vector() * 2.0).into_point();
+            for &dir in &[target - orig, a - orig, b - orig] {
+                check_against_sequential(&tris, orig, dir);
+                check_against_sequential(&[tris[1], tris[0]], orig, dir);
+            }
+        }
+    }
+
+    #[test]
+    fn triangle4_lane_order_decides_overlapping_hits() {
+        let tri = |z: f32| {
+            (
+                Point::new(-1.0, -1.0, z),
+                Point::new(1.0, -1.0, z),
+                Point::new(0.0, 1.0, z),
+            )
+        };
+        let orig = Point::new(0.0, 0.0, 0.0);
+        let dir = Vector::new(0.0, 0.0, 1.0);
+        let pre = RayTriPrecompute::new(dir);
+
+        // Coincident triangles: the last lane wins, as it would when testing
+        // them in order.
+        let tris = [tri(1.0), tri(2.0), tri(1.0), tri(3.0)];
+        let hit = Triangle4::from_triangles(&tris).intersect_ray(orig, pre, f32::INFINITY);
+        assert_eq!(hit.map(|h| h.0), Some(2));
+        check_against_sequential(&tris, orig, dir);
+
+        // The closest hit wins regardless of lane.
+        let tris = [tri(3.0), tri(2.0), tri(0.5), tri(1.0)];
+        let hit = Triangle4::from_triangles(&tris).intersect_ray(orig, pre, f32::INFINITY);
+        assert_eq!(hit.map(|h| (h.0, h.1)), Some((2, 0.5)));
+        check_against_sequential(&tris, orig, dir);
+
+        // Hits beyond the ray's max t don't count.
+        let hit = Triangle4::from_triangles(&tris[..2]).intersect_ray(orig, pre, 2.5);
+        assert_eq!(hit.map(|h| (h.0, h.1)), Some((1, 2.0)));
+    }
+
+    #[test]
+    fn split_bounds_straddling_triangle() {
+        let tri = (
+            Point::new(0.0, 0.0, 0.0),
+            Point::new(1.0, 0.0, 0.0),
+            Point::new(0.0, 1.0, 0.0),
+        );
+        let bounds = BBox::from_points(Point::new(-1.0, -1.0, -1.0), Point::new(2.0, 2.0, 1.0));
+        let (below, above) = split_bounds(tri, bounds, 0, 0.5);
+        assert_eq!(below.min, Point::new(0.0, 0.0, 0.0));
+        assert_eq!(below.max, Point::new(0.5, 1.0, 0.0));
+        assert_eq!(above.min, Point::new(0.5, 0.0, 0.0));
+        assert_eq!(above.max, Point::new(1.0, 0.5, 0.0));
+
+        // The parts' bounds are clipped to the bounds.
+        let bounds = BBox::from_points(Point::new(-1.0, 0.25, -1.0), Point::new(2.0, 2.0, 1.0));
+        let (below, above) = split_bounds(tri, bounds, 0, 0.5);
+        assert_eq!(below.min, Point::new(0.0, 0.25, 0.0));
+        assert_eq!(below.max, Point::new(0.5, 1.0, 0.0));
+        assert_eq!(above.min, Point::new(0.5, 0.25, 0.0));
+        assert_eq!(above.max, Point::new(1.0, 0.5, 0.0));
+
+        // A triangle entirely on one side leaves the other side empty.
+        let (below, above) = split_bounds(tri, bounds, 1, 2.0);
+        assert_eq!(below.min, Point::new(0.0, 0.25, 0.0));
+        assert_eq!(below.max, Point::new(1.0, 1.0, 0.0));
+        assert!(above.is_empty());
+    }
+
+    #[test]
+    fn split_bounds_triangle_on_plane() {
+        let tri = (
+            Point::new(0.0, 0.0, 0.5),
+            Point::new(1.0, 0.0, 0.5),
+            Point::new(0.0, 1.0, 0.5),
+        );
+        let bounds = BBox::from_points(Point::new(-1.0, -1.0, -1.0), Point::new(2.0, 2.0, 1.0));
+
+        // The whole triangle is on both sides.
+        let (below, above) = split_bounds(tri, bounds, 2, 0.5);
+        for b in &[below, above] {
+            assert_eq!(b.min, Point::new(0.0, 0.0, 0.5));
+            assert_eq!(b.max, Point::new(1.0, 1.0, 0.5));
+        }
+
+        // Likewise for just an edge on the plane, with the rest of the
+        // triangle on one side.
+        let (below, above) = split_bounds(tri, bounds, 1, 0.0);
+        assert_eq!(below.min, Point::new(0.0, 0.0, 0.5));
+        assert_eq!(below.max, Point::new(1.0, 0.0, 0.5));
+        assert_eq!(above.min, Point::new(0.0, 0.0, 0.5));
+        assert_eq!(above.max, Point::new(1.0, 1.0, 0.5));
+    }
+}
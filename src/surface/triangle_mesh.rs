@@ -1,58 +1,219 @@
-#![allow(dead_code)]
+use std::{io, mem::size_of};
 
 use kioku::Arena;
 
 use crate::{
-    accel::BVH4,
+    accel::{SplitMode, WideBVH},
     bbox::BBox,
+    bbox8::simd8_is_supported,
     boundable::Boundable,
-    lerp::lerp_slice,
-    math::{cross, dot, Matrix4x4, Normal, Point},
+    cache::{self, CacheReader, CacheWriter, Cacheable},
+    hash::ContentHasher,
+    lerp::{lerp, lerp_slice, Lerp},
+    math::{cross, dot, Matrix4x4, Normal, Point, Vector},
+    paging::{PageFile, PageFiles},
     ray::{RayBatch, RayStack},
     shading::SurfaceShader,
 };
 
-use super::{triangle, Surface, SurfaceIntersection, SurfaceIntersectionData};
+use super::{
+    triangle::{self, Triangle4},
+    Surface, SurfaceIntersection, SurfaceIntersectionData,
+};
+
+// Leaves hold one `Triangle4` worth of triangles, for the SIMD kernel.
+const MAX_LEAF_TRIANGLE_COUNT: usize = 4;
+
+// Compressed meshes store their vertices per BVH leaf, so they use larger
+// leaves to share more vertices between triangles.
+const MAX_COMPRESSED_LEAF_TRIANGLE_COUNT: usize = 8;
+
+// The most `Triangle4`s any leaf's triangles are tested as.
+const MAX_LEAF_TRIANGLE4_COUNT: usize = MAX_COMPRESSED_LEAF_TRIANGLE_COUNT.div_ceil(4);
+
+// The minimum number of grid cells across the bounds of a compressed mesh
+// that its vertex positions are snapped to.  This is close to the precision
+// of 32-bit floats at the scale of the mesh.
+const COMPRESSED_GRID_RES: f32 = (1 << 22) as f32;
 
-const MAX_LEAF_TRIANGLE_COUNT: usize = 3;
+// The size in bytes that paged meshes group their leaves into pages of.
+const PAGE_SIZE: usize = 1 << 16;
+
+/// How a mesh's geometry is stored.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum MeshStorage {
+    /// Full-precision vertex positions and normals, in memory.  The
+    /// positions are stored per BVH leaf, in the SoA layout of the SIMD
+    /// intersection kernel, so they take more memory than the others.
+    Full,
+
+    /// Quantized vertex positions and oct-encoded normals, which take less
+    /// memory at the cost of decoding them during intersection.  The
+    /// quantization is to a grid with over four million cells across the
+    /// mesh, which is close to the precision of the original positions,
+    /// except for vertices of very large triangles, which use a coarser grid.
+    Compressed,
+
+    /// Full-precision vertex positions and normals in a memory-mapped file,
+    /// which are paged in a BVH leaf at a time as rays reach them.  Only
+    /// the mesh's BVH stays in memory.  See the `paging` module.
+    Paged,
+}
 
 #[derive(Copy, Clone, Debug)]
 pub struct TriangleMesh<'a> {
     time_sample_count: usize,
-    vertices: &'a [Point], // Vertices, with the time samples for each vertex stored contiguously
-    normals: Option<&'a [Normal]>, // Vertex normals, organized the same as `vertices`
-    indices: &'a [(u32, u32, u32, u32)], // (v0_idx, v1_idx, v2_idx, original_tri_idx)
-    accel: BVH4<'a>,
+    geometry: MeshGeometry<'a>,
+    accel: WideBVH<'a>,
+}
+
+#[derive(Copy, Clone, Debug)]
+enum MeshGeometry<'a> {
+    Full {
+        triangles: &'a [Triangle4], // Triangles of each leaf, with the time samples for each leaf stored contiguously
+        leaf_starts: &'a [u32],     // Index of the first triangle of each leaf
+        normals: Option<&'a [Normal]>, // Vertex normals, with the time samples for each vertex stored contiguously
+        indices: &'a [(u32, u32, u32, u32)], // (v0_idx, v1_idx, v2_idx, original_tri_idx)
+    },
+    Compressed(CompressedGeometry<'a>),
+    Paged(PagedGeometry<'a>),
+}
+
+/// Mesh geometry with quantized vertex positions and oct-encoded normals.
+///
+/// Each leaf of the BVH has its own block of vertices, whose positions are
+/// stored as 16-bit offsets from the minimum corner of the leaf's bounds.
+/// Those offsets count cells of a grid that covers the whole mesh, and all
+/// vertices are snapped to that grid, so vertices shared between leaves
+/// decode to exactly the same position and the mesh stays watertight.
+///
+/// Blocks too large to fit in 16 bits count cells of a coarser level of the
+/// grid instead, whose cells are 2^level times the size.  Their vertices are
+/// snapped to that coarser level in every block they're in, so that large
+/// triangles only reduce the precision of the vertices they touch.
+#[derive(Copy, Clone, Debug)]
+struct CompressedGeometry<'a> {
+    grid_origins: &'a [Point],  // Minimum corner of the grid, per time sample
+    grid_cell_sizes: &'a [f32], // Size of the grid cells, per time sample
+    block_origins: &'a [[u32; 3]], // Grid coordinates of each block's bounds, per time sample
+    block_levels: &'a [u8],     // Level of the grid each block is quantized at, per time sample
+    block_starts: &'a [u32],    // Index of the first vertex of each block
+    vertices: &'a [[u16; 3]],   // Offsets from the block origin, with time samples contiguous
+    normals: Option<&'a [u32]>, // Oct-encoded vertex normals, organized the same as `vertices`
+    triangles: &'a [(u32, u32)], // (block_idx, 8-bit local vertex indices packed together)
+}
+
+/// Mesh geometry that's paged in from a memory-mapped file.
+///
+/// The triangles are stored in the order the BVH's leaves refer to them,
+/// each with its vertices followed by its vertex normals, if any.  The
+/// leaves are grouped into pages of around `PAGE_SIZE` bytes, and no leaf is
+/// split between pages, so a leaf only ever needs one page to be paged in.
+#[derive(Copy, Clone, Debug)]
+struct PagedGeometry<'a> {
+    file: &'a PageFile,
+    pages: &'a [(usize, usize)], // (first_tri_idx, byte_offset) of each page
+    has_normals: bool,
+}
+
+/// A decoded page of `PagedGeometry`.
+struct MeshPage {
+    first_tri_idx: usize,
+    vertices: Vec<Point>, // Vertices of each triangle, with the time samples for each vertex stored contiguously
+    normals: Vec<Normal>, // Vertex normals, organized the same as `vertices`, or empty
 }
 
 impl<'a> TriangleMesh<'a> {
+    /// Creates a mesh from the given vertices and triangles, with its
+    /// geometry stored as `storage`.
+    ///
+    /// The page files of paged meshes are kept in `page_files`.  If caching
+    /// is enabled, the mesh is loaded from the cache when it has been built
+    /// from the same data before, and page files are then kept in the cache
+    /// directory as well.
+    ///
+    /// Returns an error if the page file of a paged mesh can't be written.
     pub fn from_verts_and_indices<'b>(
         arena: &'b Arena,
+        page_files: &'b PageFiles,
         verts: &[Vec<Point>],
         vert_normals: &Option<Vec<Vec<Normal>>>,
         tri_indices: &[(usize, usize, usize)],
-    ) -> TriangleMesh<'b> {
-        let vert_count = verts[0].len();
-        let time_sample_count = verts.len();
-
-        // Copy verts over to a contiguous area of memory, reorganizing them
-        // so that each vertices' time samples are contiguous in memory.
-        let vertices = {
-            let vertices = arena.alloc_array_uninit(vert_count * time_sample_count);
-
-            for vi in 0..vert_count {
-                for ti in 0..time_sample_count {
-                    unsafe {
-                        *vertices[(vi * time_sample_count) + ti].as_mut_ptr() = verts[ti][vi];
+        split_mode: SplitMode,
+        storage: MeshStorage,
+    ) -> io::Result<TriangleMesh<'b>> {
+        cache::load_or_build(
+            arena,
+            page_files,
+            || {
+                let mut hasher = ContentHasher::new();
+                hasher.write_u64(verts.len() as u64);
+                for time_sample in verts {
+                    hasher.write_slice(time_sample);
+                }
+                match vert_normals {
+                    Some(ref vnors) => {
+                        hasher.write_u64(1);
+                        for time_sample in vnors {
+                            hasher.write_slice(time_sample);
+                        }
                     }
+                    None => hasher.write_u64(0),
                 }
-            }
+                hasher.write_slice(tri_indices);
+                match split_mode {
+                    SplitMode::Object => hasher.write_u64(0),
+                    SplitMode::Spatial(threshold) => {
+                        hasher.write_u64(1);
+                        hasher.write_u64(threshold.to_bits() as u64);
+                    }
+                }
+                hasher.write_u64(storage as u64);
+                hasher.write_u64(MAX_LEAF_TRIANGLE_COUNT as u64);
+                hasher.write_u64(MAX_COMPRESSED_LEAF_TRIANGLE_COUNT as u64);
+                hasher.write_u64(PAGE_SIZE as u64);
+                hasher.write_u64(simd8_is_supported() as u64);
+                hasher.finish()
+            },
+            || match storage {
+                MeshStorage::Full => Ok(TriangleMesh::build(
+                    arena,
+                    verts,
+                    vert_normals,
+                    tri_indices,
+                    split_mode,
+                )),
+                MeshStorage::Compressed => Ok(TriangleMesh::build_compressed(
+                    arena,
+                    verts,
+                    vert_normals,
+                    tri_indices,
+                    split_mode,
+                )),
+                MeshStorage::Paged => TriangleMesh::build_paged(
+                    arena,
+                    page_files,
+                    verts,
+                    vert_normals,
+                    tri_indices,
+                    split_mode,
+                ),
+            },
+        )
+    }
 
-            unsafe { std::mem::transmute(vertices) }
-        };
+    fn build<'b>(
+        arena: &'b Arena,
+        verts: &[Vec<Point>],
+        vert_normals: &Option<Vec<Vec<Normal>>>,
+        tri_indices: &[(usize, usize, usize)],
+        split_mode: SplitMode,
+    ) -> TriangleMesh<'b> {
+        let vert_count = verts[0].len();
+        let time_sample_count = verts.len();
 
-        // Copy vertex normals, if any, organizing them the same as vertices
-        // above.
+        // Copy vertex normals, if any, reorganizing them so that each
+        // vertex's time samples are contiguous in memory.
         let normals = match vert_normals {
             Some(ref vnors) => {
                 let normals = arena.alloc_array_uninit(vert_count * time_sample_count);
@@ -71,48 +232,834 @@ impl<'a> TriangleMesh<'a> {
             None => None,
         };
 
-        // Copy triangle vertex indices over, appending the triangle index itself to the tuple
-        let indices: &mut [(u32, u32, u32, u32)] = {
-            let indices = arena.alloc_array_uninit(tri_indices.len());
-            for (i, tri_i) in tri_indices.iter().enumerate() {
-                unsafe {
-                    *indices[i].as_mut_ptr() =
-                        (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32, i as u32);
+        let (accel, indices) = build_accel(
+            arena,
+            verts,
+            tri_indices,
+            MAX_LEAF_TRIANGLE_COUNT,
+            split_mode,
+        );
+
+        // Store the triangles of each leaf together in SoA layout.
+        let mut leaf_ranges = accel.leaf_object_ranges();
+        leaf_ranges.sort_unstable();
+        leaf_ranges.dedup();
+        leaf_ranges.retain(|&(start, end)| start < end);
+        let mut leaf_starts = Vec::with_capacity(leaf_ranges.len());
+        let mut triangles = Vec::with_capacity(leaf_ranges.len() * time_sample_count);
+        for &(start, end) in &leaf_ranges {
+            leaf_starts.push(start as u32);
+            for time_sample in verts {
+                let tris: Vec<_> = indices[start..end]
+                    .iter()
+                    .map(|tri| {
+                        (
+                            time_sample[tri.0 as usize],
+                            time_sample[tri.1 as usize],
+                            time_sample[tri.2 as usize],
+                        )
+                    })
+                    .collect();
+                triangles.push(Triangle4::from_triangles(&tris));
+            }
+        }
+
+        TriangleMesh {
+            time_sample_count: time_sample_count,
+            geometry: MeshGeometry::Full {
+                triangles: arena.copy_slice(&triangles),
+                leaf_starts: arena.copy_slice(&leaf_starts),
+                normals: normals,
+                indices: arena.copy_slice(&indices),
+            },
+            accel: accel,
+        }
+    }
+
+    fn build_compressed<'b>(
+        arena: &'b Arena,
+        verts: &[Vec<Point>],
+        vert_normals: &Option<Vec<Vec<Normal>>>,
+        tri_indices: &[(usize, usize, usize)],
+        split_mode: SplitMode,
+    ) -> TriangleMesh<'b> {
+        let time_sample_count = verts.len();
+
+        // Choose the grid for each time sample.
+        let grids: Vec<(Point, f32)> = verts
+            .iter()
+            .map(|time_sample| {
+                let mut bounds = BBox::new();
+                for &p in time_sample {
+                    bounds |= p;
+                }
+                let extent = max_component(bounds.max - bounds.min);
+                (bounds.min, grid_cell_size(extent / COMPRESSED_GRID_RES))
+            })
+            .collect();
+
+        // Snaps the vertices to the grid, each at the level given by
+        // `vert_levels`.
+        let snap = |vert_levels: &[Vec<u8>]| -> Vec<Vec<[u32; 3]>> {
+            verts
+                .iter()
+                .zip(grids.iter())
+                .zip(vert_levels.iter())
+                .map(|((time_sample, &(origin, cell_size)), levels)| {
+                    time_sample
+                        .iter()
+                        .zip(levels.iter())
+                        .map(|(&p, &level)| {
+                            let v = (p - origin) / (cell_size * (1u32 << level) as f32);
+                            [
+                                (v.x().round() as u32) << level,
+                                (v.y().round() as u32) << level,
+                                (v.z().round() as u32) << level,
+                            ]
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        let grid_verts = |coords: &[Vec<[u32; 3]>]| -> Vec<Vec<Point>> {
+            coords
+                .iter()
+                .zip(grids.iter())
+                .map(|(time_sample, &(origin, cell_size))| {
+                    time_sample
+                        .iter()
+                        .map(|&co| grid_point(origin, cell_size, co))
+                        .collect()
+                })
+                .collect()
+        };
+
+        let mut vert_levels = vec![vec![0u8; verts[0].len()]; time_sample_count];
+        let mut coords = snap(&vert_levels);
+        let (mut accel, indices) = build_accel(
+            arena,
+            &grid_verts(&coords),
+            tri_indices,
+            MAX_COMPRESSED_LEAF_TRIANGLE_COUNT,
+            split_mode,
+        );
+
+        // Group the vertices of each leaf into a block.
+        let mut leaf_ranges = accel.leaf_object_ranges();
+        leaf_ranges.sort_unstable();
+        leaf_ranges.dedup();
+        let mut block_starts = Vec::with_capacity(leaf_ranges.len());
+        let mut block_verts = Vec::new(); // Indices of the original vertices
+        let mut triangles = vec![(0, 0); indices.len()];
+        for (block_i, &(start, end)) in leaf_ranges.iter().enumerate() {
+            let block_start = block_verts.len();
+            block_starts.push(block_start as u32);
+            for tri_i in start..end {
+                let tri = indices[tri_i];
+                let mut packed = 0;
+                for (corner, &vert_i) in [tri.0, tri.1, tri.2].iter().enumerate() {
+                    let local_i = match block_verts[block_start..].iter().position(|&v| v == vert_i)
+                    {
+                        Some(i) => i,
+                        None => {
+                            block_verts.push(vert_i);
+                            block_verts.len() - block_start - 1
+                        }
+                    };
+                    debug_assert!(local_i < 256);
+                    packed |= (local_i as u32) << (corner * 8);
                 }
+                triangles[tri_i] = (block_i as u32, packed);
             }
-            unsafe { std::mem::transmute(indices) }
+        }
+        let block_range = |block_i: usize| {
+            let start = block_starts[block_i] as usize;
+            let end = block_starts
+                .get(block_i + 1)
+                .map_or(block_verts.len(), |&end| end as usize);
+            &block_verts[start..end]
         };
 
-        // Create bounds array for use during BVH construction
-        let bounds = {
-            let mut bounds = Vec::with_capacity(indices.len() * time_sample_count);
-            for tri in tri_indices {
+        // Choose the level of each block, which is the smallest that its
+        // vertices fit in 16 bits at.  A vertex is snapped to the coarsest
+        // level of the blocks it's in, so that it decodes to the same
+        // position in all of them.  Snapping to a coarser level can grow the
+        // blocks the vertex is in, so repeat until nothing changes.  This is
+        // rare, since leaves are usually only a little larger than their
+        // largest triangle.
+        let mut block_origins = Vec::with_capacity(leaf_ranges.len() * time_sample_count);
+        let mut block_levels = Vec::with_capacity(leaf_ranges.len() * time_sample_count);
+        loop {
+            block_origins.clear();
+            block_levels.clear();
+            let mut changed = false;
+            for block_i in 0..leaf_ranges.len() {
+                for ti in 0..time_sample_count {
+                    let mut min = [u32::MAX; 3];
+                    let mut max = [0; 3];
+                    for &vert_i in block_range(block_i) {
+                        let co = coords[ti][vert_i as usize];
+                        for axis in 0..3 {
+                            min[axis] = min[axis].min(co[axis]);
+                            max[axis] = max[axis].max(co[axis]);
+                        }
+                    }
+                    let extent = (0..3).map(|axis| max[axis] - min[axis]).max().unwrap();
+                    let mut level = 0u8;
+                    while (extent >> level) > u16::MAX as u32 {
+                        level += 1;
+                    }
+                    for &vert_i in block_range(block_i) {
+                        let vert_level = &mut vert_levels[ti][vert_i as usize];
+                        if *vert_level < level {
+                            *vert_level = level;
+                            changed = true;
+                        }
+                    }
+                    block_origins.push(min);
+                    block_levels.push(level);
+                }
+            }
+            if !changed {
+                break;
+            }
+            coords = snap(&vert_levels);
+        }
+
+        // Snapping vertices to coarser levels moved them, so the BVH needs
+        // refitting to the moved triangles.
+        if vert_levels.iter().flatten().any(|&level| level > 0) {
+            let snapped_verts = grid_verts(&coords);
+            let bounds: Vec<BBox> = tri_indices
+                .iter()
+                .flat_map(|tri| {
+                    snapped_verts.iter().map(move |time_sample| {
+                        let p0 = time_sample[tri.0];
+                        let p1 = time_sample[tri.1];
+                        let p2 = time_sample[tri.2];
+                        BBox::from_points(p0.min(p1.min(p2)), p0.max(p1.max(p2)))
+                    })
+                })
+                .collect();
+            accel = accel.refit(arena, &indices, |tri: &(u32, u32, u32, u32)| {
+                let i = tri.3 as usize * time_sample_count;
+                &bounds[i..(i + time_sample_count)]
+            });
+        }
+
+        // Quantize the vertex positions relative to their blocks.
+        let mut vertices = Vec::with_capacity(block_verts.len() * time_sample_count);
+        for block_i in 0..leaf_ranges.len() {
+            for &vert_i in block_range(block_i) {
                 for ti in 0..time_sample_count {
-                    let p0 = verts[ti][tri.0];
-                    let p1 = verts[ti][tri.1];
-                    let p2 = verts[ti][tri.2];
-                    let minimum = p0.min(p1.min(p2));
-                    let maximum = p0.max(p1.max(p2));
-                    bounds.push(BBox::from_points(minimum, maximum));
+                    let co = coords[ti][vert_i as usize];
+                    let origin = block_origins[block_i * time_sample_count + ti];
+                    let level = block_levels[block_i * time_sample_count + ti];
+                    vertices.push([
+                        ((co[0] - origin[0]) >> level) as u16,
+                        ((co[1] - origin[1]) >> level) as u16,
+                        ((co[2] - origin[2]) >> level) as u16,
+                    ]);
                 }
             }
-            bounds
-        };
+        }
 
-        // Build BVH
-        let accel = BVH4::from_objects(arena, &mut indices[..], MAX_LEAF_TRIANGLE_COUNT, |tri| {
-            &bounds
-                [(tri.3 as usize * time_sample_count)..((tri.3 as usize + 1) * time_sample_count)]
+        // Encode the vertex normals, if any.
+        let normals = vert_normals.as_ref().map(|vnors| {
+            let mut normals = Vec::with_capacity(block_verts.len() * time_sample_count);
+            for &vert_i in &block_verts {
+                for time_sample in vnors {
+                    let n = time_sample[vert_i as usize];
+                    normals.push(oct32norm::encode((n.x(), n.y(), n.z())));
+                }
+            }
+            normals
         });
 
+        let grid_origins: Vec<Point> = grids.iter().map(|g| g.0).collect();
+        let grid_cell_sizes: Vec<f32> = grids.iter().map(|g| g.1).collect();
         TriangleMesh {
             time_sample_count: time_sample_count,
+            geometry: MeshGeometry::Compressed(CompressedGeometry {
+                grid_origins: arena.copy_slice(&grid_origins),
+                grid_cell_sizes: arena.copy_slice(&grid_cell_sizes),
+                block_origins: arena.copy_slice(&block_origins),
+                block_levels: arena.copy_slice(&block_levels),
+                block_starts: arena.copy_slice(&block_starts),
+                vertices: arena.copy_slice(&vertices),
+                normals: normals.map(|n| &arena.copy_slice(&n)[..]),
+                triangles: arena.copy_slice(&triangles),
+            }),
+            accel: accel,
+        }
+    }
+
+    fn build_paged<'b>(
+        arena: &'b Arena,
+        page_files: &'b PageFiles,
+        verts: &[Vec<Point>],
+        vert_normals: &Option<Vec<Vec<Normal>>>,
+        tri_indices: &[(usize, usize, usize)],
+        split_mode: SplitMode,
+    ) -> io::Result<TriangleMesh<'b>> {
+        let time_sample_count = verts.len();
+        let (accel, indices) = build_accel(
+            arena,
+            verts,
+            tri_indices,
+            MAX_LEAF_TRIANGLE_COUNT,
+            split_mode,
+        );
+
+        // Write out the triangles leaf by leaf, starting a new page whenever
+        // the next leaf doesn't fit in the current one.
+        let tri_size = 3 * time_sample_count * 12 * if vert_normals.is_some() { 2 } else { 1 };
+        let mut leaf_ranges = accel.leaf_object_ranges();
+        leaf_ranges.sort_unstable();
+        leaf_ranges.dedup();
+        leaf_ranges.retain(|&(start, end)| start < end);
+        let mut pages = Vec::new();
+        let mut data = Vec::with_capacity(indices.len() * tri_size);
+        let mut page_start = 0;
+        for &(start, end) in &leaf_ranges {
+            let page_len = data.len() - page_start;
+            if pages.is_empty() || (page_len > 0 && page_len + (end - start) * tri_size > PAGE_SIZE)
+            {
+                page_start = data.len();
+                pages.push((start, page_start));
+            }
+
+            for tri in &indices[start..end] {
+                for &vert_i in &[tri.0, tri.1, tri.2] {
+                    for time_sample in verts {
+                        let p = time_sample[vert_i as usize];
+                        write_f32s(&mut data, &[p.x(), p.y(), p.z()]);
+                    }
+                }
+                if let Some(ref vnors) = vert_normals {
+                    for &vert_i in &[tri.0, tri.1, tri.2] {
+                        for time_sample in vnors {
+                            let n = time_sample[vert_i as usize];
+                            write_f32s(&mut data, &[n.x(), n.y(), n.z()]);
+                        }
+                    }
+                }
+            }
+        }
+
+        // When caching, keep the page file in the cache directory, named by
+        // its contents, so that cached meshes can refer to it.
+        let path = cache::cache_dir().map(|dir| {
+            let mut hasher = ContentHasher::new();
+            hasher.write_bytes(&data);
+            dir.join(format!("{:016x}.psypages", hasher.finish()))
+        });
+        let file = PageFile::create(&data, path.as_deref())?;
+
+        Ok(TriangleMesh {
+            time_sample_count: time_sample_count,
+            geometry: MeshGeometry::Paged(PagedGeometry {
+                file: page_files.add(file),
+                pages: arena.copy_slice(&pages),
+                has_normals: vert_normals.is_some(),
+            }),
+            accel: accel,
+        })
+    }
+
+    /// Returns the page of the leaf whose triangles start at `tri_idx`,
+    /// paging it in if necessary, or `None` if the mesh isn't paged.
+    fn leaf_page(&self, tri_idx: usize) -> Option<std::sync::Arc<MeshPage>> {
+        if let MeshGeometry::Paged(ref geo) = self.geometry {
+            let page_i = geo.pages.partition_point(|page| page.0 <= tri_idx) - 1;
+            let (first_tri_idx, start) = geo.pages[page_i];
+            let end = geo
+                .pages
+                .get(page_i + 1)
+                .map_or(geo.file.len(), |page| page.1);
+            Some(geo.file.page(page_i, (start, end), |bytes| {
+                let page = MeshPage::decode(
+                    bytes,
+                    first_tri_idx,
+                    self.time_sample_count,
+                    geo.has_normals,
+                );
+                let size = page.size();
+                (page, size)
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the leaf of a mesh with full geometry that the given
+    /// triangle is in, and the index of the leaf's first triangle.
+    fn full_leaf(&self, tri_idx: usize) -> (usize, usize) {
+        if let MeshGeometry::Full { leaf_starts, .. } = self.geometry {
+            let leaf_i = leaf_starts.partition_point(|&start| start as usize <= tri_idx) - 1;
+            (leaf_i, leaf_starts[leaf_i] as usize)
+        } else {
+            unreachable!()
+        }
+    }
+
+    /// Returns the triangles of the leaf with the given triangle range at
+    /// the given time, in groups of four, along with the number of groups.
+    ///
+    /// `page` is the page of the leaf, which must be given for paged meshes
+    /// and is ignored otherwise.
+    fn leaf_triangles(
+        &self,
+        page: Option<&MeshPage>,
+        idx_range: std::ops::Range<usize>,
+        time: f32,
+    ) -> ([Triangle4; MAX_LEAF_TRIANGLE4_COUNT], usize) {
+        let mut tris = [Triangle4::new(); MAX_LEAF_TRIANGLE4_COUNT];
+        let count = (idx_range.end - idx_range.start).div_ceil(4);
+
+        if let MeshGeometry::Full { triangles, .. } = self.geometry {
+            // Already stored in groups of four.
+            let (leaf_i, _) = self.full_leaf(idx_range.start);
+            let tsc = self.time_sample_count;
+            tris[0] = lerp_slice(&triangles[(leaf_i * tsc)..((leaf_i + 1) * tsc)], time);
+        } else {
+            let origin = Point::new(0.0, 0.0, 0.0);
+            let mut group = [(origin, origin, origin); 4];
+            for (i, tri4) in tris[..count].iter_mut().enumerate() {
+                let start = idx_range.start + (i * 4);
+                let end = (start + 4).min(idx_range.end);
+                for (tri, tri_idx) in group.iter_mut().zip(start..end) {
+                    *tri = self.triangle(page, tri_idx, time);
+                }
+                *tri4 = Triangle4::from_triangles(&group[..(end - start)]);
+            }
+        }
+
+        (tris, count)
+    }
+
+    /// Returns the vertices of the given triangle at the given time.
+    ///
+    /// `page` is the page of the triangle's leaf, which must be given for
+    /// paged meshes and is ignored otherwise.
+    #[inline(always)]
+    fn triangle(
+        &self,
+        page: Option<&MeshPage>,
+        tri_idx: usize,
+        time: f32,
+    ) -> (Point, Point, Point) {
+        match self.geometry {
+            MeshGeometry::Full { triangles, .. } => {
+                let (leaf_i, leaf_start) = self.full_leaf(tri_idx);
+                let tsc = self.time_sample_count;
+                let leaf = &triangles[(leaf_i * tsc)..((leaf_i + 1) * tsc)];
+                lerp_slice(leaf, time).triangle(tri_idx - leaf_start)
+            }
+
+            MeshGeometry::Compressed(ref geo) => {
+                let (block_i, v0, v1, v2) = geo.triangle_vertices(tri_idx);
+                if self.time_sample_count == 1 {
+                    (
+                        geo.vertex(block_i, v0, 0, 1),
+                        geo.vertex(block_i, v1, 0, 1),
+                        geo.vertex(block_i, v2, 0, 1),
+                    )
+                } else {
+                    let tsc = self.time_sample_count;
+                    (
+                        lerp_samples(tsc, time, |ti| geo.vertex(block_i, v0, ti, tsc)),
+                        lerp_samples(tsc, time, |ti| geo.vertex(block_i, v1, ti, tsc)),
+                        lerp_samples(tsc, time, |ti| geo.vertex(block_i, v2, ti, tsc)),
+                    )
+                }
+            }
+
+            MeshGeometry::Paged(_) => {
+                let page = page.expect("Paged mesh triangle accessed without its page.");
+                let tsc = self.time_sample_count;
+                let i = (tri_idx - page.first_tri_idx) * 3 * tsc;
+                let v = &page.vertices[i..(i + 3 * tsc)];
+                if tsc == 1 {
+                    (v[0], v[1], v[2])
+                } else {
+                    (
+                        lerp_slice(&v[..tsc], time),
+                        lerp_slice(&v[tsc..(2 * tsc)], time),
+                        lerp_slice(&v[(2 * tsc)..], time),
+                    )
+                }
+            }
+        }
+    }
+
+    /// Returns the normalized vertex normals of the given triangle at the
+    /// given time, if the mesh has vertex normals.
+    ///
+    /// `page` is the page of the triangle's leaf, which must be given for
+    /// paged meshes and is ignored otherwise.
+    fn vertex_normals(
+        &self,
+        page: Option<&MeshPage>,
+        tri_idx: usize,
+        time: f32,
+    ) -> Option<(Normal, Normal, Normal)> {
+        match self.geometry {
+            MeshGeometry::Full {
+                normals, indices, ..
+            } => {
+                let normals = normals?;
+                let tri_indices = indices[tri_idx];
+                let n0_slice = &normals[(tri_indices.0 as usize * self.time_sample_count)
+                    ..((tri_indices.0 as usize + 1) * self.time_sample_count)];
+                let n1_slice = &normals[(tri_indices.1 as usize * self.time_sample_count)
+                    ..((tri_indices.1 as usize + 1) * self.time_sample_count)];
+                let n2_slice = &normals[(tri_indices.2 as usize * self.time_sample_count)
+                    ..((tri_indices.2 as usize + 1) * self.time_sample_count)];
+
+                Some((
+                    lerp_slice(n0_slice, time).normalized(),
+                    lerp_slice(n1_slice, time).normalized(),
+                    lerp_slice(n2_slice, time).normalized(),
+                ))
+            }
+
+            MeshGeometry::Compressed(ref geo) => {
+                let normals = geo.normals?;
+                let (_, v0, v1, v2) = geo.triangle_vertices(tri_idx);
+                let tsc = self.time_sample_count;
+                let normal = |vert_i: usize| {
+                    lerp_samples(tsc, time, |ti| {
+                        let (x, y, z) = oct32norm::decode(normals[vert_i * tsc + ti]);
+                        Normal::new(x, y, z)
+                    })
+                    .normalized()
+                };
+
+                Some((normal(v0), normal(v1), normal(v2)))
+            }
+
+            MeshGeometry::Paged(ref geo) => {
+                if !geo.has_normals {
+                    return None;
+                }
+                let page = page.expect("Paged mesh normals accessed without their page.");
+                let tsc = self.time_sample_count;
+                let i = (tri_idx - page.first_tri_idx) * 3 * tsc;
+                let n = &page.normals[i..(i + 3 * tsc)];
+                Some((
+                    lerp_slice(&n[..tsc], time).normalized(),
+                    lerp_slice(&n[tsc..(2 * tsc)], time).normalized(),
+                    lerp_slice(&n[(2 * tsc)..], time).normalized(),
+                ))
+            }
+        }
+    }
+}
+
+impl<'a> CompressedGeometry<'a> {
+    /// Returns the block of the given triangle, and the indices of its
+    /// vertices.
+    #[inline(always)]
+    fn triangle_vertices(&self, tri_idx: usize) -> (usize, usize, usize, usize) {
+        let (block_i, packed) = self.triangles[tri_idx];
+        let block_start = self.block_starts[block_i as usize] as usize;
+        (
+            block_i as usize,
+            block_start + (packed & 0xff) as usize,
+            block_start + ((packed >> 8) & 0xff) as usize,
+            block_start + ((packed >> 16) & 0xff) as usize,
+        )
+    }
+
+    /// Decodes the position of the given vertex at the given time sample.
+    #[inline(always)]
+    fn vertex(
+        &self,
+        block_i: usize,
+        vert_i: usize,
+        time_sample: usize,
+        time_sample_count: usize,
+    ) -> Point {
+        let origin = self.block_origins[block_i * time_sample_count + time_sample];
+        let level = self.block_levels[block_i * time_sample_count + time_sample];
+        let offset = self.vertices[vert_i * time_sample_count + time_sample];
+        grid_point(
+            self.grid_origins[time_sample],
+            self.grid_cell_sizes[time_sample],
+            [
+                origin[0] + ((offset[0] as u32) << level),
+                origin[1] + ((offset[1] as u32) << level),
+                origin[2] + ((offset[2] as u32) << level),
+            ],
+        )
+    }
+}
+
+impl MeshPage {
+    /// Decodes a page written by `TriangleMesh::build_paged()`.
+    fn decode(
+        bytes: &[u8],
+        first_tri_idx: usize,
+        time_sample_count: usize,
+        has_normals: bool,
+    ) -> MeshPage {
+        let vert_count = bytes.len() / (12 * if has_normals { 2 } else { 1 });
+        let mut vertices = Vec::with_capacity(vert_count);
+        let mut normals = Vec::with_capacity(if has_normals { vert_count } else { 0 });
+        let tri_vert_count = 3 * time_sample_count;
+        let mut floats = bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]));
+        while vertices.len() < vert_count {
+            for _ in 0..tri_vert_count {
+                let (x, y, z) = (floats.next(), floats.next(), floats.next());
+                vertices.push(Point::new(x.unwrap(), y.unwrap(), z.unwrap()));
+            }
+            if has_normals {
+                for _ in 0..tri_vert_count {
+                    let (x, y, z) = (floats.next(), floats.next(), floats.next());
+                    normals.push(Normal::new(x.unwrap(), y.unwrap(), z.unwrap()));
+                }
+            }
+        }
+
+        MeshPage {
+            first_tri_idx: first_tri_idx,
             vertices: vertices,
             normals: normals,
-            indices: indices,
-            accel: accel,
         }
     }
+
+    /// The number of bytes of memory the page takes.
+    fn size(&self) -> usize {
+        size_of::<MeshPage>()
+            + self.vertices.capacity() * size_of::<Point>()
+            + self.normals.capacity() * size_of::<Normal>()
+    }
+}
+
+/// Builds a BVH over the given triangles.
+///
+/// With spatial splits, triangles can end up in more than one leaf, so the
+/// triangle indices the BVH refers to are returned along with it.
+fn build_accel<'b>(
+    arena: &'b Arena,
+    verts: &[Vec<Point>],
+    tri_indices: &[(usize, usize, usize)],
+    objects_per_leaf: usize,
+    split_mode: SplitMode,
+) -> (WideBVH<'b>, Vec<(u32, u32, u32, u32)>) {
+    let time_sample_count = verts.len();
+
+    // Copy triangle vertex indices over, appending the triangle index itself to the tuple
+    let indices: Vec<(u32, u32, u32, u32)> = tri_indices
+        .iter()
+        .enumerate()
+        .map(|(i, tri_i)| (tri_i.0 as u32, tri_i.2 as u32, tri_i.1 as u32, i as u32))
+        .collect();
+
+    // Create bounds array for use during BVH construction
+    let bounds = {
+        let mut bounds = Vec::with_capacity(indices.len() * time_sample_count);
+        for tri in tri_indices {
+            for ti in 0..time_sample_count {
+                let p0 = verts[ti][tri.0];
+                let p1 = verts[ti][tri.1];
+                let p2 = verts[ti][tri.2];
+                let minimum = p0.min(p1.min(p2));
+                let maximum = p0.max(p1.max(p2));
+                bounds.push(BBox::from_points(minimum, maximum));
+            }
+        }
+        bounds
+    };
+
+    // Build BVH
+    WideBVH::from_objects_with_splits(
+        arena,
+        &indices,
+        objects_per_leaf,
+        split_mode,
+        |tri| {
+            &bounds
+                [(tri.3 as usize * time_sample_count)..((tri.3 as usize + 1) * time_sample_count)]
+        },
+        |tri, bounds, axis, pos| {
+            let tri_verts = (
+                verts[0][tri.0 as usize],
+                verts[0][tri.1 as usize],
+                verts[0][tri.2 as usize],
+            );
+            triangle::split_bounds(tri_verts, bounds, axis, pos)
+        },
+    )
+}
+
+/// Returns the position of the given grid coordinates.
+///
+/// The coordinates are always integers below 2^24, so their conversion is
+/// exact and a given grid point always decodes to the same position.
+#[inline(always)]
+fn grid_point(origin: Point, cell_size: f32, co: [u32; 3]) -> Point {
+    Point::new(
+        origin.x() + (co[0] as f32 * cell_size),
+        origin.y() + (co[1] as f32 * cell_size),
+        origin.z() + (co[2] as f32 * cell_size),
+    )
+}
+
+/// Returns the smallest power of two that's at least `size`, for use as a
+/// grid cell size.  Powers of two keep the grid coordinates exact.
+fn grid_cell_size(size: f32) -> f32 {
+    let size = size.max(f32::MIN_POSITIVE);
+    let bits = size.to_bits();
+    if bits & 0x007f_ffff == 0 {
+        size
+    } else {
+        f32::from_bits((bits & 0x7f80_0000) + 0x0080_0000)
+    }
+}
+
+fn write_f32s(data: &mut Vec<u8>, fs: &[f32]) {
+    for f in fs {
+        data.extend_from_slice(&f.to_ne_bytes());
+    }
+}
+
+fn max_component(v: Vector) -> f32 {
+    v.x().max(v.y()).max(v.z())
+}
+
+/// Like `lerp_slice()`, but over `count` items returned by `item`.
+#[inline(always)]
+fn lerp_samples<T: Lerp, F: Fn(usize) -> T>(count: usize, alpha: f32, item: F) -> T {
+    if count == 1 || alpha == 1.0 {
+        item(count - 1)
+    } else {
+        let tmp = alpha * ((count - 1) as f32);
+        let i1 = tmp as usize;
+        let alpha2 = tmp - (i1 as f32);
+
+        lerp(item(i1), item(i1 + 1), alpha2)
+    }
+}
+
+impl<'a> Cacheable<'a> for TriangleMesh<'a> {
+    const KIND: [u8; 4] = *b"TMSH";
+
+    fn write_cache(&self, writer: &mut CacheWriter) {
+        writer.write_u64(self.time_sample_count as u64);
+        match self.geometry {
+            MeshGeometry::Full {
+                triangles,
+                leaf_starts,
+                normals,
+                indices,
+            } => {
+                writer.write_u8(0);
+                writer.write_slice(triangles);
+                writer.write_slice(leaf_starts);
+                write_optional_slice(writer, normals);
+                writer.write_slice(indices);
+            }
+
+            MeshGeometry::Compressed(ref geo) => {
+                writer.write_u8(1);
+                writer.write_slice(geo.grid_origins);
+                writer.write_slice(geo.grid_cell_sizes);
+                writer.write_slice(geo.block_origins);
+                writer.write_slice(geo.block_levels);
+                writer.write_slice(geo.block_starts);
+                writer.write_slice(geo.vertices);
+                write_optional_slice(writer, geo.normals);
+                writer.write_slice(geo.triangles);
+            }
+
+            MeshGeometry::Paged(ref geo) => {
+                // Only page files kept in the cache directory can be referred
+                // to.  Otherwise an empty name is written, which makes the
+                // entry fail to load.
+                let name = geo
+                    .file
+                    .path()
+                    .and_then(|path| path.file_name())
+                    .and_then(|name| name.to_str())
+                    .unwrap_or("");
+                writer.write_u8(2);
+                writer.write_slice(name.as_bytes());
+                writer.write_u64(geo.file.len() as u64);
+                writer.write_slice(geo.pages);
+                writer.write_u8(geo.has_normals as u8);
+            }
+        }
+        self.accel.write_cache(writer);
+    }
+
+    fn read_cache(arena: &'a Arena, reader: &mut CacheReader<'a, '_>) -> Option<TriangleMesh<'a>> {
+        Some(TriangleMesh {
+            time_sample_count: reader.read_u64()? as usize,
+            geometry: match reader.read_u8()? {
+                0 => MeshGeometry::Full {
+                    triangles: reader.read_slice(arena)?,
+                    leaf_starts: reader.read_slice(arena)?,
+                    normals: read_optional_slice(arena, reader)?,
+                    indices: reader.read_slice(arena)?,
+                },
+                1 => MeshGeometry::Compressed(CompressedGeometry {
+                    grid_origins: reader.read_slice(arena)?,
+                    grid_cell_sizes: reader.read_slice(arena)?,
+                    block_origins: reader.read_slice(arena)?,
+                    block_levels: reader.read_slice(arena)?,
+                    block_starts: reader.read_slice(arena)?,
+                    vertices: reader.read_slice(arena)?,
+                    normals: read_optional_slice(arena, reader)?,
+                    triangles: reader.read_slice(arena)?,
+                }),
+                2 => {
+                    let name = std::str::from_utf8(reader.read_slice::<u8>(arena)?).ok()?;
+                    let len = reader.read_u64()? as usize;
+                    let pages = reader.read_slice(arena)?;
+                    let has_normals = reader.read_u8()? != 0;
+                    if name.is_empty() {
+                        return None;
+                    }
+                    let file = PageFile::open(&cache::cache_dir()?.join(name)).ok()?;
+                    if file.len() != len {
+                        return None;
+                    }
+                    MeshGeometry::Paged(PagedGeometry {
+                        file: reader.page_files().add(file),
+                        pages: pages,
+                        has_normals: has_normals,
+                    })
+                }
+                _ => return None,
+            },
+            accel: WideBVH::read_cache(arena, reader)?,
+        })
+    }
+}
+
+fn write_optional_slice<T: Copy>(writer: &mut CacheWriter, data: Option<&[T]>) {
+    match data {
+        Some(data) => {
+            writer.write_u8(1);
+            writer.write_slice(data);
+        }
+        None => writer.write_u8(0),
+    }
+}
+
+fn read_optional_slice<'a, T: Copy>(
+    arena: &'a Arena,
+    reader: &mut CacheReader<'a, '_>,
+) -> Option<Option<&'a [T]>> {
+    match reader.read_u8()? {
+        0 => Some(None),
+        1 => Some(Some(reader.read_slice(arena)?)),
+        _ => None,
+    }
 }
 
 impl<'a> Boundable for TriangleMesh<'a> {
@@ -129,6 +1076,7 @@ impl<'a> Surface for TriangleMesh<'a> {
         isects: &mut [SurfaceIntersection],
         shader: &dyn SurfaceShader,
         space: &[Matrix4x4],
+        object_id: usize,
     ) {
         // Precalculate transform for non-motion blur cases
         let static_mat_space = if space.len() == 1 {
@@ -141,32 +1089,23 @@ impl<'a> Surface for TriangleMesh<'a> {
             .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
                 let tri_count = idx_range.end - idx_range.start;
 
+                // Page in the leaf's triangles, if the mesh is paged.
+                let page = self.leaf_page(idx_range.start);
+                let page = page.as_deref();
+
                 // Build the triangle cache if we can!
                 let is_cached = ray_stack.ray_count_in_next_task() >= tri_count
                     && self.time_sample_count == 1
                     && space.len() <= 1;
-                let mut tri_cache = [std::mem::MaybeUninit::uninit(); MAX_LEAF_TRIANGLE_COUNT];
-                if is_cached {
-                    for tri_idx in idx_range.clone() {
-                        let i = tri_idx - idx_range.start;
-                        let tri_indices = self.indices[tri_idx];
-
-                        // For static triangles with static transforms, cache them.
-                        unsafe {
-                            *tri_cache[i].as_mut_ptr() = (
-                                self.vertices[tri_indices.0 as usize],
-                                self.vertices[tri_indices.1 as usize],
-                                self.vertices[tri_indices.2 as usize],
-                            );
-                            if !space.is_empty() {
-                                (*tri_cache[i].as_mut_ptr()).0 =
-                                    (*tri_cache[i].as_mut_ptr()).0 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).1 =
-                                    (*tri_cache[i].as_mut_ptr()).1 * static_mat_space;
-                                (*tri_cache[i].as_mut_ptr()).2 =
-                                    (*tri_cache[i].as_mut_ptr()).2 * static_mat_space;
-                            }
-                        }
+                let (mut tri_cache, tri4_count) = if is_cached {
+                    self.leaf_triangles(page, idx_range.clone(), 0.0)
+                } else {
+                    ([Triangle4::new(); MAX_LEAF_TRIANGLE4_COUNT], 0)
+                };
+                if is_cached && !space.is_empty() {
+                    // For static triangles with static transforms, cache them.
+                    for tris in &mut tri_cache[..tri4_count] {
+                        *tris = tris.transformed(static_mat_space);
                     }
                 }
 
@@ -188,62 +1127,30 @@ impl<'a> Surface for TriangleMesh<'a> {
                         static_mat_space
                     };
 
-                    // Iterate through the triangles and test the ray against them.
+                    // Get the triangles if necessary.
+                    let (tris, tri4_count) = if is_cached {
+                        (tri_cache, tri4_count)
+                    } else {
+                        let (mut tris, tri4_count) =
+                            self.leaf_triangles(page, idx_range.clone(), ray_time);
+                        if !space.is_empty() {
+                            for tris in &mut tris[..tri4_count] {
+                                *tris = tris.transformed(mat_space);
+                            }
+                        }
+                        (tris, tri4_count)
+                    };
+
+                    // Test the ray against the triangles, four at a time.
                     let mut non_shadow_hit = false;
                     let mut hit_tri = std::mem::MaybeUninit::uninit();
-                    let mut hit_tri_indices = std::mem::MaybeUninit::uninit();
+                    let mut hit_tri_idx = std::mem::MaybeUninit::uninit();
                     let mut hit_tri_data = std::mem::MaybeUninit::uninit();
                     let ray_pre = triangle::RayTriPrecompute::new(rays.dir(ray_idx));
-                    for tri_idx in idx_range.clone() {
-                        let tri_indices = self.indices[tri_idx];
-
-                        // Get triangle if necessary
-                        let tri = if is_cached {
-                            let i = tri_idx - idx_range.start;
-                            unsafe { tri_cache[i].assume_init() }
-                        } else {
-                            let mut tri = if self.time_sample_count == 1 {
-                                // No deformation motion blur, so fast-path it.
-                                (
-                                    self.vertices[tri_indices.0 as usize],
-                                    self.vertices[tri_indices.1 as usize],
-                                    self.vertices[tri_indices.2 as usize],
-                                )
-                            } else {
-                                // Deformation motion blur, need to interpolate.
-                                let p0_slice = &self.vertices[(tri_indices.0 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.0 as usize + 1) * self.time_sample_count)];
-                                let p1_slice = &self.vertices[(tri_indices.1 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.1 as usize + 1) * self.time_sample_count)];
-                                let p2_slice = &self.vertices[(tri_indices.2 as usize
-                                    * self.time_sample_count)
-                                    ..((tri_indices.2 as usize + 1) * self.time_sample_count)];
-
-                                let p0 = lerp_slice(p0_slice, ray_time);
-                                let p1 = lerp_slice(p1_slice, ray_time);
-                                let p2 = lerp_slice(p2_slice, ray_time);
-
-                                (p0, p1, p2)
-                            };
-
-                            if !space.is_empty() {
-                                tri.0 = tri.0 * mat_space;
-                                tri.1 = tri.1 * mat_space;
-                                tri.2 = tri.2 * mat_space;
-                            }
-
-                            tri
-                        };
-
-                        // Test ray against triangle
-                        if let Some((t, b0, b1, b2)) = triangle::intersect_ray(
-                            rays.orig(ray_idx),
-                            ray_pre,
-                            rays.max_t(ray_idx),
-                            tri,
-                        ) {
+                    for (i, tris) in tris[..tri4_count].iter().enumerate() {
+                        if let Some((lane, t, b0, b1, b2)) =
+                            tris.intersect_ray(rays.orig(ray_idx), ray_pre, rays.max_t(ray_idx))
+                        {
                             if rays.is_occlusion(ray_idx) {
                                 isects[ray_idx] = SurfaceIntersection::Occlude;
                                 rays.mark_done(ray_idx);
@@ -252,8 +1159,8 @@ impl<'a> Surface for TriangleMesh<'a> {
                                 non_shadow_hit = true;
                                 rays.set_max_t(ray_idx, t);
                                 unsafe {
-                                    *hit_tri.as_mut_ptr() = tri;
-                                    *hit_tri_indices.as_mut_ptr() = tri_indices;
+                                    *hit_tri.as_mut_ptr() = tris.triangle(lane);
+                                    *hit_tri_idx.as_mut_ptr() = idx_range.start + (i * 4) + lane;
                                     *hit_tri_data.as_mut_ptr() = (t, b0, b1, b2);
                                 }
                             }
@@ -273,22 +1180,10 @@ impl<'a> Surface for TriangleMesh<'a> {
                             cross(hit_tri.0 - hit_tri.1, hit_tri.0 - hit_tri.2).into_normal();
 
                         // Calculate interpolated surface normal, if any
-                        let shading_normal = if let Some(normals) = self.normals {
-                            let hit_tri_indices = unsafe { hit_tri_indices.assume_init() };
-                            let n0_slice = &normals[(hit_tri_indices.0 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.0 as usize + 1) * self.time_sample_count)];
-                            let n1_slice = &normals[(hit_tri_indices.1 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.1 as usize + 1) * self.time_sample_count)];
-                            let n2_slice = &normals[(hit_tri_indices.2 as usize
-                                * self.time_sample_count)
-                                ..((hit_tri_indices.2 as usize + 1) * self.time_sample_count)];
-
-                            let n0 = lerp_slice(n0_slice, ray_time).normalized();
-                            let n1 = lerp_slice(n1_slice, ray_time).normalized();
-                            let n2 = lerp_slice(n2_slice, ray_time).normalized();
-
+                        let hit_tri_idx = unsafe { hit_tri_idx.assume_init() };
+                        let shading_normal = if let Some((n0, n1, n2)) =
+                            self.vertex_normals(page, hit_tri_idx, ray_time)
+                        {
                             let s_nor = ((n0 * b0) + (n1 * b1) + (n2 * b2)) * mat_space;
                             if dot(s_nor, geo_normal) >= 0.0 {
                                 s_nor
@@ -306,6 +1201,9 @@ impl<'a> Surface for TriangleMesh<'a> {
                             pos_err: pos_err,
                             nor: shading_normal,
                             nor_g: geo_normal,
+                            tangent: Vector::new(0.0, 0.0, 0.0),
+                            color: None,
+                            object_id: object_id,
                             local_space: mat_space,
                             sample_pdf: 0.0,
                         };
@@ -321,3 +1219,170 @@ impl<'a> Surface for TriangleMesh<'a> {
             });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (vertices, vertex normals, triangle vertex indices)
+    type MeshData = (
+        Vec<Vec<Point>>,
+        Option<Vec<Vec<Normal>>>,
+        Vec<(usize, usize, usize)>,
+    );
+
+    /// A grid of small triangles with a much larger triangle attached to one
+    /// of its corners, at two time samples, with vertex normals.
+    fn grid_with_large_triangle() -> MeshData {
+        let res = 16;
+        let mut verts = Vec::new();
+        let mut normals = Vec::new();
+        for y in 0..=res {
+            for x in 0..=res {
+                let (fx, fy) = (x as f32, y as f32);
+                verts.push(Point::new(
+                    fx * 0.1,
+                    fy * 0.1,
+                    (fx * 0.7 + fy * 1.3).sin() * 0.05,
+                ));
+                normals.push(Normal::new(fx.sin(), fy.cos(), 1.0).normalized());
+            }
+        }
+        let mut tris = Vec::new();
+        for y in 0..res {
+            for x in 0..res {
+                let i = y * (res + 1) + x;
+                tris.push((i, i + 1, i + res + 1));
+                tris.push((i + 1, i + res + 2, i + res + 1));
+            }
+        }
+        verts.push(Point::new(-10000.0, 0.0, -1.0));
+        verts.push(Point::new(0.0, -10000.0, -1.0));
+        normals.push(Normal::new(0.0, 0.0, 1.0));
+        normals.push(Normal::new(0.0, 0.0, 1.0));
+        tris.push((0, verts.len() - 2, verts.len() - 1));
+
+        let moved = verts
+            .iter()
+            .map(|&p| p + Vector::new(0.5, 0.25, 2.0))
+            .collect();
+        (
+            vec![verts, moved],
+            Some(vec![normals.clone(), normals]),
+            tris,
+        )
+    }
+
+    /// Returns the decoded position and oct-decoded normal of every vertex of
+    /// every block of a compressed mesh at time sample `ti`, along with the
+    /// size of the grid cells it was quantized to.
+    fn decoded_vertices(mesh: &TriangleMesh, ti: usize) -> Vec<(Point, Normal, f32)> {
+        let geo = match mesh.geometry {
+            MeshGeometry::Compressed(geo) => geo,
+            _ => panic!("Mesh isn't compressed."),
+        };
+        let tsc = mesh.time_sample_count;
+        let mut decoded = Vec::new();
+        for block_i in 0..geo.block_starts.len() {
+            let start = geo.block_starts[block_i] as usize;
+            let end = geo
+                .block_starts
+                .get(block_i + 1)
+                .map_or(geo.vertices.len() / tsc, |&end| end as usize);
+            let level = geo.block_levels[block_i * tsc + ti];
+            let cell_size = geo.grid_cell_sizes[ti] * (1u32 << level) as f32;
+            for vert_i in start..end {
+                let (x, y, z) = oct32norm::decode(geo.normals.unwrap()[vert_i * tsc + ti]);
+                decoded.push((
+                    geo.vertex(block_i, vert_i, ti, tsc),
+                    Normal::new(x, y, z).normalized(),
+                    cell_size,
+                ));
+            }
+        }
+        decoded
+    }
+
+    fn nearest(verts: &[Point], p: Point) -> usize {
+        (0..verts.len())
+            .min_by(|&a, &b| {
+                let da = (verts[a] - p).length();
+                let db = (verts[b] - p).length();
+                da.partial_cmp(&db).unwrap()
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn compressed_positions_within_grid_error() {
+        let (verts, normals, tris) = grid_with_large_triangle();
+        let arena = Arena::new();
+        let mesh =
+            TriangleMesh::build_compressed(&arena, &verts, &normals, &tris, SplitMode::Object);
+        let geo = match mesh.geometry {
+            MeshGeometry::Compressed(geo) => geo,
+            _ => unreachable!(),
+        };
+
+        for ti in 0..2 {
+            let fine_cell_size = geo.grid_cell_sizes[ti];
+            for (p, _, cell_size) in decoded_vertices(&mesh, ti) {
+                let orig_i = nearest(&verts[ti], p);
+                let error = p - verts[ti][orig_i];
+                // Snapping moves a vertex by at most half a cell, on top of
+                // rounding its position relative to the grid's origin, which
+                // is well under half a cell of the finest level.
+                let max_error = (cell_size + fine_cell_size) * 0.5;
+                assert!(error.x().abs() <= max_error, "{:?}", error);
+                assert!(error.y().abs() <= max_error, "{:?}", error);
+                assert!(error.z().abs() <= max_error, "{:?}", error);
+
+                // The large triangle only coarsens the grid for its own
+                // vertices.
+                if orig_i != 0 && orig_i < verts[ti].len() - 2 {
+                    assert_eq!(cell_size, fine_cell_size);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn compressed_shared_vertices_match() {
+        let (verts, normals, tris) = grid_with_large_triangle();
+        let arena = Arena::new();
+        let mesh =
+            TriangleMesh::build_compressed(&arena, &verts, &normals, &tris, SplitMode::Object);
+
+        for ti in 0..2 {
+            let mut positions: Vec<Option<Point>> = vec![None; verts[ti].len()];
+            let mut shared_count = 0;
+            for (p, _, _) in decoded_vertices(&mesh, ti) {
+                let orig_i = nearest(&verts[ti], p);
+                match positions[orig_i] {
+                    Some(prev) => {
+                        assert_eq!(prev, p);
+                        shared_count += 1;
+                    }
+                    None => positions[orig_i] = Some(p),
+                }
+            }
+            assert!(positions.iter().all(|p| p.is_some()));
+            assert!(shared_count > 0);
+        }
+    }
+
+    #[test]
+    fn compressed_normals_round_trip() {
+        let (verts, normals, tris) = grid_with_large_triangle();
+        let arena = Arena::new();
+        let mesh =
+            TriangleMesh::build_compressed(&arena, &verts, &normals, &tris, SplitMode::Object);
+
+        for ti in 0..2 {
+            for (p, n, _) in decoded_vertices(&mesh, ti) {
+                let orig_n = normals.as_ref().unwrap()[ti][nearest(&verts[ti], p)];
+                assert!((n - orig_n).length() < 1.0e-4, "{:?} vs {:?}", n, orig_n);
+            }
+        }
+    }
+}
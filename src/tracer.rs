@@ -4,10 +4,11 @@ use crate::{
     accel::ray_code,
     color::{rec709_to_xyz, Color},
     lerp::lerp_slice,
-    math::Matrix4x4,
+    math::{Matrix4x4, Point},
+    morton,
     ray::{RayBatch, RayStack},
     scene::{Assembly, InstanceType, Object},
-    shading::{SimpleSurfaceShader, SurfaceShader},
+    shading::{MediumBoundaryShader, SimpleSurfaceShader, SurfaceShader},
     surface::SurfaceIntersection,
     transform_stack::TransformStack,
 };
@@ -15,36 +16,139 @@ use crate::{
 pub struct Tracer<'a> {
     ray_trace_count: u64,
     ray_stack: RayStack,
+    sort_rays: bool,
+    ray_order: Vec<u64>,          // Sort keys from `ray_sort_key()`
+    sorted_positions: Vec<usize>, // Where each ray ended up in the sorted order
+    sorted_rays: RayBatch,
+    isects: Vec<SurfaceIntersection>,
     inner: TracerInner<'a>,
 }
 
 impl<'a> Tracer<'a> {
-    pub fn from_assembly(assembly: &'a Assembly) -> Tracer<'a> {
+    /// Creates a tracer for the scene in the given assembly.
+    ///
+    /// If `sort_rays` is true, each batch of rays is sorted into a more
+    /// coherent order before being traced.  See `sort_ray_order()`.
+    pub fn from_assembly(assembly: &'a Assembly, sort_rays: bool) -> Tracer<'a> {
         Tracer {
             ray_trace_count: 0,
             ray_stack: RayStack::new(),
+            sort_rays: sort_rays,
+            ray_order: Vec::new(),
+            sorted_positions: Vec::new(),
+            sorted_rays: RayBatch::new(),
+            isects: Vec::new(),
             inner: TracerInner {
                 root: assembly,
                 xform_stack: TransformStack::new(),
                 isects: Vec::new(),
+                skipped_rays: Vec::new(),
+                placement_ids: Vec::new(),
             },
         }
     }
 
     pub fn trace<'b>(&'b mut self, rays: &mut RayBatch) -> &'b [SurfaceIntersection] {
         self.ray_trace_count += rays.len() as u64;
-        self.inner.trace(rays, &mut self.ray_stack)
+
+        if !self.sort_rays {
+            return self.inner.trace(rays, &mut self.ray_stack);
+        }
+
+        // Trace a sorted copy of the rays, and then copy the rays and
+        // their intersections back into their original order.
+        self.sort_ray_order(rays);
+        self.sorted_rays.clear();
+        self.sorted_positions.clear();
+        self.sorted_positions.resize(rays.len(), 0);
+        for (i, &key) in self.ray_order.iter().enumerate() {
+            let ray_idx = sort_key_ray_index(key);
+            self.sorted_rays.push_from(rays, ray_idx);
+            self.sorted_positions[ray_idx] = i;
+        }
+
+        let sorted_isects = self.inner.trace(&mut self.sorted_rays, &mut self.ray_stack);
+
+        self.isects.clear();
+        self.isects
+            .extend(self.sorted_positions.iter().map(|&i| sorted_isects[i]));
+        for (ray_idx, &i) in self.sorted_positions.iter().enumerate() {
+            rays.copy_from(ray_idx, &self.sorted_rays, i);
+        }
+
+        &self.isects
     }
 
     pub fn rays_traced(&self) -> u64 {
         self.ray_trace_count
     }
+
+    /// Fills in `ray_order` with the indices of the rays sorted into an
+    /// order where nearby rays tend to traverse the same parts of the
+    /// scene.
+    ///
+    /// The rays are sorted first by the octant of their direction, since
+    /// that's how they're divided into lanes for traversal anyway, and then
+    /// along a morton curve by their origin within the bounds of all of the
+    /// ray origins.
+    fn sort_ray_order(&mut self, rays: &RayBatch) {
+        const CELL_RES: f32 = 1024.0; // Number of cells along each axis
+
+        let (bounds_min, bounds_max) = (0..rays.len()).fold(
+            (
+                Point::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+                Point::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            ),
+            |(min, max), i| (min.min(rays.orig(i)), max.max(rays.orig(i))),
+        );
+        let extent = bounds_max - bounds_min;
+        let cell = |n: f32, min: f32, extent: f32| {
+            if extent > 0.0 {
+                (((n - min) / extent * CELL_RES) as u32).min(CELL_RES as u32 - 1)
+            } else {
+                0
+            }
+        };
+
+        self.ray_order.clear();
+        self.ray_order.extend((0..rays.len()).map(|i| {
+            let orig = rays.orig(i);
+            let d = morton::xyz2d(
+                cell(orig.x(), bounds_min.x(), extent.x()),
+                cell(orig.y(), bounds_min.y(), extent.y()),
+                cell(orig.z(), bounds_min.z(), extent.z()),
+            );
+            ray_sort_key(ray_code(rays.dir(i)), d, i)
+        }));
+        self.ray_order.sort_unstable();
+    }
+}
+
+// The number of low bits of a ray sort key that hold the ray's index.  The
+// three bits of the ray code and the 30 bits of the morton index take up
+// the rest.
+const SORT_KEY_INDEX_BITS: u32 = 31;
+
+/// Packs a ray's direction octant code, the morton index of its origin,
+/// and its index in the batch into a key that sorts in that order.
+fn ray_sort_key(ray_code: usize, morton_d: u32, ray_idx: usize) -> u64 {
+    debug_assert!(ray_code < 8 && morton_d < (1 << 30));
+    debug_assert!(ray_idx < (1 << SORT_KEY_INDEX_BITS));
+    let key = ((ray_code as u64) << 30) | morton_d as u64;
+    (key << SORT_KEY_INDEX_BITS) | ray_idx as u64
+}
+
+/// Returns the ray index of a key made by `ray_sort_key()`.
+fn sort_key_ray_index(key: u64) -> usize {
+    (key & ((1 << SORT_KEY_INDEX_BITS) - 1)) as usize
 }
 
 struct TracerInner<'a> {
     root: &'a Assembly<'a>,
     xform_stack: TransformStack,
     isects: Vec<SurfaceIntersection>,
+    skipped_rays: Vec<usize>, // Rays skipping the current object due to their filter
+    placement_ids: Vec<usize>, // Index of the first object placement of the current assembly/object
 }
 
 impl<'a> TracerInner<'a> {
@@ -95,6 +199,12 @@ impl<'a> TracerInner<'a> {
             .traverse(rays, ray_stack, |idx_range, rays, ray_stack| {
                 let inst = &assembly.instances[idx_range.start];
 
+                // Identify the placements made by the instance by their
+                // index among all of the object placements in the scene,
+                // since assemblies can be instanced more than once.
+                let parent_id = self.placement_ids.last().map_or(0, |&id| id);
+                self.placement_ids.push(parent_id + inst.placement_offset);
+
                 // Transform rays if needed
                 if let Some((xstart, xend)) = inst.transform_indices {
                     // Push transforms to stack
@@ -113,10 +223,20 @@ impl<'a> TracerInner<'a> {
                 // Trace rays
                 match inst.instance_type {
                     InstanceType::Object => {
+                        // Surfaces bounding a medium get a special shader
+                        // in place of their usual one.
+                        let boundary_shader;
+                        let surface_shader = if let Some(i) = inst.medium_index {
+                            boundary_shader = MediumBoundaryShader { medium_index: i };
+                            Some(&boundary_shader as &dyn SurfaceShader)
+                        } else {
+                            inst.surface_shader_index
+                                .map(|i| assembly.surface_shaders[i])
+                        };
+
                         self.trace_object(
                             &assembly.objects[inst.data_index],
-                            inst.surface_shader_index
-                                .map(|i| assembly.surface_shaders[i]),
+                            surface_shader,
                             rays,
                             ray_stack,
                         );
@@ -126,6 +246,7 @@ impl<'a> TracerInner<'a> {
                         self.trace_assembly(&assembly.assemblies[inst.data_index], rays, ray_stack);
                     }
                 }
+                self.placement_ids.pop();
 
                 // Un-transform rays if needed
                 if inst.transform_indices.is_some() {
@@ -156,6 +277,26 @@ impl<'a> TracerInner<'a> {
         rays: &mut RayBatch,
         ray_stack: &mut RayStack,
     ) {
+        // Objects are identified by their placement, so that each placement
+        // of an instanced object gets its own id.
+        let object_id = *self.placement_ids.last().unwrap();
+
+        // Rays restricted to other objects skip this one, by temporarily
+        // marking them as done.
+        self.skipped_rays.clear();
+        if rays.has_object_filters() {
+            for i in 0..ray_stack.ray_count_in_next_task() {
+                let ray_idx = ray_stack.next_task_ray_idx(i);
+                match rays.object_filter(ray_idx) {
+                    Some(id) if id != object_id && !rays.is_done(ray_idx) => {
+                        rays.mark_done(ray_idx);
+                        self.skipped_rays.push(ray_idx);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
         match *obj {
             Object::Surface(surface) => {
                 let unassigned_shader = SimpleSurfaceShader::Emit {
@@ -169,6 +310,7 @@ impl<'a> TracerInner<'a> {
                     &mut self.isects,
                     shader,
                     self.xform_stack.top(),
+                    object_id,
                 );
             }
 
@@ -184,8 +326,42 @@ impl<'a> TracerInner<'a> {
                     &mut self.isects,
                     &bogus_shader,
                     self.xform_stack.top(),
+                    object_id,
                 );
             }
         }
+
+        for &ray_idx in &self.skipped_rays {
+            rays.unmark_done(ray_idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sort_key_order() {
+        // Sorted by ray code first, including the z sign bit, then by
+        // morton index, and then by ray index.
+        let mut keys = vec![
+            ray_sort_key(4, 0, 0),
+            ray_sort_key(3, (1 << 30) - 1, 1),
+            ray_sort_key(7, 5, 2),
+            ray_sort_key(0, 5, 3),
+            ray_sort_key(4, 0, 4),
+            ray_sort_key(7, 0, 5),
+        ];
+        keys.sort_unstable();
+        let order: Vec<_> = keys.iter().map(|&k| sort_key_ray_index(k)).collect();
+        assert_eq!(order, vec![3, 1, 0, 4, 5, 2]);
+    }
+
+    #[test]
+    fn sort_key_index_round_trip() {
+        for &i in &[0, 1, 12345, (1 << SORT_KEY_INDEX_BITS) - 1] {
+            assert_eq!(sort_key_ray_index(ray_sort_key(7, (1 << 30) - 1, i)), i);
+        }
     }
 }
@@ -1,4 +1,4 @@
-// Generate table for traversal order of quad BVHs.
+// Generate tables for traversal order of quad and oct BVHs.
 
 use std::{env, fs::File, io::Write, path::Path};
 
@@ -97,4 +97,94 @@ fn main() {
     }
 
     f.write_all("\n];".as_bytes()).unwrap();
+
+    // Build the traversal table for oct BVHs.  See `calc_traversal_code_8()`
+    // in lib.rs for how the codes are laid out.
+    let mut traversal_table_8 = [
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+        Vec::new(),
+    ];
+    for (raydir, sub_table) in traversal_table_8.iter_mut().enumerate() {
+        let ray = [raydir & 1, (raydir >> 1) & 1, (raydir >> 2) & 1];
+
+        for code in 0..TRAVERSAL_TABLE_8_LEN {
+            let top = code % 3;
+            let left = sub_tree_order((code / 3) % 49, 0, ray);
+            let right = sub_tree_order(code / (3 * 49), left.len() as u32, ray);
+            let perm = if ray[top] == 1 {
+                [right, left].concat()
+            } else {
+                [left, right].concat()
+            };
+            sub_table.push(
+                perm.iter()
+                    .enumerate()
+                    .fold(0u32, |acc, (i, &n)| acc + (n << (i * 3))),
+            );
+        }
+    }
+
+    // Write oct traversal table to Rust file
+    f.write_all(
+        format!(
+            "\n\npub static TRAVERSAL_TABLE_8: [[u32; {}]; 8] = [",
+            TRAVERSAL_TABLE_8_LEN
+        )
+        .as_bytes(),
+    )
+    .unwrap();
+
+    for sub_table in traversal_table_8.iter() {
+        f.write_all("\n    [".as_bytes()).unwrap();
+        for (i, n) in sub_table.iter().enumerate() {
+            if i != 0 && i % 8 == 0 {
+                f.write_all("\n     ".as_bytes()).unwrap();
+            }
+            f.write_all(format!("{}", n).as_bytes()).unwrap();
+            if i != (TRAVERSAL_TABLE_8_LEN - 1) {
+                f.write_all(", ".as_bytes()).unwrap();
+            }
+        }
+        f.write_all("],".as_bytes()).unwrap();
+    }
+
+    f.write_all("\n];".as_bytes()).unwrap();
+}
+
+const TRAVERSAL_TABLE_8_LEN: usize = 3 * 49 * 49;
+
+/// Returns the traversal order of the leaves of a sub-tree below the top
+/// split of an oct BVH node, given the sub-tree's code and the index of its
+/// first leaf.
+fn sub_tree_order(code: usize, first: u32, ray: [usize; 3]) -> Vec<u32> {
+    if code == 0 {
+        return vec![first];
+    }
+
+    let axis = (code - 1) / 16;
+    let left = split_order((code - 1) / 4 % 4, first, ray);
+    let right = split_order((code - 1) % 4, first + left.len() as u32, ray);
+    if ray[axis] == 1 {
+        [right, left].concat()
+    } else {
+        [left, right].concat()
+    }
+}
+
+/// Returns the traversal order of the leaves of a single optional split,
+/// given its code and the index of its first leaf.
+fn split_order(code: usize, first: u32, ray: [usize; 3]) -> Vec<u32> {
+    if code == 0 {
+        vec![first]
+    } else if ray[code - 1] == 1 {
+        vec![first + 1, first]
+    } else {
+        vec![first, first + 1]
+    }
 }
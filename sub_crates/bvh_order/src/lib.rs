@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 
-// Include TRAVERSAL_TABLE generated by the build.rs script
+// Include TRAVERSAL_TABLE and TRAVERSAL_TABLE_8 generated by the build.rs script
 include!(concat!(env!("OUT_DIR"), "/table_inc.rs"));
 
 /// Represents the split axes of the BVH2 node(s) that a BVH4 node was created
@@ -35,3 +35,169 @@ pub fn calc_traversal_code(split: SplitAxes) -> u8 {
         SplitAxes::TopOnly(top) => top + (27 + 9 + 9),
     }
 }
+
+/// Represents the split axes of the BVH2 nodes that a BVH8 node was created
+/// from.
+///
+/// A BVH8 node is created from up to three levels of BVH2 splits: the top
+/// split, and then the splits of the left and right sub-trees below it, if
+/// any.  Its children are the leaves of those splits, in left-to-right
+/// order.
+///
+/// The values representing each axis are x = 0, y = 1, and z = 2.
+#[derive(Debug, Copy, Clone)]
+pub struct SplitAxes8 {
+    pub top: u8,
+    pub left: Option<SubSplitAxes>,
+    pub right: Option<SubSplitAxes>,
+}
+
+/// Represents the split axes of a sub-tree below the top split of a BVH8
+/// node: the sub-tree's own split, and the splits of its left and right
+/// children, if any.
+#[derive(Debug, Copy, Clone)]
+pub struct SubSplitAxes {
+    pub top: u8,
+    pub left: Option<u8>,
+    pub right: Option<u8>,
+}
+
+/// Calculates the traversal code for a BVH8 node based on the splits and
+/// topology of the BVH2 nodes it was created from.
+#[inline(always)]
+pub fn calc_traversal_code_8(split: SplitAxes8) -> u16 {
+    fn split_code(split: Option<u8>) -> u16 {
+        split.map_or(0, |axis| axis as u16 + 1)
+    }
+
+    fn sub_tree_code(split: Option<SubSplitAxes>) -> u16 {
+        split.map_or(0, |s| {
+            1 + (s.top as u16 * 16) + (split_code(s.left) * 4) + split_code(s.right)
+        })
+    }
+
+    split.top as u16 + (sub_tree_code(split.left) * 3) + (sub_tree_code(split.right) * 3 * 49)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The BVH2 splits that a BVH8 node was created from, with the indices of
+    // the leaves below each split.
+    enum Tree {
+        Leaf(u32),
+        Split(u8, Box<Tree>, Box<Tree>),
+    }
+
+    impl Tree {
+        fn leaves(&self) -> Vec<u32> {
+            match self {
+                Tree::Leaf(i) => vec![*i],
+                Tree::Split(_, left, right) => [left.leaves(), right.leaves()].concat(),
+            }
+        }
+
+        // Calls `f` with the axis and left and right leaves of every split.
+        fn for_each_split<F: FnMut(u8, &[u32], &[u32])>(&self, f: &mut F) {
+            if let Tree::Split(axis, left, right) = self {
+                f(*axis, &left.leaves(), &right.leaves());
+                left.for_each_split(f);
+                right.for_each_split(f);
+            }
+        }
+    }
+
+    fn all_sub_split_axes() -> Vec<Option<SubSplitAxes>> {
+        let axes = [None, Some(0), Some(1), Some(2)];
+        let mut all = vec![None];
+        for top in 0..3 {
+            for &left in &axes {
+                for &right in &axes {
+                    all.push(Some(SubSplitAxes {
+                        top: top,
+                        left: left,
+                        right: right,
+                    }));
+                }
+            }
+        }
+        all
+    }
+
+    fn build_tree(split: SplitAxes8) -> Tree {
+        let mut next_leaf = 0;
+        let mut leaf = || {
+            next_leaf += 1;
+            Box::new(Tree::Leaf(next_leaf - 1))
+        };
+        let mut sub_tree = |split: Option<SubSplitAxes>| match split {
+            None => leaf(),
+            Some(s) => {
+                let mut side = |axis: Option<u8>| match axis {
+                    None => leaf(),
+                    Some(axis) => {
+                        let l = leaf();
+                        Box::new(Tree::Split(axis, l, leaf()))
+                    }
+                };
+                let l = side(s.left);
+                Box::new(Tree::Split(s.top, l, side(s.right)))
+            }
+        };
+        let l = sub_tree(split.left);
+        Tree::Split(split.top, l, sub_tree(split.right))
+    }
+
+    #[test]
+    fn traversal_codes_8_are_front_to_back_permutations() {
+        let mut codes = Vec::new();
+        for top in 0..3 {
+            for &left in &all_sub_split_axes() {
+                for &right in &all_sub_split_axes() {
+                    let split = SplitAxes8 {
+                        top: top,
+                        left: left,
+                        right: right,
+                    };
+                    let code = calc_traversal_code_8(split) as usize;
+                    codes.push(code);
+                    let tree = build_tree(split);
+                    let child_count = tree.leaves().len();
+
+                    for (raydir, table) in TRAVERSAL_TABLE_8.iter().enumerate() {
+                        let order: Vec<u32> = (0..child_count)
+                            .map(|i| (table[code] >> (i * 3)) & 7)
+                            .collect();
+
+                        // Every child is visited exactly once.
+                        let mut sorted = order.clone();
+                        sorted.sort();
+                        assert_eq!(sorted, tree.leaves(), "{:?} {}", split, raydir);
+
+                        // At every split, all of the children on the near
+                        // side come before those on the far side.
+                        let pos = |leaf: &u32| order.iter().position(|n| n == leaf).unwrap();
+                        tree.for_each_split(&mut |axis, left, right| {
+                            let ray_is_neg = (raydir >> axis) & 1 == 1;
+                            let (near, far) = if ray_is_neg {
+                                (right, left)
+                            } else {
+                                (left, right)
+                            };
+                            let last_near = near.iter().map(pos).max().unwrap();
+                            let first_far = far.iter().map(pos).min().unwrap();
+                            assert!(last_near < first_far, "{:?} {}", split, raydir);
+                        });
+                    }
+                }
+            }
+        }
+
+        // Every configuration has its own code.
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), 3 * 49 * 49);
+        assert!(codes.iter().all(|&c| c < TRAVERSAL_TABLE_8[0].len()));
+    }
+}